@@ -1,10 +1,29 @@
 //! Client library for interacting with tap sessions.
 
+mod lines;
+mod reconnect;
+mod screen;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use futures_util::Stream;
 use thiserror::Error;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::UnixStream;
+use unicode_segmentation::UnicodeSegmentation;
 
-pub use tap_protocol::{Request, Response, Session, sessions_file, socket_dir, socket_path};
+pub use lines::strip_ansi;
+pub use reconnect::ReconnectPolicy;
+pub use screen::LocalScreen;
+pub use tap_protocol::{
+    Cell, Color, DiffOp, ErrorCode, InputModes, Request, Response, ScreenBuffer, Session,
+    SessionInfo, SessionStats, Snapshot, TerminalState, TranscriptEntry, apply_diff, sessions_file,
+    socket_dir, socket_path,
+};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -18,66 +37,644 @@ pub enum Error {
     SessionNotFound(String),
     #[error("Server error: {0}")]
     Server(String),
+    /// The server closed the connection before replying.
+    #[error("Connection closed by server")]
+    ConnectionClosed,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// List all active tap sessions.
 pub fn list_sessions() -> Result<Vec<Session>> {
-    let sessions_file = sessions_file();
-    let content = std::fs::read_to_string(&sessions_file).unwrap_or_else(|_| "[]".to_string());
+    list_sessions_in(&socket_dir())
+}
+
+/// List sessions in another socket directory, e.g. one belonging to a
+/// different user or container. A directory with no sessions index has no
+/// sessions; one that can't be read is an error.
+pub fn list_sessions_in(dir: &Path) -> Result<Vec<Session>> {
+    let content = match std::fs::read_to_string(dir.join("sessions.json")) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
     let sessions: Vec<Session> = serde_json::from_str(&content)?;
 
     // Filter to only sessions with valid sockets
     let sessions: Vec<Session> = sessions
         .into_iter()
-        .filter(|s| socket_path(&s.id).exists())
+        .filter(|s| dir.join(format!("{}.sock", s.id)).exists())
         .collect();
 
     Ok(sessions)
 }
 
+/// The `CUP` sequence for a 0-based `(row, col)`; the terminal counts from 1.
+fn cursor_position(row: u16, col: u16) -> String {
+    format!("\x1b[{};{}H", u32::from(row) + 1, u32::from(col) + 1)
+}
+
 /// Client for interacting with a tap session.
 pub struct Client {
     stream: BufReader<UnixStream>,
+    /// Events that arrived while waiting for a reply.
+    pending: VecDeque<Response>,
+    size_cache: Option<SizeCache>,
+    /// Whether the reply to the `stream_mode` request sent on connect is
+    /// still to come. Output pushed before it is dropped.
+    switching: bool,
+}
+
+/// Opt-in cache for `get_size_cached`.
+struct SizeCache {
+    ttl: Duration,
+    value: Option<((u16, u16), Instant)>,
 }
 
 impl Client {
-    /// Connect to a session by ID.
+    /// Connect to a session by ID, by its short ID, or by a name given with
+    /// `rename`.
     pub async fn connect(session_id: &str) -> Result<Self> {
         let path = socket_path(session_id);
         if !path.exists() {
-            return Err(Error::SessionNotFound(session_id.to_string()));
+            let named = list_sessions()
+                .unwrap_or_default()
+                .into_iter()
+                .find(|session| {
+                    session.name.as_deref() == Some(session_id)
+                        || session.short_id.as_deref() == Some(session_id)
+                });
+            return match named {
+                Some(session) => Self::connect_path(socket_path(&session.id)).await,
+                None => Err(Error::SessionNotFound(session_id.to_string())),
+            };
         }
-        let stream = UnixStream::connect(&path).await?;
-        Ok(Self {
+        Self::connect_path(&path).await
+    }
+
+    /// Connect to a session socket at an explicit path.
+    pub async fn connect_path(path: impl AsRef<Path>) -> Result<Self> {
+        let stream = UnixStream::connect(path.as_ref()).await?;
+        let mut client = Self {
             stream: BufReader::new(stream),
-        })
+            pending: VecDeque::new(),
+            size_cache: None,
+            switching: true,
+        };
+        // Only see output after subscribing. The reply is read along with
+        // the first one the caller waits for.
+        client.write_request(&Request::StreamMode).await?;
+        Ok(client)
     }
 
-    /// Connect to the most recent session.
+    /// Connect to a session socket, retrying as `policy` allows while it
+    /// can't be reached, e.g. because the session is still starting.
+    pub async fn connect_retrying(
+        path: impl AsRef<Path>,
+        policy: &ReconnectPolicy,
+    ) -> Result<Self> {
+        let mut retry = 0;
+        loop {
+            match Self::connect_path(path.as_ref()).await {
+                Ok(client) => return Ok(client),
+                Err(e) => match policy.delay(retry) {
+                    Some(delay) => {
+                        tracing::debug!("Retrying {} in {delay:?}: {e}", path.as_ref().display());
+                        tokio::time::sleep(delay).await;
+                        retry += 1;
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Connect to the most recent session that is reachable.
     pub async fn connect_latest() -> Result<Self> {
         let sessions = list_sessions()?;
-        let session = sessions.last().ok_or(Error::NoSessions)?;
-        Self::connect(&session.id).await
+        Self::connect_newest(sessions, socket_path).await
+    }
+
+    /// Try sessions newest-first, returning the first that accepts a connection.
+    async fn connect_newest(
+        mut sessions: Vec<Session>,
+        path_of: impl Fn(&str) -> PathBuf,
+    ) -> Result<Self> {
+        // Later entries win ties, so reverse before the stable sort.
+        // `started` is RFC 3339 UTC, so string order is chronological.
+        sessions.reverse();
+        sessions.sort_by(|a, b| b.started.cmp(&a.started));
+
+        for session in &sessions {
+            match Self::connect_path(path_of(&session.id)).await {
+                Ok(client) => return Ok(client),
+                Err(e) => tracing::debug!("Skipping unreachable session {}: {e}", session.id),
+            }
+        }
+        Err(Error::NoSessions)
     }
 
     async fn send_request(&mut self, request: &Request) -> Result<Response> {
-        let request_bytes = serde_json::to_vec(request)?;
-        self.stream.get_mut().write_all(&request_bytes).await?;
+        self.write_request(request).await?;
 
-        let mut line = String::new();
-        self.stream.read_line(&mut line).await?;
-        let response: Response = serde_json::from_str(&line)?;
-        Ok(response)
+        // Subscribed connections may see events before the reply; keep them
+        loop {
+            let Some(message) = self.read_message().await? else {
+                return Err(Error::ConnectionClosed);
+            };
+            if message.is_event() {
+                self.pending.push_back(message);
+            } else {
+                return Ok(message);
+            }
+        }
+    }
+
+    async fn write_request(&mut self, request: &Request) -> Result<()> {
+        let mut request_bytes = serde_json::to_vec(request)?;
+        request_bytes.push(b'\n');
+        match self.stream.get_mut().write_all(&request_bytes).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(match e.kind() {
+                std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset => {
+                    Error::ConnectionClosed
+                }
+                _ => e.into(),
+            }),
+        }
+    }
+
+    /// Read one message off the wire, noting any resize.
+    async fn read_message(&mut self) -> Result<Option<Response>> {
+        loop {
+            let mut line = String::new();
+            let n = self.stream.read_line(&mut line).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            let message: Response = serde_json::from_str(&line)?;
+            if self.switching {
+                // Either output pushed before the switch, or the reply to it.
+                // Refused (e.g. by `--allow`), the connection stays in push
+                // mode, which `send_request` copes with too.
+                self.switching = message.is_event();
+                continue;
+            }
+            if let Response::Resized { rows, cols } = message
+                && let Some(cache) = &mut self.size_cache
+            {
+                cache.value = Some(((rows, cols), Instant::now()));
+            }
+            return Ok(Some(message));
+        }
     }
 
     /// Get scrollback buffer content.
     pub async fn get_scrollback(&mut self, lines: Option<usize>) -> Result<String> {
-        let response = self.send_request(&Request::GetScrollback { lines }).await?;
+        self.get_scrollback_lines(lines, true).await
+    }
+
+    /// Get scrollback buffer content, optionally leaving out the in-progress
+    /// line (e.g. a prompt) so only completed lines are returned.
+    pub async fn get_scrollback_lines(
+        &mut self,
+        lines: Option<usize>,
+        include_current: bool,
+    ) -> Result<String> {
+        let response = self
+            .send_request(&Request::GetScrollback {
+                lines,
+                include_current,
+                buffer: None,
+            })
+            .await?;
+        match response {
+            Response::Scrollback { content } => Ok(content),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Like [`get_scrollback_lines`](Self::get_scrollback_lines), but from
+    /// the given screen regardless of which one is showing. The alternate
+    /// screen keeps a full-screen app's last contents after it exits.
+    pub async fn get_screen_buffer(
+        &mut self,
+        buffer: ScreenBuffer,
+        lines: Option<usize>,
+        include_current: bool,
+    ) -> Result<String> {
+        let response = self
+            .send_request(&Request::GetScrollback {
+                lines,
+                include_current,
+                buffer: Some(buffer),
+            })
+            .await?;
         match response {
             Response::Scrollback { content } => Ok(content),
-            Response::Error { message } => Err(Error::Server(message)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get the most recent completed lines that fit in `max_bytes`, cut on a
+    /// line boundary, and whether older lines were left out.
+    pub async fn get_scrollback_bytes(&mut self, max_bytes: usize) -> Result<(String, bool)> {
+        let response = self
+            .send_request(&Request::GetScrollbackBytes { max_bytes })
+            .await?;
+        match response {
+            Response::ScrollbackBytes { content, truncated } => Ok((content, truncated)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get the current (not yet newline-terminated) line, e.g. the prompt.
+    pub async fn get_current_line(&mut self) -> Result<String> {
+        let response = self.send_request(&Request::GetCurrentLine).await?;
+        match response {
+            Response::CurrentLine { content } => Ok(content),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get OSC 8 hyperlinks seen in the output as `(text, uri)` pairs.
+    pub async fn get_links(&mut self) -> Result<Vec<(String, String)>> {
+        let response = self.send_request(&Request::GetLinks).await?;
+        match response {
+            Response::Links { links } => Ok(links),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get bytes that reproduce the current screen when written to a fresh
+    /// terminal of the session's size.
+    pub async fn get_screen_replay(&mut self) -> Result<Vec<u8>> {
+        let response = self.send_request(&Request::GetScreenReplay).await?;
+        match response {
+            Response::ScreenReplay { data } => Ok(data),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get the terminal state to rebuild the screen locally with
+    /// [`LocalScreen::from_state`]. Apply the output of a subscription from
+    /// then on to keep the copy current.
+    pub async fn get_state(&mut self) -> Result<TerminalState> {
+        let response = self.send_request(&Request::GetState).await?;
+        match response {
+            Response::State(state) => Ok(state),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get the input modes the program has set, e.g. whether the arrows
+    /// should send application-mode sequences.
+    pub async fn get_input_modes(&mut self) -> Result<InputModes> {
+        let response = self.send_request(&Request::GetInputModes).await?;
+        match response {
+            Response::InputModes(modes) => Ok(modes),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// What changed on screen since `seq`, as ops for [`apply_diff`] to
+    /// apply to a copy of the screen as of then, and the `seq` to ask from
+    /// next time. Pass `0` to get the whole screen.
+    pub async fn get_diff_since(&mut self, seq: u64) -> Result<(Vec<DiffOp>, u64)> {
+        let response = self.send_request(&Request::GetDiffSince { seq }).await?;
+        match response {
+            Response::Diff { ops, seq } => Ok((ops, seq)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get session counters.
+    pub async fn get_stats(&mut self) -> Result<SessionStats> {
+        let response = self.send_request(&Request::GetStats).await?;
+        match response {
+            Response::Stats(stats) => Ok(stats),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Time and size of the last `count` output chunks, oldest first, as
+    /// `(elapsed_ms, byte_len)` since the session started.
+    pub async fn get_recent_chunks(&mut self, count: usize) -> Result<Vec<(u64, usize)>> {
+        let response = self
+            .send_request(&Request::GetRecentChunks { count })
+            .await?;
+        match response {
+            Response::RecentChunks { chunks } => Ok(chunks),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// The child's CPU time in seconds and peak resident set size in kB.
+    /// After it exits, the last known usage.
+    pub async fn get_resource_usage(&mut self) -> Result<(f64, u64)> {
+        let response = self.send_request(&Request::GetResourceUsage).await?;
+        match response {
+            Response::ResourceUsage {
+                cpu_secs,
+                max_rss_kb,
+            } => Ok((cpu_secs, max_rss_kb)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Output and exit code of the last finished command. Needs a shell
+    /// that marks commands with OSC 133 (shell integration); until it has
+    /// marked one, this is an error.
+    pub async fn get_last_command_output(&mut self) -> Result<(String, Option<i32>)> {
+        let response = self.send_request(&Request::GetLastCommandOutput).await?;
+        match response {
+            Response::CommandOutput { output, exit_code } => Ok((output, exit_code)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// The request types the server understands, e.g. to check for
+    /// `get_snapshot` before relying on it.
+    pub async fn capabilities(&mut self) -> Result<Vec<String>> {
+        let response = self.send_request(&Request::Capabilities).await?;
+        match response {
+            Response::Capabilities { requests } => Ok(requests),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Check on the session: whether its command is still running, the
+    /// command's PID, the server's uptime in seconds, and whether the
+    /// command is paused.
+    pub async fn health(&mut self) -> Result<(bool, u32, u64, bool)> {
+        let response = self.send_request(&Request::Health).await?;
+        match response {
+            Response::Health {
+                child_alive,
+                child_pid,
+                uptime_secs,
+                paused,
+            } => Ok((child_alive, child_pid, uptime_secs, paused)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Block until the session's command exits, returning its exit code, or
+    /// the signal that killed it (e.g. `SIGKILL`). With a `timeout`, fails
+    /// if the command is still running by then.
+    pub async fn wait_exit(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<(Option<i32>, Option<String>)> {
+        let timeout_ms = timeout.map(|t| t.as_millis().try_into().unwrap_or(u64::MAX));
+        self.write_request(&Request::WaitExit { timeout_ms })
+            .await?;
+        // The reply is `Exited`, which subscribed connections also get as an
+        // event; either way the command is done
+        loop {
+            match self.read_message().await? {
+                Some(Response::Exited { code, signal }) => return Ok((code, signal)),
+                Some(Response::Error { message, .. }) => return Err(Error::Server(message)),
+                Some(message) if message.is_event() => self.pending.push_back(message),
+                Some(_) => return Err(Error::Server("Unexpected response".to_string())),
+                None => return Err(Error::ConnectionClosed),
+            }
+        }
+    }
+
+    /// Get completed output lines interleaved with injected input. Input
+    /// only appears if the server runs with `--echo-injections`.
+    pub async fn get_transcript(&mut self) -> Result<Vec<TranscriptEntry>> {
+        let response = self.send_request(&Request::GetTranscript).await?;
+        match response {
+            Response::Transcript { entries } => Ok(entries),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Give the session a new display name, shown by `list` and accepted by
+    /// [`Client::connect`]. The session ID and socket don't change.
+    pub async fn rename(&mut self, new_name: &str) -> Result<()> {
+        let response = self
+            .send_request(&Request::Rename {
+                new_name: new_name.to_string(),
+            })
+            .await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get this session's metadata and whether its command is paused.
+    pub async fn get_session_info(&mut self) -> Result<SessionInfo> {
+        let response = self.send_request(&Request::GetSessionInfo).await?;
+        match response {
+            Response::SessionInfo(session) => Ok(session),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Advertise that this client is (or stopped) typing, so other clients
+    /// sharing the session can hold off. Advisory only.
+    pub async fn set_typing(&mut self, typing: bool) -> Result<()> {
+        let response = self.send_request(&Request::SetTyping { typing }).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get this client's ID and the IDs of clients currently typing.
+    pub async fn get_input_owners(&mut self) -> Result<(u64, Vec<u64>)> {
+        let response = self.send_request(&Request::GetInputOwners).await?;
+        match response {
+            Response::InputOwners { client_id, typing } => Ok((client_id, typing)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Clear the scrollback buffer without touching terminal state.
+    pub async fn clear_scrollback(&mut self) -> Result<()> {
+        let response = self.send_request(&Request::ClearScrollback).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// End the session, stopping its command.
+    pub async fn terminate(&mut self) -> Result<()> {
+        let response = self.send_request(&Request::Terminate).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Send signal number `signal` to the session's command, or to its
+    /// process group if `group` is set.
+    pub async fn signal(&mut self, signal: i32, group: bool) -> Result<()> {
+        let response = self
+            .send_request(&Request::Signal { signal, group })
+            .await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Stop the session's command (`SIGSTOP`), or its process group if
+    /// `group` is set, until `resume`.
+    pub async fn pause(&mut self, group: bool) -> Result<()> {
+        let response = self.send_request(&Request::Pause { group }).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Continue a command stopped by `pause` (`SIGCONT`).
+    pub async fn resume(&mut self, group: bool) -> Result<()> {
+        let response = self.send_request(&Request::Resume { group }).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Reset the terminal and scrollback model to defaults.
+    pub async fn reset_terminal(&mut self) -> Result<()> {
+        let response = self.send_request(&Request::ResetTerminal).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get completed line `index` (`None` if out of range), the total line
+    /// count, and how many lines were discarded before index 0.
+    pub async fn get_line(&mut self, index: usize) -> Result<(Option<String>, usize, u64)> {
+        let response = self.send_request(&Request::GetLine { index }).await?;
+        match response {
+            Response::Line {
+                content,
+                total,
+                discarded,
+            } => Ok((content, total, discarded)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Text of a rectangle of the screen: `rows` rows from `top`, `cols`
+    /// columns from `left`, counted from 0. Parts past the screen's edges
+    /// are left out, so fewer or shorter lines may come back.
+    pub async fn get_region(
+        &mut self,
+        top: u16,
+        left: u16,
+        rows: u16,
+        cols: u16,
+    ) -> Result<Vec<String>> {
+        Ok(self.region(top, left, rows, cols, false).await?.0)
+    }
+
+    /// Like [`get_region`](Self::get_region), as styled cells.
+    pub async fn get_region_cells(
+        &mut self,
+        top: u16,
+        left: u16,
+        rows: u16,
+        cols: u16,
+    ) -> Result<Vec<Vec<Cell>>> {
+        Ok(self.region(top, left, rows, cols, true).await?.1)
+    }
+
+    async fn region(
+        &mut self,
+        top: u16,
+        left: u16,
+        rows: u16,
+        cols: u16,
+        styled: bool,
+    ) -> Result<(Vec<String>, Vec<Vec<Cell>>)> {
+        let response = self
+            .send_request(&Request::GetRegion {
+                top,
+                left,
+                rows,
+                cols,
+                styled,
+            })
+            .await?;
+        match response {
+            Response::Region { lines, cells } => Ok((lines, cells)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get a page of the full grid (scrollback, then screen) as styled cells,
+    /// starting at row `start`, plus the total row count. The server may
+    /// return fewer rows than asked for; keep paging until `total`.
+    pub async fn get_full_grid(
+        &mut self,
+        start: usize,
+        count: Option<usize>,
+    ) -> Result<(Vec<Vec<Cell>>, usize)> {
+        let response = self
+            .send_request(&Request::GetFullGrid { start, count })
+            .await?;
+        match response {
+            Response::FullGrid { rows, total, .. } => Ok((rows, total)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Capture the visible screen's cells, cursor, and size.
+    pub async fn snapshot(&mut self) -> Result<Snapshot> {
+        let response = self.send_request(&Request::GetSnapshot).await?;
+        match response {
+            Response::Snapshot(snapshot) => Ok(snapshot),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Get the environment the child was launched with, as `(name, value)`.
+    pub async fn get_child_env(&mut self) -> Result<Vec<(String, String)>> {
+        let response = self.send_request(&Request::GetChildEnv).await?;
+        match response {
+            Response::ChildEnv { vars } => Ok(vars),
+            Response::Error { message, .. } => Err(Error::Server(message)),
             _ => Err(Error::Server("Unexpected response".to_string())),
         }
     }
@@ -87,7 +684,7 @@ impl Client {
         let response = self.send_request(&Request::GetCursor).await?;
         match response {
             Response::Cursor { row, col } => Ok((row, col)),
-            Response::Error { message } => Err(Error::Server(message)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
             _ => Err(Error::Server("Unexpected response".to_string())),
         }
     }
@@ -97,51 +694,291 @@ impl Client {
         let response = self.send_request(&Request::GetSize).await?;
         match response {
             Response::Size { rows, cols } => Ok((rows, cols)),
-            Response::Error { message } => Err(Error::Server(message)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
             _ => Err(Error::Server("Unexpected response".to_string())),
         }
     }
 
+    /// Cache sizes from `get_size_cached` for up to `ttl`. A `Resized` event
+    /// seen on this connection updates the cache immediately.
+    pub fn enable_size_cache(&mut self, ttl: Duration) {
+        self.size_cache = Some(SizeCache { ttl, value: None });
+    }
+
+    /// Like `get_size`, but served from the cache when it is enabled and
+    /// fresh. Without `enable_size_cache` this always asks the server.
+    pub async fn get_size_cached(&mut self) -> Result<(u16, u16)> {
+        if let Some(SizeCache {
+            ttl,
+            value: Some((size, at)),
+        }) = &self.size_cache
+            && at.elapsed() < *ttl
+        {
+            return Ok(*size);
+        }
+        let size = self.get_size().await?;
+        if let Some(cache) = &mut self.size_cache {
+            cache.value = Some((size, Instant::now()));
+        }
+        Ok(size)
+    }
+
     /// Inject input into the PTY.
     pub async fn inject(&mut self, data: &str) -> Result<()> {
+        self.inject_encoded(data, None).await
+    }
+
+    /// Type a cursor position (`CUP`) sequence for `(row, col)`, counted
+    /// from 0 like [`get_cursor`](Self::get_cursor). Like any input, it only
+    /// moves the cursor if the program acts on it or echoes it back raw,
+    /// e.g. an editor or a program in raw mode; a shell at its prompt won't.
+    pub async fn move_cursor(&mut self, row: u16, col: u16) -> Result<()> {
+        self.inject(&cursor_position(row, col)).await
+    }
+
+    /// Move the cursor to `(row, col)` (see [`move_cursor`](Self::move_cursor)),
+    /// then type `text`, as one injection.
+    pub async fn inject_at(&mut self, row: u16, col: u16, text: &str) -> Result<()> {
+        self.inject(&format!("{}{text}", cursor_position(row, col)))
+            .await
+    }
+
+    /// Inject input, transcoded by the server to `encoding` (e.g. `latin1`)
+    /// for programs that don't expect UTF-8.
+    pub async fn inject_encoded(&mut self, data: &str, encoding: Option<&str>) -> Result<()> {
         let response = self
             .send_request(&Request::Inject {
                 data: data.to_string(),
+                encoding: encoding.map(str::to_string),
             })
             .await?;
         match response {
             Response::Ok => Ok(()),
-            Response::Error { message } => Err(Error::Server(message)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
             _ => Err(Error::Server("Unexpected response".to_string())),
         }
     }
 
+    /// Type `text` one grapheme at a time, pausing `per_char_delay` between
+    /// each, for programs that mishandle input arriving all at once.
+    pub async fn inject_typed(&mut self, text: &str, per_char_delay: Duration) -> Result<()> {
+        for (i, grapheme) in text.graphemes(true).enumerate() {
+            if i > 0 {
+                tokio::time::sleep(per_char_delay).await;
+            }
+            self.inject(grapheme).await?;
+        }
+        Ok(())
+    }
+
     /// Subscribe to live output stream.
     /// After calling this, use `read_output()` to receive output chunks.
     pub async fn subscribe(&mut self) -> Result<()> {
         let response = self.send_request(&Request::Subscribe).await?;
         match response {
             Response::Subscribed => Ok(()),
-            Response::Error { message } => Err(Error::Server(message)),
+            Response::Error { message, .. } => Err(Error::Server(message)),
             _ => Err(Error::Server("Unexpected response".to_string())),
         }
     }
 
-    /// Read the next output chunk after subscribing.
-    /// Returns None if the connection is closed.
-    pub async fn read_output(&mut self) -> Result<Option<Vec<u8>>> {
-        let mut line = String::new();
-        let n = self.stream.read_line(&mut line).await?;
-        if n == 0 {
-            return Ok(None);
+    /// Subscribe and yield output one line at a time, without the line
+    /// ending, with escape sequences removed if `strip_ansi`. An
+    /// unterminated last line is yielded when the stream ends.
+    pub async fn subscribe_lines(
+        mut self,
+        strip_ansi: bool,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        self.subscribe().await?;
+        let state = (self, lines::LineBuffer::new(strip_ansi), VecDeque::new());
+        Ok(futures_util::stream::unfold(
+            Some(state),
+            |state| async move {
+                let (mut client, mut buffer, mut ready) = state?;
+                loop {
+                    if let Some(line) = ready.pop_front() {
+                        return Some((Ok(line), Some((client, buffer, ready))));
+                    }
+                    match client.read_output().await {
+                        Ok(Some(chunk)) => ready.extend(buffer.push(&chunk)),
+                        Ok(None) => {
+                            let line = buffer.finish()?;
+                            return Some((Ok(line), None));
+                        }
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Follow the session like a viewer: the last `lines` lines of
+    /// scrollback (all if `None`), then live output from exactly where they
+    /// end, as one stream of chunks. It ends when the session does.
+    pub async fn replay_and_follow(
+        mut self,
+        lines: Option<usize>,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>>>> {
+        let response = self
+            .send_request(&Request::ReplayAndFollow { lines })
+            .await?;
+        match response {
+            Response::Subscribed => {}
+            Response::Error { message, .. } => return Err(Error::Server(message)),
+            _ => return Err(Error::Server("Unexpected response".to_string())),
         }
-        let response: Response = serde_json::from_str(&line)?;
+        Ok(futures_util::stream::unfold(
+            Some(self),
+            |client| async move {
+                let mut client = client?;
+                match client.read_output().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk), Some(client))),
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e), None)),
+                }
+            },
+        ))
+    }
+
+    /// Subscribe to output lines matching the regex `pattern`. After calling
+    /// this, `read_output()` returns matching lines as plain text, each
+    /// ending in `\n`.
+    pub async fn subscribe_filtered(&mut self, pattern: &str) -> Result<()> {
+        let response = self
+            .send_request(&Request::SubscribeFiltered {
+                pattern: pattern.to_string(),
+            })
+            .await?;
         match response {
-            Response::Output { data } => Ok(Some(data)),
-            Response::Error { message } => Err(Error::Server(message)),
+            Response::Subscribed => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
             _ => Err(Error::Server("Unexpected response".to_string())),
         }
     }
+
+    /// Subscribe to cursor changes.
+    /// After calling this, use `read_cursor()` to receive cursor states.
+    pub async fn subscribe_cursor(&mut self) -> Result<()> {
+        let response = self.send_request(&Request::SubscribeCursor).await?;
+        match response {
+            Response::Subscribed => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Read the next `(row, col, visible)` cursor state after subscribing.
+    /// Returns None once the child exits or the connection is closed.
+    pub async fn read_cursor(&mut self) -> Result<Option<(usize, usize, bool)>> {
+        loop {
+            match self.read_event().await? {
+                None | Some(Response::Exited { .. }) => return Ok(None),
+                Some(Response::CursorMoved { row, col, visible }) => {
+                    return Ok(Some((row, col, visible)));
+                }
+                // Size changes are recorded for `get_size_cached`
+                Some(Response::Resized { .. }) => {}
+                Some(Response::Error { message, .. }) => return Err(Error::Server(message)),
+                Some(_) => return Err(Error::Server("Unexpected response".to_string())),
+            }
+        }
+    }
+
+    /// Cap this connection's injections at `per_sec` a second, so an
+    /// automation bug can't flood the session; over the cap, injecting
+    /// fails with a rate-limited error. `None` removes the cap.
+    pub async fn set_inject_rate_limit(&mut self, per_sec: Option<u32>) -> Result<()> {
+        let response = self
+            .send_request(&Request::SetInjectRateLimit { per_sec })
+            .await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Subscribe to keystrokes typed at the session's terminal; read them
+    /// with [`read_input`](Self::read_input). Refused unless the session
+    /// was started with `--share-input`.
+    pub async fn subscribe_input(&mut self) -> Result<()> {
+        let response = self.send_request(&Request::SubscribeInput).await?;
+        match response {
+            Response::Subscribed => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Read the next keystrokes after `subscribe_input`. Returns None when
+    /// the session ends.
+    pub async fn read_input(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.read_event().await? {
+            None | Some(Response::Exited { .. }) => Ok(None),
+            Some(Response::Input { data }) => Ok(Some(data)),
+            Some(Response::Error { message, .. }) => Err(Error::Server(message)),
+            Some(_) => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Read the next raw event (`Output`, `CursorMoved`, `Resized`,
+    /// `Exited`) after subscribing. Returns None if the connection is closed.
+    pub async fn read_event(&mut self) -> Result<Option<Response>> {
+        match self.pending.pop_front() {
+            Some(event) => Ok(Some(event)),
+            None => self.read_message().await,
+        }
+    }
+
+    /// Make this connection read-only: the server refuses anything that
+    /// would write to the session, such as `inject`. There's no way back.
+    pub async fn set_read_only(&mut self) -> Result<()> {
+        let response = self
+            .send_request(&Request::SetMode { read_only: true })
+            .await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Stop receiving output on this connection without unsubscribing.
+    pub async fn pause_output(&mut self) -> Result<()> {
+        let response = self.send_request(&Request::PauseOutput).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Resume output after `pause_output`. The skipped byte count arrives as
+    /// an `OutputGap` event (see `read_event`).
+    pub async fn resume_output(&mut self) -> Result<()> {
+        let response = self.send_request(&Request::ResumeOutput).await?;
+        match response {
+            Response::Ok => Ok(()),
+            Response::Error { message, .. } => Err(Error::Server(message)),
+            _ => Err(Error::Server("Unexpected response".to_string())),
+        }
+    }
+
+    /// Read the next output chunk after subscribing.
+    /// Returns None once the child exits or the connection is closed.
+    pub async fn read_output(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_event().await? {
+                None | Some(Response::Exited { .. }) => return Ok(None),
+                Some(Response::Output { data }) => return Ok(Some(data)),
+                // Size changes are recorded for `get_size_cached`; gaps are
+                // only visible through `read_event`
+                Some(Response::Resized { .. } | Response::OutputGap { .. }) => {}
+                Some(Response::Error { message, .. }) => return Err(Error::Server(message)),
+                Some(_) => return Err(Error::Server("Unexpected response".to_string())),
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -160,4 +997,145 @@ mod tests {
         let result = list_sessions();
         assert!(result.is_ok());
     }
+
+    fn session(id: &str, started: &str) -> Session {
+        Session {
+            id: id.to_string(),
+            pid: 1,
+            started: started.to_string(),
+            command: vec!["sh".to_string()],
+            description: None,
+            name: None,
+            short_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_closing_mid_request_is_connection_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("closing.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            // Read the request, then hang up without replying
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).await.unwrap();
+        });
+
+        let mut client = Client::connect_path(&path).await.unwrap();
+        assert!(matches!(
+            client.get_stats().await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connect_latest_skips_unreachable() {
+        let server = testing::MockSession::new()
+            .scrollback("reachable")
+            .spawn()
+            .unwrap();
+        let reachable = server.socket_path().to_path_buf();
+        let stale = reachable.with_file_name("stale.sock");
+
+        let sessions = vec![
+            session("older", "2024-01-01T00:00:00+00:00"),
+            session("newest", "2024-06-01T00:00:00+00:00"),
+        ];
+        let mut client = Client::connect_newest(sessions, |id| {
+            if id == "older" {
+                reachable.clone()
+            } else {
+                stale.clone()
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.get_scrollback(None).await.unwrap(), "reachable");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_lines() {
+        use futures_util::StreamExt;
+
+        let server = testing::MockSession::new()
+            .output("$ ca")
+            .output("t log\r\nfirst li")
+            .output("ne\r\n\x1b[32msecond\x1b[0m\r\n$ ")
+            .exited(Some(0), None)
+            .spawn()
+            .unwrap();
+        let client = server.connect().await.unwrap();
+
+        let lines: Vec<String> = client
+            .subscribe_lines(true)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(lines, ["$ cat log", "first line", "second", "$ "]);
+    }
+
+    #[tokio::test]
+    async fn test_inject_at_sends_cursor_position() {
+        let server = testing::MockSession::new().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+
+        client.move_cursor(0, 0).await.unwrap();
+        client.inject_at(4, 9, "ok").await.unwrap();
+        assert_eq!(server.injected(), ["\x1b[1;1H", "\x1b[5;10Hok"]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_and_follow() {
+        use futures_util::StreamExt;
+
+        let server = testing::MockSession::new()
+            .scrollback("make\nok")
+            .output("$ ")
+            .exited(Some(0), None)
+            .spawn()
+            .unwrap();
+        let client = server.connect().await.unwrap();
+
+        let chunks: Vec<Vec<u8>> = client
+            .replay_and_follow(Some(1))
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(chunks, [b"ok".to_vec(), b"$ ".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_retrying_waits_for_the_session() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("late.sock");
+        let policy = ReconnectPolicy::new().base_delay(Duration::from_millis(10));
+
+        assert!(
+            Client::connect_retrying(&path, &policy.clone().max_retries(2))
+                .await
+                .is_err()
+        );
+
+        let bind_path = path.clone();
+        let listener = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let listener = tokio::net::UnixListener::bind(bind_path).unwrap();
+            listener.accept().await.unwrap()
+        });
+        Client::connect_retrying(&path, &policy).await.unwrap();
+        listener.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_latest_none_reachable() {
+        let sessions = vec![session("gone", "2024-01-01T00:00:00+00:00")];
+        let result =
+            Client::connect_newest(sessions, |_| PathBuf::from("/nonexistent/tap.sock")).await;
+        assert!(matches!(result, Err(Error::NoSessions)));
+    }
 }
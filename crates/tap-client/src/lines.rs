@@ -0,0 +1,137 @@
+//! Output split into lines, for `Client::subscribe_lines`.
+
+/// Collects output chunks and hands back each line once it's terminated.
+///
+/// Bytes are held until a `\n` arrives, so a UTF-8 codepoint split across
+/// chunks is decoded whole (a `\n` byte never occurs inside one).
+#[derive(Debug, Default)]
+pub(crate) struct LineBuffer {
+    pending: Vec<u8>,
+    strip_ansi: bool,
+}
+
+impl LineBuffer {
+    pub(crate) fn new(strip_ansi: bool) -> Self {
+        Self {
+            pending: Vec::new(),
+            strip_ansi,
+        }
+    }
+
+    /// Add a chunk, returning the lines it completed without their `\n`
+    /// (or `\r\n`).
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let Some(last) = self.pending.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let rest = self.pending.split_off(last + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        complete[..last]
+            .split(|&b| b == b'\n')
+            .map(|line| self.decode(line))
+            .collect()
+    }
+
+    /// The unterminated line left when the stream ends, if any.
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let line = std::mem::take(&mut self.pending);
+        Some(self.decode(&line))
+    }
+
+    fn decode(&self, line: &[u8]) -> String {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let text = String::from_utf8_lossy(line);
+        if self.strip_ansi {
+            strip_ansi(&text)
+        } else {
+            text.into_owned()
+        }
+    }
+}
+
+/// Remove escape sequences: CSI (`ESC [ ... final`), OSC (`ESC ] ...`
+/// ended by BEL or `ESC \`), and other escapes such as `ESC ( B`.
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Intermediates then a final byte, e.g. `ESC ( B`
+            Some(c) if ('\x20'..='\x2f').contains(&c) => {
+                for c in chars.by_ref() {
+                    if !('\x20'..='\x2f').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_split_across_chunks() {
+        let mut buffer = LineBuffer::new(false);
+        assert!(buffer.push(b"fir").is_empty());
+        assert_eq!(buffer.push(b"st\r\nsecond\nthi"), ["first", "second"]);
+        assert_eq!(buffer.push(b"rd\n\n"), ["third", ""]);
+        assert_eq!(buffer.finish(), None);
+    }
+
+    #[test]
+    fn test_split_codepoint() {
+        let mut buffer = LineBuffer::new(false);
+        let text = "héllo\n".as_bytes();
+        assert!(buffer.push(&text[..2]).is_empty());
+        assert_eq!(buffer.push(&text[2..]), ["héllo"]);
+    }
+
+    #[test]
+    fn test_partial_line_at_end() {
+        let mut buffer = LineBuffer::new(false);
+        buffer.push(b"done\n$ ");
+        assert_eq!(buffer.finish().as_deref(), Some("$ "));
+        assert_eq!(buffer.finish(), None);
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        let mut buffer = LineBuffer::new(true);
+        let line = b"\x1b[1;31merror\x1b[0m: \x1b]8;;http://x\x1b\\link\x1b]8;;\x07 \x1b(Bok\n";
+        assert_eq!(buffer.push(line), ["error: link ok"]);
+
+        let mut raw = LineBuffer::new(false);
+        assert_eq!(raw.push(b"\x1b[1mbold\x1b[0m\n"), ["\x1b[1mbold\x1b[0m"]);
+    }
+}
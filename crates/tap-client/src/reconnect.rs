@@ -0,0 +1,131 @@
+//! How long to wait between attempts to reach a session.
+
+use std::hash::{BuildHasher, RandomState};
+use std::time::Duration;
+
+/// Exponential backoff for reconnecting. Retry `n` (from 0) waits
+/// `base_delay * 2^n`, capped at `max_delay`, less a random part of up to
+/// `jitter` of that, so clients that lost the same session don't all retry
+/// at once.
+///
+/// The default suits an interactive client: 10 retries from 100ms up to 5s.
+/// Tests may want something faster; production links that drop for minutes
+/// something more patient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up after this many retries (0 to try only once).
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Wait before the first retry; each later one waits twice as long.
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    /// Longest wait between retries.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Fraction of each wait, from 0 to 1, that may be taken off at random.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// How long to wait before retry `retry` (from 0), or `None` once the
+    /// retries are used up.
+    pub fn delay(&self, retry: u32) -> Option<Duration> {
+        self.delay_with(retry, random_unit())
+    }
+
+    /// [`delay`](Self::delay) with the random part fixed: `unit` from 0 (no
+    /// jitter) to 1 (all of it).
+    fn delay_with(&self, retry: u32, unit: f64) -> Option<Duration> {
+        if retry >= self.max_retries {
+            return None;
+        }
+        let factor = 2u32.checked_pow(retry).unwrap_or(u32::MAX);
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        Some(delay.mul_f64(1.0 - self.jitter * unit))
+    }
+}
+
+/// A number in `[0, 1)`, different on each call.
+fn random_unit() -> f64 {
+    // Each `RandomState` is seeded afresh
+    let bits = RandomState::new().hash_one(0u8);
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy::new()
+            .max_retries(8)
+            .base_delay(Duration::from_millis(10))
+            .max_delay(Duration::from_millis(200))
+            .jitter(0.5)
+    }
+
+    #[test]
+    fn test_delays_double_up_to_the_cap() {
+        let policy = policy();
+        let delays: Vec<u64> = (0..8)
+            .map(|retry| policy.delay_with(retry, 0.0).unwrap().as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [10, 20, 40, 80, 160, 200, 200, 200]);
+        assert_eq!(policy.delay(8), None);
+        assert_eq!(ReconnectPolicy::new().max_retries(0).delay(0), None);
+
+        // Far past the point of overflowing, still capped
+        let policy = policy.max_retries(u32::MAX);
+        assert_eq!(
+            policy.delay_with(200, 0.0),
+            Some(Duration::from_millis(200))
+        );
+    }
+
+    #[test]
+    fn test_jitter_stays_in_bounds() {
+        let policy = policy();
+        assert_eq!(policy.delay_with(2, 1.0), Some(Duration::from_millis(20)));
+        for _ in 0..100 {
+            let delay = policy.delay(2).unwrap();
+            assert!(delay > Duration::from_millis(20), "{delay:?}");
+            assert!(delay <= Duration::from_millis(40), "{delay:?}");
+        }
+        assert_eq!(ReconnectPolicy::new().jitter(3.0).jitter, 1.0);
+    }
+}
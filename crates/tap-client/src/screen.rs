@@ -0,0 +1,59 @@
+//! A local copy of a session's screen, for `Client::get_state`.
+
+use tap_protocol::TerminalState;
+
+/// A session's screen rebuilt from its [`TerminalState`] and kept current
+/// by pushing the output that follows, so a viewer that reconnects doesn't
+/// need the whole grid again.
+pub struct LocalScreen {
+    parser: vt100::Parser,
+}
+
+impl LocalScreen {
+    /// The screen as `state` describes it.
+    pub fn from_state(state: &TerminalState) -> Self {
+        let (rows, cols) = state.size;
+        let mut parser = vt100::Parser::new(rows, cols, 0);
+        if let Some(title) = &state.title {
+            parser.process(format!("\x1b]2;{title}\x07").as_bytes());
+        }
+        parser.process(&state.screen);
+        Self { parser }
+    }
+
+    /// Apply output from the session.
+    pub fn push(&mut self, output: &[u8]) {
+        self.parser.process(output);
+    }
+
+    /// The text of each row, without trailing blanks.
+    pub fn lines(&self) -> Vec<String> {
+        let screen = self.parser.screen();
+        let (_, cols) = screen.size();
+        screen
+            .rows(0, cols)
+            .map(|row| row.trim_end().to_string())
+            .collect()
+    }
+
+    /// The cursor as `(row, col)`, from the top left.
+    pub fn cursor_position(&self) -> (u16, u16) {
+        self.parser.screen().cursor_position()
+    }
+
+    /// Last window title the program set.
+    pub fn title(&self) -> Option<&str> {
+        Some(self.parser.screen().title()).filter(|title| !title.is_empty())
+    }
+
+    /// The state to rebuild this screen again, in the form `GetState`
+    /// replies with.
+    pub fn state(&self) -> TerminalState {
+        let screen = self.parser.screen();
+        TerminalState {
+            size: screen.size(),
+            title: self.title().map(str::to_string),
+            screen: screen.state_formatted(),
+        }
+    }
+}
@@ -0,0 +1,942 @@
+//! In-process mock server for testing code built on the client.
+//!
+//! Spins up a Unix socket in a temp directory that speaks the tap protocol
+//! against canned state, so tests don't need to fork a PTY or a shell.
+//! [`spawn_session`] starts a real `tap` session instead, for tests that do.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+
+use crate::{Client, Request, Response, Result};
+
+/// Builder for a mock session with scripted responses.
+#[derive(Debug, Clone)]
+pub struct MockSession {
+    scrollback: String,
+    current_line: String,
+    cursor: (usize, usize),
+    size: (u16, u16),
+    links: Vec<(String, String)>,
+    /// Rows for `GetFullGrid`; the scrollback as plain cells if unset.
+    grid: Option<Vec<Vec<crate::Cell>>>,
+    /// Events sent after `Subscribe`, in order.
+    output: Vec<Response>,
+    cursor_moves: Vec<(usize, usize, bool)>,
+    exit: Option<(Option<i32>, Option<String>)>,
+    input_modes: crate::InputModes,
+}
+
+impl Default for MockSession {
+    fn default() -> Self {
+        Self {
+            scrollback: String::new(),
+            current_line: String::new(),
+            cursor: (0, 0),
+            size: (24, 80),
+            links: Vec::new(),
+            grid: None,
+            output: Vec::new(),
+            cursor_moves: Vec::new(),
+            exit: None,
+            input_modes: crate::InputModes::default(),
+        }
+    }
+}
+
+impl MockSession {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the completed lines returned by `GetScrollback`. The current line
+    /// is appended unless the request excludes it.
+    #[must_use]
+    pub fn scrollback(mut self, content: impl Into<String>) -> Self {
+        self.scrollback = content.into();
+        self
+    }
+
+    /// Set the styled rows returned by `GetFullGrid`, oldest first.
+    #[must_use]
+    pub fn grid(mut self, rows: Vec<Vec<crate::Cell>>) -> Self {
+        self.grid = Some(rows);
+        self
+    }
+
+    /// Set the line returned by `GetCurrentLine`.
+    #[must_use]
+    pub fn current_line(mut self, content: impl Into<String>) -> Self {
+        self.current_line = content.into();
+        self
+    }
+
+    /// Set the position returned by `GetCursor`.
+    #[must_use]
+    pub fn cursor(mut self, row: usize, col: usize) -> Self {
+        self.cursor = (row, col);
+        self
+    }
+
+    /// Set the modes returned by `GetInputModes`.
+    #[must_use]
+    pub fn input_modes(mut self, modes: crate::InputModes) -> Self {
+        self.input_modes = modes;
+        self
+    }
+
+    /// Set the size returned by `GetSize`.
+    #[must_use]
+    pub fn size(mut self, rows: u16, cols: u16) -> Self {
+        self.size = (rows, cols);
+        self
+    }
+
+    /// Add a hyperlink returned by `GetLinks`.
+    #[must_use]
+    pub fn link(mut self, text: impl Into<String>, uri: impl Into<String>) -> Self {
+        self.links.push((text.into(), uri.into()));
+        self
+    }
+
+    /// Queue an output chunk sent to clients after they subscribe.
+    #[must_use]
+    pub fn output(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.output.push(Response::Output { data: data.into() });
+        self
+    }
+
+    /// Queue a `Resized` event among the subscription output.
+    #[must_use]
+    pub fn resized(mut self, rows: u16, cols: u16) -> Self {
+        self.output.push(Response::Resized { rows, cols });
+        self
+    }
+
+    /// Queue a cursor state sent to clients after they subscribe to the cursor.
+    #[must_use]
+    pub fn cursor_move(mut self, row: usize, col: usize, visible: bool) -> Self {
+        self.cursor_moves.push((row, col, visible));
+        self
+    }
+
+    /// End every subscription with an `Exited` event.
+    #[must_use]
+    pub fn exited(mut self, code: Option<i32>, signal: Option<&str>) -> Self {
+        self.exit = Some((code, signal.map(str::to_string)));
+        self
+    }
+
+    /// Bind the mock socket and start serving. Must be called within a Tokio runtime.
+    pub fn spawn(self) -> std::io::Result<MockServer> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mock.sock");
+        let listener = UnixListener::bind(&path)?;
+        let injected = Arc::new(Mutex::new(Vec::new()));
+
+        let session = Arc::new(self);
+        let server_injected = Arc::clone(&injected);
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(
+                    stream,
+                    Arc::clone(&session),
+                    Arc::clone(&server_injected),
+                ));
+            }
+        });
+
+        Ok(MockServer {
+            _dir: dir,
+            path,
+            injected,
+            handle,
+        })
+    }
+}
+
+/// A running mock server. Stops serving when dropped.
+pub struct MockServer {
+    _dir: tempfile::TempDir,
+    path: PathBuf,
+    injected: Arc<Mutex<Vec<String>>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Path of the mock socket.
+    #[must_use]
+    pub fn socket_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Connect a new client to the mock.
+    pub async fn connect(&self) -> Result<Client> {
+        Client::connect_path(&self.path).await
+    }
+
+    /// Everything injected so far, in arrival order.
+    #[must_use]
+    pub fn injected(&self) -> Vec<String> {
+        self.injected.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// How long [`spawn_session`] waits for the session socket to appear.
+const SESSION_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A real `tap` session started by [`spawn_session`]. Kills the server and
+/// removes its socket directory when dropped.
+pub struct SessionGuard {
+    child: std::process::Child,
+    /// Held open: a PTY session ends when its stdin closes.
+    _stdin: Option<std::process::ChildStdin>,
+    dir: tempfile::TempDir,
+    id: String,
+}
+
+impl SessionGuard {
+    /// ID of the session.
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Private socket directory the session lives in.
+    #[must_use]
+    pub fn socket_dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Connect another client to the session.
+    pub async fn connect(&self) -> Result<Client> {
+        Client::connect_path(self.dir.path().join(format!("{}.sock", self.id))).await
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Launch `tap start -- <command>` in a temp socket directory and connect to
+/// it once the socket is ready.
+///
+/// The binary is taken from `TAP_BIN`, falling back to `tap` on `PATH`.
+/// Integration tests in a crate that builds `tap` should prefer
+/// [`spawn_session_with`] and `env!("CARGO_BIN_EXE_tap")`.
+pub async fn spawn_session(command: &[&str]) -> Result<(SessionGuard, Client)> {
+    let binary = std::env::var_os("TAP_BIN").unwrap_or_else(|| "tap".into());
+    spawn_session_with(binary, command).await
+}
+
+/// Like [`spawn_session`], with an explicit path to the `tap` binary.
+pub async fn spawn_session_with(
+    binary: impl AsRef<std::ffi::OsStr>,
+    command: &[&str],
+) -> Result<(SessionGuard, Client)> {
+    use std::process::{Command, Stdio};
+
+    let dir = tempfile::tempdir()?;
+    let mut child = Command::new(binary)
+        .arg("start")
+        .arg("--")
+        .args(command)
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdin = child.stdin.take();
+    let mut guard = SessionGuard {
+        child,
+        _stdin: stdin,
+        dir,
+        id: String::new(),
+    };
+
+    let deadline = tokio::time::Instant::now() + SESSION_READY_TIMEOUT;
+    loop {
+        if let Some(session) = crate::list_sessions_in(guard.dir.path())?
+            .into_iter()
+            .next()
+        {
+            guard.id = session.id;
+            if let Ok(client) = guard.connect().await {
+                return Ok((guard, client));
+            }
+        }
+        if let Some(status) = guard.child.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "tap exited before its session was ready: {status}"
+            ))
+            .into());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out waiting for the tap session socket",
+            )
+            .into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Panic unless a row of the visible screen contains `text`. The message
+/// shows the whole screen with the cursor marked.
+pub async fn assert_screen_contains(client: &mut Client, text: &str) {
+    let snapshot = client.snapshot().await.expect("failed to read the screen");
+    if !screen_lines(&snapshot)
+        .iter()
+        .any(|line| line.contains(text))
+    {
+        panic!(
+            "screen does not contain {text:?}\n{}",
+            render_screen(&snapshot)
+        );
+    }
+}
+
+/// Panic unless screen row `row` (from 0) reads `expected`, ignoring
+/// trailing blanks. The message shows the whole screen with the cursor
+/// marked.
+pub async fn assert_line_eq(client: &mut Client, row: usize, expected: &str) {
+    let snapshot = client.snapshot().await.expect("failed to read the screen");
+    let lines = screen_lines(&snapshot);
+    let actual = lines.get(row).map_or("", String::as_str);
+    if actual != expected.trim_end() {
+        panic!(
+            "row {row} is {actual:?}, expected {expected:?}\n{}",
+            render_screen(&snapshot)
+        );
+    }
+}
+
+/// Each screen row's text, one column per cell, without trailing blanks.
+fn screen_lines(snapshot: &crate::Snapshot) -> Vec<String> {
+    snapshot
+        .rows
+        .iter()
+        .map(|row| {
+            let line: String = row
+                .iter()
+                .map(|cell| match cell.text.as_str() {
+                    "" => " ",
+                    text => text,
+                })
+                .collect();
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+/// The screen for a failure message: numbered rows down to the last one
+/// with text (or the cursor's), and a `^` under the cursor.
+fn render_screen(snapshot: &crate::Snapshot) -> String {
+    let lines = screen_lines(snapshot);
+    let (cursor_row, cursor_col) = snapshot.cursor;
+    let end = lines
+        .iter()
+        .rposition(|line| !line.is_empty())
+        .map_or(0, |i| i + 1)
+        .max(cursor_row + 1);
+    let (rows, cols) = snapshot.size;
+    let mut out = format!("screen ({rows}x{cols}, cursor at {cursor_row},{cursor_col}):\n");
+    for row in 0..end {
+        let line = lines.get(row).map_or("", String::as_str);
+        let _ = writeln!(out, "{row:>4} |{line}");
+        if row == cursor_row {
+            let _ = writeln!(out, "     |{}^", " ".repeat(cursor_col));
+        }
+    }
+    out
+}
+
+fn tail(content: &str, lines: Option<usize>) -> String {
+    match lines {
+        Some(n) => {
+            let lines: Vec<&str> = content.lines().collect();
+            let start = lines.len().saturating_sub(n);
+            lines[start..].join("\n")
+        }
+        None => content.to_string(),
+    }
+}
+
+/// Unstyled cells for each line of `text`.
+fn plain_rows(text: &str) -> Vec<Vec<crate::Cell>> {
+    text.lines()
+        .map(|line| {
+            line.chars()
+                .map(|c| crate::Cell {
+                    text: c.to_string(),
+                    ..crate::Cell::default()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+async fn write_response(stream: &mut UnixStream, response: &Response) -> std::io::Result<()> {
+    let mut bytes = serde_json::to_vec(response)?;
+    bytes.push(b'\n');
+    stream.write_all(&bytes).await
+}
+
+fn exited(session: &MockSession) -> Option<Response> {
+    let (code, signal) = session.exit.clone()?;
+    Some(Response::Exited { code, signal })
+}
+
+/// Confirm a subscription, then send its scripted events.
+async fn write_events(
+    stream: &mut UnixStream,
+    events: impl Iterator<Item = Response>,
+) -> std::io::Result<()> {
+    write_response(stream, &Response::Subscribed).await?;
+    for event in events {
+        write_response(stream, &event).await?;
+    }
+    Ok(())
+}
+
+async fn serve(
+    mut stream: UnixStream,
+    session: Arc<MockSession>,
+    injected: Arc<Mutex<Vec<String>>>,
+) {
+    let mut buf = Vec::with_capacity(4096);
+    let mut read_only = false;
+
+    loop {
+        let frame = match tap_protocol::next_frame(&mut buf) {
+            Some(frame) => frame,
+            None => match stream.read_buf(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            },
+        };
+
+        let Ok(request) = serde_json::from_slice::<Request>(&frame) else {
+            continue;
+        };
+
+        let response = match request {
+            _ if read_only && request.writes_to_session() => Response::Error {
+                message: "Connection is read-only".to_string(),
+                code: None,
+            },
+            Request::SetMode { read_only: mode } => {
+                read_only |= mode;
+                Response::Ok
+            }
+            Request::GetScrollback {
+                buffer: Some(crate::ScreenBuffer::Alternate),
+                ..
+            } => Response::Scrollback {
+                content: String::new(),
+            },
+            Request::GetScrollback {
+                lines,
+                include_current,
+                ..
+            } => {
+                let mut content = session.scrollback.clone();
+                if include_current && !session.current_line.is_empty() {
+                    content.push('\n');
+                    content.push_str(&session.current_line);
+                }
+                Response::Scrollback {
+                    content: tail(&content, lines),
+                }
+            }
+            Request::GetScrollbackBytes { max_bytes } => {
+                let lines: Vec<&str> = session.scrollback.lines().collect();
+                let mut start = lines.len();
+                let mut used = 0;
+                while start > 0 {
+                    let needed = lines[start - 1].len() + usize::from(start < lines.len());
+                    if used + needed > max_bytes {
+                        break;
+                    }
+                    used += needed;
+                    start -= 1;
+                }
+                Response::ScrollbackBytes {
+                    content: lines[start..].join("\n"),
+                    truncated: start > 0,
+                }
+            }
+            Request::GetCurrentLine => Response::CurrentLine {
+                content: session.current_line.clone(),
+            },
+            Request::GetLinks => Response::Links {
+                links: session.links.clone(),
+            },
+            Request::GetScreenReplay => Response::ScreenReplay {
+                data: session.scrollback.clone().into_bytes(),
+            },
+            Request::SetInjectRateLimit { .. } => Response::Ok,
+            Request::SubscribeInput => Response::Error {
+                message: "Input is not shared on this session".to_string(),
+                code: Some(crate::ErrorCode::PermissionDenied),
+            },
+            Request::GetState => Response::State(crate::TerminalState {
+                size: session.size,
+                title: None,
+                screen: session.scrollback.clone().into_bytes(),
+            }),
+            Request::GetDiffSince { .. } => {
+                let (rows, cols) = session.size;
+                let mut ops = vec![crate::DiffOp::Reset { rows, cols }];
+                ops.extend(
+                    (0..rows)
+                        .zip(session.scrollback.lines())
+                        .map(|(row, line)| crate::DiffOp::SetLine {
+                            row,
+                            content: line.to_string(),
+                        }),
+                );
+                Response::Diff { ops, seq: 1 }
+            }
+            Request::GetStats => Response::Stats(crate::SessionStats::default()),
+            Request::GetInputModes => Response::InputModes(session.input_modes),
+            Request::GetRecentChunks { .. } => Response::RecentChunks { chunks: Vec::new() },
+            Request::GetResourceUsage => Response::ResourceUsage {
+                cpu_secs: 0.0,
+                max_rss_kb: 0,
+            },
+            Request::Health => Response::Health {
+                child_alive: session.exit.is_none(),
+                child_pid: 1,
+                uptime_secs: 0,
+                paused: false,
+            },
+            Request::WaitExit { timeout_ms } => match &session.exit {
+                Some((code, signal)) => Response::Exited {
+                    code: *code,
+                    signal: signal.clone(),
+                },
+                None => {
+                    if let Some(ms) = timeout_ms {
+                        tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+                    }
+                    Response::Error {
+                        message: "Command still running".to_string(),
+                        code: Some(crate::ErrorCode::TimedOut),
+                    }
+                }
+            },
+            Request::GetSessionInfo => Response::SessionInfo(crate::SessionInfo {
+                session: crate::Session {
+                    id: "mock".to_string(),
+                    pid: std::process::id(),
+                    started: String::new(),
+                    command: Vec::new(),
+                    description: None,
+                    name: None,
+                    short_id: None,
+                },
+                paused: false,
+            }),
+            Request::GetCursor => Response::Cursor {
+                row: session.cursor.0,
+                col: session.cursor.1,
+            },
+            Request::GetSize => Response::Size {
+                rows: session.size.0,
+                cols: session.size.1,
+            },
+            Request::ClearScrollback
+            | Request::ResetTerminal
+            | Request::SetTyping { .. }
+            | Request::StreamMode
+            | Request::PauseOutput
+            | Request::ResumeOutput
+            | Request::Terminate
+            | Request::Signal { .. }
+            | Request::Pause { .. }
+            | Request::Resume { .. }
+            | Request::Rename { .. } => Response::Ok,
+            Request::GetChildEnv => Response::ChildEnv { vars: Vec::new() },
+            Request::GetLastCommandOutput => Response::Error {
+                message: "No finished command yet".to_string(),
+                code: None,
+            },
+            Request::Capabilities => Response::Capabilities {
+                requests: tap_protocol::REQUEST_TYPES
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect(),
+            },
+            Request::GetLine { index } => {
+                let lines: Vec<&str> = session.scrollback.lines().collect();
+                Response::Line {
+                    content: lines.get(index).map(|line| line.to_string()),
+                    total: lines.len(),
+                    discarded: 0,
+                }
+            }
+            Request::GetTranscript => Response::Transcript {
+                entries: session
+                    .scrollback
+                    .lines()
+                    .map(|line| crate::TranscriptEntry::Output {
+                        text: line.to_string(),
+                    })
+                    .collect(),
+            },
+            Request::GetFullGrid { start, count } => {
+                let rows = session
+                    .grid
+                    .clone()
+                    .unwrap_or_else(|| plain_rows(&session.scrollback));
+                let total = rows.len();
+                Response::FullGrid {
+                    rows: rows
+                        .into_iter()
+                        .skip(start)
+                        .take(count.unwrap_or(usize::MAX))
+                        .collect(),
+                    start,
+                    total,
+                }
+            }
+            Request::GetSnapshot => Response::Snapshot(crate::Snapshot {
+                size: session.size,
+                cursor: session.cursor,
+                rows: plain_rows(&session.scrollback),
+            }),
+            Request::GetRegion {
+                top,
+                left,
+                rows,
+                cols,
+                styled,
+            } => {
+                let cells: Vec<Vec<crate::Cell>> = plain_rows(&session.scrollback)
+                    .into_iter()
+                    .skip(usize::from(top))
+                    .take(usize::from(rows))
+                    .map(|row| {
+                        let row = row.into_iter().skip(usize::from(left));
+                        row.take(usize::from(cols)).collect()
+                    })
+                    .collect();
+                let lines = cells
+                    .iter()
+                    .map(|row| row.iter().map(|cell| cell.text.as_str()).collect())
+                    .collect();
+                Response::Region {
+                    lines,
+                    cells: if styled { cells } else { Vec::new() },
+                }
+            }
+            Request::GetInputOwners => Response::InputOwners {
+                client_id: 1,
+                typing: Vec::new(),
+            },
+            Request::Inject { data, .. } => {
+                injected.lock().unwrap().push(data);
+                Response::Ok
+            }
+            // The scripted output stands in for whatever would match
+            Request::Subscribe | Request::SubscribeFiltered { .. } => {
+                let events = session.output.iter().cloned();
+                if write_events(&mut stream, events.chain(exited(&session)))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            Request::ReplayAndFollow { lines } => {
+                let replay = tail(&session.scrollback, lines).replace('\n', "\r\n");
+                let replay = (!replay.is_empty()).then(|| Response::Output {
+                    data: replay.into_bytes(),
+                });
+                let events = replay.into_iter().chain(session.output.iter().cloned());
+                if write_events(&mut stream, events.chain(exited(&session)))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            Request::SubscribeCursor => {
+                let events = session.cursor_moves.iter().copied();
+                let events =
+                    events.map(|(row, col, visible)| Response::CursorMoved { row, col, visible });
+                if write_events(&mut stream, events.chain(exited(&session)))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        if write_response(&mut stream, &response).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt_session() -> MockSession {
+        MockSession::new()
+            .scrollback("$ make\nerror: disk full\n$")
+            .cursor(2, 2)
+    }
+
+    #[tokio::test]
+    async fn test_screen_assertions_pass() {
+        let server = prompt_session().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_screen_contains(&mut client, "disk full").await;
+        assert_line_eq(&mut client, 1, "error: disk full").await;
+        assert_line_eq(&mut client, 5, "").await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "row 1 is \"error: disk full\", expected \"ok\"")]
+    async fn test_screen_assertion_fails() {
+        let server = prompt_session().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_line_eq(&mut client, 1, "ok").await;
+    }
+
+    #[tokio::test]
+    async fn test_screen_dump() {
+        let server = prompt_session().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        let snapshot = client.snapshot().await.unwrap();
+        assert_eq!(
+            render_screen(&snapshot),
+            "screen (24x80, cursor at 2,2):\n   \
+               0 |$ make\n   \
+               1 |error: disk full\n   \
+               2 |$\n     \
+                 |  ^\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_scrollback() {
+        let server = MockSession::new()
+            .scrollback("one\ntwo\nthree")
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            client.get_scrollback(None).await.unwrap(),
+            "one\ntwo\nthree"
+        );
+        assert_eq!(client.get_scrollback(Some(2)).await.unwrap(), "two\nthree");
+    }
+
+    #[tokio::test]
+    async fn test_get_scrollback_current_line() {
+        let server = MockSession::new()
+            .scrollback("one\ntwo")
+            .current_line("$ ")
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            client.get_scrollback_lines(None, true).await.unwrap(),
+            "one\ntwo\n$ "
+        );
+        assert_eq!(
+            client.get_scrollback_lines(None, false).await.unwrap(),
+            "one\ntwo"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_scrollback_bytes() {
+        let server = MockSession::new()
+            .scrollback("one\ntwo\nthree")
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            client.get_scrollback_bytes(13).await.unwrap(),
+            ("one\ntwo\nthree".to_string(), false)
+        );
+        assert_eq!(
+            client.get_scrollback_bytes(12).await.unwrap(),
+            ("two\nthree".to_string(), true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_current_line() {
+        let server = MockSession::new().current_line("$ ").spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(client.get_current_line().await.unwrap(), "$ ");
+    }
+
+    #[tokio::test]
+    async fn test_get_links() {
+        let server = MockSession::new()
+            .link("docs", "https://example.com")
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            client.get_links().await.unwrap(),
+            vec![("docs".to_string(), "https://example.com".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_cursor() {
+        let server = MockSession::new().cursor(3, 7).spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(client.get_cursor().await.unwrap(), (3, 7));
+    }
+
+    #[tokio::test]
+    async fn test_get_size() {
+        let server = MockSession::new().size(50, 132).spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(client.get_size().await.unwrap(), (50, 132));
+    }
+
+    #[tokio::test]
+    async fn test_inject() {
+        let server = MockSession::new().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.inject("ls\n").await.unwrap();
+        assert_eq!(server.injected(), vec!["ls\n".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_inject_typed() {
+        let server = MockSession::new().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        let start = std::time::Instant::now();
+        client
+            .inject_typed("he\u{301}y\n", std::time::Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(30));
+        assert_eq!(server.injected(), ["h", "e\u{301}", "y", "\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let server = MockSession::new()
+            .output(b"hello ".to_vec())
+            .output(b"world".to_vec())
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.subscribe().await.unwrap();
+        assert_eq!(
+            client.read_output().await.unwrap(),
+            Some(b"hello ".to_vec())
+        );
+        assert_eq!(client.read_output().await.unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_size_cache_updated_by_resize() {
+        let server = MockSession::new()
+            .size(24, 80)
+            .resized(50, 120)
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.enable_size_cache(std::time::Duration::from_secs(60));
+        assert_eq!(client.get_size_cached().await.unwrap(), (24, 80));
+
+        client.subscribe().await.unwrap();
+        assert!(matches!(
+            client.read_event().await.unwrap(),
+            Some(Response::Resized {
+                rows: 50,
+                cols: 120
+            })
+        ));
+        // Served from the cache: the mock itself still answers 24x80
+        assert_eq!(client.get_size_cached().await.unwrap(), (50, 120));
+        assert_eq!(client.get_size().await.unwrap(), (24, 80));
+    }
+
+    #[tokio::test]
+    async fn test_size_cache_expires() {
+        let server = MockSession::new()
+            .size(24, 80)
+            .resized(50, 120)
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.enable_size_cache(std::time::Duration::ZERO);
+        client.subscribe().await.unwrap();
+        client.read_event().await.unwrap();
+        assert_eq!(client.get_size_cached().await.unwrap(), (24, 80));
+    }
+
+    #[tokio::test]
+    async fn test_events_before_reply_are_kept() {
+        let server = MockSession::new()
+            .output(b"early".to_vec())
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.subscribe().await.unwrap();
+        // The output event is already in flight ahead of this reply
+        assert_eq!(client.get_size().await.unwrap(), (24, 80));
+        assert_eq!(client.read_output().await.unwrap(), Some(b"early".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_ends_with_exit() {
+        let server = MockSession::new()
+            .output(b"bye".to_vec())
+            .exited(None, Some("SIGSEGV"))
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.subscribe().await.unwrap();
+        assert_eq!(client.read_output().await.unwrap(), Some(b"bye".to_vec()));
+        assert!(matches!(
+            client.read_event().await.unwrap(),
+            Some(Response::Exited { code: None, signal: Some(ref s) }) if s == "SIGSEGV"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_exit() {
+        let server = MockSession::new().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert!(client.health().await.unwrap().0);
+
+        let server = MockSession::new().exited(Some(0), None).spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert!(!client.health().await.unwrap().0);
+    }
+}
@@ -1,4 +1,34 @@
 //! Shared protocol types for tap terminal sessions.
+//!
+//! # Wire format
+//!
+//! The conversation over a session socket is JSON Lines in both directions:
+//!
+//! ```text
+//! client -> server:  request  = <Request JSON object> "\n"
+//! server -> client:  message  = <Response JSON object> "\n"
+//! ```
+//!
+//! Every object carries a `type` tag. Each request gets exactly one reply,
+//! in order. After `subscribe` (or `subscribe_cursor`), the server also
+//! pushes *events* at any time, interleaved between replies: `output` (or
+//! `cursor_moved`), `output_gap`, `resized`, then a final `exited` when the
+//! child ends. Use
+//! [`Response::is_event`] to tell them apart. Blank lines are ignored. For
+//! compatibility, a single request sent without a trailing newline is still
+//! accepted.
+//!
+//! ## Modes
+//!
+//! A new connection starts in *push mode*, as servers always have: every
+//! chunk of output is pushed to it as an `output` event from the moment it
+//! connects, subscribed or not. A client that reads replies line by line
+//! has to skip those.
+//!
+//! Sending `stream_mode` (reply `ok`) switches the connection to *stream
+//! mode*, where nothing arrives unasked: each request gets its reply, and
+//! events only follow a `subscribe*` request. Send it first, before
+//! subscribing. `tap_client::Client` always does.
 
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +39,176 @@ pub struct Session {
     pub pid: u32,
     pub started: String,
     pub command: Vec<String>,
+    /// Free-text description given at launch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Display name set with `rename`. Unlike `id`, which names the socket,
+    /// it can change while the session runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Short code accepted in place of `id`, unique among running sessions
+    /// when it was assigned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub short_id: Option<String>,
+}
+
+/// A running session's metadata together with its live state, as
+/// `get_session_info` replies with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: Session,
+    /// Whether the command is stopped by `pause`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+}
+
+/// Counters describing a running session.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionStats {
+    /// Bytes read from the child.
+    pub bytes_out: u64,
+    /// Bytes written to the child (stdin and injections).
+    pub bytes_in: u64,
+    /// Output bytes skipped by scrollback while shedding load.
+    pub dropped_scrollback_bytes: u64,
+    /// Scrollback updates slow enough to trigger load shedding.
+    pub slow_pushes: u64,
+    /// Lines trimmed off the front of scrollback so far.
+    pub lines_discarded: u64,
+    /// Output bytes not written to `--output-fifo` because no reader was
+    /// connected or it fell behind.
+    pub dropped_fifo_bytes: u64,
+    /// Output skipped by lagging subscribers to stay within
+    /// `--memory-budget`.
+    pub shed_subscriber_bytes: u64,
+    /// Approximate bytes held in scrollback and subscriber queues.
+    pub memory_bytes: u64,
+    /// Connections currently subscribed to output.
+    pub subscribers: u64,
+}
+
+/// One terminal cell: its text and style.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cell {
+    /// The cell's character(s); empty for a blank cell or the right half of
+    /// a wide character.
+    pub text: String,
+    /// Foreground color, `None` for the terminal default.
+    pub fg: Option<Color>,
+    /// Background color, `None` for the terminal default.
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+}
+
+/// The visible screen at one moment, as saved by `tap snapshot` and
+/// compared by `tap diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Snapshot {
+    /// Terminal size as `(rows, cols)`.
+    pub size: (u16, u16),
+    /// Cursor position as `(row, col)`.
+    pub cursor: (usize, usize),
+    /// Styled cells of each screen row, top to bottom.
+    pub rows: Vec<Vec<Cell>>,
+}
+
+/// The session's terminal state, enough to build a local copy of its screen
+/// and keep it current by applying the output that follows.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerminalState {
+    /// Grid size as `(rows, cols)`.
+    pub size: (u16, u16),
+    /// Window title, if the program set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Escape sequences that reproduce the screen on a fresh terminal of
+    /// that size: styled cells, cursor, current attributes, input modes.
+    pub screen: Vec<u8>,
+}
+
+/// Input modes the program has set, which change what some keys send.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputModes {
+    /// Cursor keys in application mode (DECCKM, `CSI ? 1 h`), as in `vim`
+    /// or `less`: the arrows send `ESC O A` rather than `ESC [ A`.
+    pub application_cursor: bool,
+    /// Keypad in application mode (DECKPAM, `ESC =`).
+    pub application_keypad: bool,
+}
+
+/// One step of a screen diff (`GetDiffSince`), on the screen's text with
+/// one string per row.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum DiffOp {
+    /// Start over with `rows` blank rows.
+    Reset { rows: u16, cols: u16 },
+    /// Move every row up `n`; the bottom `n` rows become blank.
+    ScrollUp { n: u16 },
+    /// Replace the text of row `row`.
+    SetLine { row: u16, content: String },
+}
+
+/// Apply diff `ops` to `screen`, one string per row.
+pub fn apply_diff(screen: &mut Vec<String>, ops: &[DiffOp]) {
+    for op in ops {
+        match op {
+            DiffOp::Reset { rows, .. } => *screen = vec![String::new(); usize::from(*rows)],
+            DiffOp::ScrollUp { n } => {
+                let n = usize::from(*n).min(screen.len());
+                screen.drain(..n);
+                screen.resize(screen.len() + n, String::new());
+            }
+            DiffOp::SetLine { row, content } => {
+                if let Some(line) = screen.get_mut(usize::from(*row)) {
+                    line.clone_from(content);
+                }
+            }
+        }
+    }
+}
+
+/// A cell color: a palette index (0-255) or 24-bit RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Color {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// One entry of a session transcript, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TranscriptEntry {
+    /// A completed line of output.
+    Output { text: String },
+    /// Text injected by a client, recorded when the server runs with
+    /// `--echo-injections`. Follows the line it was typed on.
+    Input { text: String },
+}
+
+/// One of the terminal's two screens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreenBuffer {
+    /// The normal screen, e.g. the shell.
+    Primary,
+    /// The screen full-screen apps switch to (`CSI ? 1049 h`). Once the app
+    /// switches back, this holds its last contents.
+    Alternate,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 /// Client requests to the server.
@@ -16,40 +216,459 @@ pub struct Session {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Request {
     /// Get the last N lines from scrollback buffer.
-    GetScrollback { lines: Option<usize> },
+    GetScrollback {
+        lines: Option<usize>,
+        /// Include the in-progress line at the cursor, e.g. a prompt.
+        #[serde(default = "default_true")]
+        include_current: bool,
+        /// Read this screen instead of whichever one is showing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        buffer: Option<ScreenBuffer>,
+    },
+    /// Get the most recent completed lines that fit in `max_bytes`,
+    /// truncated on a line boundary.
+    GetScrollbackBytes { max_bytes: usize },
     /// Get current cursor position.
     GetCursor,
-    /// Inject input into the PTY.
-    Inject { data: String },
+    /// Inject input into the PTY. Several clients may inject into the same
+    /// session; each payload is written contiguously, so input from different
+    /// clients interleaves only at request boundaries.
+    Inject {
+        data: String,
+        /// Encoding to send `data` in, e.g. `latin1` (default UTF-8). Unknown
+        /// encodings and unrepresentable text are rejected.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<String>,
+    },
     /// Get terminal size.
     GetSize,
-    /// Subscribe to live output.
+    /// Subscribe to live output. Everything output after the server handles
+    /// this request is delivered, starting right after the `subscribed`
+    /// reply; use `get_screen_replay` or `get_scrollback` for what came before.
+    /// In push mode, output is already flowing and keeps doing so.
     Subscribe,
+    /// Get the current (not yet newline-terminated) line, e.g. a prompt.
+    GetCurrentLine,
+    /// Get OSC 8 hyperlinks seen in the output.
+    GetLinks,
+    /// Get bytes that reproduce the current screen in a fresh terminal.
+    GetScreenReplay,
+    /// Get session counters.
+    GetStats,
+    /// Get the time and size of the last `count` reads from the child
+    /// (the server remembers a few hundred).
+    GetRecentChunks { count: usize },
+    /// Get CPU time and peak memory of the child. Once it has exited, the
+    /// last known usage.
+    GetResourceUsage,
+    /// Get this session's metadata.
+    GetSessionInfo,
+    /// Switch this connection to stream mode: output is no longer pushed
+    /// unasked, only after a `subscribe*` request (see the crate docs).
+    StreamMode,
+    /// Advertise that this connection is (or stopped) typing. Advisory only.
+    SetTyping { typing: bool },
+    /// Get which connections are currently marked as typing.
+    GetInputOwners,
+    /// Clear the scrollback buffer. The child and the real terminal are untouched.
+    ClearScrollback,
+    /// Reset to a clean slate: clear the scrollback model and send a full
+    /// terminal reset (`ESC c`) to the local terminal and subscribers, so SGR,
+    /// cursor, and scroll-region state all return to defaults. Unlike
+    /// `ClearScrollback`, which only empties the buffer, this resets every
+    /// terminal showing the session too.
+    ///
+    /// The reset goes out with the output rather than into the PTY: that
+    /// state belongs to the terminals drawing the child's output, while bytes
+    /// written to the PTY reach the child as typed input (`ESC c` would read
+    /// as Alt-C).
+    ResetTerminal,
+    /// Subscribe to cursor changes. The current state is sent immediately,
+    /// then one `cursor_moved` event per change.
+    SubscribeCursor,
+    /// Get the environment the child was launched with (secrets redacted).
+    GetChildEnv,
+    /// Get one completed line by index, counting from the oldest retained.
+    GetLine { index: usize },
+    /// Stop delivering `output` events to this connection, without
+    /// unsubscribing. Output in the meantime is skipped, not buffered.
+    PauseOutput,
+    /// Resume `output` events; an `output_gap` event reports what was skipped.
+    ResumeOutput,
+    /// End the session: the child gets `SIGTERM`, then `SIGKILL` if it is
+    /// still running after the server's grace period.
+    Terminate,
+    /// Send a signal (by number) to the child, or to its whole process group.
+    /// `SIGKILL` and `SIGSTOP` are refused; use `Terminate` or `Pause`.
+    Signal {
+        signal: i32,
+        #[serde(default)]
+        group: bool,
+    },
+    /// Stop the child with `SIGSTOP`, or its whole process group, until
+    /// `Resume`. It produces no output meanwhile; the server keeps answering.
+    Pause {
+        #[serde(default)]
+        group: bool,
+    },
+    /// Continue a child stopped by `Pause` (`SIGCONT`).
+    Resume {
+        #[serde(default)]
+        group: bool,
+    },
+    /// Check whether the session's command is still running.
+    Health,
+    /// Reply with `Exited` once the session's command exits, or with a
+    /// `timed_out` error after `timeout_ms`. Nothing else is answered on
+    /// this connection meanwhile.
+    WaitExit { timeout_ms: Option<u64> },
+    /// Get the whole grid, scrolled-off rows then the screen, as styled
+    /// cells: `count` rows from row `start` (oldest first). The server caps
+    /// the page size, so large buffers are fetched a page at a time.
+    GetFullGrid {
+        #[serde(default)]
+        start: usize,
+        #[serde(default)]
+        count: Option<usize>,
+    },
+    /// Get the visible screen's cells with the cursor and size.
+    GetSnapshot,
+    /// Get a rectangle of the visible screen: `rows` rows from row `top`,
+    /// `cols` columns from column `left` (all from 0). The rectangle is
+    /// clipped to the screen, so rows past the bottom are left out and rows
+    /// past the right edge come back shorter. With `styled`, cells too.
+    GetRegion {
+        top: u16,
+        left: u16,
+        rows: u16,
+        cols: u16,
+        #[serde(default)]
+        styled: bool,
+    },
+    /// Get completed output lines interleaved with recorded injected input.
+    GetTranscript,
+    /// Get the output of the last finished command, as marked by a shell
+    /// with OSC 133 integration. An error until the shell has marked one.
+    GetLastCommandOutput,
+    /// Set this connection's mode. A read-only connection can watch but not
+    /// touch: requests that write to the session (see
+    /// [`Request::writes_to_session`]) are refused. It can't be switched back.
+    SetMode { read_only: bool },
+    /// Give the session a new display name. The ID and socket path stay the
+    /// same, so connected clients are unaffected. Fails if another running
+    /// session already has that name or ID.
+    Rename { new_name: String },
+    /// Like `Subscribe`, but `output` events carry only completed lines that
+    /// match the regex `pattern`, as plain text with each line ending in
+    /// `\n`. An invalid pattern gets an error and no subscription.
+    SubscribeFiltered { pattern: String },
+    /// Subscribe, first replaying the last `lines` lines of scrollback (all
+    /// if `None`) as an `output` event after the `subscribed` reply. Live
+    /// output continues where the replay ends: nothing produced in between
+    /// is missed or sent twice.
+    ReplayAndFollow {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lines: Option<usize>,
+    },
+    /// List the request types this server understands, to feature-detect
+    /// without going by version.
+    Capabilities,
+    /// Get the terminal state (grid size, screen, cursor, attributes, modes,
+    /// title) to rebuild the screen locally, then follow it by applying
+    /// `output` events rather than fetching the whole grid again.
+    GetState,
+    /// Subscribe to keystrokes typed at the session's own terminal, as
+    /// `input` events of the bytes forwarded to the child. Refused with
+    /// `permission_denied` unless the session shares input
+    /// (`--share-input`), since keystrokes can include passwords.
+    SubscribeInput,
+    /// Cap this connection's `inject` requests at `per_sec` a second (with
+    /// bursts up to that many); faster ones get a `rate_limited` error.
+    /// `None` removes the cap.
+    SetInjectRateLimit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        per_sec: Option<u32>,
+    },
+    /// What changed on screen since the `seq` of an earlier `diff` reply,
+    /// as ops that turn the screen as it was then into the screen now. For
+    /// polling over slow links; `0` (or a `seq` too old to remember) gets a
+    /// full repaint.
+    GetDiffSince { seq: u64 },
+    /// Get the input modes the program has set, to send keys the way it
+    /// expects them.
+    GetInputModes,
+}
+
+/// Define [`Request::type_name`] and [`REQUEST_TYPES`] from one table, so
+/// the two can't drift apart. The match is exhaustive: a variant missing
+/// from the table doesn't compile.
+macro_rules! request_types {
+    ($($variant:ident => $name:literal,)*) => {
+        impl Request {
+            /// The request's `type` on the wire, e.g. `get_scrollback`.
+            #[must_use]
+            pub fn type_name(&self) -> &'static str {
+                match self {
+                    $(Request::$variant { .. } => $name,)*
+                }
+            }
+        }
+
+        /// Every request `type`, as accepted by `--allow`.
+        pub const REQUEST_TYPES: &[&str] = &[$($name),*];
+    };
+}
+
+request_types! {
+    GetScrollback => "get_scrollback",
+    GetScrollbackBytes => "get_scrollback_bytes",
+    GetCursor => "get_cursor",
+    Inject => "inject",
+    GetSize => "get_size",
+    Subscribe => "subscribe",
+    GetCurrentLine => "get_current_line",
+    GetLinks => "get_links",
+    GetScreenReplay => "get_screen_replay",
+    GetStats => "get_stats",
+    GetRecentChunks => "get_recent_chunks",
+    GetResourceUsage => "get_resource_usage",
+    GetSessionInfo => "get_session_info",
+    StreamMode => "stream_mode",
+    SetTyping => "set_typing",
+    GetInputOwners => "get_input_owners",
+    ClearScrollback => "clear_scrollback",
+    ResetTerminal => "reset_terminal",
+    SubscribeCursor => "subscribe_cursor",
+    GetChildEnv => "get_child_env",
+    GetLine => "get_line",
+    PauseOutput => "pause_output",
+    ResumeOutput => "resume_output",
+    Terminate => "terminate",
+    Signal => "signal",
+    Pause => "pause",
+    Resume => "resume",
+    Health => "health",
+    WaitExit => "wait_exit",
+    GetFullGrid => "get_full_grid",
+    GetSnapshot => "get_snapshot",
+    GetRegion => "get_region",
+    GetTranscript => "get_transcript",
+    GetLastCommandOutput => "get_last_command_output",
+    SetMode => "set_mode",
+    Rename => "rename",
+    SubscribeFiltered => "subscribe_filtered",
+    ReplayAndFollow => "replay_and_follow",
+    Capabilities => "capabilities",
+    GetState => "get_state",
+    SubscribeInput => "subscribe_input",
+    SetInjectRateLimit => "set_inject_rate_limit",
+    GetDiffSince => "get_diff_since",
+    GetInputModes => "get_input_modes",
+}
+
+impl Request {
+    /// Whether this request changes the session (input, signals, state)
+    /// rather than just observing it. Refused on read-only connections.
+    #[must_use]
+    pub fn writes_to_session(&self) -> bool {
+        matches!(
+            self,
+            Request::Inject { .. }
+                | Request::SetTyping { .. }
+                | Request::ClearScrollback
+                | Request::ResetTerminal
+                | Request::Terminate
+                | Request::Signal { .. }
+                | Request::Pause { .. }
+                | Request::Resume { .. }
+                | Request::Rename { .. }
+        )
+    }
+}
+
+/// Look up a request type by its wire name (`get_scrollback`) or variant
+/// name (`GetScrollback`).
+#[must_use]
+pub fn request_type(name: &str) -> Option<&'static str> {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    REQUEST_TYPES.iter().copied().find(|&known| known == snake)
 }
 
 /// Server responses.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Response {
     /// Scrollback buffer content.
     Scrollback { content: String },
+    /// A scrollback tail within a byte budget; `truncated` is set if older
+    /// lines were left out.
+    ScrollbackBytes { content: String, truncated: bool },
     /// Cursor position.
     Cursor { row: usize, col: usize },
     /// Terminal size.
     Size { rows: u16, cols: u16 },
     /// Live output data (for subscribed clients).
     Output { data: Vec<u8> },
+    /// Keystrokes forwarded to the child (for input subscribers).
+    Input { data: Vec<u8> },
     /// Subscription confirmed.
     Subscribed,
+    /// Cursor position or visibility changed (for cursor subscribers).
+    CursorMoved {
+        row: usize,
+        col: usize,
+        visible: bool,
+    },
+    /// Current line content.
+    CurrentLine { content: String },
+    /// Hyperlinks as `(text, uri)` pairs, oldest first.
+    Links { links: Vec<(String, String)> },
+    /// Escape sequences reproducing the screen: clear, styled cells, cursor.
+    ScreenReplay { data: Vec<u8> },
+    /// Session counters.
+    Stats(SessionStats),
+    /// Recent output chunks, oldest first, as `(elapsed_ms, byte_len)`:
+    /// milliseconds since the session started and bytes read.
+    RecentChunks { chunks: Vec<(u64, usize)> },
+    /// Child CPU time (user plus system, seconds) and peak resident set
+    /// size (kB).
+    ResourceUsage { cpu_secs: f64, max_rss_kb: u64 },
+    /// Session metadata and state.
+    SessionInfo(SessionInfo),
+    /// Input arbitration state: this connection's ID and who is typing.
+    InputOwners { client_id: u64, typing: Vec<u64> },
     /// Success.
     Ok,
+    /// Output this connection missed: skipped while it was paused, or
+    /// dropped from its backlog to stay within the server's memory budget.
+    OutputGap { dropped_bytes: u64 },
+    /// The terminal was resized (for subscribers).
+    Resized { rows: u16, cols: u16 },
+    /// The child exited (for subscribers). `code` is set for a normal exit,
+    /// `signal` (e.g. `SIGSEGV`) if it was killed by a signal.
+    Exited {
+        code: Option<i32>,
+        signal: Option<String>,
+    },
+    /// A single line (`None` if out of range), the number of lines retained,
+    /// and how many were discarded before the first one.
+    Line {
+        content: Option<String>,
+        total: usize,
+        #[serde(default)]
+        discarded: u64,
+    },
+    /// Child environment as `(name, value)`, sorted by name.
+    ChildEnv { vars: Vec<(String, String)> },
+    /// Session health. `child_alive` is false once the command has exited,
+    /// even while the server is still shutting down.
+    Health {
+        child_alive: bool,
+        child_pid: u32,
+        uptime_secs: u64,
+        /// Stopped by `Pause`.
+        #[serde(default)]
+        paused: bool,
+    },
+    /// One page of the full grid: rows `start..start + rows.len()` of `total`.
+    FullGrid {
+        rows: Vec<Vec<Cell>>,
+        start: usize,
+        total: usize,
+    },
+    /// The visible screen.
+    Snapshot(Snapshot),
+    /// A screen rectangle: each row's text (blanks as spaces, trailing
+    /// blanks trimmed) and, if asked for, its cells.
+    Region {
+        lines: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        cells: Vec<Vec<Cell>>,
+    },
+    /// Output lines and injected input, oldest first.
+    Transcript { entries: Vec<TranscriptEntry> },
+    /// A command's output lines, newline-separated, and its exit code if the
+    /// shell reported one.
+    CommandOutput {
+        output: String,
+        exit_code: Option<i32>,
+    },
+    /// Every request type the server understands, by wire name.
+    Capabilities { requests: Vec<String> },
+    /// Terminal state.
+    State(TerminalState),
+    /// Screen changes, and the sequence number to ask from next time.
+    Diff { ops: Vec<DiffOp>, seq: u64 },
+    /// Input modes.
+    InputModes(InputModes),
     /// Error.
-    Error { message: String },
+    Error {
+        message: String,
+        /// Machine-readable category, when there is one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+}
+
+/// Machine-readable error categories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request was malformed or too large; the server drops the connection.
+    InvalidRequest,
+    /// The session doesn't allow this request type (`--allow`).
+    PermissionDenied,
+    /// The request's timeout elapsed first.
+    TimedOut,
+    /// The connection went over its `SetInjectRateLimit` cap.
+    RateLimited,
+}
+
+impl Response {
+    /// Whether this is an unsolicited event rather than a reply to a request.
+    #[must_use]
+    pub fn is_event(&self) -> bool {
+        matches!(
+            self,
+            Response::Output { .. }
+                | Response::Input { .. }
+                | Response::CursorMoved { .. }
+                | Response::OutputGap { .. }
+                | Response::Resized { .. }
+                | Response::Exited { .. }
+        )
+    }
+}
+
+/// Remove the next newline-terminated frame from `buf`, returning it without
+/// the newline, or `None` if no complete frame has arrived yet.
+pub fn next_frame(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let end = buf.iter().position(|&b| b == b'\n')?;
+    let mut frame: Vec<u8> = buf.drain(..=end).collect();
+    frame.pop();
+    Some(frame)
 }
 
 /// Get the socket directory path.
+///
+/// `TAP_SOCKET_DIR`, when set and non-empty, overrides the default.
 #[must_use]
 pub fn socket_dir() -> std::path::PathBuf {
+    if let Some(dir) = std::env::var_os("TAP_SOCKET_DIR")
+        && !dir.is_empty()
+    {
+        return std::path::PathBuf::from(dir);
+    }
     dirs::runtime_dir()
         .or_else(|| dirs::home_dir().map(|h| h.join(".tap")))
         .unwrap_or_else(|| std::path::PathBuf::from("/tmp/tap"))
@@ -66,3 +685,207 @@ pub fn socket_path(session_id: &str) -> std::path::PathBuf {
 pub fn sessions_file() -> std::path::PathBuf {
     socket_dir().join("sessions.json")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_name_matches_wire_tag() {
+        for request in [
+            Request::GetCursor,
+            Request::Inject {
+                data: "ls".to_string(),
+                encoding: None,
+            },
+            Request::GetRecentChunks { count: 1 },
+            Request::SubscribeFiltered {
+                pattern: "x".to_string(),
+            },
+        ] {
+            let json = serde_json::to_value(&request).unwrap();
+            assert_eq!(json["type"], request.type_name());
+            assert!(REQUEST_TYPES.contains(&request.type_name()));
+        }
+    }
+
+    #[test]
+    fn test_every_request_type_is_a_wire_tag() {
+        for name in REQUEST_TYPES {
+            let json = format!(r#"{{"type":"{name}"}}"#);
+            // Requests with required fields fail, but not on the tag
+            if let Err(e) = serde_json::from_str::<Request>(&json) {
+                assert!(e.to_string().starts_with("missing field"), "{name}: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff_ops_wire_format_and_apply() {
+        let response: Response = serde_json::from_str(
+            r#"{"type":"diff","seq":7,"ops":[
+                {"op":"reset","rows":3,"cols":10},
+                {"op":"set_line","row":0,"content":"a"},
+                {"op":"set_line","row":2,"content":"c"},
+                {"op":"scroll_up","n":2},
+                {"op":"set_line","row":9,"content":"off screen"}
+            ]}"#,
+        )
+        .unwrap();
+        let Response::Diff { ops, seq } = response else {
+            panic!("{response:?}");
+        };
+        assert_eq!(seq, 7);
+        let mut screen = vec!["stale".to_string()];
+        apply_diff(&mut screen, &ops);
+        assert_eq!(screen, ["c", "", ""]);
+    }
+
+    #[test]
+    fn test_request_type_lookup() {
+        assert_eq!(request_type("get_scrollback"), Some("get_scrollback"));
+        assert_eq!(request_type("GetScrollback"), Some("get_scrollback"));
+        assert_eq!(request_type("Subscribe"), Some("subscribe"));
+        assert_eq!(request_type("get_everything"), None);
+        assert_eq!(request_type(""), None);
+    }
+
+    #[test]
+    fn test_session_description_round_trip() {
+        let session = Session {
+            id: "blue-moon-fire".to_string(),
+            pid: 42,
+            started: "2024-01-01T00:00:00+00:00".to_string(),
+            command: vec!["make".to_string()],
+            description: Some("nightly build run #42".to_string()),
+            name: None,
+            short_id: None,
+        };
+        let json = serde_json::to_string(&session).unwrap();
+        let parsed: Session = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.description.as_deref(), Some("nightly build run #42"));
+    }
+
+    #[test]
+    fn test_session_info_keeps_pause_state_out_of_session() {
+        let info = SessionInfo {
+            session: Session {
+                id: "blue-moon-fire".to_string(),
+                pid: 42,
+                started: "2024-01-01T00:00:00+00:00".to_string(),
+                command: vec!["make".to_string()],
+                description: None,
+                name: None,
+                short_id: None,
+            },
+            paused: true,
+        };
+        let json = serde_json::to_value(Response::SessionInfo(info)).unwrap();
+        assert_eq!(json["id"], "blue-moon-fire");
+        assert_eq!(json["paused"], true);
+
+        // The session's own fields still read back as a plain `Session`
+        let session: Session = serde_json::from_value(json).unwrap();
+        assert!(!serde_json::to_string(&session).unwrap().contains("paused"));
+    }
+
+    #[test]
+    fn test_scrollback_include_current_defaults_true() {
+        let request: Request =
+            serde_json::from_str(r#"{"type":"get_scrollback","lines":null}"#).unwrap();
+        assert!(matches!(
+            request,
+            Request::GetScrollback {
+                include_current: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_next_frame_partial() {
+        let mut buf = b"{\"type\":\"get_cursor\"}\n{\"type\":".to_vec();
+        assert_eq!(
+            next_frame(&mut buf).as_deref(),
+            Some(&b"{\"type\":\"get_cursor\"}"[..])
+        );
+        assert_eq!(next_frame(&mut buf), None);
+        buf.extend_from_slice(b"\"get_size\"}\n");
+        assert_eq!(
+            next_frame(&mut buf).as_deref(),
+            Some(&b"{\"type\":\"get_size\"}"[..])
+        );
+        assert!(buf.is_empty());
+    }
+
+    /// A mixed conversation: requests, replies, and interleaved stream events,
+    /// each one JSON object per line.
+    #[test]
+    fn test_jsonl_conversation_round_trip() {
+        let requests = [
+            Request::StreamMode,
+            Request::GetSize,
+            Request::Subscribe,
+            Request::Inject {
+                data: "ls\n".to_string(),
+                encoding: None,
+            },
+        ];
+        let mut wire = Vec::new();
+        for request in &requests {
+            serde_json::to_writer(&mut wire, request).unwrap();
+            wire.push(b'\n');
+        }
+        let mut parsed = Vec::new();
+        while let Some(frame) = next_frame(&mut wire) {
+            parsed.push(serde_json::from_slice::<Request>(&frame).unwrap());
+        }
+        assert!(matches!(
+            parsed.as_slice(),
+            [
+                Request::StreamMode,
+                Request::GetSize,
+                Request::Subscribe,
+                Request::Inject { .. }
+            ]
+        ));
+
+        let messages = [
+            Response::Ok,
+            Response::Size { rows: 24, cols: 80 },
+            Response::Subscribed,
+            Response::Output {
+                data: b"$ ".to_vec(),
+            },
+            Response::Ok,
+            Response::Output {
+                data: b"ls\r\n".to_vec(),
+            },
+        ];
+        let mut wire = Vec::new();
+        for message in &messages {
+            serde_json::to_writer(&mut wire, message).unwrap();
+            wire.push(b'\n');
+        }
+        assert_eq!(wire.iter().filter(|&&b| b == b'\n').count(), messages.len());
+
+        let mut events = 0;
+        let mut replies = 0;
+        while let Some(frame) = next_frame(&mut wire) {
+            let message: Response = serde_json::from_slice(&frame).unwrap();
+            if message.is_event() {
+                events += 1;
+            } else {
+                replies += 1;
+            }
+        }
+        assert_eq!((events, replies), (2, 4));
+    }
+
+    #[test]
+    fn test_session_without_description() {
+        let json = r#"{"id":"a","pid":1,"started":"x","command":["sh"]}"#;
+        let parsed: Session = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.description, None);
+    }
+}
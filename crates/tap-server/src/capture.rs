@@ -0,0 +1,88 @@
+//! One-shot capture (`tap run-capture`): run a command on a PTY and collect
+//! everything it prints, with no socket server or session entry.
+
+use std::io::Read;
+
+use nix::libc;
+use nix::pty::{self, OpenptyResult};
+
+use crate::scrollback::ScrollbackBuffer;
+use crate::{child_env, exit, spawn};
+
+/// Run `command` on a fresh PTY until it exits. Returns its output and exit
+/// code: the raw bytes, or with `plain` the rendered text without escapes.
+pub fn capture(command: &[String], plain: bool) -> eyre::Result<(Vec<u8>, i32)> {
+    // Match the caller's terminal when there is one
+    let ws = crate::get_window_size();
+    let OpenptyResult { master, slave } =
+        pty::openpty(Some(&ws), None).map_err(|e| eyre::eyre!("openpty failed: {e}"))?;
+
+    let env = child_env::build(false, &[]);
+    let child = spawn::ChildCommand::new(command, &env)?;
+    let child_pid = match child.spawn(&master, slave) {
+        Ok(child_pid) => child_pid,
+        Err(e) => match e.downcast_ref::<spawn::ExecError>() {
+            Some(exec) => {
+                eprintln!("tap: {exec}");
+                return Ok((Vec::new(), exec.exit_code()));
+            }
+            None => return Err(e),
+        },
+    };
+
+    let mut master = std::fs::File::from(master);
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match master.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => output.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            // Linux reports EIO once the child side has closed
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let code = exit::wait_for_child(child_pid).exit_code();
+
+    if plain {
+        let mut scrollback = ScrollbackBuffer::new();
+        scrollback.push(&output);
+        let mut text = scrollback.get_lines(None, true);
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        output = text.into_bytes();
+    }
+    Ok((output, code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn test_capture_keeps_colors() {
+        let (output, code) = capture(&sh("printf '\\033[31mred\\033[0m\\n'"), false).unwrap();
+        assert_eq!(code, 0);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\x1b[31mred\x1b[0m"), "{output:?}");
+    }
+
+    #[test]
+    fn test_capture_plain_strips_escapes() {
+        let (output, _) = capture(&sh("printf '\\033[31mred\\033[0m\\n'"), true).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "red\n");
+    }
+
+    #[test]
+    fn test_capture_runs_on_a_tty() {
+        let (output, code) = capture(&sh("[ -t 1 ] && echo tty; exit 3"), false).unwrap();
+        assert_eq!(code, 3);
+        assert!(String::from_utf8(output).unwrap().contains("tty"));
+    }
+}
@@ -0,0 +1,104 @@
+//! The environment the child is launched with (`--env`, `--env-clear`).
+
+use std::ffi::OsStr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Variable name suffixes whose values are hidden from `GetChildEnv`.
+const REDACTED_SUFFIXES: &[&str] = &["_TOKEN", "_SECRET", "_PASSWORD"];
+
+const REDACTED: &str = "<redacted>";
+
+/// The effective child environment: our own (unless `clear`), with
+/// `overrides` applied in order. Sorted by name.
+pub fn build(clear: bool, overrides: &[(String, String)]) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = if clear {
+        Vec::new()
+    } else {
+        std::env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+            .collect()
+    };
+    for (key, value) in overrides {
+        match vars.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => v.clone_from(value),
+            None => vars.push((key.clone(), value.clone())),
+        }
+    }
+    vars.sort();
+    vars
+}
+
+/// Copy of `vars` with secret-looking values replaced.
+pub fn redact(vars: &[(String, String)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|(key, value)| {
+            let upper = key.to_ascii_uppercase();
+            if REDACTED_SUFFIXES.iter().any(|s| upper.ends_with(s)) {
+                (key.clone(), REDACTED.to_string())
+            } else {
+                (key.clone(), value.clone())
+            }
+        })
+        .collect()
+}
+
+/// Find `program` on the `PATH` from `vars`, as `execvp` would, so the
+/// child can be exec'd with an explicit environment.
+pub fn resolve_program(program: &str, vars: &[(String, String)]) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program));
+    }
+    let path = vars.iter().find(|(k, _)| k == "PATH")?;
+    std::env::split_paths(OsStr::new(&path.1))
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_override_is_reported() {
+        let vars = build(false, &[var("TAP_TEST_VAR", "hello")]);
+        assert!(vars.contains(&var("TAP_TEST_VAR", "hello")));
+        assert!(vars.iter().any(|(k, _)| k == "PATH"));
+    }
+
+    #[test]
+    fn test_clear_keeps_only_overrides() {
+        let vars = build(true, &[var("B", "2"), var("A", "1"), var("B", "3")]);
+        assert_eq!(vars, vec![var("A", "1"), var("B", "3")]);
+    }
+
+    #[test]
+    fn test_redacts_secrets() {
+        let vars = redact(&[var("GITHUB_TOKEN", "ghp_x"), var("HOME", "/home/a")]);
+        assert_eq!(
+            vars,
+            vec![var("GITHUB_TOKEN", REDACTED), var("HOME", "/home/a")]
+        );
+    }
+
+    #[test]
+    fn test_resolve_program() {
+        let vars = vec![var("PATH", "/nonexistent:/bin:/usr/bin")];
+        let sh = resolve_program("sh", &vars).unwrap();
+        assert!(sh.ends_with("sh"));
+        assert_eq!(
+            resolve_program("./script", &vars),
+            Some(PathBuf::from("./script"))
+        );
+        assert_eq!(resolve_program("sh", &[]), None);
+    }
+}
@@ -0,0 +1,122 @@
+//! Command output from shell integration marks (OSC 133).
+//!
+//! A shell set up for it marks where each prompt starts (`A`), where the
+//! command line starts (`B`), where the command's output starts (`C`), and
+//! where the command finished (`D`, with its exit code). What is printed
+//! between `C` and `D` is the command's output, with no guessing from
+//! prompts. Shells without these marks simply never report a command.
+
+use crate::history::LineHistory;
+use crate::parser::{Event, ShellMark};
+
+/// Most lines kept from one command's output; earlier ones are dropped.
+const MAX_OUTPUT_LINES: usize = 10_000;
+
+/// A finished command's output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Output lines, newline-separated, with escape sequences applied.
+    pub output: String,
+    /// `None` if the shell didn't report one.
+    pub exit_code: Option<i32>,
+}
+
+/// Follows shell marks, keeping the output of the last finished command.
+pub struct CommandTracker {
+    /// Output of the command running now, since its `C` mark.
+    running: Option<LineHistory>,
+    last: Option<CommandOutput>,
+}
+
+impl CommandTracker {
+    pub const fn new() -> Self {
+        Self {
+            running: None,
+            last: None,
+        }
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::ShellMark(ShellMark::OutputStart) => {
+                self.running = Some(LineHistory::new(MAX_OUTPUT_LINES));
+            }
+            Event::ShellMark(ShellMark::CommandEnd(exit_code)) => self.finish(*exit_code),
+            // A new prompt without a `D` still means the command is over
+            Event::ShellMark(ShellMark::PromptStart) => self.finish(None),
+            _ => {
+                if let Some(output) = &mut self.running {
+                    output.handle(event);
+                }
+            }
+        }
+    }
+
+    /// The last finished command, if the shell has marked one.
+    pub fn last(&self) -> Option<&CommandOutput> {
+        self.last.as_ref()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    fn finish(&mut self, exit_code: Option<i32>) {
+        let Some(mut output) = self.running.take() else {
+            return;
+        };
+        let mut lines = output.take_lines();
+        let current = output.current();
+        if !current.is_empty() {
+            lines.push(current);
+        }
+        self.last = Some(CommandOutput {
+            output: lines.join("\n"),
+            exit_code,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::TerminalParser;
+
+    fn track(data: &[u8]) -> CommandTracker {
+        let mut tracker = CommandTracker::new();
+        for event in TerminalParser::new().feed(data) {
+            tracker.handle(&event);
+        }
+        tracker
+    }
+
+    #[test]
+    fn test_output_between_marks() {
+        let tracker = track(
+            b"\x1b]133;A\x07$ \x1b]133;B\x07ls\r\n\x1b]133;C\x07a.txt\r\nb.txt\r\n\x1b]133;D;0\x07\
+              \x1b]133;A\x07$ ",
+        );
+        let last = tracker.last().unwrap();
+        assert_eq!(last.output, "a.txt\nb.txt");
+        assert_eq!(last.exit_code, Some(0));
+    }
+
+    #[test]
+    fn test_running_command_not_reported() {
+        let tracker = track(b"\x1b]133;C\x07one\r\n\x1b]133;D;1\x07\x1b]133;C\x07partial");
+        let last = tracker.last().unwrap();
+        assert_eq!(last.output, "one");
+        assert_eq!(last.exit_code, Some(1));
+
+        // Without marks there's nothing to report
+        assert!(track(b"$ ls\r\na.txt\r\n$ ").last().is_none());
+    }
+
+    #[test]
+    fn test_unterminated_line_and_missing_end() {
+        let tracker = track(b"\x1b]133;C\x07no newline\x1b]133;A\x07$ ");
+        let last = tracker.last().unwrap();
+        assert_eq!(last.output, "no newline");
+        assert_eq!(last.exit_code, None);
+    }
+}
@@ -0,0 +1,84 @@
+//! Cleaning up stray control bytes before they reach scrollback
+//! (`--normalize-controls`).
+//!
+//! Terminals silently ignore C0 controls like NUL or SOH, but programs
+//! that emit them by mistake leave them in captured text, where they trip
+//! up tools reading it. Controls a terminal acts on (BEL, BS, tab and line
+//! breaks, SO/SI, CAN/SUB, ESC) are left alone.
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// What to do with stray control bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlNormalization {
+    /// Leave them out.
+    Drop,
+    /// Show them in caret notation, e.g. `^@` for NUL.
+    Caret,
+}
+
+impl FromStr for ControlNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "caret" => Ok(Self::Caret),
+            _ => Err(format!(
+                "invalid control normalization: {s} (expected drop or caret)"
+            )),
+        }
+    }
+}
+
+/// C0 controls that no terminal acts on.
+fn is_stray(byte: u8) -> bool {
+    matches!(byte, 0x00..=0x06 | 0x10..=0x17 | 0x19 | 0x1c..=0x1f)
+}
+
+impl ControlNormalization {
+    /// `data` with stray controls dropped or escaped; borrowed if it has none.
+    pub fn apply<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if !data.iter().copied().any(is_stray) {
+            return Cow::Borrowed(data);
+        }
+        let mut out = Vec::with_capacity(data.len() + 8);
+        for &byte in data {
+            if !is_stray(byte) {
+                out.push(byte);
+            } else if *self == Self::Caret {
+                out.extend([b'^', byte + 0x40]);
+            }
+        }
+        Cow::Owned(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stray_controls() {
+        let data = b"a\x00b\x01c\r\n\t\x07\x1b[0m";
+        assert_eq!(
+            ControlNormalization::Drop.apply(data),
+            &b"abc\r\n\t\x07\x1b[0m"[..]
+        );
+        assert_eq!(
+            ControlNormalization::Caret.apply(data),
+            &b"a^@b^Ac\r\n\t\x07\x1b[0m"[..]
+        );
+        assert!(matches!(
+            ControlNormalization::Drop.apply(b"plain\r\n"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("caret".parse(), Ok(ControlNormalization::Caret));
+        assert!("escape".parse::<ControlNormalization>().is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Transcoding injected text for programs that don't read UTF-8, and
+//! repairing output that isn't valid UTF-8 before it reaches scrollback.
+
+use std::borrow::Cow;
+
+use encoding_rs::Encoding;
+
+/// `text` in the encoding named by `label` (any WHATWG label, e.g. `latin1`,
+/// `shift_jis`), or as UTF-8 if `None`. Fails for unknown labels and for
+/// text the encoding can't represent.
+pub fn encode<'a>(text: &'a str, label: Option<&str>) -> Result<Cow<'a, [u8]>, String> {
+    let Some(label) = label else {
+        return Ok(Cow::Borrowed(text.as_bytes()));
+    };
+    let encoding = Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("Unknown encoding `{label}`"))?;
+    // UTF-16 labels encode to UTF-8 in encoding_rs; that isn't what was asked
+    if encoding.output_encoding() != encoding {
+        return Err(format!("Cannot encode input as {}", encoding.name()));
+    }
+    let (bytes, _, had_errors) = encoding.encode(text);
+    if had_errors {
+        return Err(format!("Text can't be represented in {}", encoding.name()));
+    }
+    Ok(bytes)
+}
+
+/// Makes a stream of output valid UTF-8, replacing each invalid sequence
+/// with U+FFFD. A sequence split across chunks is held until the rest
+/// arrives, so only truly invalid bytes are replaced.
+///
+/// The terminal parsers already substitute U+FFFD for bad input, but they
+/// swallow the byte that ends a truncated sequence, which may be a newline.
+#[derive(Debug, Default)]
+pub struct Utf8Repair {
+    /// Start of a sequence cut off at the end of the last chunk.
+    pending: Vec<u8>,
+}
+
+impl Utf8Repair {
+    pub const fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+
+    /// `data` as valid UTF-8, borrowed when it needs no repair.
+    pub fn repair<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.pending.is_empty() && std::str::from_utf8(data).is_ok() {
+            return Cow::Borrowed(data);
+        }
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+
+        let mut out = Vec::with_capacity(input.len() + 2);
+        let mut chunks = input.utf8_chunks().peekable();
+        while let Some(chunk) = chunks.next() {
+            out.extend_from_slice(chunk.valid().as_bytes());
+            let invalid = chunk.invalid();
+            if invalid.is_empty() {
+                continue;
+            }
+            if chunks.peek().is_none() && is_truncated(invalid) {
+                self.pending = invalid.to_vec();
+            } else {
+                out.extend_from_slice("\u{FFFD}".as_bytes());
+            }
+        }
+        Cow::Owned(out)
+    }
+
+    /// Forget a held partial sequence.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Whether `bytes`, which `utf8_chunks` found invalid at the end of the
+/// input, are the start of a sequence that could still be completed.
+fn is_truncated(bytes: &[u8]) -> bool {
+    let width = match bytes[0] {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return false,
+    };
+    bytes.len() < width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repair_replaces_invalid_bytes() {
+        let mut repair = Utf8Repair::new();
+        assert!(matches!(repair.repair(b"plain"), Cow::Borrowed(_)));
+        assert_eq!(
+            repair.repair(b"a\xff b\xe2\x82\n").as_ref(),
+            "a\u{FFFD} b\u{FFFD}\n".as_bytes()
+        );
+        // A lone continuation byte at the end can't be completed
+        assert_eq!(repair.repair(b"x\x80").as_ref(), "x\u{FFFD}".as_bytes());
+    }
+
+    #[test]
+    fn test_repair_holds_split_sequence() {
+        let mut repair = Utf8Repair::new();
+        let euro = "€".as_bytes();
+        assert_eq!(repair.repair(&euro[..1]).as_ref(), b"");
+        assert_eq!(repair.repair(&euro[1..2]).as_ref(), b"");
+        assert_eq!(repair.repair(&euro[2..]).as_ref(), euro);
+
+        // Held bytes followed by something else are invalid after all
+        assert_eq!(repair.repair(&euro[..2]).as_ref(), b"");
+        assert_eq!(repair.repair(b"!").as_ref(), "\u{FFFD}!".as_bytes());
+    }
+
+    #[test]
+    fn test_utf8_by_default() {
+        assert_eq!(encode("café", None).unwrap().as_ref(), "café".as_bytes());
+    }
+
+    #[test]
+    fn test_latin1() {
+        assert_eq!(encode("café", Some("latin1")).unwrap().as_ref(), b"caf\xe9");
+        assert_eq!(
+            encode("ÀÿE", Some("ISO-8859-1")).unwrap().as_ref(),
+            b"\xc0\xffE"
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_and_unrepresentable() {
+        assert!(
+            encode("x", Some("klingon"))
+                .unwrap_err()
+                .contains("Unknown encoding")
+        );
+        assert!(encode("日本", Some("latin1")).is_err());
+        assert!(encode("x", Some("utf-16le")).is_err());
+    }
+}
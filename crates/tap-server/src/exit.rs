@@ -0,0 +1,193 @@
+//! How the child process ended.
+
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::signal::{Signal, kill};
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::Pid;
+
+/// How often to check on the child while it has time to exit.
+const TERM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Exit status of the child: a code for a normal exit, or the signal that
+/// killed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChildExit {
+    pub code: Option<i32>,
+    pub signal: Option<Signal>,
+}
+
+impl ChildExit {
+    /// Shell-style exit code: the code itself, or `128 + signal`.
+    pub fn exit_code(&self) -> i32 {
+        match (self.code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(sig)) => 128 + sig as i32,
+            (None, None) => 1,
+        }
+    }
+
+    /// Signal name, e.g. `SIGSEGV`.
+    pub fn signal_name(&self) -> Option<&'static str> {
+        self.signal.map(Signal::as_str)
+    }
+}
+
+/// Block until the child exits, retrying on `EINTR`.
+pub fn wait_for_child(child: Pid) -> ChildExit {
+    loop {
+        if let Some(exit) = reap(child, None) {
+            return exit;
+        }
+    }
+}
+
+/// Whether the child is still running, without reaping it: a child that has
+/// exited but not been waited for yet counts as not alive.
+pub fn is_alive(child: Pid) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        use nix::sys::wait::{Id, waitid};
+
+        let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+        matches!(waitid(Id::Pid(child), flags), Ok(WaitStatus::StillAlive))
+    }
+    // Without `WNOWAIT`, an unreaped child still looks alive here
+    #[cfg(not(target_os = "linux"))]
+    {
+        kill(child, None).is_ok()
+    }
+}
+
+/// Stop the child: `SIGTERM`, up to `grace` for it to exit, then `SIGKILL`.
+/// The child is always reaped.
+pub fn terminate(child: Pid, grace: Duration) -> ChildExit {
+    if kill(child, Signal::SIGTERM).is_ok() {
+        // A paused child can't act on SIGTERM until it's continued
+        let _ = kill(child, Signal::SIGCONT);
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            if let Some(exit) = reap(child, Some(WaitPidFlag::WNOHANG)) {
+                return exit;
+            }
+            std::thread::sleep(TERM_POLL_INTERVAL);
+        }
+        let _ = kill(child, Signal::SIGKILL);
+    }
+    wait_for_child(child)
+}
+
+/// One `waitpid` call: the exit status, or `None` if the child is still
+/// running (or the wait was interrupted).
+fn reap(child: Pid, flags: Option<WaitPidFlag>) -> Option<ChildExit> {
+    match waitpid(child, flags) {
+        Ok(WaitStatus::Exited(_, code)) => Some(ChildExit {
+            code: Some(code),
+            signal: None,
+        }),
+        Ok(WaitStatus::Signaled(_, sig, _)) => Some(ChildExit {
+            code: None,
+            signal: Some(sig),
+        }),
+        Ok(_) | Err(Errno::EINTR) => None,
+        Err(_) => Some(ChildExit {
+            code: Some(1),
+            signal: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_sh(script: &str) -> Pid {
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .spawn()
+            .unwrap();
+        // Reaped with `waitpid` by the code under test, not through `child`
+        let pid = Pid::from_raw(child.id() as i32);
+        drop(child);
+        pid
+    }
+
+    #[test]
+    fn test_normal_exit() {
+        let exit = wait_for_child(spawn_sh("exit 3"));
+        assert_eq!(exit.code, Some(3));
+        assert_eq!(exit.signal_name(), None);
+        assert_eq!(exit.exit_code(), 3);
+    }
+
+    #[test]
+    fn test_killed_by_signal() {
+        let exit = wait_for_child(spawn_sh("kill -SEGV $$"));
+        assert_eq!(exit.code, None);
+        assert_eq!(exit.signal_name(), Some("SIGSEGV"));
+        assert_eq!(exit.exit_code(), 128 + 11);
+    }
+
+    /// Spawn `script` and wait until it has printed a line, so any traps it
+    /// sets are in place.
+    fn spawn_ready(script: &str) -> Pid {
+        use std::io::BufRead;
+
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        // As in `spawn_sh`
+        let pid = Pid::from_raw(child.id() as i32);
+        drop(child);
+        pid
+    }
+
+    #[test]
+    fn test_is_alive_does_not_reap() {
+        let child = spawn_ready("echo ready; exec sleep 10");
+        assert!(is_alive(child));
+
+        kill(child, Signal::SIGKILL).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while is_alive(child) {
+            assert!(Instant::now() < deadline, "child still alive");
+            std::thread::sleep(TERM_POLL_INTERVAL);
+        }
+
+        // Still there to be reaped
+        assert_eq!(wait_for_child(child).signal_name(), Some("SIGKILL"));
+        assert!(!is_alive(child));
+    }
+
+    #[test]
+    fn test_terminate_exits_on_sigterm() {
+        let start = Instant::now();
+        let exit = terminate(
+            spawn_ready("echo ready; exec sleep 10"),
+            Duration::from_secs(5),
+        );
+        assert_eq!(exit.signal_name(), Some("SIGTERM"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_terminate_kills_after_grace() {
+        let grace = Duration::from_millis(200);
+        let start = Instant::now();
+        let exit = terminate(
+            spawn_ready("trap '' TERM; echo ready; exec sleep 10"),
+            grace,
+        );
+        assert_eq!(exit.signal_name(), Some("SIGKILL"));
+        assert!(start.elapsed() >= grace);
+    }
+}
@@ -0,0 +1,261 @@
+//! Live copy of child output to a named pipe (`--output-fifo`).
+//!
+//! A writer thread owns the FIFO so a slow or absent reader never stalls the
+//! main loop. The FIFO is opened non-blocking whenever there is output and
+//! no reader yet; a reader that goes away is simply waited for again. What
+//! happens to output while nobody is reading is set by [`FifoPolicy`].
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsFd;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+use nix::libc;
+use nix::sys::stat::Mode;
+use tracing::{debug, warn};
+
+use crate::stats;
+
+/// Chunks queued for the writer thread before further output is dropped.
+const QUEUE_CHUNKS: usize = 256;
+
+/// Most output held for a reader under [`FifoPolicy::Buffer`].
+const BUFFER_BYTES: usize = 1024 * 1024;
+
+/// How often held output is retried while no reader is connected.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What to do with output while no reader has the FIFO open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FifoPolicy {
+    /// Discard it; a reader sees output from when it connects.
+    #[default]
+    Drop,
+    /// Hold the most recent output (up to 1 MiB) for the next reader.
+    Buffer,
+}
+
+impl FromStr for FifoPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "buffer" => Ok(Self::Buffer),
+            _ => Err(format!(
+                "invalid FIFO policy: {s} (expected drop or buffer)"
+            )),
+        }
+    }
+}
+
+/// Handle to the FIFO writer thread. Dropping it lets the thread write what
+/// is queued and exit, removing the FIFO if it created it. The thread isn't
+/// joined: a reader that stops reading must not hold up the session.
+pub struct FifoSink {
+    tx: mpsc::SyncSender<Vec<u8>>,
+}
+
+impl FifoSink {
+    /// Create the FIFO at `path` (or reuse an existing one) and start the
+    /// writer thread.
+    pub fn create(path: &Path, policy: FifoPolicy) -> io::Result<Self> {
+        let created = match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_fifo() => false,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a FIFO", path.display()),
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                nix::unistd::mkfifo(path, Mode::from_bits_truncate(0o600))?;
+                true
+            }
+            Err(e) => return Err(e),
+        };
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CHUNKS);
+        let writer = Writer {
+            path: path.to_path_buf(),
+            policy,
+            created,
+            file: None,
+            held: VecDeque::new(),
+            held_bytes: 0,
+        };
+        std::thread::Builder::new()
+            .name("tap-fifo".to_string())
+            .spawn(move || writer.run(rx))?;
+
+        Ok(Self { tx })
+    }
+
+    /// Queue a chunk for the reader. Dropped if the writer is behind.
+    pub fn push(&self, data: &[u8]) {
+        if let Err(mpsc::TrySendError::Full(data)) = self.tx.try_send(data.to_vec()) {
+            stats::add(&stats::COUNTERS.dropped_fifo_bytes, data.len());
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    policy: FifoPolicy,
+    /// Whether we made the FIFO and should remove it when done.
+    created: bool,
+    file: Option<File>,
+    /// Output waiting for a reader under [`FifoPolicy::Buffer`].
+    held: VecDeque<Vec<u8>>,
+    held_bytes: usize,
+}
+
+impl Writer {
+    fn run(mut self, rx: mpsc::Receiver<Vec<u8>>) {
+        loop {
+            let chunk = if self.held.is_empty() {
+                match rx.recv() {
+                    Ok(chunk) => Some(chunk),
+                    Err(_) => break,
+                }
+            } else {
+                match rx.recv_timeout(RETRY_INTERVAL) {
+                    Ok(chunk) => Some(chunk),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            };
+            if let Some(chunk) = chunk {
+                self.hold(chunk);
+            }
+            self.deliver();
+        }
+        // The sink is gone; one last try for a reader that just connected
+        if !self.held.is_empty() {
+            self.deliver();
+        }
+        drop(self.file.take());
+        if self.created {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Queue a chunk, trimming the oldest held output past the budget.
+    fn hold(&mut self, chunk: Vec<u8>) {
+        self.held_bytes += chunk.len();
+        self.held.push_back(chunk);
+        while self.held_bytes > BUFFER_BYTES
+            && let Some(old) = self.held.pop_front()
+        {
+            self.held_bytes -= old.len();
+            stats::add(&stats::COUNTERS.dropped_fifo_bytes, old.len());
+        }
+    }
+
+    /// Write held output to the reader, connecting one if needed.
+    fn deliver(&mut self) {
+        if self.file.is_none() {
+            self.file = self.open();
+        }
+        let Some(file) = &mut self.file else {
+            if self.policy == FifoPolicy::Drop {
+                stats::add(&stats::COUNTERS.dropped_fifo_bytes, self.held_bytes);
+                self.held.clear();
+                self.held_bytes = 0;
+            }
+            return;
+        };
+        while let Some(chunk) = self.held.pop_front() {
+            self.held_bytes -= chunk.len();
+            if let Err(e) = file.write_all(&chunk) {
+                // EPIPE: the reader went away. Wait for the next one.
+                debug!("FIFO reader disconnected: {e}");
+                self.file = None;
+                if self.policy == FifoPolicy::Buffer {
+                    self.held_bytes += chunk.len();
+                    self.held.push_front(chunk);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Open the FIFO for writing if a reader has it open.
+    fn open(&self) -> Option<File> {
+        let file = match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(&self.path)
+        {
+            Ok(file) => file,
+            // ENXIO: no reader yet
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => return None,
+            Err(e) => {
+                warn!("Failed to open output FIFO {}: {e}", self.path.display());
+                return None;
+            }
+        };
+        // Block once connected, so a chunk is written whole; the queue in
+        // front of this thread absorbs a slow reader.
+        if let Err(e) = fcntl(file.as_fd(), FcntlArg::F_SETFL(OFlag::empty())) {
+            warn!("Failed to make output FIFO blocking: {e}");
+            return None;
+        }
+        Some(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_reader_gets_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let sink = FifoSink::create(&path, FifoPolicy::Buffer).unwrap();
+        sink.push(b"before reader ");
+
+        let reader_path = path.clone();
+        let reader = std::thread::spawn(move || {
+            let mut out = String::new();
+            File::open(reader_path)
+                .unwrap()
+                .read_to_string(&mut out)
+                .unwrap();
+            out
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        sink.push(b"after reader");
+        drop(sink);
+
+        assert_eq!(reader.join().unwrap(), "before reader after reader");
+    }
+
+    #[test]
+    fn test_no_reader_does_not_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let sink = FifoSink::create(&path, FifoPolicy::Drop).unwrap();
+        for _ in 0..QUEUE_CHUNKS * 4 {
+            sink.push(&[b'x'; 4096]);
+        }
+        drop(sink);
+    }
+
+    #[test]
+    fn test_refuses_regular_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        std::fs::write(&path, "keep").unwrap();
+        assert!(FifoSink::create(&path, FifoPolicy::Drop).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,89 @@
+//! External output filter (`--output-filter`).
+//!
+//! Child output is written to the filter's stdin and whatever the filter
+//! prints drives scrollback, subscribers, and stdout instead of the raw bytes.
+
+use std::process::Stdio;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+/// Spawn `command` through `sh -c` with piped stdin and stdout.
+/// The child is killed when the returned handle drops.
+pub fn spawn(command: &str) -> std::io::Result<(Child, ChildStdin, ChildStdout)> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child.stdin.take().expect("filter stdin is piped");
+    let stdout = child.stdout.take().expect("filter stdout is piped");
+    Ok((child, stdin, stdout))
+}
+
+/// Write to the filter, if one is running.
+pub async fn write(input: &mut Option<ChildStdin>, data: &[u8]) -> std::io::Result<()> {
+    match input {
+        Some(stdin) => stdin.write_all(data).await,
+        None => Err(std::io::ErrorKind::BrokenPipe.into()),
+    }
+}
+
+/// Read filtered output, pending forever when there is no filter.
+pub async fn read(output: &mut Option<ChildStdout>, buf: &mut [u8]) -> std::io::Result<usize> {
+    match output {
+        Some(stdout) => stdout.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrollback::ScrollbackBuffer;
+
+    async fn run_filter(command: &str, input: &[u8]) -> Vec<u8> {
+        let (_child, stdin, stdout) = spawn(command).unwrap();
+        let (mut stdin, mut stdout) = (Some(stdin), Some(stdout));
+        write(&mut stdin, input).await.unwrap();
+        drop(stdin);
+
+        let mut out = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            match read(&mut stdout, &mut buf).await.unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn test_filter_transforms_output() {
+        let filtered = run_filter("tr a-z A-Z", b"hello world\r\n").await;
+        assert_eq!(filtered, b"HELLO WORLD\r\n");
+
+        let mut scrollback = ScrollbackBuffer::new();
+        scrollback.push(&filtered);
+        assert_eq!(scrollback.get_lines(None, true).trim(), "HELLO WORLD");
+    }
+
+    #[tokio::test]
+    async fn test_dead_filter_reports_eof() {
+        let (mut child, stdin, stdout) = spawn("exit 0").unwrap();
+        child.wait().await.unwrap();
+        let mut stdout = Some(stdout);
+        let mut buf = [0u8; 16];
+        assert_eq!(read(&mut stdout, &mut buf).await.unwrap(), 0);
+        drop(stdin);
+    }
+
+    #[tokio::test]
+    async fn test_write_without_filter_fails() {
+        let mut none = None;
+        assert!(write(&mut none, b"x").await.is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Stdout flush policy for mirrored child output.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How long a partial line may sit unflushed under [`FlushPolicy::Line`],
+/// so prompts still show up promptly.
+const LINE_IDLE_FLUSH: Duration = Duration::from_millis(10);
+
+/// When to flush stdout after writing child output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flush after every write (lowest latency).
+    #[default]
+    Always,
+    /// Flush when a newline is written, or once output goes idle.
+    Line,
+    /// Flush at most once per interval.
+    Interval(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "line" => Ok(Self::Line),
+            _ => {
+                let ms = s.strip_prefix("interval:").ok_or_else(|| {
+                    format!("invalid flush policy: {s} (expected always, line, or interval:<ms>)")
+                })?;
+                let ms: u64 = ms
+                    .parse()
+                    .map_err(|_| format!("invalid flush interval: {ms}"))?;
+                Ok(Self::Interval(Duration::from_millis(ms)))
+            }
+        }
+    }
+}
+
+/// Tracks pending output and decides when stdout needs flushing.
+pub struct Flusher {
+    policy: FlushPolicy,
+    dirty: bool,
+    last_flush: Instant,
+    last_write: Instant,
+}
+
+impl Flusher {
+    pub fn new(policy: FlushPolicy) -> Self {
+        let now = Instant::now();
+        Self {
+            policy,
+            dirty: false,
+            last_flush: now,
+            last_write: now,
+        }
+    }
+
+    /// Record that `data` was written. Returns true if stdout should be flushed now.
+    pub fn wrote(&mut self, data: &[u8], now: Instant) -> bool {
+        self.dirty = true;
+        self.last_write = now;
+        match self.policy {
+            FlushPolicy::Always => true,
+            FlushPolicy::Line => data.contains(&b'\n'),
+            FlushPolicy::Interval(interval) => now.duration_since(self.last_flush) >= interval,
+        }
+    }
+
+    /// Record that stdout was flushed.
+    pub fn flushed(&mut self, now: Instant) {
+        self.dirty = false;
+        self.last_flush = now;
+    }
+
+    /// When pending output must be flushed even if no more arrives.
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.dirty {
+            return None;
+        }
+        match self.policy {
+            FlushPolicy::Always => Some(self.last_write),
+            FlushPolicy::Line => Some(self.last_write + LINE_IDLE_FLUSH),
+            FlushPolicy::Interval(interval) => Some(self.last_flush + interval),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `chunks` spaced `gap` apart and count the flushes requested.
+    fn count_flushes(policy: FlushPolicy, chunks: &[&str], gap: Duration) -> usize {
+        let mut flusher = Flusher::new(policy);
+        let mut now = Instant::now();
+        let mut flushes = 0;
+        for chunk in chunks {
+            now += gap;
+            if flusher.wrote(chunk.as_bytes(), now) {
+                flusher.flushed(now);
+                flushes += 1;
+            }
+        }
+        flushes
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("always".parse(), Ok(FlushPolicy::Always));
+        assert_eq!("line".parse(), Ok(FlushPolicy::Line));
+        assert_eq!(
+            "interval:25".parse(),
+            Ok(FlushPolicy::Interval(Duration::from_millis(25)))
+        );
+        assert!("interval:".parse::<FlushPolicy>().is_err());
+        assert!("sometimes".parse::<FlushPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_always_flushes_every_write() {
+        let chunks = ["a", "b", "c\n", "d"];
+        assert_eq!(
+            count_flushes(FlushPolicy::Always, &chunks, Duration::ZERO),
+            4
+        );
+    }
+
+    #[test]
+    fn test_line_flushes_on_newline() {
+        let chunks = ["a", "b", "c\n", "d", "e\nf"];
+        assert_eq!(count_flushes(FlushPolicy::Line, &chunks, Duration::ZERO), 2);
+    }
+
+    #[test]
+    fn test_interval_limits_flush_rate() {
+        let chunks = ["x\n"; 100];
+        let policy = FlushPolicy::Interval(Duration::from_millis(10));
+        // 100 writes 1ms apart over 100ms -> about one flush per 10ms.
+        assert_eq!(count_flushes(policy, &chunks, Duration::from_millis(1)), 10);
+    }
+
+    #[test]
+    fn test_pending_output_has_deadline() {
+        let mut flusher = Flusher::new(FlushPolicy::Line);
+        assert_eq!(flusher.deadline(), None);
+
+        let now = Instant::now();
+        assert!(!flusher.wrote(b"$ ", now));
+        assert_eq!(flusher.deadline(), Some(now + LINE_IDLE_FLUSH));
+
+        flusher.flushed(now);
+        assert_eq!(flusher.deadline(), None);
+    }
+}
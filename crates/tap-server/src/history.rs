@@ -0,0 +1,499 @@
+//! Plain-text line history.
+//!
+//! The vt100 screen only models what is visible. This keeps every completed
+//! line in order (up to a capacity) so lines can be addressed by index.
+//! Injected input can be noted alongside, for a transcript of who typed what.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tap_protocol::TranscriptEntry;
+
+use crate::parser::{Clear, Erase, Event};
+
+const TAB_WIDTH: usize = 8;
+
+/// Completed lines are frozen in blocks of this many.
+const BLOCK_LINES: usize = 256;
+
+/// Completed lines, oldest first, kept so that a copy is cheap: full blocks
+/// are frozen and shared between copies, and only the lines of the block
+/// still filling up are copied. Readers copy this under the scrollback lock
+/// and do the expensive work after releasing it.
+#[derive(Clone)]
+pub(crate) struct Lines {
+    frozen: VecDeque<Arc<[String]>>,
+    /// Lines already dropped from the front of the first frozen block.
+    skip: usize,
+    open: Vec<String>,
+}
+
+impl Lines {
+    const fn new() -> Self {
+        Self {
+            frozen: VecDeque::new(),
+            skip: 0,
+            open: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frozen.len() * BLOCK_LINES - self.skip + self.open.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        let index = index + self.skip;
+        let frozen = self.frozen.len() * BLOCK_LINES;
+        if index < frozen {
+            Some(self.frozen[index / BLOCK_LINES][index % BLOCK_LINES].as_str())
+        } else {
+            self.open.get(index - frozen).map(String::as_str)
+        }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &str> {
+        let skip = self.skip;
+        self.frozen
+            .iter()
+            .enumerate()
+            .flat_map(move |(i, block)| &block[if i == 0 { skip } else { 0 }..])
+            .chain(&self.open)
+            .map(String::as_str)
+    }
+
+    fn push(&mut self, line: String) {
+        self.open.push(line);
+        if self.open.len() == BLOCK_LINES {
+            self.frozen.push_back(std::mem::take(&mut self.open).into());
+        }
+    }
+
+    /// Drop the oldest line, returning its length.
+    fn pop_front(&mut self) -> Option<usize> {
+        let Some(block) = self.frozen.front() else {
+            return (!self.open.is_empty()).then(|| self.open.remove(0).len());
+        };
+        let len = block[self.skip].len();
+        self.skip += 1;
+        if self.skip == BLOCK_LINES {
+            self.frozen.pop_front();
+            self.skip = 0;
+        }
+        Some(len)
+    }
+
+    /// The most recent lines, newline-separated, that fit in `max_bytes`,
+    /// and whether older lines were left out. A line that would not fit
+    /// whole is left out along with everything before it.
+    pub fn tail_bytes(&self, max_bytes: usize) -> (String, bool) {
+        let mut used = 0;
+        let mut count = 0;
+        for line in self.iter().rev() {
+            let needed = line.len() + usize::from(count > 0);
+            if used + needed > max_bytes {
+                break;
+            }
+            used += needed;
+            count += 1;
+        }
+
+        let mut content = String::with_capacity(used);
+        for (i, line) in self.iter().skip(self.len() - count).enumerate() {
+            if i > 0 {
+                content.push('\n');
+            }
+            content.push_str(line);
+        }
+        (content, count < self.len())
+    }
+}
+
+/// Completed lines plus the line being written, built from parser events.
+pub struct LineHistory {
+    lines: Lines,
+    current: Vec<char>,
+    col: usize,
+    max_lines: usize,
+    /// Lines dropped off the front so far; never decreases.
+    discarded: u64,
+    /// Injected input, keyed by the absolute number of the line that was
+    /// being written when it arrived.
+    inputs: VecDeque<(u64, String)>,
+    /// Text held in `lines` and `inputs`.
+    bytes: usize,
+    /// When the newest `finished.len()` lines were completed, if lines are
+    /// being stamped (see [`stamp`](Self::stamp)).
+    finished: VecDeque<Instant>,
+    /// The time to stamp lines completed now with.
+    now: Option<Instant>,
+}
+
+impl LineHistory {
+    pub const fn new(max_lines: usize) -> Self {
+        Self {
+            lines: Lines::new(),
+            current: Vec::new(),
+            col: 0,
+            max_lines,
+            discarded: 0,
+            inputs: VecDeque::new(),
+            bytes: 0,
+            finished: VecDeque::new(),
+            now: None,
+        }
+    }
+
+    /// Stamp lines completed from here on with `now`, for [`expire`](Self::expire).
+    pub fn stamp(&mut self, now: Instant) {
+        self.now = Some(now);
+    }
+
+    /// Discard lines completed before `cutoff`. Lines from before stamping
+    /// started count as older than any stamped line.
+    pub fn expire(&mut self, cutoff: Instant) {
+        while self.finished.front().is_some_and(|&at| at < cutoff) {
+            while self.lines.len() > self.finished.len() {
+                self.drop_oldest();
+            }
+            self.drop_oldest();
+        }
+        self.drop_old_inputs();
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Print(c) => self.put(*c),
+            Event::Tab => {
+                let next = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.col < next {
+                    self.put(' ');
+                }
+            }
+            Event::Newline => self.finish_line(),
+            Event::CarriageReturn => self.col = 0,
+            Event::Backspace => self.col = self.col.saturating_sub(1),
+            Event::Clear(Clear::Line(Erase::All)) => self.current.clear(),
+            Event::Clear(Clear::Line(Erase::ToEnd)) => self.current.truncate(self.col),
+            _ => {}
+        }
+    }
+
+    /// Completed line `index`, oldest retained line first.
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.lines.get(index)
+    }
+
+    /// The line being written, without trailing blanks.
+    pub fn current(&self) -> String {
+        let line: String = self.current.iter().collect();
+        line.trim_end().to_string()
+    }
+
+    /// Number of completed lines retained.
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Lines discarded so far. Line `i` has absolute number `discarded + i`.
+    pub fn discarded(&self) -> u64 {
+        self.discarded
+    }
+
+    /// The completed lines, cheap to copy; see [`Lines`].
+    pub fn lines(&self) -> &Lines {
+        &self.lines
+    }
+
+    /// Remove and return the completed lines, oldest first. They count as
+    /// discarded afterwards.
+    pub fn take_lines(&mut self) -> Vec<String> {
+        self.discarded += self.lines.len() as u64;
+        self.bytes = self.inputs.iter().map(|(_, text)| text.len()).sum();
+        let lines = self.lines.iter().map(str::to_string).collect();
+        self.lines = Lines::new();
+        self.finished.clear();
+        lines
+    }
+
+    /// Approximate memory held by retained lines and recorded input.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Discard the oldest lines until at most `max_bytes` are held (or none
+    /// are left).
+    pub fn trim_to_bytes(&mut self, max_bytes: usize) {
+        while self.bytes > max_bytes && self.drop_oldest() {}
+        self.drop_old_inputs();
+    }
+
+    /// Note input injected while the current line is being written.
+    pub fn record_input(&mut self, text: &str) {
+        let line = self.discarded + self.lines.len() as u64;
+        self.bytes += text.len();
+        self.inputs.push_back((line, text.to_string()));
+    }
+
+    /// Completed lines with recorded input interleaved; input follows the
+    /// line it was typed on. Input on the current line comes last.
+    pub fn transcript(&self) -> Vec<TranscriptEntry> {
+        let mut entries = Vec::with_capacity(self.lines.len() + self.inputs.len());
+        let mut inputs = self.inputs.iter().peekable();
+        for (i, line) in self.lines.iter().enumerate() {
+            let number = self.discarded + i as u64;
+            entries.push(TranscriptEntry::Output {
+                text: line.to_string(),
+            });
+            while let Some((_, text)) = inputs.next_if(|(at, _)| *at <= number) {
+                entries.push(TranscriptEntry::Input { text: text.clone() });
+            }
+        }
+        entries.extend(inputs.map(|(_, text)| TranscriptEntry::Input { text: text.clone() }));
+        entries
+    }
+
+    /// Drop everything. Cleared lines count as discarded, so absolute line
+    /// numbers stay stable.
+    pub fn clear(&mut self) {
+        let discarded = self.discarded + self.lines.len() as u64;
+        *self = Self {
+            now: self.now,
+            ..Self::new(self.max_lines)
+        };
+        self.discarded = discarded;
+    }
+
+    fn put(&mut self, c: char) {
+        if self.col < self.current.len() {
+            self.current[self.col] = c;
+        } else {
+            self.current.resize(self.col, ' ');
+            self.current.push(c);
+        }
+        self.col += 1;
+    }
+
+    fn finish_line(&mut self) {
+        let line: String = self.current.drain(..).collect();
+        self.col = 0;
+        let line = line.trim_end().to_string();
+        self.bytes += line.len();
+        self.lines.push(line);
+        if let Some(now) = self.now {
+            self.finished.push_back(now);
+        }
+        while self.lines.len() > self.max_lines && self.drop_oldest() {}
+        self.drop_old_inputs();
+    }
+
+    /// Discard the oldest completed line, if there is one.
+    fn drop_oldest(&mut self) -> bool {
+        let Some(len) = self.lines.pop_front() else {
+            return false;
+        };
+        self.bytes -= len;
+        self.discarded += 1;
+        if self.finished.len() > self.lines.len() {
+            self.finished.pop_front();
+        }
+        true
+    }
+
+    /// Forget input recorded on lines that have been discarded.
+    fn drop_old_inputs(&mut self) {
+        while self
+            .inputs
+            .front()
+            .is_some_and(|(line, _)| *line < self.discarded)
+            && let Some((_, text)) = self.inputs.pop_front()
+        {
+            self.bytes -= text.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::TerminalParser;
+
+    fn history(max_lines: usize, data: &[u8]) -> LineHistory {
+        let mut history = LineHistory::new(max_lines);
+        for event in TerminalParser::new().feed(data) {
+            history.handle(&event);
+        }
+        history
+    }
+
+    #[test]
+    fn test_get_line() {
+        let history = history(10, b"one\r\ntwo\r\nthree");
+        assert_eq!(history.line_count(), 2);
+        assert_eq!(history.get(0), Some("one"));
+        assert_eq!(history.get(1), Some("two"));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let history = history(10, b"one\r\n");
+        assert_eq!(history.get(1), None);
+        assert_eq!(LineHistory::new(10).get(0), None);
+    }
+
+    #[test]
+    fn test_get_line_after_trimming() {
+        let history = history(2, b"a\r\nb\r\nc\r\nd\r\n");
+        assert_eq!(history.line_count(), 2);
+        assert_eq!(history.get(0), Some("c"));
+        assert_eq!(history.get(1), Some("d"));
+        assert_eq!(history.get(2), None);
+    }
+
+    #[test]
+    fn test_discarded_counts_trimmed_lines() {
+        let mut history = history(3, b"");
+        assert_eq!(history.discarded(), 0);
+        for i in 0..10 {
+            for event in TerminalParser::new().feed(format!("{i}\r\n").as_bytes()) {
+                history.handle(&event);
+            }
+        }
+        assert_eq!(history.discarded(), 7);
+        assert_eq!(history.get(0), Some("7"));
+
+        history.clear();
+        assert_eq!(history.discarded(), 10);
+    }
+
+    #[test]
+    fn test_carriage_return_overwrites() {
+        let history = history(10, b"50%\r100%\r\nab\x08c\r\n\tx\r\n");
+        assert_eq!(history.get(0), Some("100%"));
+        assert_eq!(history.get(1), Some("ac"));
+        assert_eq!(history.get(2), Some("        x"));
+    }
+
+    #[test]
+    fn test_escapes_stripped() {
+        let history = history(10, b"\x1b[1;31mred\x1b[0m\r\n");
+        assert_eq!(history.get(0), Some("red"));
+    }
+
+    #[test]
+    fn test_transcript_interleaves_input() {
+        let mut history = history(10, b"$ ");
+        history.record_input("ls\n");
+        for event in TerminalParser::new().feed(b"ls\r\nfile\r\n$ ") {
+            history.handle(&event);
+        }
+        history.record_input("exit\n");
+
+        let output = |text: &str| TranscriptEntry::Output {
+            text: text.to_string(),
+        };
+        let input = |text: &str| TranscriptEntry::Input {
+            text: text.to_string(),
+        };
+        assert_eq!(
+            history.transcript(),
+            vec![
+                output("$ ls"),
+                input("ls\n"),
+                output("file"),
+                input("exit\n")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_drops_input_with_its_line() {
+        let mut history = history(1, b"");
+        history.record_input("a");
+        for event in TerminalParser::new().feed(b"1\r\n2\r\n") {
+            history.handle(&event);
+        }
+        assert_eq!(
+            history.transcript(),
+            vec![TranscriptEntry::Output {
+                text: "2".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tail_bytes_under_budget() {
+        let history = history(10, b"ab\r\ncd\r\n");
+        assert_eq!(
+            history.lines().tail_bytes(100),
+            ("ab\ncd".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn test_tail_bytes_exact_fit() {
+        let history = history(10, b"ab\r\ncd\r\n");
+        assert_eq!(history.lines().tail_bytes(5), ("ab\ncd".to_string(), false));
+    }
+
+    #[test]
+    fn test_tail_bytes_truncates_on_line_boundary() {
+        let history = history(10, b"ab\r\ncd\r\nefg\r\n");
+        assert_eq!(history.lines().tail_bytes(7), ("cd\nefg".to_string(), true));
+        assert_eq!(history.lines().tail_bytes(6), ("cd\nefg".to_string(), true));
+        assert_eq!(history.lines().tail_bytes(5), ("efg".to_string(), true));
+        assert_eq!(history.lines().tail_bytes(2), (String::new(), true));
+    }
+
+    #[test]
+    fn test_expire_drops_lines_finished_before_the_cutoff() {
+        let start = Instant::now();
+        let at = |secs| start + std::time::Duration::from_secs(secs);
+        let mut history = history(10, b"unstamped\r\n");
+        for (secs, line) in [(0, "zero\r\n"), (10, "ten\r\n"), (20, "twenty\r\n")] {
+            history.stamp(at(secs));
+            for event in TerminalParser::new().feed(line.as_bytes()) {
+                history.handle(&event);
+            }
+        }
+        assert_eq!(history.line_count(), 4);
+
+        history.expire(at(0));
+        assert_eq!(history.line_count(), 4);
+        history.expire(at(15));
+        assert_eq!(history.line_count(), 1);
+        assert_eq!(history.get(0), Some("twenty"));
+        assert_eq!(history.discarded(), 3);
+        assert_eq!(history.bytes(), "twenty".len());
+        history.expire(at(60));
+        assert_eq!(history.line_count(), 0);
+    }
+
+    #[test]
+    fn test_lines_across_blocks() {
+        let text: String = (0..600).map(|i| format!("{i}\r\n")).collect();
+        let mut history = history(BLOCK_LINES * 2, text.as_bytes());
+        assert_eq!(history.line_count(), 512);
+        assert_eq!(history.get(0), Some("88"));
+        assert_eq!(history.get(511), Some("599"));
+
+        let copy = history.lines().clone();
+        history.trim_to_bytes(0);
+        assert_eq!(history.line_count(), 0);
+        assert_eq!(copy.len(), 512);
+        assert_eq!(copy.iter().nth(300), Some("388"));
+        assert_eq!(copy.tail_bytes(7), ("598\n599".to_string(), true));
+    }
+
+    #[test]
+    fn test_trim_to_bytes_drops_oldest_lines() {
+        let mut history = history(10, b"aaaa\r\nbb\r\ncc\r\n");
+        assert_eq!(history.bytes(), 8);
+        history.trim_to_bytes(5);
+        assert_eq!(history.bytes(), 4);
+        assert_eq!(history.get(0), Some("bb"));
+        assert_eq!(history.discarded(), 1);
+        history.trim_to_bytes(0);
+        assert_eq!((history.bytes(), history.line_count()), (0, 0));
+    }
+}
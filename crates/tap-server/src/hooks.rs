@@ -0,0 +1,102 @@
+//! Session lifecycle hooks (`--on-exit`).
+
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+/// What the exit hook learns about the finished session, via environment.
+pub struct ExitInfo<'a> {
+    pub session_id: &'a str,
+    pub exit_code: i32,
+    /// Name of the signal that killed the child, e.g. `SIGSEGV`.
+    pub signal: Option<&'a str>,
+}
+
+/// Run `command` through `sh -c` with `TAP_SESSION_ID` and `TAP_EXIT_CODE` set,
+/// plus `TAP_EXIT_SIGNAL` if the child was killed by a signal.
+/// Unless `wait` is set, the hook runs detached in its own process group and
+/// may outlive tap.
+pub fn run_on_exit(command: &str, info: &ExitInfo<'_>, wait: bool) -> std::io::Result<()> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("TAP_SESSION_ID", info.session_id)
+        .env("TAP_EXIT_CODE", info.exit_code.to_string())
+        .stdin(Stdio::null());
+    if let Some(signal) = info.signal {
+        cmd.env("TAP_EXIT_SIGNAL", signal);
+    }
+
+    if wait {
+        let status = cmd.status()?;
+        if !status.success() {
+            tracing::warn!("on-exit hook exited with {status}");
+        }
+    } else {
+        cmd.process_group(0).spawn()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_sees_session_env() {
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join("marker");
+        let command = format!(
+            "echo \"$TAP_SESSION_ID $TAP_EXIT_CODE\" > '{}'",
+            marker.display()
+        );
+        let info = ExitInfo {
+            session_id: "blue-moon-fire",
+            exit_code: 3,
+            signal: None,
+        };
+        run_on_exit(&command, &info, true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&marker).unwrap().trim(),
+            "blue-moon-fire 3"
+        );
+    }
+
+    #[test]
+    fn test_hook_sees_exit_signal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join("marker");
+        let command = format!(
+            "echo \"$TAP_EXIT_CODE $TAP_EXIT_SIGNAL\" > '{}'",
+            marker.display()
+        );
+        let info = ExitInfo {
+            session_id: "s",
+            exit_code: 139,
+            signal: Some("SIGSEGV"),
+        };
+        run_on_exit(&command, &info, true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&marker).unwrap().trim(),
+            "139 SIGSEGV"
+        );
+    }
+
+    #[test]
+    fn test_detached_hook_runs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join("marker");
+        let command = format!("touch '{}'", marker.display());
+        let info = ExitInfo {
+            session_id: "s",
+            exit_code: 0,
+            signal: None,
+        };
+        run_on_exit(&command, &info, false).unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !marker.exists() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(marker.exists());
+    }
+}
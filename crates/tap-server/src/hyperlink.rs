@@ -0,0 +1,118 @@
+//! OSC 8 hyperlink extraction.
+//!
+//! The vt100 screen model drops OSC 8 sequences, so link targets would be
+//! lost once escapes are stripped. This tracks `(text, uri)` pairs alongside it.
+
+use std::collections::VecDeque;
+
+use crate::parser::Event;
+
+/// Maximum number of links retained; the oldest are dropped first.
+const MAX_LINKS: usize = 1000;
+
+/// Collects OSC 8 hyperlinks from parsed terminal output.
+pub struct LinkTracker {
+    /// URI and text of the link currently open, if any.
+    active: Option<(String, String)>,
+    links: VecDeque<(String, String)>,
+}
+
+impl LinkTracker {
+    pub const fn new() -> Self {
+        Self {
+            active: None,
+            links: VecDeque::new(),
+        }
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Print(c) => {
+                if let Some((_, text)) = &mut self.active {
+                    text.push(*c);
+                }
+            }
+            Event::Hyperlink(uri) => {
+                self.close();
+                if let Some(uri) = uri {
+                    self.active = Some((uri.clone(), String::new()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Completed links as `(text, uri)`, oldest first.
+    pub fn links(&self) -> Vec<(String, String)> {
+        self.links.iter().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    fn close(&mut self) {
+        if let Some((uri, text)) = self.active.take()
+            && !text.is_empty()
+        {
+            if self.links.len() == MAX_LINKS {
+                self.links.pop_front();
+            }
+            self.links.push_back((text, uri));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::TerminalParser;
+
+    fn track(chunks: &[&[u8]]) -> LinkTracker {
+        let mut parser = TerminalParser::new();
+        let mut tracker = LinkTracker::new();
+        for chunk in chunks {
+            for event in parser.feed(chunk) {
+                tracker.handle(&event);
+            }
+        }
+        tracker
+    }
+
+    #[test]
+    fn test_extracts_link() {
+        let tracker = track(&[b"see \x1b]8;;https://example.com\x1b\\the docs\x1b]8;;\x1b\\ here"]);
+        assert_eq!(
+            tracker.links(),
+            vec![("the docs".to_string(), "https://example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_bel_terminated_with_params() {
+        let tracker = track(&[b"\x1b]8;id=1;https://a.test/?x=1;y=2\x07link\x1b]8;;\x07"]);
+        assert_eq!(
+            tracker.links(),
+            vec![("link".to_string(), "https://a.test/?x=1;y=2".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_link_split_across_pushes() {
+        let tracker = track(&[
+            b"\x1b]8;;https://exa",
+            b"mple.com\x1b\\te",
+            b"xt\x1b]8;;\x1b\\",
+        ]);
+        assert_eq!(
+            tracker.links(),
+            vec![("text".to_string(), "https://example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_link_not_reported() {
+        let tracker = track(&[b"\x1b]8;;https://example.com\x1b\\dangling"]);
+        assert!(tracker.links().is_empty());
+    }
+}
@@ -1,39 +1,178 @@
 //! PTY wrapper server library for terminal introspection.
 
+mod capture;
+mod child_env;
+mod commands;
+mod controls;
 mod editor;
+mod encoding;
+mod exit;
+mod fifo;
+mod filter;
+mod flush;
+mod history;
+mod hooks;
+mod hyperlink;
 mod input;
+mod limits;
+mod line_filter;
+mod memory;
+mod output;
+pub mod parser;
+mod paths;
+mod pty_writer;
+mod rate_limit;
+mod resources;
+mod screen_diff;
 mod scrollback;
+mod sessions;
+mod signals;
+mod spawn;
+mod stats;
+mod stdin_queue;
+mod stdout_writer;
 
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::Path;
+use std::sync::Arc;
 
 use crossterm::event::{
     KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
 };
 use crossterm::execute;
+use exit::ChildExit;
 use nix::libc;
 use nix::pty::{self, OpenptyResult, Winsize};
-use nix::sys::signal::{self, SigHandler, Signal};
 use nix::sys::termios::{self, SetArg, Termios};
-use nix::unistd::{self, ForkResult, Pid};
 use parking_lot::RwLock;
 use scrollback::ScrollbackBuffer;
-use tap_protocol::{Request, Response};
+use tap_protocol::{ErrorCode, Request, Response, Session, SessionInfo, Snapshot};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc, watch};
 use tracing::{debug, error, info, warn};
 
+pub use capture::capture;
+pub use controls::ControlNormalization;
+pub use fifo::FifoPolicy;
+pub use flush::FlushPolicy;
+pub use limits::{ChildLimits, SETUP_FAILED_EXIT};
+pub use parser::TerminalParser;
+pub use sessions::{GcReport, gc};
+pub use signals::parse_signal;
+pub use stdin_queue::StdinOverflow;
+
 static SCROLLBACK: RwLock<ScrollbackBuffer> = RwLock::new(ScrollbackBuffer::new());
 static MASTER_FD: std::sync::OnceLock<i32> = std::sync::OnceLock::new();
+static CHILD_PID: std::sync::OnceLock<nix::unistd::Pid> = std::sync::OnceLock::new();
+static STARTED: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+static SESSION: RwLock<Option<Session>> = RwLock::new(None);
+/// The `sessions.json` this session is listed in.
+static SESSIONS_FILE: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
+/// Screens handed out by `GetDiffSince`, to diff later polls against.
+static DIFF_BASES: parking_lot::Mutex<screen_diff::Bases> =
+    parking_lot::Mutex::new(screen_diff::Bases::new());
+/// Child environment as launched, with secrets redacted.
+static CHILD_ENV: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
 
 /// Configuration for starting a server session.
 #[derive(Debug, Clone, Default)]
 pub struct ServerConfig {
     /// Command to run (defaults to $SHELL if empty).
     pub command: Vec<String>,
+    /// Start the command as a login shell (`argv[0]` prefixed with `-`).
+    pub login: bool,
+    /// Pass the command `-i`, so a shell runs as interactive.
+    pub interactive: bool,
     /// Custom session ID (auto-generated human-readable ID if None).
     pub session_id: Option<String>,
+    /// When to flush stdout after mirroring child output.
+    pub flush_policy: FlushPolicy,
+    /// Allow the socket directory or sessions file to be symlinks (warn only).
+    pub follow_symlinks: bool,
+    /// Free-text description shown in `list`.
+    pub description: Option<String>,
+    /// Shell command that child output is piped through before it reaches
+    /// scrollback, subscribers, and stdout.
+    pub output_filter: Option<String>,
+    /// Shell command run after the session ends, with `TAP_SESSION_ID` and
+    /// `TAP_EXIT_CODE` in its environment.
+    pub on_exit: Option<String>,
+    /// Wait for the on-exit hook to finish instead of running it detached.
+    pub wait_on_exit: bool,
+    /// Extra `(name, value)` variables for the child, applied in order.
+    pub env: Vec<(String, String)>,
+    /// Start the child with an empty environment (plus `env`).
+    pub env_clear: bool,
+    /// `TERM` for the child, over the inherited value and any in `env`.
+    /// `None` leaves it as inherited.
+    pub term: Option<String>,
+    /// Largest request frame accepted from a client, in bytes
+    /// (default [`DEFAULT_MAX_REQUEST_SIZE`]). Bigger requests get an
+    /// `invalid_request` error and the connection is dropped.
+    pub max_request_size: Option<usize>,
+    /// How long the child gets to exit after `SIGTERM` before it is sent
+    /// `SIGKILL` (default [`DEFAULT_TERM_GRACE`]).
+    pub term_grace: Option<std::time::Duration>,
+    /// Queue up to this many bytes of stdin for the child, written by a
+    /// separate thread so a child that stops reading input can't stall the
+    /// session. `None` writes stdin inline.
+    pub stdin_buffer: Option<usize>,
+    /// What to do with stdin while that queue is full.
+    pub stdin_overflow: StdinOverflow,
+    /// Record injected input in the transcript, tagged as input. It is
+    /// never echoed to stdout or subscribers.
+    pub echo_injections: bool,
+    /// Drop or caret-escape stray control bytes (NUL, SOH, ...) before they
+    /// are stored in scrollback. Stdout and subscribers get them unchanged.
+    pub normalize_controls: Option<ControlNormalization>,
+    /// Run the child on pipes instead of a PTY: its stdout and stderr feed
+    /// scrollback and subscribers, and input goes to its stdin.
+    pub no_pty: bool,
+    /// Don't write anything to our own stdout: child output only goes to
+    /// scrollback, subscribers, and the FIFO. For running under a
+    /// supervisor, where stdout goes nowhere useful.
+    pub no_local_output: bool,
+    /// Don't read our own stdin or touch the terminal it's on; the child
+    /// only gets input from clients. Stdin closing no longer ends the session.
+    pub no_local_input: bool,
+    /// If the socket directory is read-only, full, or not writable, use a
+    /// per-user directory under the system temp dir instead of failing.
+    pub socket_dir_fallback: bool,
+    /// Batch output for subscribers for up to this long, so a program
+    /// writing a byte at a time doesn't send a frame per byte. Local stdout
+    /// is unaffected.
+    pub subscriber_coalesce: Option<std::time::Duration>,
+    /// Priority, resource limits, and user for the child, applied before
+    /// it execs.
+    pub limits: ChildLimits,
+    /// Also write all output to a named pipe at this path, created if
+    /// missing and removed again if we created it.
+    pub output_fifo: Option<std::path::PathBuf>,
+    /// What to do with FIFO output while no reader is connected.
+    pub fifo_policy: FifoPolicy,
+    /// Don't start the command until a client has subscribed to output,
+    /// so nothing it prints is missed. The session is listed meanwhile.
+    pub wait_for_client: bool,
+    /// Give up waiting for a subscriber after this long.
+    pub wait_timeout: Option<std::time::Duration>,
+    /// Answer the child's terminal queries (cursor position, status, device
+    /// attributes) ourselves, for when no real terminal sees its output.
+    pub answer_queries: bool,
+    /// Let clients watch keystrokes typed at our terminal with
+    /// `SubscribeInput`. Off by default, since they can include passwords.
+    pub share_input: bool,
+    /// If non-empty, the only request types clients may send, by wire name
+    /// (`get_scrollback`) or variant name (`GetScrollback`). Others get a
+    /// `permission_denied` error.
+    pub allow: Vec<String>,
+    /// Try to keep scrollback and subscriber queues within this many bytes,
+    /// dropping old history and lagging subscribers' backlog when over.
+    pub memory_budget: Option<usize>,
+    /// Drop scrollback older than this. The line limit and `memory_budget`
+    /// still apply; whichever drops a line first wins.
+    pub scrollback_ttl: Option<std::time::Duration>,
 }
 
 fn setup_terminal(fd: &OwnedFd) -> nix::Result<Termios> {
@@ -48,12 +187,47 @@ fn restore_terminal(fd: &OwnedFd, termios: &Termios) {
     let _ = termios::tcsetattr(fd, SetArg::TCSANOW, termios);
 }
 
+/// Size assumed when the real one is unknown, e.g. stdin isn't a terminal.
+const DEFAULT_SIZE: (u16, u16) = (24, 80);
+
+/// Largest row or column count passed on to the child and clients. Bigger
+/// reports are clamped; the screen would be billions of cells otherwise.
+const MAX_DIMENSION: u16 = 4096;
+
+/// The local terminal's size, or [`DEFAULT_SIZE`] if there isn't one.
 fn get_window_size() -> Winsize {
+    read_window_size().unwrap_or_else(|| sane_window_size(0, 0))
+}
+
+/// The local terminal's size, if stdin is a terminal that reports one.
+fn read_window_size() -> Option<Winsize> {
     let mut ws: Winsize = unsafe { std::mem::zeroed() };
-    unsafe {
-        libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut ws);
+    if unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut ws) } != 0 {
+        return None;
+    }
+    if ws.ws_row == 0 || ws.ws_col == 0 {
+        return None;
+    }
+    Some(sane_window_size(ws.ws_row, ws.ws_col))
+}
+
+/// A window size with zero dimensions replaced by [`DEFAULT_SIZE`] and
+/// huge ones clamped to [`MAX_DIMENSION`].
+fn sane_window_size(rows: u16, cols: u16) -> Winsize {
+    let (rows, cols) = if rows == 0 || cols == 0 {
+        DEFAULT_SIZE
+    } else {
+        (rows, cols)
+    };
+    if rows > MAX_DIMENSION || cols > MAX_DIMENSION {
+        warn!("Terminal size {rows}x{cols} is out of range; using at most {MAX_DIMENSION}");
+    }
+    Winsize {
+        ws_row: rows.min(MAX_DIMENSION),
+        ws_col: cols.min(MAX_DIMENSION),
+        ws_xpixel: 0,
+        ws_ypixel: 0,
     }
-    ws
 }
 
 fn set_window_size(fd: i32, ws: &Winsize) {
@@ -62,75 +236,834 @@ fn set_window_size(fd: i32, ws: &Winsize) {
     }
 }
 
-extern "C" fn handle_sigwinch(_: libc::c_int) {
-    if let Some(&master_fd) = MASTER_FD.get() {
-        let ws = get_window_size();
-        set_window_size(master_fd, &ws);
+/// Resolve `--allow` names to request types, rejecting unknown ones.
+fn allowlist(names: &[String]) -> eyre::Result<Option<Arc<[&'static str]>>> {
+    if names.is_empty() {
+        return Ok(None);
     }
+    let types = names
+        .iter()
+        .map(|name| {
+            tap_protocol::request_type(name).ok_or_else(|| {
+                eyre::eyre!(
+                    "Unknown request type: {name} (known: {})",
+                    tap_protocol::REQUEST_TYPES.join(", ")
+                )
+            })
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    Ok(Some(types.into()))
 }
 
-async fn handle_client(mut stream: UnixStream, output_rx: broadcast::Receiver<Vec<u8>>) {
-    let mut buf = bytes::BytesMut::with_capacity(4096);
-    let mut output_rx = output_rx;
+/// Remove the session's socket and its entry in `sessions.json`.
+fn remove_session(socket_path: &Path, sessions_file: &Path, session_id: &str) {
+    let _lock = sessions_file
+        .parent()
+        .and_then(|dir| sessions::lock(dir).ok());
+    let _ = std::fs::remove_file(socket_path);
 
-    loop {
-        buf.clear();
+    if let Ok(content) = std::fs::read_to_string(sessions_file)
+        && let Ok(mut sessions) = serde_json::from_str::<Vec<serde_json::Value>>(&content)
+    {
+        sessions.retain(|s| s.get("id").and_then(|v| v.as_str()) != Some(session_id));
+        let _ = std::fs::write(
+            sessions_file,
+            serde_json::to_string_pretty(&sessions).unwrap(),
+        );
+    }
+}
+
+/// The child and what was set up around it, for [`shutdown`] to undo.
+struct Running {
+    pid: nix::unistd::Pid,
+    /// Stop the child rather than wait for it to exit.
+    terminate: bool,
+    /// How the main loop ended; non-zero if output couldn't be written.
+    code: i32,
+    pipeline: output::OutputPipeline,
+    filter_in: Option<tokio::process::ChildStdin>,
+    filter_out: Option<tokio::process::ChildStdout>,
+    keyboard_enhanced: bool,
+    orig_termios: Option<Termios>,
+}
+
+/// End the session, in order: let the output filter finish, flush what is
+/// buffered, give the terminal back, remove the session, reap the child,
+/// tell subscribers how it ended, and run the on-exit hook.
+///
+/// Every way out of [`run`] once the session is listed comes through here,
+/// with `running` unset if the child was never started. Returns the
+/// session's exit code if it was.
+async fn shutdown(
+    socket_path: &Path,
+    sessions_file: &Path,
+    session_id: &str,
+    running: Option<Running>,
+    exit_tx: &watch::Sender<Option<ChildExit>>,
+    config: &ServerConfig,
+) -> Option<i32> {
+    let Some(mut running) = running else {
+        remove_session(socket_path, sessions_file, session_id);
+        return None;
+    };
+
+    // Let the filter finish what it was given
+    if let Some(mut out) = running.filter_out.take() {
+        drop(running.filter_in.take());
+        let mut buf = vec![0u8; 4096];
+        while let Ok(Ok(n)) = tokio::time::timeout(FILTER_DRAIN_TIMEOUT, out.read(&mut buf)).await
+            && n > 0
+        {
+            if running.pipeline.emit(buf[..n].to_vec()).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Make sure nothing buffered under a lazy flush policy is lost
+    running.pipeline.flush().await;
+
+    // Disable Kitty keyboard protocol
+    if running.keyboard_enhanced {
+        let mut stdout = stdout_writer::lock();
+        let _ = execute!(*stdout, PopKeyboardEnhancementFlags);
+        debug!("Disabled Kitty keyboard protocol");
+    }
+
+    // Restore terminal
+    if let Some(ref termios) = running.orig_termios {
+        let stdin_fd = unsafe { OwnedFd::from_raw_fd(libc::STDIN_FILENO) };
+        restore_terminal(&stdin_fd, termios);
+        std::mem::forget(stdin_fd);
+    }
+
+    // Clean up socket and session entry
+    remove_session(socket_path, sessions_file, session_id);
+
+    // Wait for child, or stop it if we were asked to
+    let exit = if running.terminate {
+        exit::terminate(running.pid, config.term_grace.unwrap_or(DEFAULT_TERM_GRACE))
+    } else {
+        exit::wait_for_child(running.pid)
+    };
+    signals::clear_paused();
+    let final_code = exit.exit_code();
+    let code = if final_code == 0 && running.code == 0 {
+        0
+    } else {
+        final_code
+    };
+
+    // Tell subscribers how the child ended before we go away
+    let _ = exit_tx.send(Some(exit));
+    if exit_tx.receiver_count() > 1 {
+        tokio::time::sleep(EXIT_NOTIFY_GRACE).await;
+    }
+
+    if let Some(command) = &config.on_exit {
+        let info = hooks::ExitInfo {
+            session_id,
+            exit_code: code,
+            signal: exit.signal_name(),
+        };
+        if let Err(e) = hooks::run_on_exit(command, &info, config.wait_on_exit) {
+            error!("Failed to run on-exit hook: {e}");
+        }
+    }
+
+    Some(code)
+}
+
+/// How long to wait for the output filter to drain after the child exits.
+const FILTER_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Default cap on a single client request.
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Default time between `SIGTERM` and `SIGKILL` when stopping the child.
+pub const DEFAULT_TERM_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// How long subscribers get to receive the exit event before we shut down.
+const EXIT_NOTIFY_GRACE: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Malformed requests in a row after which a client is disconnected.
+const MAX_INVALID_REQUESTS: usize = 8;
+
+/// Most rows returned by one `GetFullGrid` request.
+const MAX_GRID_PAGE_ROWS: usize = 500;
+
+/// Full terminal reset (RIS).
+const RESET_SEQUENCE: &[u8] = b"\x1bc";
+
+/// Stop or continue the child for `Pause` / `Resume`.
+fn set_child_paused(paused: bool, group: bool) -> Response {
+    let Some(&pid) = CHILD_PID.get() else {
+        return Response::Error {
+            message: "No child process".to_string(),
+            code: None,
+        };
+    };
+    match signals::set_paused(pid, paused, group) {
+        Ok(()) => Response::Ok,
+        Err(message) => Response::Error {
+            message,
+            code: None,
+        },
+    }
+}
+
+fn handle_request(request: Request, client_id: u64, ctx: &ClientContext) -> Response {
+    match request {
+        Request::GetScrollback {
+            lines,
+            include_current,
+            buffer,
+        } => {
+            // Only the screen is read, so the lock is held for as long as
+            // rendering one screen takes, however much history there is
+            let scrollback = ctx.scrollback.read();
+            let content = match buffer {
+                Some(buffer) => scrollback.get_screen_lines(buffer, lines, include_current),
+                None => scrollback.get_lines(lines, include_current),
+            };
+            Response::Scrollback { content }
+        }
+        Request::GetScrollbackBytes { max_bytes } => {
+            // Build the reply from a copy, so output isn't held up meanwhile
+            let lines = ctx.scrollback.read().lines();
+            let (content, truncated) = lines.tail_bytes(max_bytes);
+            Response::ScrollbackBytes { content, truncated }
+        }
+        Request::GetCursor => {
+            let scrollback = ctx.scrollback.read();
+            let (row, col) = scrollback.cursor_position();
+            Response::Cursor { row, col }
+        }
+        Request::Inject { data, encoding } => {
+            if let Some(&master_fd) = MASTER_FD.get() {
+                inject(master_fd, &data, encoding.as_deref())
+            } else {
+                Response::Error {
+                    message: "No master FD".to_string(),
+                    code: None,
+                }
+            }
+        }
+        Request::GetSize => {
+            let ws = get_window_size();
+            Response::Size {
+                rows: ws.ws_row,
+                cols: ws.ws_col,
+            }
+        }
+        Request::Subscribe
+        | Request::SubscribeCursor
+        | Request::SubscribeFiltered { .. }
+        | Request::ReplayAndFollow { .. } => Response::Subscribed,
+        // Answered by the caller, which holds the input channel
+        Request::SubscribeInput => Response::Ok,
+        // Per-connection state, tracked by the caller
+        Request::PauseOutput
+        | Request::ResumeOutput
+        | Request::Terminate
+        | Request::SetMode { .. }
+        | Request::StreamMode
+        | Request::SetInjectRateLimit { .. } => Response::Ok,
+        // Answered by the caller once the child exits
+        Request::WaitExit { .. } => Response::Ok,
+        Request::GetCurrentLine => {
+            let scrollback = ctx.scrollback.read();
+            let content = scrollback.current_line();
+            Response::CurrentLine { content }
+        }
+        Request::GetScreenReplay => {
+            let scrollback = ctx.scrollback.read();
+            let data = scrollback.screen_replay();
+            Response::ScreenReplay { data }
+        }
+        Request::GetState => Response::State(ctx.scrollback.read().state()),
+        Request::GetInputModes => Response::InputModes(ctx.scrollback.read().input_modes()),
+        Request::GetDiffSince { seq: since } => {
+            let (seq, size, screen) = {
+                let scrollback = ctx.scrollback.read();
+                (
+                    scrollback.seq(),
+                    scrollback.screen_size(),
+                    scrollback.screen_lines(),
+                )
+            };
+            let ops = DIFF_BASES.lock().diff_since(since, seq, size, screen);
+            Response::Diff { ops, seq }
+        }
+        Request::SetTyping { typing } => {
+            pty_writer::set_typing(client_id, typing);
+            Response::Ok
+        }
+        Request::GetInputOwners => Response::InputOwners {
+            client_id,
+            typing: pty_writer::typing_clients(),
+        },
+        Request::ClearScrollback => {
+            ctx.scrollback.write().clear();
+            Response::Ok
+        }
+        Request::ResetTerminal => {
+            // Reset the model, then put the same reset through the output
+            // path so the local terminal and subscribers match it.
+            ctx.scrollback.write().clear();
+            let _ = ctx.output_tx.send(RESET_SEQUENCE.to_vec());
+            if ctx.local_output {
+                let mut stdout = stdout_writer::lock();
+                let _ = std::io::Write::write_all(&mut *stdout, RESET_SEQUENCE);
+                let _ = std::io::Write::flush(&mut *stdout);
+            }
+            Response::Ok
+        }
+        Request::GetStats => {
+            let mut stats = stats::COUNTERS.snapshot();
+            let scrollback = ctx.scrollback.read();
+            stats.lines_discarded = scrollback.lines_discarded();
+            stats.memory_bytes = memory::usage(&scrollback, &ctx.output_tx) as u64;
+            stats.subscribers = ctx.output_tx.receiver_count() as u64;
+            Response::Stats(stats)
+        }
+        Request::GetRecentChunks { count } => Response::RecentChunks {
+            chunks: stats::RECENT_CHUNKS.lock().recent(count),
+        },
+        Request::Capabilities => Response::Capabilities {
+            requests: tap_protocol::REQUEST_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        },
+        Request::GetResourceUsage => match CHILD_PID.get() {
+            Some(&pid) => {
+                let usage = resources::usage(pid);
+                Response::ResourceUsage {
+                    cpu_secs: usage.cpu_secs,
+                    max_rss_kb: usage.max_rss_kb,
+                }
+            }
+            None => Response::Error {
+                message: "No child process".to_string(),
+                code: None,
+            },
+        },
+        Request::GetSessionInfo => match SESSION.read().clone() {
+            Some(session) => Response::SessionInfo(SessionInfo {
+                session,
+                paused: signals::is_paused(),
+            }),
+            None => Response::Error {
+                message: "Session not registered".to_string(),
+                code: None,
+            },
+        },
+        Request::Rename { new_name } => {
+            let mut session = SESSION.write();
+            match (session.as_mut(), SESSIONS_FILE.get()) {
+                (Some(session), Some(file)) => {
+                    match sessions::rename(file, &session.id, &new_name) {
+                        Ok(()) => {
+                            session.name = Some(new_name);
+                            Response::Ok
+                        }
+                        Err(message) => Response::Error {
+                            message,
+                            code: None,
+                        },
+                    }
+                }
+                _ => Response::Error {
+                    message: "Session not registered".to_string(),
+                    code: None,
+                },
+            }
+        }
+        Request::GetLine { index } => {
+            let scrollback = ctx.scrollback.read();
+            Response::Line {
+                content: scrollback.get_line(index).map(str::to_string),
+                total: scrollback.line_count(),
+                discarded: scrollback.lines_discarded(),
+            }
+        }
+        Request::Signal { signal, group } => match CHILD_PID.get() {
+            Some(&pid) => match signals::forward(pid, signal, group) {
+                Ok(()) => Response::Ok,
+                Err(message) => Response::Error {
+                    message,
+                    code: None,
+                },
+            },
+            None => Response::Error {
+                message: "No child process".to_string(),
+                code: None,
+            },
+        },
+        Request::Pause { group } => set_child_paused(true, group),
+        Request::Resume { group } => set_child_paused(false, group),
+        Request::Health => {
+            let pid = CHILD_PID.get().copied();
+            Response::Health {
+                child_alive: pid.is_some_and(exit::is_alive),
+                child_pid: pid.map_or(0, |pid| pid.as_raw() as u32),
+                uptime_secs: STARTED.get().map_or(0, |at| at.elapsed().as_secs()),
+                paused: signals::is_paused(),
+            }
+        }
+        Request::GetFullGrid { start, count } => {
+            let count = count.map_or(MAX_GRID_PAGE_ROWS, |n| n.min(MAX_GRID_PAGE_ROWS));
+            let (rows, total) = ctx.scrollback.write().grid_rows(start, count);
+            Response::FullGrid { rows, start, total }
+        }
+        Request::GetSnapshot => {
+            let scrollback = ctx.scrollback.read();
+            Response::Snapshot(Snapshot {
+                size: scrollback.screen_size(),
+                cursor: scrollback.cursor_position(),
+                rows: scrollback.screen_rows(),
+            })
+        }
+        Request::GetRegion {
+            top,
+            left,
+            rows,
+            cols,
+            styled,
+        } => {
+            let (lines, cells) = ctx.scrollback.read().region(top, left, rows, cols, styled);
+            Response::Region { lines, cells }
+        }
+        Request::GetTranscript => Response::Transcript {
+            entries: ctx.scrollback.read().transcript(),
+        },
+        Request::GetLastCommandOutput => match ctx.scrollback.read().last_command_output() {
+            Some(command) => Response::CommandOutput {
+                output: command.output.clone(),
+                exit_code: command.exit_code,
+            },
+            None => Response::Error {
+                message: "No finished command yet (the shell must emit OSC 133 marks)".to_string(),
+                code: None,
+            },
+        },
+        Request::GetChildEnv => Response::ChildEnv {
+            vars: CHILD_ENV.read().clone(),
+        },
+        Request::GetLinks => {
+            let scrollback = ctx.scrollback.read();
+            let links = scrollback.links();
+            Response::Links { links }
+        }
+    }
+}
+
+/// Write injected text to the PTY, transcoded if an encoding is given.
+fn inject(master_fd: i32, data: &str, encoding: Option<&str>) -> Response {
+    let bytes = match encoding::encode(data, encoding) {
+        Ok(bytes) => bytes,
+        Err(message) => {
+            return Response::Error {
+                message,
+                code: None,
+            };
+        }
+    };
+    match pty_writer::write_all(master_fd, &bytes) {
+        Ok(()) => {
+            stats::add(&stats::COUNTERS.bytes_in, bytes.len());
+            SCROLLBACK.write().record_input(data);
+            Response::Ok
+        }
+        Err(e) => Response::Error {
+            message: e.to_string(),
+            code: None,
+        },
+    }
+}
+
+/// Cursor row, column, and visibility.
+fn cursor_state(scrollback: &RwLock<ScrollbackBuffer>) -> (usize, usize, bool) {
+    let scrollback = scrollback.read();
+    let (row, col) = scrollback.cursor_position();
+    (row, col, scrollback.cursor_visible())
+}
+
+async fn write_message(stream: &mut UnixStream, response: &Response) -> std::io::Result<()> {
+    let mut bytes = serde_json::to_vec(response)?;
+    bytes.push(b'\n');
+    stream.write_all(&bytes).await
+}
+
+/// Channels and limits shared by every client connection.
+#[derive(Clone)]
+struct ClientContext {
+    /// The buffer requests read; the session's `SCROLLBACK` outside tests.
+    scrollback: &'static RwLock<ScrollbackBuffer>,
+    output_tx: broadcast::Sender<Vec<u8>>,
+    /// Off with `--no-local-output`: nothing may be written to stdout.
+    local_output: bool,
+    exit_rx: watch::Receiver<Option<ChildExit>>,
+    size_rx: watch::Receiver<(u16, u16)>,
+    /// Asks the main loop to stop the child.
+    terminate_tx: mpsc::UnboundedSender<()>,
+    max_request_size: usize,
+    /// Request types clients may send (`--allow`); `None` allows all.
+    allowed: Option<Arc<[&'static str]>>,
+    /// Keystrokes forwarded to the child, if shared (`--share-input`).
+    input_tx: Option<broadcast::Sender<Vec<u8>>>,
+    /// Signalled once a client is subscribed to output, which is what
+    /// `--wait-for-client` holds the child back for.
+    subscriber: Arc<tokio::sync::Notify>,
+}
+
+/// Wait for the child to exit, for at most `timeout_ms` if given.
+async fn wait_exit(
+    mut exit_rx: watch::Receiver<Option<ChildExit>>,
+    timeout_ms: Option<u64>,
+) -> Response {
+    let exited = async { exit_rx.wait_for(Option::is_some).await.map(|exit| *exit) };
+    let exit = match timeout_ms {
+        Some(ms) => {
+            match tokio::time::timeout(std::time::Duration::from_millis(ms), exited).await {
+                Ok(exit) => exit,
+                Err(_) => {
+                    return Response::Error {
+                        message: format!("Command still running after {ms} ms"),
+                        code: Some(ErrorCode::TimedOut),
+                    };
+                }
+            }
+        }
+        None => exited.await,
+    };
+    match exit {
+        Ok(Some(exit)) => Response::Exited {
+            code: exit.code,
+            signal: exit.signal_name().map(str::to_string),
+        },
+        _ => Response::Error {
+            message: "Session ended without reporting an exit".to_string(),
+            code: None,
+        },
+    }
+}
+
+/// The next chunk of output for a subscribed connection. Pending until the
+/// connection subscribes.
+async fn next_output(
+    output_rx: &mut Option<broadcast::Receiver<Vec<u8>>>,
+) -> Result<Vec<u8>, broadcast::error::RecvError> {
+    match output_rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// How long a dropped client's remaining input is read and discarded.
+const CLOSE_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Most input read and discarded from a dropped client.
+const CLOSE_DRAIN_BYTES: usize = 4 * 1024 * 1024;
 
+/// Send a final error and close the connection. Input still unread at
+/// close makes the client's reads fail with a reset, often before it sees
+/// the error, so the write half is shut down and what the client sends is
+/// discarded until it closes or a time or byte bound is hit.
+async fn close_with_error(stream: &mut UnixStream, response: &Response) {
+    if write_message(stream, response).await.is_err() {
+        return;
+    }
+    let _ = stream.shutdown().await;
+    let drain = async {
+        let mut scratch = [0u8; 8192];
+        let mut discarded = 0;
+        while discarded < CLOSE_DRAIN_BYTES {
+            match stream.read(&mut scratch).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => discarded += n,
+            }
+        }
+    };
+    let _ = tokio::time::timeout(CLOSE_DRAIN_TIMEOUT, drain).await;
+}
+
+/// Show keystrokes forwarded to the child to input subscribers, if input
+/// is shared.
+fn share_input(input_tx: Option<&broadcast::Sender<Vec<u8>>>, bytes: &[u8]) {
+    if let Some(input_tx) = input_tx {
+        let _ = input_tx.send(bytes.to_vec());
+    }
+}
+
+async fn handle_client(mut stream: UnixStream, mut ctx: ClientContext) {
+    let max_request_size = ctx.max_request_size;
+    let mut buf = Vec::with_capacity(4096);
+    // In stream mode, created when the client subscribes, so it sees output
+    // from that point on: nothing earlier, and nothing queued up while it
+    // wasn't listening
+    let mut output_rx = Some(ctx.output_tx.subscribe());
+    let client_id = pty_writer::next_client_id();
+    let mut subscribed = false;
+    // Output is pushed unasked until the client asks for `StreamMode`
+    let mut pushed = true;
+    // Last cursor state sent to a cursor subscriber
+    let mut cursor: Option<(usize, usize, bool)> = None;
+    // While output is paused, how many bytes this connection has missed
+    let mut paused: Option<u64> = None;
+    // Set by `SubscribeFiltered`: only matching lines are forwarded
+    let mut line_filter: Option<line_filter::LineFilter> = None;
+    // Malformed requests since the last good one
+    let mut invalid = 0;
+    // Set by `SetMode`; this connection may only observe
+    let mut read_only = false;
+    // Live output already sent as part of a `ReplayAndFollow` replay
+    let mut replayed: usize = 0;
+    // Set by `SubscribeInput`
+    let mut input_rx = None;
+    // Set by `SetInjectRateLimit`
+    let mut inject_limit: Option<rate_limit::RateLimit> = None;
+
+    'conn: loop {
         tokio::select! {
             result = stream.read_buf(&mut buf) => {
                 match result {
                     Ok(0) => break,
                     Ok(_) => {
-                        let request: Request = match serde_json::from_slice(&buf) {
-                            Ok(r) => r,
-                            Err(e) => {
-                                warn!("Invalid request: {e}");
+                        let mut frames = Vec::new();
+                        while let Some(frame) = tap_protocol::next_frame(&mut buf) {
+                            frames.push(frame);
+                        }
+                        if buf.len() > max_request_size
+                            || frames.iter().any(|f| f.len() > max_request_size)
+                        {
+                            warn!("Dropping client: request exceeds {max_request_size} bytes");
+                            let response = Response::Error {
+                                message: format!("Request exceeds {max_request_size} bytes"),
+                                code: Some(ErrorCode::InvalidRequest),
+                            };
+                            close_with_error(&mut stream, &response).await;
+                            break;
+                        }
+                        // Older clients send a bare JSON object without a newline
+                        if frames.is_empty() && serde_json::from_slice::<Request>(&buf).is_ok() {
+                            frames.push(std::mem::take(&mut buf));
+                        }
+
+                        for frame in frames {
+                            if frame.iter().all(u8::is_ascii_whitespace) {
                                 continue;
                             }
-                        };
-
-                        let response = match request {
-                            Request::GetScrollback { lines } => {
-                                let scrollback = SCROLLBACK.read();
-                                let content = scrollback.get_lines(lines);
-                                Response::Scrollback { content }
+                            // Each frame is consumed whole, so a bad one is
+                            // skipped up to its newline, never re-parsed
+                            let request: Request = match serde_json::from_slice(&frame) {
+                                Ok(r) => {
+                                    invalid = 0;
+                                    r
+                                }
+                                Err(e) => {
+                                    warn!("Invalid request: {e}");
+                                    invalid += 1;
+                                    if invalid >= MAX_INVALID_REQUESTS {
+                                        warn!("Dropping client: {invalid} invalid requests in a row");
+                                        let response = Response::Error {
+                                            message: format!("{invalid} invalid requests in a row"),
+                                            code: Some(ErrorCode::InvalidRequest),
+                                        };
+                                        close_with_error(&mut stream, &response).await;
+                                        break 'conn;
+                                    }
+                                    continue;
+                                }
+                            };
+                            if let Some(allowed) = &ctx.allowed
+                                && !allowed.contains(&request.type_name())
+                            {
+                                let response = Response::Error {
+                                    message: format!(
+                                        "Request type {} is not allowed on this session",
+                                        request.type_name()
+                                    ),
+                                    code: Some(ErrorCode::PermissionDenied),
+                                };
+                                if write_message(&mut stream, &response).await.is_err() {
+                                    break 'conn;
+                                }
+                                continue;
                             }
-                            Request::GetCursor => {
-                                let scrollback = SCROLLBACK.read();
-                                let (row, col) = scrollback.cursor_position();
-                                Response::Cursor { row, col }
+                            if read_only
+                                && (request.writes_to_session()
+                                    || matches!(request, Request::SetMode { read_only: false }))
+                            {
+                                let response = Response::Error {
+                                    message: "Connection is read-only".to_string(),
+                                    code: None,
+                                };
+                                if write_message(&mut stream, &response).await.is_err() {
+                                    break 'conn;
+                                }
+                                continue;
                             }
-                            Request::Inject { data } => {
-                                if let Some(&master_fd) = MASTER_FD.get() {
-                                    let fd = unsafe { OwnedFd::from_raw_fd(master_fd) };
-                                    let result = unistd::write(&fd, data.as_bytes());
-                                    std::mem::forget(fd);
-                                    match result {
-                                        Ok(_) => Response::Ok,
-                                        Err(e) => Response::Error { message: e.to_string() },
+                            match &request {
+                                Request::SetMode { read_only: mode } => read_only = *mode,
+                                Request::StreamMode => {
+                                    pushed = false;
+                                    if !subscribed && cursor.is_none() {
+                                        output_rx = None;
                                     }
-                                } else {
-                                    Response::Error { message: "No master FD".to_string() }
                                 }
+                                Request::SetInjectRateLimit { per_sec } => {
+                                    let response = match per_sec {
+                                        Some(0) => Response::Error {
+                                            message: "Rate limit must be at least 1 per second".to_string(),
+                                            code: None,
+                                        },
+                                        Some(per_sec) => {
+                                            inject_limit = Some(rate_limit::RateLimit::new(*per_sec, std::time::Instant::now()));
+                                            Response::Ok
+                                        }
+                                        None => {
+                                            inject_limit = None;
+                                            Response::Ok
+                                        }
+                                    };
+                                    if write_message(&mut stream, &response).await.is_err() {
+                                        break 'conn;
+                                    }
+                                    continue;
+                                }
+                                Request::Inject { .. } => {
+                                    if let Some(limit) = &mut inject_limit
+                                        && !limit.allow(std::time::Instant::now())
+                                    {
+                                        let response = Response::Error {
+                                            message: format!(
+                                                "Injection rate limit of {} per second exceeded",
+                                                limit.per_sec()
+                                            ),
+                                            code: Some(ErrorCode::RateLimited),
+                                        };
+                                        if write_message(&mut stream, &response).await.is_err() {
+                                            break 'conn;
+                                        }
+                                        continue;
+                                    }
+                                }
+                                Request::WaitExit { timeout_ms } => {
+                                    let response = wait_exit(ctx.exit_rx.clone(), *timeout_ms).await;
+                                    if write_message(&mut stream, &response).await.is_err() {
+                                        break 'conn;
+                                    }
+                                    continue;
+                                }
+                                Request::Subscribe => {
+                                    subscribed = true;
+                                    line_filter = None;
+                                }
+                                Request::SubscribeFiltered { pattern } => {
+                                    match line_filter::LineFilter::new(pattern) {
+                                        Ok(filter) => {
+                                            subscribed = true;
+                                            line_filter = Some(filter);
+                                        }
+                                        Err(e) => {
+                                            let response = Response::Error {
+                                                message: format!("Invalid pattern: {e}"),
+                                                code: None,
+                                            };
+                                            if write_message(&mut stream, &response).await.is_err() {
+                                                break 'conn;
+                                            }
+                                            continue;
+                                        }
+                                    }
+                                }
+                                Request::ReplayAndFollow { lines } => {
+                                    // Under the lock output is published with,
+                                    // each chunk is either in scrollback or
+                                    // still to come on the new receiver
+                                    let replay = {
+                                        let scrollback = ctx.scrollback.read();
+                                        output_rx = Some(ctx.output_tx.subscribe());
+                                        replayed = output::held_back();
+                                        scrollback.replay_text(*lines)
+                                    };
+                                    ctx.subscriber.notify_one();
+                                    subscribed = true;
+                                    line_filter = None;
+                                    if write_message(&mut stream, &Response::Subscribed).await.is_err() {
+                                        break 'conn;
+                                    }
+                                    if !replay.is_empty() {
+                                        let data = replay.into_bytes();
+                                        if write_message(&mut stream, &Response::Output { data }).await.is_err() {
+                                            break 'conn;
+                                        }
+                                    }
+                                    continue;
+                                }
+                                Request::SubscribeInput => {
+                                    let response = match &ctx.input_tx {
+                                        Some(input_tx) => {
+                                            input_rx = Some(input_tx.subscribe());
+                                            Response::Subscribed
+                                        }
+                                        None => Response::Error {
+                                            message: "Input is not shared on this session (start it with --share-input)".to_string(),
+                                            code: Some(ErrorCode::PermissionDenied),
+                                        },
+                                    };
+                                    if write_message(&mut stream, &response).await.is_err() {
+                                        break 'conn;
+                                    }
+                                    continue;
+                                }
+                                _ => {}
                             }
-                            Request::GetSize => {
-                                let ws = get_window_size();
-                                Response::Size {
-                                    rows: ws.ws_row,
-                                    cols: ws.ws_col,
+                            let subscribe_cursor = matches!(request, Request::SubscribeCursor);
+                            // Subscribe before replying, so output emitted after
+                            // the reply is guaranteed to follow it
+                            if (subscribed || subscribe_cursor) && output_rx.is_none() {
+                                output_rx = Some(ctx.output_tx.subscribe());
+                            }
+                            // A cursor watcher doesn't count: it never sees output
+                            if matches!(request, Request::Subscribe | Request::SubscribeFiltered { .. }) {
+                                ctx.subscriber.notify_one();
+                            }
+                            let mut gap = None;
+                            match request {
+                                Request::PauseOutput => {
+                                    paused.get_or_insert(0);
+                                }
+                                Request::ResumeOutput => gap = paused.take().filter(|&n| n > 0),
+                                Request::Terminate => {
+                                    info!("Client {client_id} asked to terminate the session");
+                                    let _ = ctx.terminate_tx.send(());
                                 }
+                                _ => {}
                             }
-                            Request::Subscribe => {
-                                Response::Subscribed
+
+                            let response = handle_request(request, client_id, &ctx);
+                            if write_message(&mut stream, &response).await.is_err() {
+                                break 'conn;
                             }
-                        };
 
-                        let response_bytes = serde_json::to_vec(&response).unwrap();
-                        if stream.write_all(&response_bytes).await.is_err() {
-                            break;
-                        }
-                        if stream.write_all(b"\n").await.is_err() {
-                            break;
+                            if let Some(dropped_bytes) = gap {
+                                let event = Response::OutputGap { dropped_bytes };
+                                if write_message(&mut stream, &event).await.is_err() {
+                                    break 'conn;
+                                }
+                            }
+
+                            if subscribe_cursor {
+                                let (row, col, visible) = cursor_state(ctx.scrollback);
+                                let event = Response::CursorMoved { row, col, visible };
+                                if write_message(&mut stream, &event).await.is_err() {
+                                    break 'conn;
+                                }
+                                cursor = Some((row, col, visible));
+                            }
                         }
                     }
                     Err(e) => {
@@ -139,43 +1072,146 @@ async fn handle_client(mut stream: UnixStream, output_rx: broadcast::Receiver<Ve
                     }
                 }
             }
-            result = output_rx.recv() => {
+            result = next_output(&mut output_rx) => {
                 match result {
-                    Ok(data) => {
-                        let response = Response::Output { data };
-                        let response_bytes = serde_json::to_vec(&response).unwrap();
-                        if stream.write_all(&response_bytes).await.is_err() {
-                            break;
+                    Ok(mut data) => {
+                        if replayed > 0 {
+                            let n = replayed.min(data.len());
+                            data.drain(..n);
+                            replayed -= n;
                         }
-                        if stream.write_all(b"\n").await.is_err() {
-                            break;
+                        // Over budget, a subscriber with a backlog jumps to
+                        // the present
+                        let skipped = match &mut output_rx {
+                            Some(rx) if memory::over_budget() => memory::skip_backlog(rx),
+                            _ => 0,
+                        };
+                        let data = match &mut line_filter {
+                            Some(filter) => filter.feed(&data),
+                            None => data,
+                        };
+                        if let Some(dropped) = &mut paused {
+                            *dropped += data.len() as u64 + skipped;
+                        } else if subscribed || pushed {
+                            if !data.is_empty()
+                                && write_message(&mut stream, &Response::Output { data }).await.is_err()
+                            {
+                                break;
+                            }
+                            if skipped > 0 {
+                                let event = Response::OutputGap { dropped_bytes: skipped };
+                                if write_message(&mut stream, &event).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        if let Some(last) = cursor {
+                            let (row, col, visible) = cursor_state(ctx.scrollback);
+                            if (row, col, visible) != last {
+                                let event = Response::CursorMoved { row, col, visible };
+                                if write_message(&mut stream, &event).await.is_err() {
+                                    break;
+                                }
+                                cursor = Some((row, col, visible));
+                            }
                         }
                     }
                     Err(broadcast::error::RecvError::Lagged(_)) => continue,
                     Err(broadcast::error::RecvError::Closed) => break,
                 }
             }
+            result = next_output(&mut input_rx) => {
+                match result {
+                    Ok(data) => {
+                        if write_message(&mut stream, &Response::Input { data }).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => input_rx = None,
+                }
+            }
+            result = ctx.size_rx.changed(), if subscribed || cursor.is_some() => {
+                if result.is_err() {
+                    break;
+                }
+                let (rows, cols) = *ctx.size_rx.borrow_and_update();
+                if write_message(&mut stream, &Response::Resized { rows, cols }).await.is_err() {
+                    break;
+                }
+            }
+            result = ctx.exit_rx.changed(), if subscribed || cursor.is_some() || input_rx.is_some() => {
+                if result.is_err() {
+                    break;
+                }
+                let exit = *ctx.exit_rx.borrow_and_update();
+                if let Some(exit) = exit {
+                    let event = Response::Exited {
+                        code: exit.code,
+                        signal: exit.signal_name().map(str::to_string),
+                    };
+                    let _ = write_message(&mut stream, &event).await;
+                    break;
+                }
+            }
         }
     }
+
+    pty_writer::set_typing(client_id, false);
+}
+
+/// Set up `dir` for a session: create it, bind the session's socket, and
+/// add `session` to its `sessions.json`. Failures to write there, e.g. on a
+/// read-only or full filesystem, are explained (see [`paths::dir_error`]).
+fn claim_socket_dir(
+    dir: &std::path::Path,
+    session: &mut Session,
+    follow_symlinks: bool,
+) -> eyre::Result<StdUnixListener> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| paths::dir_error(dir, "create the socket directory", e))?;
+    paths::check_socket_dir(dir, follow_symlinks)?;
+    let _lock = sessions::lock(dir).map_err(|e| paths::dir_error(dir, "lock sessions.json", e))?;
+
+    let socket_path = dir.join(format!("{}.sock", session.id));
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = StdUnixListener::bind(&socket_path)
+        .map_err(|e| paths::dir_error(dir, "create the session socket", e))?;
+
+    let sessions_file = dir.join("sessions.json");
+    paths::check_regular_file(&sessions_file, follow_symlinks)?;
+    let mut sessions: Vec<serde_json::Value> = std::fs::read_to_string(&sessions_file)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    session.short_id = Some(sessions::short_id(&session.id, dir, &sessions));
+    let entry = Session {
+        command: sessions::indexed_command(&session.command),
+        ..session.clone()
+    };
+    sessions.push(serde_json::to_value(entry)?);
+    if let Err(e) = std::fs::write(
+        &sessions_file,
+        serde_json::to_string_pretty(&sessions).unwrap(),
+    ) {
+        let _ = std::fs::remove_file(&socket_path);
+        return Err(paths::dir_error(dir, "write sessions.json", e));
+    }
+    Ok(listener)
 }
 
 async fn run_socket_server(
-    socket_path: std::path::PathBuf,
-    output_tx: broadcast::Sender<Vec<u8>>,
+    std_listener: StdUnixListener,
+    ctx: ClientContext,
 ) -> std::io::Result<()> {
-    let _ = std::fs::remove_file(&socket_path);
-    let std_listener = StdUnixListener::bind(&socket_path)?;
     std_listener.set_nonblocking(true)?;
     let listener = UnixListener::from_std(std_listener)?;
 
-    info!("Listening on {}", socket_path.display());
-
     loop {
         match listener.accept().await {
             Ok((stream, _)) => {
                 debug!("Client connected");
-                let output_rx = output_tx.subscribe();
-                tokio::spawn(handle_client(stream, output_rx));
+                tokio::spawn(handle_client(stream, ctx.clone()));
             }
             Err(e) => {
                 error!("Accept error: {e}");
@@ -184,32 +1220,46 @@ async fn run_socket_server(
     }
 }
 
-fn wait_for_child(child: Pid) -> i32 {
-    use nix::sys::wait::{WaitStatus, waitpid};
-    loop {
-        match waitpid(child, None) {
-            Ok(WaitStatus::Exited(_, code)) => return code,
-            Ok(WaitStatus::Signaled(_, sig, _)) => return 128 + sig as i32,
-            Ok(_) => continue,
-            Err(nix::errno::Errno::EINTR) => continue,
-            Err(_) => return 1,
-        }
-    }
-}
-
 /// Run the PTY server with the given configuration.
 /// Returns the exit code of the child process.
 pub async fn run(config: ServerConfig) -> eyre::Result<i32> {
+    let _ = STARTED.set(std::time::Instant::now());
+    SCROLLBACK.write().set_record_input(config.echo_injections);
+    SCROLLBACK.write().set_answer_queries(config.answer_queries);
+    SCROLLBACK.write().set_ttl(config.scrollback_ttl);
+    SCROLLBACK
+        .write()
+        .set_normalize_controls(config.normalize_controls);
+
     // Load tap config for keybinds
     let tap_config = tap_config::load()?;
     let mut input_processor = input::InputProcessor::new(&tap_config)?;
     let editor_cmd = tap_config::get_editor(&tap_config);
 
-    let session_id = config.session_id.unwrap_or_else(|| human_id::gen_id(3));
+    let session_id = config
+        .session_id
+        .clone()
+        .unwrap_or_else(|| human_id::gen_id(3));
+    let allowed = allowlist(&config.allow)?;
 
-    let socket_dir = tap_protocol::socket_dir();
-    std::fs::create_dir_all(&socket_dir)?;
-    let socket_path = tap_protocol::socket_path(&session_id);
+    // The output FIFO and filter are set up before anything else, so a bad
+    // path or command leaves nothing to clean up
+    let fifo = match &config.output_fifo {
+        Some(path) => Some(
+            fifo::FifoSink::create(path, config.fifo_policy)
+                .map_err(|e| eyre::eyre!("Failed to create output FIFO {}: {e}", path.display()))?,
+        ),
+        None => None,
+    };
+    // Optional output filter; falls back to passthrough if it dies
+    let (_filter_child, mut filter_in, mut filter_out) = match &config.output_filter {
+        Some(command) => {
+            let (child, stdin, stdout) = filter::spawn(command)
+                .map_err(|e| eyre::eyre!("Failed to start output filter: {e}"))?;
+            (Some(child), Some(stdin), Some(stdout))
+        }
+        None => (None, None, None),
+    };
 
     let command = if config.command.is_empty() {
         vec![std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string())]
@@ -217,99 +1267,195 @@ pub async fn run(config: ServerConfig) -> eyre::Result<i32> {
         config.command.clone()
     };
 
-    // Write session info
-    let sessions_file = tap_protocol::sessions_file();
-    let mut sessions: Vec<serde_json::Value> = std::fs::read_to_string(&sessions_file)
-        .ok()
-        .and_then(|s| serde_json::from_str(&s).ok())
-        .unwrap_or_default();
-    sessions.push(serde_json::json!({
-        "id": session_id,
-        "pid": std::process::id(),
-        "started": chrono::Utc::now().to_rfc3339(),
-        "command": command,
-    }));
-    std::fs::write(
-        &sessions_file,
-        serde_json::to_string_pretty(&sessions).unwrap(),
-    )?;
+    // Bind the socket and write session info
+    let mut session = Session {
+        id: session_id.clone(),
+        pid: std::process::id(),
+        started: chrono::Utc::now().to_rfc3339(),
+        command: command.clone(),
+        description: config.description.clone(),
+        name: None,
+        short_id: None,
+    };
+    let mut socket_dir = tap_protocol::socket_dir();
+    let listener = match claim_socket_dir(&socket_dir, &mut session, config.follow_symlinks) {
+        Ok(listener) => listener,
+        Err(e) if config.socket_dir_fallback && paths::is_unwritable(&e) => {
+            let fallback = paths::fallback_socket_dir();
+            eprintln!(
+                "warning: {e:#}; using {} (see it with `tap list --dir {}`)",
+                fallback.display(),
+                fallback.display()
+            );
+            socket_dir = fallback;
+            claim_socket_dir(&socket_dir, &mut session, config.follow_symlinks)?
+        }
+        Err(e) => return Err(e),
+    };
+    let socket_path = socket_dir.join(format!("{session_id}.sock"));
+    let sessions_file = socket_dir.join("sessions.json");
+    let short_id = session.short_id.clone().unwrap_or_default();
+    *SESSION.write() = Some(session);
+    let _ = SESSIONS_FILE.set(sessions_file.clone());
 
-    // Open PTY using openpty
     let ws = get_window_size();
-    let OpenptyResult { master, slave } =
-        pty::openpty(Some(&ws), None).map_err(|e| eyre::eyre!("openpty failed: {e}"))?;
-
-    let master_raw_fd = master.as_raw_fd();
-
-    // Store master FD for signal handler
-    MASTER_FD
-        .set(master_raw_fd)
-        .map_err(|_| eyre::eyre!("Failed to set MASTER_FD"))?;
-
-    // Set up SIGWINCH handler
-    unsafe {
-        signal::signal(Signal::SIGWINCH, SigHandler::Handler(handle_sigwinch))
-            .map_err(|e| eyre::eyre!("Failed to set SIGWINCH handler: {e}"))?;
-    }
+    SCROLLBACK.write().set_size(ws.ws_row, ws.ws_col);
 
-    // Fork child process
-    let child_pid = match unsafe { unistd::fork() } {
-        Ok(ForkResult::Child) => {
-            drop(master);
+    // Resizes are handled in the main loop, which also tells subscribers
+    let mut winch = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change())?;
+    // So are requests to stop, which are passed on to the child
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
 
-            unistd::setsid().expect("setsid failed");
+    // Set up broadcast channel for output
+    let (output_tx, _) = broadcast::channel::<Vec<u8>>(1024);
+    let (exit_tx, exit_rx) = watch::channel(None);
+    let (size_tx, size_rx) = watch::channel((ws.ws_row, ws.ws_col));
+    let (terminate_tx, mut terminate_rx) = mpsc::unbounded_channel();
+    let input_tx = config
+        .share_input
+        .then(|| broadcast::channel::<Vec<u8>>(1024).0);
+    let subscriber = Arc::new(tokio::sync::Notify::new());
+    let ctx = ClientContext {
+        scrollback: &SCROLLBACK,
+        output_tx: output_tx.clone(),
+        local_output: !config.no_local_output,
+        exit_rx,
+        size_rx,
+        terminate_tx,
+        max_request_size: config.max_request_size.unwrap_or(DEFAULT_MAX_REQUEST_SIZE),
+        allowed,
+        input_tx: input_tx.clone(),
+        subscriber: subscriber.clone(),
+    };
 
-            // Set controlling terminal
-            unsafe {
-                libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY as _, 0);
-            }
+    // Start server
+    info!("Listening on {}", socket_path.display());
+    tokio::spawn(async move {
+        if let Err(e) = run_socket_server(listener, ctx).await {
+            error!("Server error: {e}");
+        }
+    });
 
-            // Dup slave to stdin/stdout/stderr using libc directly
-            let slave_raw = slave.as_raw_fd();
-            unsafe {
-                libc::dup2(slave_raw, libc::STDIN_FILENO);
-                libc::dup2(slave_raw, libc::STDOUT_FILENO);
-                libc::dup2(slave_raw, libc::STDERR_FILENO);
-            }
+    if !config.no_local_output {
+        let banner =
+            format!("\x1b[2m[tap: session {session_id}, or {short_id} for short]\x1b[0m\n");
+        let _ = stdout_writer::write_all(banner.as_bytes());
+    }
 
-            if slave_raw > 2 {
-                drop(slave);
+    // Hold the child back until someone is watching, so they see its first byte
+    if config.wait_for_client {
+        info!("Waiting for a client to subscribe before starting the child");
+        let timeout = async {
+            match config.wait_timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
             }
+        };
+        let stopped = tokio::select! {
+            _ = subscriber.notified() => None,
+            _ = timeout => Some(Err(eyre::eyre!(
+                "no client subscribed within {} ms",
+                config.wait_timeout.unwrap_or_default().as_millis()
+            ))),
+            _ = sigterm.recv() => Some(Ok(0)),
+            _ = sighup.recv() => Some(Ok(0)),
+            Some(()) = terminate_rx.recv() => Some(Ok(0)),
+        };
+        if let Some(result) = stopped {
+            shutdown(
+                &socket_path,
+                &sessions_file,
+                &session_id,
+                None,
+                &exit_tx,
+                &config,
+            )
+            .await;
+            return result;
+        }
+    }
 
-            let c_cmd: Vec<std::ffi::CString> = command
-                .iter()
-                .map(|s| std::ffi::CString::new(s.as_str()).unwrap())
-                .collect();
+    // Resolve the child's environment and program before forking
+    let mut overrides = config.env.clone();
+    if let Some(term) = &config.term {
+        overrides.push(("TERM".to_string(), term.clone()));
+    }
+    let env = child_env::build(config.env_clear, &overrides);
+    *CHILD_ENV.write() = child_env::redact(&env);
 
-            unistd::execvp(&c_cmd[0], &c_cmd).expect("execvp failed");
-            unreachable!()
+    // Fork child process, on a PTY unless asked not to. We write input to
+    // `master` and read output from `output`; on a PTY they're the same file.
+    let spawned = (|| -> eyre::Result<_> {
+        let mut child = spawn::ChildCommand::new(&command, &env)?.with_limits(config.limits)?;
+        if config.login {
+            child = child.login()?;
+        }
+        if config.interactive {
+            child = child.interactive();
         }
-        Ok(ForkResult::Parent { child }) => child,
+        if config.no_pty {
+            child.spawn_piped()
+        } else {
+            let OpenptyResult { master, slave } =
+                pty::openpty(Some(&ws), None).map_err(|e| eyre::eyre!("openpty failed: {e}"))?;
+            let child_pid = child.spawn(&master, slave)?;
+            let output = master.try_clone()?;
+            Ok((child_pid, master, output))
+        }
+    })();
+    let (child_pid, master, output) = match spawned {
+        Ok(spawned) => spawned,
+        // Nothing ran, so there is no session to serve
         Err(e) => {
-            return Err(eyre::eyre!("Fork failed: {e}"));
+            shutdown(
+                &socket_path,
+                &sessions_file,
+                &session_id,
+                None,
+                &exit_tx,
+                &config,
+            )
+            .await;
+            return match e.downcast_ref::<spawn::ExecError>() {
+                Some(exec) => {
+                    eprintln!("tap: {exec}");
+                    Ok(exec.exit_code())
+                }
+                None => Err(e),
+            };
         }
     };
+    let _ = CHILD_PID.set(child_pid);
+    let master_raw_fd = master.as_raw_fd();
 
-    // Close slave in parent
-    drop(slave);
+    // Store master FD for injection from clients
+    MASTER_FD
+        .set(master_raw_fd)
+        .map_err(|_| eyre::eyre!("Failed to set MASTER_FD"))?;
 
-    // Save terminal state and set raw mode
+    // Save terminal state and set raw mode. Without a PTY, the local
+    // terminal keeps its line discipline and the child reads lines.
     let stdin_fd = unsafe { OwnedFd::from_raw_fd(libc::STDIN_FILENO) };
-    let orig_termios = match setup_terminal(&stdin_fd) {
-        Ok(t) => Some(t),
-        Err(e) => {
-            debug!("Not a terminal or failed to set raw mode: {e}");
-            None
+    let orig_termios = if config.no_pty || config.no_local_input {
+        None
+    } else {
+        match setup_terminal(&stdin_fd) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                debug!("Not a terminal or failed to set raw mode: {e}");
+                None
+            }
         }
     };
     // Don't close stdin
     std::mem::forget(stdin_fd);
 
     // Enable Kitty keyboard protocol for proper Alt-key detection
-    let keyboard_enhanced = if orig_termios.is_some() {
-        let mut stdout = std::io::stdout();
+    let keyboard_enhanced = if orig_termios.is_some() && !config.no_local_output {
+        let mut stdout = stdout_writer::lock();
         match execute!(
-            stdout,
+            *stdout,
             PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
         ) {
             Ok(()) => {
@@ -325,51 +1471,87 @@ pub async fn run(config: ServerConfig) -> eyre::Result<i32> {
         false
     };
 
-    // Set up broadcast channel for output
-    let (output_tx, _) = broadcast::channel::<Vec<u8>>(1024);
-
-    // Start server
-    let server_output_tx = output_tx.clone();
-    let server_socket_path = socket_path.clone();
-    tokio::spawn(async move {
-        if let Err(e) = run_socket_server(server_socket_path, server_output_tx).await {
-            error!("Server error: {e}");
-        }
-    });
-
-    println!("\x1b[2m[tap: session {session_id}]\x1b[0m");
-
     // Main I/O loop
-    let mut master_file =
-        tokio::fs::File::from_std(unsafe { std::fs::File::from_raw_fd(master.as_raw_fd()) });
-    // Prevent double-close
-    std::mem::forget(master);
+    let mut master_file = tokio::fs::File::from_std(std::fs::File::from(output));
 
     let mut stdin = tokio::io::stdin();
-    let mut stdout = tokio::io::stdout();
+    let stdin_queue = config.stdin_buffer.map(|capacity| {
+        stdin_queue::StdinQueue::spawn(master_raw_fd, capacity, config.stdin_overflow)
+    });
+    let mut pipeline = output::OutputPipeline::new(
+        &SCROLLBACK,
+        output_tx.clone(),
+        config.flush_policy,
+        config.subscriber_coalesce,
+    );
+    if let Some(fifo) = fifo {
+        pipeline.set_fifo(fifo);
+    }
+    if let Some(budget) = config.memory_budget {
+        pipeline.set_memory_budget(budget);
+    }
+    if config.no_local_output {
+        pipeline.disable_local_output();
+    }
+
+    // Old scrollback also expires while there is no output
+    let mut expiry = tokio::time::interval(std::time::Duration::from_secs(1));
 
     let mut master_buf = vec![0u8; 4096];
+    let mut filter_buf = vec![0u8; 4096];
     let mut stdin_buf = vec![0u8; 4096];
+    // Set when we stop the child rather than wait for it to exit
+    let mut terminate = false;
+    // Without a PTY, local stdin ending (e.g. `</dev/null`) doesn't end the session
+    let mut stdin_open = !config.no_local_input;
 
     let exit_code = loop {
         tokio::select! {
+            _ = winch.recv(), if !config.no_pty => {
+                // Keep the last good size if the terminal stopped reporting one
+                if let Some(ws) = read_window_size() {
+                    set_window_size(master_raw_fd, &ws);
+                    SCROLLBACK.write().set_size(ws.ws_row, ws.ws_col);
+                    let _ = size_tx.send((ws.ws_row, ws.ws_col));
+                }
+            }
+            _ = sigterm.recv() => {
+                terminate = true;
+                break 0;
+            }
+            _ = sighup.recv() => {
+                terminate = true;
+                break 0;
+            }
+            Some(()) = terminate_rx.recv() => {
+                terminate = true;
+                break 0;
+            }
             result = master_file.read(&mut master_buf) => {
                 match result {
                     Ok(0) => break 0,
                     Ok(n) => {
-                        let data = master_buf[..n].to_vec();
-
-                        // Update scrollback
-                        SCROLLBACK.write().push(&data);
-
-                        // Broadcast to subscribers
-                        let _ = output_tx.send(data.clone());
+                        let data = if config.no_pty {
+                            spawn::onlcr(&master_buf[..n])
+                        } else {
+                            master_buf[..n].to_vec()
+                        };
+                        stats::add(&stats::COUNTERS.bytes_out, n);
+                        if let Some(started) = STARTED.get() {
+                            stats::RECENT_CHUNKS.lock().record(started.elapsed(), n);
+                        }
 
-                        // Write to stdout
-                        if stdout.write_all(&data).await.is_err() {
+                        if filter_in.is_some() {
+                            if filter::write(&mut filter_in, &data).await.is_ok() {
+                                continue;
+                            }
+                            warn!("Output filter stopped accepting input; passing output through unfiltered");
+                            filter_in = None;
+                            filter_out = None;
+                        }
+                        if pipeline.emit(data).await.is_err() {
                             break 1;
                         }
-                        let _ = stdout.flush().await;
                     }
                     Err(e) => {
                         debug!("Master read error: {e}");
@@ -377,26 +1559,43 @@ pub async fn run(config: ServerConfig) -> eyre::Result<i32> {
                     }
                 }
             }
-            result = stdin.read(&mut stdin_buf) => {
+            result = filter::read(&mut filter_out, &mut filter_buf), if filter_out.is_some() => {
                 match result {
+                    Ok(n) if n > 0 => {
+                        if pipeline.emit(filter_buf[..n].to_vec()).await.is_err() {
+                            break 1;
+                        }
+                    }
+                    _ => {
+                        warn!("Output filter exited; passing output through unfiltered");
+                        filter_in = None;
+                        filter_out = None;
+                    }
+                }
+            }
+            result = stdin.read(&mut stdin_buf), if stdin_open && stdin_queue.as_ref().is_none_or(|q| q.has_room()) => {
+                match result {
+                    Ok(0) if config.no_pty => stdin_open = false,
                     Ok(0) => break 0,
                     Ok(n) => {
                         let input_bytes = &stdin_buf[..n];
                         debug!("stdin received {} bytes: {:02x?}", n, input_bytes);
                         match input_processor.process(input_bytes) {
+                            input::InputResult::Passthrough(bytes) if bytes.is_empty() => {}
                             input::InputResult::Passthrough(bytes) => {
-                                if !bytes.is_empty() {
-                                    let fd = unsafe { OwnedFd::from_raw_fd(master_raw_fd) };
-                                    if unistd::write(&fd, &bytes).is_err() {
-                                        std::mem::forget(fd);
+                                share_input(input_tx.as_ref(), &bytes);
+                                if let Some(queue) = &stdin_queue {
+                                    queue.push(bytes);
+                                } else {
+                                    if pty_writer::write_all(master_raw_fd, &bytes).is_err() {
                                         break 1;
                                     }
-                                    std::mem::forget(fd);
+                                    stats::add(&stats::COUNTERS.bytes_in, bytes.len());
                                 }
                             }
                             input::InputResult::Action(input::KeybindAction::OpenEditor) => {
                                 debug!("OpenEditor action triggered!");
-                                let scrollback_content = SCROLLBACK.read().get_lines(None);
+                                let scrollback_content = SCROLLBACK.read().get_lines(None, true);
                                 if let Err(e) = editor::open_scrollback_in_editor(
                                     &scrollback_content,
                                     &editor_cmd,
@@ -416,52 +1615,753 @@ pub async fn run(config: ServerConfig) -> eyre::Result<i32> {
                     }
                 }
             }
+            _ = expiry.tick(), if config.scrollback_ttl.is_some() => {
+                SCROLLBACK.write().expire(std::time::Instant::now());
+            }
+            _ = tokio::time::sleep_until(pipeline.flush_deadline().unwrap_or_else(tokio::time::Instant::now)), if pipeline.flush_deadline().is_some() => {
+                pipeline.flush().await;
+            }
             _ = tokio::time::sleep(input_processor.escape_timeout()), if input_processor.has_pending_escape() => {
                 if let input::InputResult::Passthrough(bytes) = input_processor.timeout_escape()
                     && !bytes.is_empty()
                 {
-                    let fd = unsafe { OwnedFd::from_raw_fd(master_raw_fd) };
-                    let _ = unistd::write(&fd, &bytes);
-                    std::mem::forget(fd);
+                    share_input(input_tx.as_ref(), &bytes);
+                    match &stdin_queue {
+                        Some(queue) => {
+                            queue.push(bytes);
+                        }
+                        None => {
+                            let _ = pty_writer::write_all(master_raw_fd, &bytes);
+                        }
+                    }
                 }
             }
+            // Stdin reads are paused while the queue is full; look again
+            // once the writer has made progress
+            _ = stdin_queue::room(stdin_queue.as_ref()), if stdin_queue.as_ref().is_some_and(|q| !q.has_room()) => {}
         }
     };
 
-    // Disable Kitty keyboard protocol
-    if keyboard_enhanced {
-        let mut stdout = std::io::stdout();
-        let _ = execute!(stdout, PopKeyboardEnhancementFlags);
-        debug!("Disabled Kitty keyboard protocol");
+    let running = Running {
+        pid: child_pid,
+        terminate,
+        code: exit_code,
+        pipeline,
+        filter_in,
+        filter_out,
+        keyboard_enhanced,
+        orig_termios,
+    };
+    let code = shutdown(
+        &socket_path,
+        &sessions_file,
+        &session_id,
+        Some(running),
+        &exit_tx,
+        &config,
+    )
+    .await;
+    Ok(code.unwrap_or(exit_code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+
+    #[tokio::test]
+    async fn test_oversized_request_rejected() {
+        let (mut reader, mut write_half, guards) = connect(|ctx| ctx.max_request_size = 1024);
+        // An unterminated "inject" far past the limit
+        let mut payload = br#"{"type":"inject","data":""#.to_vec();
+        payload.extend(std::iter::repeat_n(b'a', 64 * 1024));
+        let _ = write_half.write_all(&payload).await;
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let response: Response = serde_json::from_str(&line).unwrap();
+        assert!(matches!(
+            response,
+            Response::Error {
+                code: Some(ErrorCode::InvalidRequest),
+                ..
+            }
+        ));
+
+        // The connection is dropped
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).await.unwrap(), 0);
+        drop(write_half);
+        guards.handler.await.unwrap();
     }
 
-    // Restore terminal
-    if let Some(ref termios) = orig_termios {
-        let stdin_fd = unsafe { OwnedFd::from_raw_fd(libc::STDIN_FILENO) };
-        restore_terminal(&stdin_fd, termios);
-        std::mem::forget(stdin_fd);
+    /// The test's ends of a connection's channels, kept alive for as long
+    /// as the connection is used.
+    struct Guards {
+        output_tx: broadcast::Sender<Vec<u8>>,
+        exit_tx: watch::Sender<Option<ChildExit>>,
+        _size_tx: watch::Sender<(u16, u16)>,
+        terminate_rx: mpsc::UnboundedReceiver<()>,
+        subscriber: Arc<tokio::sync::Notify>,
+        handler: tokio::task::JoinHandle<()>,
     }
 
-    // Clean up socket and session entry
-    let _ = std::fs::remove_file(&socket_path);
+    /// Start `handle_client` on one end of a socket pair, with a default
+    /// context adjusted by `configure`.
+    fn connect(
+        configure: impl FnOnce(&mut ClientContext),
+    ) -> (
+        tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>,
+        tokio::net::unix::OwnedWriteHalf,
+        Guards,
+    ) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (output_tx, _) = broadcast::channel(1024);
+        let (exit_tx, exit_rx) = watch::channel(None);
+        let (size_tx, size_rx) = watch::channel((24, 80));
+        let (terminate_tx, terminate_rx) = mpsc::unbounded_channel();
+        let mut ctx = ClientContext {
+            scrollback: Box::leak(Box::new(RwLock::new(ScrollbackBuffer::new()))),
+            output_tx: output_tx.clone(),
+            local_output: false,
+            exit_rx,
+            size_rx,
+            terminate_tx,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            allowed: None,
+            input_tx: None,
+            subscriber: Arc::new(tokio::sync::Notify::new()),
+        };
+        configure(&mut ctx);
+        let subscriber = ctx.subscriber.clone();
+        let handler = tokio::spawn(handle_client(server, ctx));
+        let (read_half, write_half) = client.into_split();
+        let guards = Guards {
+            output_tx,
+            exit_tx,
+            _size_tx: size_tx,
+            terminate_rx,
+            subscriber,
+            handler,
+        };
+        (tokio::io::BufReader::new(read_half), write_half, guards)
+    }
 
-    // Remove session from sessions.json
-    if let Ok(content) = std::fs::read_to_string(&sessions_file)
-        && let Ok(mut sessions) = serde_json::from_str::<Vec<serde_json::Value>>(&content)
-    {
-        sessions.retain(|s| s.get("id").and_then(|v| v.as_str()) != Some(&session_id));
-        let _ = std::fs::write(
-            &sessions_file,
-            serde_json::to_string_pretty(&sessions).unwrap(),
+    async fn send_line(stream: &mut tokio::net::unix::OwnedWriteHalf, json: &str) {
+        stream.write_all(json.as_bytes()).await.unwrap();
+        stream.write_all(b"\n").await.unwrap();
+    }
+
+    async fn read_response(
+        reader: &mut tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>,
+    ) -> Response {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_repeated_garbage_closes_connection() {
+        let (mut reader, mut write_half, guards) = connect(|_| {});
+
+        // A good request in between resets the count
+        for _ in 0..MAX_INVALID_REQUESTS - 1 {
+            send_line(&mut write_half, "not json").await;
+        }
+        send_line(&mut write_half, r#"{"type":"get_size"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Size { .. }
+        ));
+
+        for _ in 0..MAX_INVALID_REQUESTS {
+            send_line(&mut write_half, "{garbage").await;
+        }
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Error {
+                code: Some(ErrorCode::InvalidRequest),
+                ..
+            }
+        ));
+        let mut rest = String::new();
+        assert_eq!(reader.read_line(&mut rest).await.unwrap(), 0);
+        drop(write_half);
+        tokio::time::timeout(std::time::Duration::from_secs(1), guards.handler)
+            .await
+            .expect("handler still running")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_connection_cannot_write() {
+        let (mut reader, mut write_half, mut guards) = connect(|_| {});
+
+        send_line(&mut write_half, r#"{"type":"set_mode","read_only":true}"#).await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+        for request in [
+            r#"{"type":"inject","data":"rm -rf ~\n"}"#,
+            r#"{"type":"terminate"}"#,
+            r#"{"type":"set_mode","read_only":false}"#,
+        ] {
+            send_line(&mut write_half, request).await;
+            assert!(matches!(
+                read_response(&mut reader).await,
+                Response::Error { ref message, .. } if message.contains("read-only")
+            ));
+        }
+        assert!(guards.terminate_rx.try_recv().is_err());
+
+        // Observing still works
+        send_line(&mut write_half, r#"{"type":"get_size"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Size { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_refuses_other_requests() {
+        let (mut reader, mut write_half, _guards) = connect(|ctx| {
+            ctx.allowed =
+                allowlist(&["GetScrollback".to_string(), "subscribe".to_string()]).unwrap()
+        });
+
+        for request in [
+            r#"{"type":"get_size"}"#,
+            r#"{"type":"inject","data":"ls\n"}"#,
+        ] {
+            send_line(&mut write_half, request).await;
+            assert!(matches!(
+                read_response(&mut reader).await,
+                Response::Error {
+                    code: Some(ErrorCode::PermissionDenied),
+                    ..
+                }
+            ));
+        }
+
+        send_line(&mut write_half, r#"{"type":"get_scrollback"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Scrollback { .. }
+        ));
+        send_line(&mut write_half, r#"{"type":"subscribe"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_inject_rate_limit() {
+        let (mut reader, mut write_half, _guards) = connect(|_| {});
+        send_line(
+            &mut write_half,
+            r#"{"type":"set_inject_rate_limit","per_sec":3}"#,
+        )
+        .await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+
+        // Past the burst, injections are refused before reaching the PTY
+        // (the ones let through fail here for want of one)
+        let mut limited = 0;
+        for _ in 0..10 {
+            send_line(&mut write_half, r#"{"type":"inject","data":""}"#).await;
+            if let Response::Error {
+                code: Some(ErrorCode::RateLimited),
+                message,
+            } = read_response(&mut reader).await
+            {
+                assert_eq!(message, "Injection rate limit of 3 per second exceeded");
+                limited += 1;
+            }
+        }
+        assert!(limited >= 6, "{limited} of 10 limited");
+
+        send_line(&mut write_half, r#"{"type":"set_inject_rate_limit"}"#).await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+        send_line(&mut write_half, r#"{"type":"inject","data":""}"#).await;
+        assert!(!matches!(
+            read_response(&mut reader).await,
+            Response::Error {
+                code: Some(ErrorCode::RateLimited),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_input_needs_shared_input() {
+        let (mut reader, mut write_half, _guards) = connect(|_| {});
+        send_line(&mut write_half, r#"{"type":"subscribe_input"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Error {
+                code: Some(ErrorCode::PermissionDenied),
+                ..
+            }
+        ));
+
+        let (input_tx, _) = broadcast::channel(16);
+        let (mut reader, mut write_half, _guards) =
+            connect(|ctx| ctx.input_tx = Some(input_tx.clone()));
+        send_line(&mut write_half, r#"{"type":"subscribe_input"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+        share_input(Some(&input_tx), b"ls\r");
+        let Response::Input { data } = read_response(&mut reader).await else {
+            panic!("expected input");
+        };
+        assert_eq!(data, b"ls\r");
+    }
+
+    #[test]
+    fn test_window_size_bounds() {
+        let size = |rows, cols| {
+            let ws = sane_window_size(rows, cols);
+            (ws.ws_row, ws.ws_col)
+        };
+        assert_eq!(size(0, 0), DEFAULT_SIZE);
+        assert_eq!(size(0, 80), DEFAULT_SIZE);
+        assert_eq!(size(50, 0), DEFAULT_SIZE);
+        assert_eq!(size(1, 1), (1, 1));
+        assert_eq!(size(50, 200), (50, 200));
+        assert_eq!(size(u16::MAX, 80), (MAX_DIMENSION, 80));
+        assert_eq!(size(u16::MAX, u16::MAX), (MAX_DIMENSION, MAX_DIMENSION));
+    }
+
+    #[tokio::test]
+    async fn test_wait_exit_replies_when_child_exits() {
+        let (mut reader, mut write_half, guards) = connect(|_| {});
+
+        send_line(&mut write_half, r#"{"type":"wait_exit","timeout_ms":20}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Error {
+                code: Some(ErrorCode::TimedOut),
+                ..
+            }
+        ));
+
+        send_line(&mut write_half, r#"{"type":"wait_exit","timeout_ms":null}"#).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        guards
+            .exit_tx
+            .send(Some(ChildExit {
+                code: Some(3),
+                signal: None,
+            }))
+            .unwrap();
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Exited {
+                code: Some(3),
+                signal: None
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reset_terminal_clears_the_model_and_resets_subscribers() {
+        let scrollback: &'static _ = Box::leak(Box::new(RwLock::new(ScrollbackBuffer::new())));
+        scrollback.write().push(b"\x1b[1;31mstyled\x1b[10;20H");
+        let (mut reader, mut write_half, _guards) = connect(|ctx| ctx.scrollback = scrollback);
+        send_line(&mut write_half, r#"{"type":"subscribe"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+
+        send_line(&mut write_half, r#"{"type":"reset_terminal"}"#).await;
+        let mut replied = false;
+        let mut reset = Vec::new();
+        while !replied || reset.is_empty() {
+            match read_response(&mut reader).await {
+                Response::Ok => replied = true,
+                Response::Output { data } => reset.extend(data),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert_eq!(reset, RESET_SEQUENCE);
+
+        // What the child writes next starts from defaults
+        scrollback.write().push(b"x");
+        let scrollback = scrollback.read();
+        assert_eq!(scrollback.cursor_position(), (0, 1));
+        let rows = scrollback.screen_rows();
+        assert_eq!(rows[0][0].text, "x");
+        assert_eq!(rows[0][0].fg, None);
+        assert!(!rows[0][0].bold);
+        assert!(rows[9].iter().all(|cell| cell.text.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_lists_request_types() {
+        let (mut reader, mut write_half, _guards) = connect(|_| {});
+        send_line(&mut write_half, r#"{"type":"capabilities"}"#).await;
+        let Response::Capabilities { requests } = read_response(&mut reader).await else {
+            panic!("expected capabilities");
+        };
+        for core in ["get_scrollback", "inject", "subscribe", "capabilities"] {
+            assert!(requests.iter().any(|r| r == core), "{core} missing");
+        }
+        assert_eq!(requests.len(), tap_protocol::REQUEST_TYPES.len());
+    }
+
+    #[test]
+    fn test_allowlist_rejects_unknown_types() {
+        assert!(allowlist(&[]).unwrap().is_none());
+        let err = allowlist(&["get_scrollback".to_string(), "get_secrets".to_string()])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("get_secrets"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_output() {
+        let (mut reader, mut write_half, guards) = connect(|_| {});
+
+        send_line(&mut write_half, r#"{"type":"subscribe"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+        guards.output_tx.send(b"one".to_vec()).unwrap();
+        assert!(
+            matches!(read_response(&mut reader).await, Response::Output { data } if data == b"one")
+        );
+
+        send_line(&mut write_half, r#"{"type":"pause_output"}"#).await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+        guards.output_tx.send(b"missed".to_vec()).unwrap();
+        let quiet = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            read_response(&mut reader),
+        )
+        .await;
+        assert!(quiet.is_err(), "output arrived while paused");
+
+        send_line(&mut write_half, r#"{"type":"resume_output"}"#).await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::OutputGap { dropped_bytes: 6 }
+        ));
+        guards.output_tx.send(b"two".to_vec()).unwrap();
+        assert!(
+            matches!(read_response(&mut reader).await, Response::Output { data } if data == b"two")
         );
     }
 
-    // Wait for child
-    let final_code = wait_for_child(child_pid);
+    #[tokio::test]
+    async fn test_subscribe_filtered_delivers_matching_lines() {
+        let (mut reader, mut write_half, guards) = connect(|_| {});
 
-    if final_code == 0 && exit_code == 0 {
-        Ok(0)
-    } else {
-        Ok(final_code)
+        send_line(
+            &mut write_half,
+            r#"{"type":"subscribe_filtered","pattern":"("}"#,
+        )
+        .await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Error { .. }
+        ));
+
+        send_line(
+            &mut write_half,
+            r#"{"type":"subscribe_filtered","pattern":"^ERROR"}"#,
+        )
+        .await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+        guards
+            .output_tx
+            .send(b"INFO starting\r\nERROR one\r\nINFO ".to_vec())
+            .unwrap();
+        guards
+            .output_tx
+            .send(b"still fine\r\nERR".to_vec())
+            .unwrap();
+        guards.output_tx.send(b"OR two\r\n".to_vec()).unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < b"ERROR one\nERROR two\n".len() {
+            let Response::Output { data } = read_response(&mut reader).await else {
+                panic!("expected output");
+            };
+            received.extend(data);
+        }
+        assert_eq!(received, b"ERROR one\nERROR two\n");
+    }
+
+    #[tokio::test]
+    async fn test_only_output_subscribers_release_a_waiting_child() {
+        let (mut reader, mut write_half, guards) = connect(|_| {});
+        let released = || {
+            tokio::time::timeout(
+                std::time::Duration::from_millis(100),
+                guards.subscriber.notified(),
+            )
+        };
+
+        send_line(&mut write_half, r#"{"type":"subscribe_cursor"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::CursorMoved { .. }
+        ));
+        assert!(released().await.is_err());
+
+        send_line(&mut write_half, r#"{"type":"subscribe"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+        assert!(released().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_output_is_pushed_until_stream_mode() {
+        let (mut reader, mut write_half, guards) = connect(|_| {});
+
+        // Without subscribing, as older clients do
+        send_line(&mut write_half, r#"{"type":"get_cursor"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Cursor { .. }
+        ));
+        guards.output_tx.send(b"pushed".to_vec()).unwrap();
+        let Response::Output { data } = read_response(&mut reader).await else {
+            panic!("expected output");
+        };
+        assert_eq!(data, b"pushed");
+
+        send_line(&mut write_half, r#"{"type":"stream_mode"}"#).await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+        assert!(guards.output_tx.send(b"unasked".to_vec()).is_err());
+        send_line(&mut write_half, r#"{"type":"get_cursor"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Cursor { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_misses_nothing_after_subscribing() {
+        let (mut reader, mut write_half, guards) = connect(|_| {});
+        let output_tx = guards.output_tx.clone();
+        send_line(&mut write_half, r#"{"type":"stream_mode"}"#).await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+
+        // Output flows continuously while the client subscribes. A send only
+        // succeeds once the client's receiver exists; every one of those
+        // chunks must arrive.
+        let producer = tokio::spawn(async move {
+            let mut delivered = Vec::new();
+            let mut i = 0u32;
+            while delivered.len() < 200 {
+                if output_tx.send(format!("{i},").into_bytes()).is_ok() {
+                    delivered.push(i);
+                }
+                i += 1;
+                tokio::task::yield_now().await;
+            }
+            delivered
+        });
+
+        send_line(&mut write_half, r#"{"type":"subscribe"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+
+        let delivered = producer.await.unwrap();
+        let mut received = Vec::new();
+        while received.len() < delivered.len() {
+            let Response::Output { data } = read_response(&mut reader).await else {
+                panic!("expected output");
+            };
+            let text = String::from_utf8(data).unwrap();
+            received.extend(
+                text.split_terminator(',')
+                    .map(|n| n.parse::<u32>().unwrap()),
+            );
+        }
+        assert_eq!(received, delivered);
+    }
+
+    #[tokio::test]
+    async fn test_replay_and_follow_has_no_gap_or_duplicate() {
+        let scrollback: &'static _ = Box::leak(Box::new(RwLock::new(ScrollbackBuffer::new())));
+        let (mut reader, mut write_half, guards) = connect(|ctx| ctx.scrollback = scrollback);
+        send_line(&mut write_half, r#"{"type":"stream_mode"}"#).await;
+        assert!(matches!(read_response(&mut reader).await, Response::Ok));
+
+        // Numbered lines keep coming while the client asks for a replay.
+        // Coalescing holds some back, so they are in scrollback before any
+        // subscriber gets them.
+        let coalesce = Some(std::time::Duration::from_millis(2));
+        let mut pipeline = output::OutputPipeline::new(
+            scrollback,
+            guards.output_tx.clone(),
+            FlushPolicy::default(),
+            coalesce,
+        );
+        pipeline.disable_local_output();
+        let producer = tokio::spawn(async move {
+            for i in 0..400u32 {
+                pipeline
+                    .emit(format!("tick {i}\r\n").into_bytes())
+                    .await
+                    .unwrap();
+                if i % 7 == 0 {
+                    pipeline.flush().await;
+                }
+                tokio::task::yield_now().await;
+            }
+            pipeline.flush().await;
+        });
+        while !scrollback.read().get_lines(None, true).contains("tick 50") {
+            tokio::task::yield_now().await;
+        }
+
+        send_line(&mut write_half, r#"{"type":"replay_and_follow"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+        let mut text = String::new();
+        while !text.contains("tick 399\r\n") {
+            let Response::Output { data } = read_response(&mut reader).await else {
+                panic!("expected output");
+            };
+            text.push_str(&String::from_utf8(data).unwrap());
+        }
+        producer.await.unwrap();
+
+        let ticks: Vec<u32> = text
+            .split("\r\n")
+            .filter_map(|line| line.strip_prefix("tick ")?.parse().ok())
+            .collect();
+        assert!(ticks[0] > 0, "{text:?}");
+        for pair in ticks.windows(2) {
+            assert_eq!(pair[1], pair[0] + 1, "{text:?}");
+        }
+        assert_eq!(ticks.last(), Some(&399));
+    }
+
+    #[tokio::test]
+    async fn test_no_pty_feeds_scrollback_and_subscribers() {
+        let command = [
+            "sh",
+            "-c",
+            "[ -t 1 ] || echo no-tty; read line; echo \"got $line\"; echo to-stderr >&2",
+        ]
+        .map(String::from);
+        let env = child_env::build(false, &[]);
+        let child = spawn::ChildCommand::new(&command, &env).unwrap();
+        let (child_pid, stdin, output) = child.spawn_piped().unwrap();
+
+        let (mut reader, mut write_half, guards) = connect(|_| {});
+        send_line(&mut write_half, r#"{"type":"subscribe"}"#).await;
+        assert!(matches!(
+            read_response(&mut reader).await,
+            Response::Subscribed
+        ));
+
+        // Injection writes to the child's stdin
+        assert!(matches!(
+            inject(stdin.as_raw_fd(), "hi\n", None),
+            Response::Ok
+        ));
+
+        // The same path the main loop takes for output read from the pipe
+        let scrollback = Box::leak(Box::new(RwLock::new(ScrollbackBuffer::new())));
+        let mut pipeline = output::OutputPipeline::new(
+            scrollback,
+            guards.output_tx.clone(),
+            FlushPolicy::default(),
+            None,
+        );
+        pipeline.disable_local_output();
+        let mut output = tokio::fs::File::from_std(std::fs::File::from(output));
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = output.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            pipeline.emit(spawn::onlcr(&buf[..n])).await.unwrap();
+        }
+        assert_eq!(exit::wait_for_child(child_pid).exit_code(), 0);
+
+        let scrollback = scrollback.read().get_lines(None, true);
+        assert!(
+            scrollback.contains("no-tty\ngot hi\nto-stderr"),
+            "{scrollback:?}"
+        );
+
+        let mut streamed = String::new();
+        while !streamed.contains("to-stderr") {
+            let Response::Output { data } = read_response(&mut reader).await else {
+                panic!("expected output");
+            };
+            streamed.push_str(&String::from_utf8_lossy(&data));
+        }
+        assert!(streamed.contains("no-tty\r\ngot hi\r\n"), "{streamed:?}");
+    }
+
+    #[test]
+    fn test_claim_read_only_socket_dir_fails_clearly() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Permission bits don't stop root
+        if nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(tmp.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        let mut session = Session {
+            id: "read-only".to_string(),
+            pid: 1,
+            started: String::new(),
+            command: Vec::new(),
+            description: None,
+            name: None,
+            short_id: None,
+        };
+
+        let err = claim_socket_dir(tmp.path(), &mut session, false).unwrap_err();
+        assert!(paths::is_unwritable(&err));
+        assert!(err.to_string().contains("is not writable"), "{err:#}");
+        std::fs::set_permissions(tmp.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_inject_latin1() {
+        use std::io::Read;
+
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let response = inject(write_end.as_raw_fd(), "café\n", Some("latin1"));
+        assert!(matches!(response, Response::Ok));
+        assert!(matches!(
+            inject(write_end.as_raw_fd(), "x", Some("klingon")),
+            Response::Error { .. }
+        ));
+        drop(write_end);
+
+        let mut written = Vec::new();
+        std::fs::File::from(read_end)
+            .read_to_end(&mut written)
+            .unwrap();
+        assert_eq!(written, b"caf\xe9\n");
     }
 }
@@ -0,0 +1,122 @@
+//! Process settings applied in the child between `fork` and `exec`:
+//! scheduling priority, open-file limit, and dropping to another user.
+//!
+//! They're checked in the parent first where possible. Anything that still
+//! fails in the child makes it exit with [`SETUP_FAILED_EXIT`] rather than
+//! run the command without the requested restrictions.
+
+use nix::libc;
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+/// Exit code of a child that couldn't apply its [`ChildLimits`].
+pub const SETUP_FAILED_EXIT: i32 = 125;
+
+/// Settings for the child process (`--nice`, `--rlimit-nofile`,
+/// `--setuid`, `--setgid`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChildLimits {
+    /// Scheduling priority (niceness), -20 to 19.
+    pub nice: Option<i32>,
+    /// Soft limit on open file descriptors (`RLIMIT_NOFILE`).
+    pub max_open_files: Option<u64>,
+    /// Run as this user.
+    pub uid: Option<u32>,
+    /// Run as this group. Supplementary groups are dropped when running as
+    /// root.
+    pub gid: Option<u32>,
+}
+
+impl ChildLimits {
+    /// Reject settings that can't work, before forking.
+    pub fn validate(&self) -> eyre::Result<()> {
+        if let Some(nice) = self.nice
+            && !(-20..=19).contains(&nice)
+        {
+            eyre::bail!("Invalid nice value {nice} (expected -20 to 19)");
+        }
+        let root = unistd::geteuid().is_root();
+        if let Some(uid) = self.uid {
+            if User::from_uid(Uid::from_raw(uid))?.is_none() {
+                eyre::bail!("Invalid --setuid {uid}: no such user");
+            }
+            if !root && uid != unistd::getuid().as_raw() {
+                eyre::bail!("Invalid --setuid {uid}: changing user requires root");
+            }
+        }
+        if let Some(gid) = self.gid {
+            if Group::from_gid(Gid::from_raw(gid))?.is_none() {
+                eyre::bail!("Invalid --setgid {gid}: no such group");
+            }
+            if !root && gid != unistd::getgid().as_raw() {
+                eyre::bail!("Invalid --setgid {gid}: changing group requires root");
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the settings to the current process. Only makes
+    /// async-signal-safe calls, so it can run in a forked child. On failure,
+    /// returns a message for stderr.
+    pub fn apply(&self) -> Result<(), &'static str> {
+        if let Some(nice) = self.nice
+            && unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0
+        {
+            return Err("tap: failed to set priority\n");
+        }
+        if let Some(max) = self.max_open_files {
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+                return Err("tap: failed to read open file limit\n");
+            }
+            limit.rlim_cur = max;
+            limit.rlim_max = limit.rlim_max.max(limit.rlim_cur);
+            if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+                return Err("tap: failed to set open file limit\n");
+            }
+        }
+        // Group first, while we still have the privilege to change it
+        if let Some(gid) = self.gid {
+            if unsafe { libc::geteuid() } == 0
+                && unsafe { libc::setgroups(0, std::ptr::null()) } != 0
+            {
+                return Err("tap: failed to drop supplementary groups\n");
+            }
+            if unsafe { libc::setgid(gid) } != 0 {
+                return Err("tap: failed to set group\n");
+            }
+        }
+        if let Some(uid) = self.uid
+            && unsafe { libc::setuid(uid) } != 0
+        {
+            return Err("tap: failed to set user\n");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_nice_rejected() {
+        let limits = ChildLimits {
+            nice: Some(40),
+            ..ChildLimits::default()
+        };
+        assert!(limits.validate().is_err());
+    }
+
+    #[test]
+    fn test_unknown_uid_rejected() {
+        let limits = ChildLimits {
+            uid: Some(u32::MAX - 7),
+            ..ChildLimits::default()
+        };
+        let err = limits.validate().unwrap_err();
+        assert!(err.to_string().contains("no such user"), "{err}");
+    }
+}
@@ -0,0 +1,63 @@
+//! Per-subscriber line filtering (`SubscribeFiltered`).
+//!
+//! Output arrives in arbitrary chunks, so each filtered subscriber keeps its
+//! own parser and line buffer, and only completed lines are matched.
+
+use regex::Regex;
+
+use crate::history::LineHistory;
+use crate::parser::TerminalParser;
+
+/// Turns a subscriber's output stream into the completed lines that match.
+pub struct LineFilter {
+    regex: Regex,
+    parser: TerminalParser,
+    lines: LineHistory,
+}
+
+impl LineFilter {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            regex: Regex::new(pattern)?,
+            parser: TerminalParser::new(),
+            lines: LineHistory::new(usize::MAX),
+        })
+    }
+
+    /// Feed a chunk of output. Returns the lines it completed that match,
+    /// each followed by `\n`, as plain text without escape sequences.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        for event in self.parser.feed(data) {
+            self.lines.handle(&event);
+        }
+        let mut matched = Vec::new();
+        for line in self.lines.take_lines() {
+            if self.regex.is_match(&line) {
+                matched.extend_from_slice(line.as_bytes());
+                matched.push(b'\n');
+            }
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_completed_matching_lines() {
+        let mut filter = LineFilter::new("ERROR|WARN").unwrap();
+        assert_eq!(filter.feed(b"INFO ok\r\nERROR disk "), b"");
+        assert_eq!(
+            filter.feed(b"full\r\nWARN \x1b[33mslow\x1b[0m\r\n"),
+            b"ERROR disk full\nWARN slow\n"
+        );
+        assert_eq!(filter.feed(b"INFO done\r\n"), b"");
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(LineFilter::new("(").is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Keeping buffered output within `--memory-budget`.
+//!
+//! The estimate covers the scrollback model and output queued for
+//! subscribers that haven't caught up. Over budget, scrollback history goes
+//! first; if that isn't enough, lagging subscribers skip what is queued for
+//! them and get an `OutputGap`. The session loses history, not its life.
+//! Other buffers (the output FIFO, the stdin queue) have fixed caps of
+//! their own.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::broadcast;
+
+use crate::scrollback::ScrollbackBuffer;
+use crate::stats;
+
+/// Whether the last check found usage over budget, even after trimming.
+static OVER_BUDGET: AtomicBool = AtomicBool::new(false);
+
+/// Approximate bytes held in scrollback and subscriber queues.
+pub fn usage(scrollback: &ScrollbackBuffer, output_tx: &broadcast::Sender<Vec<u8>>) -> usize {
+    scrollback.approx_bytes() + queued_bytes(output_tx)
+}
+
+/// Output waiting for the slowest subscriber, estimated from the number of
+/// queued chunks and the size of recent ones.
+fn queued_bytes(output_tx: &broadcast::Sender<Vec<u8>>) -> usize {
+    output_tx.len() * stats::RECENT_CHUNKS.lock().average_len()
+}
+
+/// Check usage against `budget` after new output, trimming scrollback if
+/// it's over.
+pub fn enforce(
+    budget: usize,
+    scrollback: &mut ScrollbackBuffer,
+    output_tx: &broadcast::Sender<Vec<u8>>,
+) {
+    let queued = queued_bytes(output_tx);
+    if scrollback.approx_bytes() + queued > budget {
+        // Well under the limit, so this isn't redone for every chunk
+        scrollback.shrink_to((budget / 2).saturating_sub(queued));
+    }
+    let over = scrollback.approx_bytes() + queued > budget;
+    OVER_BUDGET.store(over, Ordering::Relaxed);
+}
+
+/// Whether subscribers should drop their backlog to free memory.
+pub fn over_budget() -> bool {
+    OVER_BUDGET.load(Ordering::Relaxed)
+}
+
+/// Discard everything queued for `rx`, returning how many bytes it missed.
+pub fn skip_backlog(rx: &mut broadcast::Receiver<Vec<u8>>) -> u64 {
+    let mut skipped = 0;
+    loop {
+        match rx.try_recv() {
+            Ok(chunk) => skipped += chunk.len() as u64,
+            Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+            Err(_) => break,
+        }
+    }
+    stats::add(&stats::COUNTERS.shed_subscriber_bytes, skipped as usize);
+    skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lots_of_output_stays_within_budget() {
+        let budget = 128 * 1024;
+        let (output_tx, _rx) = broadcast::channel(16);
+        let mut scrollback = ScrollbackBuffer::new();
+        let line = format!("{}\r\n", "x".repeat(70));
+        for _ in 0..20_000 {
+            scrollback.push(line.as_bytes());
+            enforce(budget, &mut scrollback, &output_tx);
+            assert!(usage(&scrollback, &output_tx) <= budget);
+        }
+        assert!(!over_budget());
+        assert!(scrollback.lines_discarded() > 0);
+        assert!(scrollback.get_lines(None, true).contains(&"x".repeat(70)));
+    }
+
+    #[test]
+    fn test_skip_backlog() {
+        let (output_tx, mut rx) = broadcast::channel(16);
+        for chunk in [b"abc".to_vec(), b"de".to_vec()] {
+            output_tx.send(chunk).unwrap();
+        }
+        assert_eq!(skip_backlog(&mut rx), 5);
+        assert_eq!(skip_backlog(&mut rx), 0);
+        output_tx.send(b"new".to_vec()).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), b"new");
+    }
+}
@@ -0,0 +1,331 @@
+//! Fan-out of child output to scrollback, subscribers, local stdout, and an
+//! optional output FIFO.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::fifo::FifoSink;
+use crate::flush::{FlushPolicy, Flusher};
+use crate::memory;
+use crate::pty_writer;
+use crate::scrollback::ScrollbackBuffer;
+use crate::stats::{self, LoadShedder};
+use crate::stdout_writer;
+
+/// Largest frame a [`Coalescer`] builds before sending early.
+const COALESCE_MAX_BYTES: usize = 64 * 1024;
+
+/// Bytes already in scrollback that a [`Coalescer`] hasn't sent yet. Only
+/// changed while holding the scrollback write lock.
+static HELD_BACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Output that is in scrollback but hasn't reached subscribers. Read with
+/// the scrollback lock held, it tells a new subscriber how much of what it
+/// is about to receive the scrollback already shows.
+pub fn held_back() -> usize {
+    HELD_BACK.load(Ordering::Relaxed)
+}
+
+/// Batches output for subscribers into fewer, larger frames
+/// (`--subscriber-coalesce`). A chunk waits at most one window.
+pub struct Coalescer {
+    window: Duration,
+    pending: Vec<u8>,
+    /// When the oldest pending chunk must go out.
+    deadline: Option<Instant>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    /// Add a chunk. Returns a frame to send now if the window has closed or
+    /// the frame is full.
+    pub fn push(&mut self, data: &[u8], now: Instant) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            self.deadline = Some(now + self.window);
+        }
+        self.pending.extend_from_slice(data);
+        if self.pending.len() >= COALESCE_MAX_BYTES || self.deadline.is_some_and(|d| now >= d) {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    /// When pending output must be sent even if no more arrives.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Everything pending, as one frame.
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        self.deadline = None;
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+}
+
+/// Delivers each chunk of (possibly filtered) child output to every consumer.
+pub struct OutputPipeline {
+    /// The buffer clients read; the session's `SCROLLBACK` outside tests.
+    scrollback: &'static RwLock<ScrollbackBuffer>,
+    /// Off with `--no-local-output`.
+    local_output: bool,
+    flusher: Flusher,
+    shedder: LoadShedder,
+    output_tx: broadcast::Sender<Vec<u8>>,
+    /// Batches subscriber frames; stdout and scrollback are unaffected.
+    coalescer: Option<Coalescer>,
+    fifo: Option<FifoSink>,
+    /// `--memory-budget`, checked after each scrollback update.
+    memory_budget: Option<usize>,
+}
+
+impl OutputPipeline {
+    pub fn new(
+        scrollback: &'static RwLock<ScrollbackBuffer>,
+        output_tx: broadcast::Sender<Vec<u8>>,
+        flush_policy: FlushPolicy,
+        coalesce: Option<Duration>,
+    ) -> Self {
+        Self {
+            scrollback,
+            local_output: true,
+            flusher: Flusher::new(flush_policy),
+            shedder: LoadShedder::new(),
+            output_tx,
+            coalescer: coalesce.map(Coalescer::new),
+            fifo: None,
+            memory_budget: None,
+        }
+    }
+
+    /// Also copy every chunk to a named pipe (`--output-fifo`).
+    pub fn set_fifo(&mut self, fifo: FifoSink) {
+        self.fifo = Some(fifo);
+    }
+
+    /// Stop mirroring output to our own stdout; it only goes to scrollback,
+    /// subscribers, and the FIFO.
+    pub fn disable_local_output(&mut self) {
+        self.local_output = false;
+    }
+
+    /// Keep scrollback and subscriber queues within `bytes`.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.memory_budget = Some(bytes);
+    }
+
+    /// Push a chunk into scrollback and broadcast it. Subscribers get it
+    /// before the scrollback lock is released, so a client that reads
+    /// scrollback and subscribes under the lock (`ReplayAndFollow`) finds
+    /// each chunk in exactly one of the two.
+    fn publish(&mut self, data: &[u8]) {
+        // Update scrollback, unless it recently stalled the loop
+        let start = std::time::Instant::now();
+        let mut scrollback = self.scrollback.write();
+        let pushed = !self.shedder.shedding(start);
+        let mut replies = Vec::new();
+        if pushed {
+            scrollback.push(data);
+            replies = scrollback.take_replies();
+            if let Some(budget) = self.memory_budget {
+                memory::enforce(budget, &mut scrollback, &self.output_tx);
+            }
+            let elapsed = start.elapsed();
+            if self.shedder.record(elapsed, std::time::Instant::now()) {
+                stats::add(&stats::COUNTERS.slow_pushes, 1);
+                warn!("Scrollback update took {elapsed:?}; skipping scrollback updates briefly");
+            }
+        } else {
+            stats::add(&stats::COUNTERS.dropped_scrollback_bytes, data.len());
+        }
+        self.broadcast(data, pushed);
+        drop(scrollback);
+
+        // Answers to the child's terminal queries (`--answer-queries`)
+        if !replies.is_empty()
+            && let Some(&master_fd) = crate::MASTER_FD.get()
+        {
+            match pty_writer::write_all(master_fd, &replies) {
+                Ok(()) => stats::add(&stats::COUNTERS.bytes_in, replies.len()),
+                Err(e) => warn!("Failed to answer a terminal query: {e}"),
+            }
+        }
+    }
+
+    /// Send a chunk to subscribers, now or as part of a coalesced frame.
+    /// `in_scrollback` says whether scrollback already shows it.
+    fn broadcast(&mut self, data: &[u8], in_scrollback: bool) {
+        // Most sessions have no subscribers most of the time; don't copy
+        // every chunk just for the send to fail
+        if self.output_tx.receiver_count() == 0 {
+            return;
+        }
+        match &mut self.coalescer {
+            Some(coalescer) => {
+                if in_scrollback {
+                    HELD_BACK.fetch_add(data.len(), Ordering::Relaxed);
+                }
+                if let Some(frame) = coalescer.push(data, Instant::now()) {
+                    let _ = self.output_tx.send(frame);
+                    HELD_BACK.store(0, Ordering::Relaxed);
+                }
+            }
+            None => {
+                let _ = self.output_tx.send(data.to_vec());
+            }
+        }
+    }
+
+    /// Push a chunk into scrollback, broadcast it, and mirror it to stdout.
+    /// Fails only if stdout can no longer be written.
+    pub async fn emit(&mut self, data: Vec<u8>) -> std::io::Result<()> {
+        self.publish(&data);
+
+        if let Some(fifo) = &self.fifo {
+            fifo.push(&data);
+        }
+
+        // Write to stdout
+        if self.local_output {
+            let now = Instant::now();
+            let flush = self.flusher.wrote(&data, now);
+            stdout_writer::write_async(data, flush).await?;
+            if flush {
+                self.flusher.flushed(now);
+            }
+        }
+        Ok(())
+    }
+
+    /// When buffered stdout or subscriber output must be flushed even if
+    /// nothing else arrives.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        let coalesce = self.coalescer.as_ref().and_then(Coalescer::deadline);
+        match (self.flusher.deadline(), coalesce) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Flush stdout and send any output held back for subscribers.
+    pub async fn flush(&mut self) {
+        if let Some(coalescer) = &mut self.coalescer {
+            let _scrollback = self.scrollback.write();
+            if let Some(frame) = coalescer.take() {
+                let _ = self.output_tx.send(frame);
+                HELD_BACK.store(0, Ordering::Relaxed);
+            }
+        }
+        if self.local_output {
+            stdout_writer::flush_async().await;
+        }
+        self.flusher.flushed(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// Counts allocations per thread, so a test can see what a call costs.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations_during(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    #[test]
+    fn test_broadcast_copies_only_for_subscribers() {
+        let (output_tx, _) = broadcast::channel(1024);
+        let mut pipeline = OutputPipeline::new(
+            &crate::SCROLLBACK,
+            output_tx.clone(),
+            FlushPolicy::default(),
+            None,
+        );
+        let chunk = vec![b'x'; 4096];
+
+        let idle = allocations_during(|| {
+            for _ in 0..1000 {
+                pipeline.broadcast(&chunk, true);
+            }
+        });
+        assert_eq!(idle, 0);
+
+        let mut rx = output_tx.subscribe();
+        let watched = allocations_during(|| {
+            for _ in 0..1000 {
+                pipeline.broadcast(&chunk, true);
+            }
+        });
+        assert_eq!(watched, 1000);
+        assert_eq!(rx.try_recv().unwrap(), chunk);
+    }
+
+    #[test]
+    fn test_coalesce_fewer_larger_frames() {
+        let mut coalescer = Coalescer::new(Duration::from_millis(10));
+        let start = Instant::now();
+        let mut frames = Vec::new();
+        // One byte a millisecond, as from a program writing unbuffered
+        for i in 0..100u64 {
+            let now = start + Duration::from_millis(i);
+            frames.extend(coalescer.push(b"x", now));
+            assert!(
+                coalescer
+                    .deadline()
+                    .is_none_or(|d| d <= now + Duration::from_millis(10))
+            );
+        }
+        frames.extend(coalescer.take());
+
+        assert!(frames.len() <= 10, "{} frames", frames.len());
+        assert!(frames.iter().all(|frame| frame.len() <= 11));
+        assert_eq!(frames.iter().map(Vec::len).sum::<usize>(), 100);
+    }
+
+    #[test]
+    fn test_coalesce_sends_full_frame_early() {
+        let mut coalescer = Coalescer::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(coalescer.push(&[0; 1000], now), None);
+        let frame = coalescer.push(&vec![0; COALESCE_MAX_BYTES], now).unwrap();
+        assert_eq!(frame.len(), COALESCE_MAX_BYTES + 1000);
+        assert_eq!(coalescer.take(), None);
+    }
+}
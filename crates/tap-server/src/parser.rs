@@ -0,0 +1,500 @@
+//! Incremental terminal output parser.
+//!
+//! [`TerminalParser`] turns raw output bytes into [`Event`]s. It keeps state
+//! between calls, so escape sequences split across reads parse the same as if
+//! they arrived whole. The scrollback buffer is built on it, and clients that
+//! subscribe to raw output can use it to maintain their own model.
+
+/// One parsed unit of terminal output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A printable character.
+    Print(char),
+    /// Line feed (also vertical tab and form feed).
+    Newline,
+    CarriageReturn,
+    Backspace,
+    Tab,
+    Bell,
+    CursorMove(CursorMove),
+    /// Show or hide the cursor (`CSI ? 25 h/l`).
+    CursorVisible(bool),
+    /// Switch to (`true`) or back from the alternate screen
+    /// (`CSI ? 1049 h/l`, or the older 1047 and 47).
+    AltScreen(bool),
+    /// Select Graphic Rendition parameters, e.g. `[1, 31]`. A bare `CSI m`
+    /// is reported as `[0]`.
+    Sgr(Vec<u16>),
+    Clear(Clear),
+    /// OSC 8: start a hyperlink to the URI, or end the current one.
+    Hyperlink(Option<String>),
+    /// OSC 0 or 2 window title.
+    Title(String),
+    /// OSC 133 shell integration mark.
+    ShellMark(ShellMark),
+    /// Full reset (`ESC c`).
+    Reset,
+    /// A request for the terminal to report something back.
+    Query(Query),
+}
+
+/// Queries a program sends expecting the terminal to answer on its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    /// `CSI 5 n`: is the terminal OK?
+    Status,
+    /// `CSI 6 n`: where is the cursor?
+    CursorPosition,
+    /// `CSI c`: what kind of terminal is this?
+    DeviceAttributes,
+}
+
+/// Cursor motion. Counts are cells; positions are 0-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorMove {
+    Up(u16),
+    Down(u16),
+    Forward(u16),
+    Back(u16),
+    To { row: u16, col: u16 },
+    Column(u16),
+    Row(u16),
+    Save,
+    Restore,
+}
+
+/// Where a shell says it is in a prompt/command cycle (OSC 133).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellMark {
+    /// `A`: the prompt starts.
+    PromptStart,
+    /// `B`: the prompt ends and the command line starts.
+    CommandStart,
+    /// `C`: the command runs; its output follows.
+    OutputStart,
+    /// `D`: the command finished, with its exit code if the shell gave one.
+    CommandEnd(Option<i32>),
+}
+
+/// Erase in display (`CSI J`) or in line (`CSI K`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clear {
+    Screen(Erase),
+    Line(Erase),
+}
+
+/// Which part of the screen or line to erase, relative to the cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    ToEnd,
+    ToStart,
+    All,
+    /// `CSI 3 J`: the saved scrollback lines.
+    Scrollback,
+}
+
+/// Stateful byte-to-[`Event`] parser.
+pub struct TerminalParser {
+    parser: Option<vte::Parser>,
+}
+
+impl TerminalParser {
+    pub const fn new() -> Self {
+        Self { parser: None }
+    }
+
+    /// Parse a chunk of output, returning the events it completes.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Event> {
+        self.feed_indexed(data)
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    /// Like [`feed`](Self::feed), but each event comes with the index in
+    /// `data` of the byte that completed it.
+    pub fn feed_indexed(&mut self, data: &[u8]) -> Vec<(usize, Event)> {
+        let parser = self.parser.get_or_insert_with(vte::Parser::new);
+        let mut collector = Collector {
+            events: Vec::new(),
+            pos: 0,
+        };
+        for (pos, &byte) in data.iter().enumerate() {
+            collector.pos = pos;
+            parser.advance(&mut collector, byte);
+        }
+        collector.events
+    }
+
+    /// Forget any partially parsed sequence.
+    pub fn reset(&mut self) {
+        self.parser = None;
+    }
+}
+
+impl Default for TerminalParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Collector {
+    events: Vec<(usize, Event)>,
+    /// Index of the byte being parsed.
+    pos: usize,
+}
+
+impl Collector {
+    fn push(&mut self, event: Event) {
+        self.events.push((self.pos, event));
+    }
+}
+
+/// The `i`th parameter, or `default` when missing or zero.
+fn param(params: &vte::Params, i: usize, default: u16) -> u16 {
+    match params.iter().nth(i).and_then(|p| p.first()) {
+        Some(&0) | None => default,
+        Some(&n) => n,
+    }
+}
+
+fn erase(mode: u16) -> Option<Erase> {
+    match mode {
+        0 => Some(Erase::ToEnd),
+        1 => Some(Erase::ToStart),
+        2 => Some(Erase::All),
+        3 => Some(Erase::Scrollback),
+        _ => None,
+    }
+}
+
+impl vte::Perform for Collector {
+    fn print(&mut self, c: char) {
+        self.push(Event::Print(c));
+    }
+
+    fn execute(&mut self, byte: u8) {
+        let event = match byte {
+            b'\n' | 0x0b | 0x0c => Event::Newline,
+            b'\r' => Event::CarriageReturn,
+            0x08 => Event::Backspace,
+            b'\t' => Event::Tab,
+            0x07 => Event::Bell,
+            _ => return,
+        };
+        self.push(event);
+    }
+
+    fn csi_dispatch(
+        &mut self,
+        params: &vte::Params,
+        intermediates: &[u8],
+        ignore: bool,
+        action: char,
+    ) {
+        if ignore {
+            return;
+        }
+        if intermediates == b"?" {
+            if matches!(action, 'h' | 'l') {
+                for param in params.iter() {
+                    match param.first() {
+                        Some(25) => self.push(Event::CursorVisible(action == 'h')),
+                        Some(1049 | 1047 | 47) => self.push(Event::AltScreen(action == 'h')),
+                        _ => {}
+                    }
+                }
+            }
+            return;
+        }
+        if !intermediates.is_empty() {
+            return;
+        }
+
+        let event = match action {
+            'A' => Event::CursorMove(CursorMove::Up(param(params, 0, 1))),
+            'B' => Event::CursorMove(CursorMove::Down(param(params, 0, 1))),
+            'C' => Event::CursorMove(CursorMove::Forward(param(params, 0, 1))),
+            'D' => Event::CursorMove(CursorMove::Back(param(params, 0, 1))),
+            'G' => Event::CursorMove(CursorMove::Column(param(params, 0, 1) - 1)),
+            'd' => Event::CursorMove(CursorMove::Row(param(params, 0, 1) - 1)),
+            'H' | 'f' => Event::CursorMove(CursorMove::To {
+                row: param(params, 0, 1) - 1,
+                col: param(params, 1, 1) - 1,
+            }),
+            's' => Event::CursorMove(CursorMove::Save),
+            'u' => Event::CursorMove(CursorMove::Restore),
+            'J' | 'K' => {
+                let mode = params.iter().next().and_then(|p| p.first()).copied();
+                let Some(erase) = erase(mode.unwrap_or(0)) else {
+                    return;
+                };
+                if action == 'J' {
+                    Event::Clear(Clear::Screen(erase))
+                } else if erase == Erase::Scrollback {
+                    return;
+                } else {
+                    Event::Clear(Clear::Line(erase))
+                }
+            }
+            'n' => match param(params, 0, 0) {
+                5 => Event::Query(Query::Status),
+                6 => Event::Query(Query::CursorPosition),
+                _ => return,
+            },
+            'c' if param(params, 0, 0) == 0 => Event::Query(Query::DeviceAttributes),
+            'm' => {
+                let mut sgr: Vec<u16> = params.iter().flatten().copied().collect();
+                if sgr.is_empty() {
+                    sgr.push(0);
+                }
+                Event::Sgr(sgr)
+            }
+            _ => return,
+        };
+        self.push(event);
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+        let event = match byte {
+            b'c' => Event::Reset,
+            b'7' => Event::CursorMove(CursorMove::Save),
+            b'8' => Event::CursorMove(CursorMove::Restore),
+            _ => return,
+        };
+        self.push(event);
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        match params.first() {
+            // ESC ] 8 ; params ; URI ST -- the URI itself may contain ';'.
+            Some(&b"8") if params.len() >= 3 => {
+                let uri = params[2..]
+                    .iter()
+                    .map(|p| String::from_utf8_lossy(p))
+                    .collect::<Vec<_>>()
+                    .join(";");
+                let uri = (!uri.is_empty()).then_some(uri);
+                self.push(Event::Hyperlink(uri));
+            }
+            Some(&b"0" | &b"2") if params.len() >= 2 => {
+                let title = params[1..]
+                    .iter()
+                    .map(|p| String::from_utf8_lossy(p))
+                    .collect::<Vec<_>>()
+                    .join(";");
+                self.push(Event::Title(title));
+            }
+            // ESC ] 133 ; A|B|C|D [; exit code or options] ST
+            Some(&b"133") if params.len() >= 2 => {
+                let mark = match params[1] {
+                    b"A" => ShellMark::PromptStart,
+                    b"B" => ShellMark::CommandStart,
+                    b"C" => ShellMark::OutputStart,
+                    b"D" => {
+                        let code = params.get(2).and_then(|p| std::str::from_utf8(p).ok());
+                        ShellMark::CommandEnd(code.and_then(|c| c.parse().ok()))
+                    }
+                    _ => return,
+                };
+                self.push(Event::ShellMark(mark));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Vec<Event> {
+        TerminalParser::new().feed(data)
+    }
+
+    #[test]
+    fn test_print_and_controls() {
+        assert_eq!(
+            parse(b"a\tb\r\n\x08\x07"),
+            vec![
+                Event::Print('a'),
+                Event::Tab,
+                Event::Print('b'),
+                Event::CarriageReturn,
+                Event::Newline,
+                Event::Backspace,
+                Event::Bell,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_utf8_print() {
+        assert_eq!(
+            parse("é世".as_bytes()),
+            vec![Event::Print('é'), Event::Print('世')]
+        );
+    }
+
+    #[test]
+    fn test_queries() {
+        assert_eq!(
+            parse(b"\x1b[5n\x1b[6n\x1b[c\x1b[0c\x1b[?6n\x1b[>c\x1b[1c"),
+            vec![
+                Event::Query(Query::Status),
+                Event::Query(Query::CursorPosition),
+                Event::Query(Query::DeviceAttributes),
+                Event::Query(Query::DeviceAttributes),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cursor_moves() {
+        assert_eq!(
+            parse(b"\x1b[A\x1b[3B\x1b[2C\x1b[D\x1b[5;10H\x1b[H\x1b[7G\x1b[4d\x1b[s\x1b[u"),
+            vec![
+                Event::CursorMove(CursorMove::Up(1)),
+                Event::CursorMove(CursorMove::Down(3)),
+                Event::CursorMove(CursorMove::Forward(2)),
+                Event::CursorMove(CursorMove::Back(1)),
+                Event::CursorMove(CursorMove::To { row: 4, col: 9 }),
+                Event::CursorMove(CursorMove::To { row: 0, col: 0 }),
+                Event::CursorMove(CursorMove::Column(6)),
+                Event::CursorMove(CursorMove::Row(3)),
+                Event::CursorMove(CursorMove::Save),
+                Event::CursorMove(CursorMove::Restore),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_esc_save_restore_and_reset() {
+        assert_eq!(
+            parse(b"\x1b7\x1b8\x1bc"),
+            vec![
+                Event::CursorMove(CursorMove::Save),
+                Event::CursorMove(CursorMove::Restore),
+                Event::Reset,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sgr() {
+        assert_eq!(
+            parse(b"\x1b[m\x1b[1;31m\x1b[38;5;208m"),
+            vec![
+                Event::Sgr(vec![0]),
+                Event::Sgr(vec![1, 31]),
+                Event::Sgr(vec![38, 5, 208]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clears() {
+        assert_eq!(
+            parse(b"\x1b[J\x1b[1J\x1b[2J\x1b[3J\x1b[K\x1b[2K"),
+            vec![
+                Event::Clear(Clear::Screen(Erase::ToEnd)),
+                Event::Clear(Clear::Screen(Erase::ToStart)),
+                Event::Clear(Clear::Screen(Erase::All)),
+                Event::Clear(Clear::Screen(Erase::Scrollback)),
+                Event::Clear(Clear::Line(Erase::ToEnd)),
+                Event::Clear(Clear::Line(Erase::All)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cursor_visibility() {
+        assert_eq!(
+            parse(b"\x1b[?25l\x1b[?25h\x1b[?1h"),
+            vec![Event::CursorVisible(false), Event::CursorVisible(true)]
+        );
+    }
+
+    #[test]
+    fn test_alternate_screen() {
+        assert_eq!(
+            parse(b"\x1b[?1049h\x1b[?1049l\x1b[?47h\x1b[?1;25l"),
+            vec![
+                Event::AltScreen(true),
+                Event::AltScreen(false),
+                Event::AltScreen(true),
+                Event::CursorVisible(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_hyperlink_and_title() {
+        assert_eq!(
+            parse(b"\x1b]8;;https://a.test/?x=1;y=2\x07x\x1b]8;;\x1b\\\x1b]2;my title\x07"),
+            vec![
+                Event::Hyperlink(Some("https://a.test/?x=1;y=2".to_string())),
+                Event::Print('x'),
+                Event::Hyperlink(None),
+                Event::Title("my title".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_133_marks() {
+        assert_eq!(
+            parse(
+                b"\x1b]133;A;cl=m\x07\x1b]133;B\x07\x1b]133;C\x1b\\\x1b]133;D;2\x07\x1b]133;D\x07"
+            ),
+            vec![
+                Event::ShellMark(ShellMark::PromptStart),
+                Event::ShellMark(ShellMark::CommandStart),
+                Event::ShellMark(ShellMark::OutputStart),
+                Event::ShellMark(ShellMark::CommandEnd(Some(2))),
+                Event::ShellMark(ShellMark::CommandEnd(None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_sequences_ignored() {
+        assert_eq!(
+            parse(b"\x1b[4n\x1b(B\x1b]52;c;aGk=\x07ok"),
+            vec![Event::Print('o'), Event::Print('k')]
+        );
+    }
+
+    #[test]
+    fn test_sequence_split_across_feeds() {
+        let mut parser = TerminalParser::new();
+        assert!(parser.feed(b"\x1b[1").is_empty());
+        assert_eq!(parser.feed(b";31m"), vec![Event::Sgr(vec![1, 31])]);
+        assert!(parser.feed(&"é".as_bytes()[..1]).is_empty());
+        assert_eq!(parser.feed(&"é".as_bytes()[1..]), vec![Event::Print('é')]);
+    }
+
+    #[test]
+    fn test_feed_indexed_reports_final_byte() {
+        let events = TerminalParser::new().feed_indexed(b"a\x1b[s\x1b8");
+        assert_eq!(
+            events,
+            vec![
+                (0, Event::Print('a')),
+                (3, Event::CursorMove(CursorMove::Save)),
+                (5, Event::CursorMove(CursorMove::Restore)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reset_drops_partial_sequence() {
+        let mut parser = TerminalParser::new();
+        parser.feed(b"\x1b[31");
+        parser.reset();
+        assert_eq!(parser.feed(b"m"), vec![Event::Print('m')]);
+    }
+}
@@ -0,0 +1,156 @@
+//! Sanity checks on the socket directory before writing into it.
+//!
+//! The socket dir may fall back to a shared location like `/tmp/tap`, where
+//! another user could plant a symlink to redirect our sockets and files.
+//! It may also sit on a filesystem that is read-only or full, which gets an
+//! error saying so rather than a bare IO error.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use nix::libc;
+
+/// Verify `dir` is a real directory owned by the current user, not a symlink.
+/// With `follow_symlinks`, a symlink is only warned about.
+pub fn check_socket_dir(dir: &Path, follow_symlinks: bool) -> eyre::Result<()> {
+    let meta = std::fs::symlink_metadata(dir)?;
+    if meta.file_type().is_symlink() {
+        if !follow_symlinks {
+            eyre::bail!(
+                "Socket directory {} is a symlink; refusing to use it (pass --follow-symlinks to override)",
+                dir.display()
+            );
+        }
+        tracing::warn!("Socket directory {} is a symlink", dir.display());
+    }
+
+    let meta = std::fs::metadata(dir)?;
+    if !meta.is_dir() {
+        eyre::bail!("Socket directory {} is not a directory", dir.display());
+    }
+    let uid = nix::unistd::getuid().as_raw();
+    if meta.uid() != uid {
+        eyre::bail!(
+            "Socket directory {} is owned by uid {}, not the current user ({uid})",
+            dir.display(),
+            meta.uid()
+        );
+    }
+    Ok(())
+}
+
+/// Where sessions go when the usual socket directory can't be written
+/// (`--socket-dir-fallback`).
+pub fn fallback_socket_dir() -> PathBuf {
+    let uid = nix::unistd::getuid().as_raw();
+    std::env::temp_dir().join(format!("tap-{uid}"))
+}
+
+/// Why a directory can't be written to, for the usual causes.
+fn unwritable_reason(err: &std::io::Error) -> Option<&'static str> {
+    match err.raw_os_error()? {
+        libc::EROFS => Some("is read-only"),
+        libc::ENOSPC | libc::EDQUOT => Some("is full"),
+        libc::EACCES | libc::EPERM => Some("is not writable"),
+        _ => None,
+    }
+}
+
+/// Describe an IO failure to `action` in the socket directory `dir`.
+pub fn dir_error(dir: &Path, action: &str, err: std::io::Error) -> eyre::Report {
+    let message = match unwritable_reason(&err) {
+        Some(reason) => format!(
+            "Socket directory {} {reason}: failed to {action} (pass --socket-dir-fallback to use {} instead)",
+            dir.display(),
+            fallback_socket_dir().display()
+        ),
+        None => format!("Failed to {action} in {}", dir.display()),
+    };
+    eyre::Report::new(err).wrap_err(message)
+}
+
+/// Whether `err` came from a directory that is read-only, full, or not ours
+/// to write, so trying another directory may help.
+pub fn is_unwritable(err: &eyre::Report) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| unwritable_reason(e).is_some())
+}
+
+/// Verify `path` (if it exists) is a regular file rather than a symlink.
+pub fn check_regular_file(path: &Path, follow_symlinks: bool) -> eyre::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            if !follow_symlinks {
+                eyre::bail!(
+                    "{} is a symlink; refusing to write through it (pass --follow-symlinks to override)",
+                    path.display()
+                );
+            }
+            tracing::warn!("{} is a symlink", path.display());
+            Ok(())
+        }
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_real_dir_accepted() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(check_socket_dir(tmp.path(), false).is_ok());
+    }
+
+    #[test]
+    fn test_symlinked_dir_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("elsewhere");
+        std::fs::create_dir(&target).unwrap();
+        let link = tmp.path().join("tap");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let err = check_socket_dir(&link, false).unwrap_err();
+        assert!(err.to_string().contains("symlink"));
+        assert!(check_socket_dir(&link, true).is_ok());
+    }
+
+    #[test]
+    fn test_symlinked_sessions_file_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("victim");
+        std::fs::write(&target, "[]").unwrap();
+        let link = tmp.path().join("sessions.json");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        assert!(check_regular_file(&link, false).is_err());
+        assert!(check_regular_file(&target, false).is_ok());
+        assert!(check_regular_file(&tmp.path().join("missing"), false).is_ok());
+    }
+
+    #[test]
+    fn test_read_only_dir_error_is_explained() {
+        let err = dir_error(
+            Path::new("/run/user/1000"),
+            "create the session socket",
+            std::io::Error::from_raw_os_error(libc::EROFS),
+        );
+        let message = err.to_string();
+        assert!(
+            message.starts_with("Socket directory /run/user/1000 is read-only"),
+            "{message}"
+        );
+        assert!(message.contains("--socket-dir-fallback"));
+        assert!(is_unwritable(&err));
+
+        let other = dir_error(
+            Path::new("/run/user/1000"),
+            "create the session socket",
+            std::io::Error::from_raw_os_error(libc::EADDRINUSE),
+        );
+        assert!(!is_unwritable(&other));
+    }
+}
@@ -0,0 +1,151 @@
+//! Shared writes into the PTY master.
+//!
+//! Local stdin and every connected client write to the same master FD, so
+//! input from several sources interleaves. Each call to [`write_all`] is
+//! delivered contiguously: writes are serialized and partial writes are
+//! completed before another writer gets a turn. Interleaving therefore only
+//! happens at write-call boundaries, never inside a single payload. Writes
+//! interrupted by a signal (`EINTR`) are retried.
+//!
+//! Clients can also advertise that they are typing, so cooperating tools can
+//! avoid talking over each other. This is advisory; nothing is enforced.
+
+use std::collections::BTreeSet;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use nix::errno::Errno;
+use nix::unistd;
+use parking_lot::Mutex;
+
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+static TYPING: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Write all of `data` to `fd` without interleaving with other writers.
+pub fn write_all(fd: RawFd, data: &[u8]) -> nix::Result<()> {
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let _guard = WRITE_LOCK.lock();
+    let mut remaining = data;
+    while !remaining.is_empty() {
+        match unistd::write(fd, remaining) {
+            Ok(n) => remaining = &remaining[n..],
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Allocate an ID for a new client connection.
+pub fn next_client_id() -> u64 {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Mark a client as typing or not.
+pub fn set_typing(client_id: u64, typing: bool) {
+    let mut typing_set = TYPING.lock();
+    if typing {
+        typing_set.insert(client_id);
+    } else {
+        typing_set.remove(&client_id);
+    }
+}
+
+/// Clients currently marked as typing.
+pub fn typing_clients() -> Vec<u64> {
+    TYPING.lock().iter().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_concurrent_writes_stay_contiguous() {
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        let raw = write_end.as_raw_fd();
+
+        // Larger than a pipe buffer, so each write needs several syscalls.
+        let payload_a = vec![b'a'; 200_000];
+        let payload_b = vec![b'b'; 200_000];
+
+        let reader = std::thread::spawn(move || {
+            let mut out = Vec::new();
+            std::fs::File::from(read_end).read_to_end(&mut out).unwrap();
+            out
+        });
+        let writers: Vec<_> = [payload_a.clone(), payload_b.clone()]
+            .into_iter()
+            .map(|payload| std::thread::spawn(move || write_all(raw, &payload).unwrap()))
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        drop(write_end);
+
+        let out = reader.join().unwrap();
+        let ab = [payload_a.as_slice(), payload_b.as_slice()].concat();
+        let ba = [payload_b.as_slice(), payload_a.as_slice()].concat();
+        assert!(out == ab || out == ba, "payloads were interleaved");
+    }
+
+    #[test]
+    fn test_write_survives_signals() {
+        use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+        use std::os::unix::thread::JoinHandleExt;
+
+        // No SA_RESTART, so a blocked write returns EINTR
+        extern "C" fn noop(_: nix::libc::c_int) {}
+        let action = SigAction::new(SigHandler::Handler(noop), SaFlags::empty(), SigSet::empty());
+        unsafe { signal::sigaction(Signal::SIGUSR1, &action) }.unwrap();
+
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        let payload: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+
+        // Drain slowly so the writer keeps blocking on a full pipe
+        let reader = std::thread::spawn(move || {
+            let mut file = std::fs::File::from(read_end);
+            let mut out = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                match file.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => out.extend_from_slice(&buf[..n]),
+                }
+                std::thread::sleep(std::time::Duration::from_micros(50));
+            }
+            out
+        });
+        let expected = payload.clone();
+        let writer = std::thread::spawn(move || {
+            let result = write_all(write_end.as_raw_fd(), &payload);
+            drop(write_end);
+            result
+        });
+
+        while !writer.is_finished() {
+            unsafe { nix::libc::pthread_kill(writer.as_pthread_t(), nix::libc::SIGUSR1) };
+            std::thread::sleep(std::time::Duration::from_micros(200));
+        }
+
+        writer.join().unwrap().unwrap();
+        assert_eq!(reader.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_typing_registry() {
+        let a = next_client_id();
+        let b = next_client_id();
+        assert_ne!(a, b);
+
+        set_typing(a, true);
+        assert!(typing_clients().contains(&a));
+        assert!(!typing_clients().contains(&b));
+
+        set_typing(a, false);
+        assert!(!typing_clients().contains(&a));
+    }
+}
@@ -0,0 +1,65 @@
+//! A cap on how fast one connection may inject (`SetInjectRateLimit`), so
+//! automation that reacts to output its own injections cause can't hammer
+//! the PTY in a loop.
+
+use std::time::Instant;
+
+/// A token bucket: bursts of up to `per_sec` injections, refilled at
+/// `per_sec` a second.
+#[derive(Debug)]
+pub struct RateLimit {
+    per_sec: u32,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    /// A full bucket.
+    pub fn new(per_sec: u32, now: Instant) -> Self {
+        Self {
+            per_sec,
+            tokens: f64::from(per_sec),
+            last: now,
+        }
+    }
+
+    pub fn per_sec(&self) -> u32 {
+        self.per_sec
+    }
+
+    /// Take a token for an injection at `now`; false if none is left.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let per_sec = f64::from(self.per_sec);
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * per_sec).min(per_sec);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(5, start);
+        let allowed = (0..20).filter(|_| limit.allow(start)).count();
+        assert_eq!(allowed, 5);
+
+        // Refills at 5 a second, up to a full bucket
+        assert!(!limit.allow(start + Duration::from_millis(100)));
+        assert!(limit.allow(start + Duration::from_millis(200)));
+        assert!(!limit.allow(start + Duration::from_millis(200)));
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| limit.allow(later)).count(), 5);
+    }
+}
@@ -0,0 +1,155 @@
+//! CPU time and peak memory of the child (`GetResourceUsage`).
+//!
+//! While the child runs, Linux reports both in `/proc`. Once it is gone
+//! (or on other platforms) we fall back to the last reading and to the
+//! kernel's totals for children we have reaped.
+
+use nix::libc;
+use nix::unistd::Pid;
+use parking_lot::Mutex;
+
+/// What the child has used so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    /// User plus system CPU time, including children it waited for.
+    pub cpu_secs: f64,
+    /// Peak resident set size in kilobytes.
+    pub max_rss_kb: u64,
+}
+
+/// The most recent reading from `/proc`, reported after the child exits.
+static LAST: Mutex<Usage> = Mutex::new(Usage {
+    cpu_secs: 0.0,
+    max_rss_kb: 0,
+});
+
+/// Resource usage of `pid`, or the last known usage if it has exited.
+pub fn usage(pid: Pid) -> Usage {
+    if let Some(now) = read_proc(pid) {
+        *LAST.lock() = now;
+        return now;
+    }
+    let last = *LAST.lock();
+    let reaped = reaped_children();
+    Usage {
+        cpu_secs: last.cpu_secs.max(reaped.cpu_secs),
+        max_rss_kb: last.max_rss_kb.max(reaped.max_rss_kb),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_proc(pid: Pid) -> Option<Usage> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let cpu_secs = parse_stat_cpu_ticks(&stat)? as f64 / clock_ticks_per_sec();
+    // A zombie has no memory left to report; keep the last peak
+    let max_rss_kb = std::fs::read_to_string(format!("/proc/{pid}/status"))
+        .ok()
+        .and_then(|status| parse_status_hwm_kb(&status))
+        .unwrap_or_else(|| LAST.lock().max_rss_kb);
+    Some(Usage {
+        cpu_secs,
+        max_rss_kb,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_proc(_pid: Pid) -> Option<Usage> {
+    None
+}
+
+/// `utime + stime + cutime + cstime` from `/proc/<pid>/stat`, in clock
+/// ticks. The command name may contain spaces and parentheses, so fields
+/// are counted from the last `)`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_stat_cpu_ticks(stat: &str) -> Option<u64> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    // `rest` starts at field 3 (state); utime is field 14
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    fields
+        .get(11..15)?
+        .iter()
+        .map(|field| field.parse::<u64>().ok())
+        .sum()
+}
+
+/// `VmHWM` (peak resident set size) from `/proc/<pid>/status`, in kB.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_status_hwm_kb(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(target_os = "linux")]
+fn clock_ticks_per_sec() -> f64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as f64,
+        _ => 100.0,
+    }
+}
+
+/// Totals for every child this process has reaped.
+fn reaped_children() -> Usage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return Usage::default();
+    }
+    let secs = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1e6;
+    // Linux reports kilobytes, macOS bytes
+    let max_rss = usage.ru_maxrss.max(0) as u64;
+    let max_rss_kb = if cfg!(target_os = "macos") {
+        max_rss / 1024
+    } else {
+        max_rss
+    };
+    Usage {
+        cpu_secs: secs(usage.ru_utime) + secs(usage.ru_stime),
+        max_rss_kb,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat_with_awkward_name() {
+        let stat = "42 (my (odd) cmd) R 1 42 42 0 -1 4194304 100 0 0 0 \
+                    250 50 7 3 20 0 1 0 12345 1000000 200";
+        assert_eq!(parse_stat_cpu_ticks(stat), Some(250 + 50 + 7 + 3));
+        assert_eq!(parse_stat_cpu_ticks("42 (truncated) R 1"), None);
+    }
+
+    #[test]
+    fn test_parse_status_hwm() {
+        let status = "Name:\tsh\nVmPeak:\t  9000 kB\nVmHWM:\t    1234 kB\nVmRSS:\t 1000 kB\n";
+        assert_eq!(parse_status_hwm_kb(status), Some(1234));
+        assert_eq!(parse_status_hwm_kb("Name:\tsh\n"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_busy_child_uses_cpu() {
+        use crate::{child_env, spawn};
+
+        let env = child_env::build(false, &[]);
+        let command = ["sh", "-c", "while :; do :; done"].map(str::to_string);
+        let child = spawn::ChildCommand::new(&command, &env).unwrap();
+        let (pid, _stdin, _output) = child.spawn_piped().unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        let busy = usage(pid);
+        nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL).unwrap();
+        crate::exit::wait_for_child(pid);
+
+        assert!(busy.cpu_secs > 0.0, "{busy:?}");
+        assert!(busy.max_rss_kb > 0, "{busy:?}");
+        // Once it's gone, the last reading still stands
+        assert!(usage(pid).cpu_secs >= busy.cpu_secs);
+    }
+}
@@ -0,0 +1,179 @@
+//! Screen changes between two points in the output (`GetDiffSince`), for
+//! clients that poll over slow links instead of subscribing.
+//!
+//! The terminal model only knows the screen as it is now, so screens are
+//! remembered as they are handed out: a client can ask what changed since
+//! any of the last [`MAX_BASES`] it was given.
+
+use std::collections::VecDeque;
+
+use tap_protocol::DiffOp;
+
+/// Screens remembered for clients to diff against.
+const MAX_BASES: usize = 64;
+
+/// Screens recently sent to clients, by output sequence number.
+pub struct Bases {
+    screens: VecDeque<(u64, Vec<String>)>,
+}
+
+impl Bases {
+    pub const fn new() -> Self {
+        Self {
+            screens: VecDeque::new(),
+        }
+    }
+
+    /// Ops that turn the screen as it was at `since` into `screen`, which
+    /// is the screen of size `size` at `seq`. A `since` not remembered
+    /// (including `0`) gets a full repaint.
+    pub fn diff_since(
+        &mut self,
+        since: u64,
+        seq: u64,
+        size: (u16, u16),
+        screen: Vec<String>,
+    ) -> Vec<DiffOp> {
+        let base = self
+            .screens
+            .iter()
+            .find(|(at, _)| since != 0 && *at == since);
+        let ops = match base {
+            Some((_, old)) if old.len() == screen.len() => diff(old, &screen),
+            _ => repaint(size, &screen),
+        };
+        if !self.screens.iter().any(|(at, _)| *at == seq) {
+            self.screens.push_back((seq, screen));
+            if self.screens.len() > MAX_BASES {
+                self.screens.pop_front();
+            }
+        }
+        ops
+    }
+}
+
+fn repaint((rows, cols): (u16, u16), screen: &[String]) -> Vec<DiffOp> {
+    let mut ops = vec![DiffOp::Reset { rows, cols }];
+    ops.extend(set_lines(&vec![String::new(); screen.len()], screen));
+    ops
+}
+
+/// Ops that turn `old` into `new`, both the same number of rows: a scroll
+/// if one lines up more rows than leaving them in place, then the rows
+/// that still differ.
+fn diff(old: &[String], new: &[String]) -> Vec<DiffOp> {
+    let matching = |n: usize| {
+        old[n..]
+            .iter()
+            .zip(new)
+            .filter(|(a, b)| a == b && !a.is_empty())
+            .count()
+    };
+    let (scroll, best) = (1..old.len())
+        .map(|n| (n, matching(n)))
+        .max_by_key(|&(n, count)| (count, std::cmp::Reverse(n)))
+        .unwrap_or((0, 0));
+
+    let mut ops = Vec::new();
+    let mut scrolled = old.to_vec();
+    if best > matching(0) {
+        ops.push(DiffOp::ScrollUp { n: scroll as u16 });
+        tap_protocol::apply_diff(&mut scrolled, &ops);
+    }
+    ops.extend(set_lines(&scrolled, new));
+    ops
+}
+
+fn set_lines(old: &[String], new: &[String]) -> Vec<DiffOp> {
+    (0..)
+        .zip(old.iter().zip(new))
+        .filter(|(_, (a, b))| a != b)
+        .map(|(row, (_, content))| DiffOp::SetLine {
+            row,
+            content: content.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tap_protocol::apply_diff;
+
+    use super::*;
+    use crate::scrollback::ScrollbackBuffer;
+
+    fn rows(text: &[&str], count: usize) -> Vec<String> {
+        let mut rows: Vec<String> = text.iter().map(ToString::to_string).collect();
+        rows.resize(count, String::new());
+        rows
+    }
+
+    #[test]
+    fn test_scroll_then_changed_rows() {
+        let old = rows(&["a", "b", "c", "d"], 4);
+        let new = rows(&["c", "d", "e"], 4);
+        assert_eq!(
+            diff(&old, &new),
+            [
+                DiffOp::ScrollUp { n: 2 },
+                DiffOp::SetLine {
+                    row: 2,
+                    content: "e".to_string()
+                }
+            ]
+        );
+        // Nothing lines up better than staying put
+        let edited = rows(&["a", "x", "c", "d"], 4);
+        assert_eq!(
+            diff(&old, &edited),
+            [DiffOp::SetLine {
+                row: 1,
+                content: "x".to_string()
+            }]
+        );
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_diffs_keep_a_replica_in_step() {
+        let mut bases = Bases::new();
+        let mut buf = ScrollbackBuffer::new();
+        let mut replica = Vec::new();
+        let mut seq = 0;
+        let mut poll = |buf: &ScrollbackBuffer, replica: &mut Vec<String>, seq: &mut u64| {
+            let ops = bases.diff_since(*seq, buf.seq(), buf.screen_size(), buf.screen_lines());
+            apply_diff(replica, &ops);
+            assert_eq!(*replica, buf.screen_lines());
+            *seq = buf.seq();
+            ops
+        };
+
+        // The first poll repaints
+        buf.push(b"$ ls\r\n");
+        let ops = poll(&buf, &mut replica, &mut seq);
+        assert_eq!(ops[0], DiffOp::Reset { rows: 24, cols: 80 });
+
+        // Nothing new, nothing to do
+        assert!(poll(&buf, &mut replica, &mut seq).is_empty());
+
+        // Scrolling costs one op plus the new rows
+        for i in 0..40 {
+            buf.push(format!("line {i}\r\n").as_bytes());
+        }
+        poll(&buf, &mut replica, &mut seq);
+        buf.push(b"line 40\r\nline 41\r\n$ ");
+        let ops = poll(&buf, &mut replica, &mut seq);
+        assert_eq!(ops[0], DiffOp::ScrollUp { n: 2 });
+        assert_eq!(ops.len(), 4, "{ops:?}");
+
+        // In-place edits and clears
+        buf.push(b"\x1b[5;1Hedited\x1b[2J\x1b[Htop");
+        poll(&buf, &mut replica, &mut seq);
+
+        // A client that was never given `seq` starts over
+        let mut stranger = vec!["junk".to_string()];
+        let ops = bases.diff_since(12345, buf.seq(), buf.screen_size(), buf.screen_lines());
+        apply_diff(&mut stranger, &ops);
+        assert_eq!(stranger, buf.screen_lines());
+    }
+}
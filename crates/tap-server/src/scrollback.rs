@@ -1,44 +1,541 @@
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use tap_protocol::{Cell, Color, InputModes, ScreenBuffer, TerminalState, TranscriptEntry};
+
+use crate::commands::{CommandOutput, CommandTracker};
+use crate::controls::ControlNormalization;
+use crate::encoding::Utf8Repair;
+use crate::history::{LineHistory, Lines};
+use crate::hyperlink::LinkTracker;
+use crate::parser::{CursorMove, Event, Query, TerminalParser};
+
 const DEFAULT_SCROLLBACK_LINES: usize = 10000;
 
-/// A scrollback buffer backed by vt100 terminal emulator
+/// Rough memory per cell of the terminal model: its text plus attributes.
+const CELL_BYTES: usize = 32;
+
+/// A scrollback buffer backed by vt100 terminal emulator, with extra state
+/// (hyperlinks, line history) derived from [`TerminalParser`] events.
 pub struct ScrollbackBuffer {
+    /// Keeps the stored text valid UTF-8 whatever the child writes.
+    utf8: Utf8Repair,
+    /// Stray control bytes are cleaned up first (`--normalize-controls`).
+    controls: Option<ControlNormalization>,
     parser: Option<vt100::Parser>,
+    /// Size of the terminal model as `(rows, cols)`, kept to the window's.
+    size: (u16, u16),
+    events: TerminalParser,
+    links: LinkTracker,
+    history: LineHistory,
+    commands: CommandTracker,
+    /// Last window title set with OSC 0 or 2.
+    title: Option<String>,
     max_lines: usize,
+    /// Note injected input in the transcript (`--echo-injections`).
+    record_input: bool,
+    /// Answer terminal queries (`--answer-queries`), collecting the
+    /// answers in `replies` for the caller to send to the child.
+    answer_queries: bool,
+    replies: Vec<u8>,
+    /// Keep lines for at most this long (`--scrollback-ttl`).
+    ttl: Option<Duration>,
+    /// Since when the terminal model may have held its scrolled-off rows.
+    model_since: Option<Instant>,
+    /// Scrolled-off rows the terminal model holds, counted after each
+    /// change to it, since counting them means scrolling the model.
+    model_rows: usize,
+    /// Output sequence number: starts at 1 and advances with each push and
+    /// clear, so two reads at the same number saw the same screen.
+    seq: u64,
+    /// The primary screen as it was when a full-screen app switched away.
+    primary_saved: Option<ScreenText>,
+    /// The alternate screen as it was when the app switched back.
+    alternate_saved: Option<ScreenText>,
+}
+
+/// Text of a screen that is no longer showing.
+struct ScreenText {
+    /// Everything, including the cursor's row.
+    all: String,
+    /// Only rows above the cursor.
+    completed: String,
+}
+
+impl ScreenText {
+    fn capture(screen: &vt100::Screen) -> Self {
+        Self {
+            all: screen_text(screen, true),
+            completed: screen_text(screen, false),
+        }
+    }
 }
 
 impl ScrollbackBuffer {
     pub const fn new() -> Self {
+        Self::with_max_lines(DEFAULT_SCROLLBACK_LINES)
+    }
+
+    pub const fn with_max_lines(max_lines: usize) -> Self {
         Self {
+            utf8: Utf8Repair::new(),
+            controls: None,
             parser: None,
-            max_lines: DEFAULT_SCROLLBACK_LINES,
+            size: (24, 80),
+            events: TerminalParser::new(),
+            links: LinkTracker::new(),
+            history: LineHistory::new(max_lines),
+            commands: CommandTracker::new(),
+            title: None,
+            max_lines,
+            record_input: false,
+            answer_queries: false,
+            replies: Vec::new(),
+            primary_saved: None,
+            alternate_saved: None,
+            ttl: None,
+            model_since: None,
+            model_rows: 0,
+            seq: 1,
         }
     }
 
     fn ensure_parser(&mut self) -> &mut vt100::Parser {
+        let (rows, cols) = self.size;
         self.parser
-            .get_or_insert_with(|| vt100::Parser::new(24, 80, self.max_lines))
+            .get_or_insert_with(|| vt100::Parser::new(rows, cols, self.max_lines))
+    }
+
+    /// Resize the terminal model along with the window, so the screen,
+    /// cursor, and query answers match what the child draws for.
+    pub fn set_size(&mut self, rows: u16, cols: u16) {
+        if self.size == (rows, cols) {
+            return;
+        }
+        self.size = (rows, cols);
+        if let Some(parser) = &mut self.parser {
+            parser.set_size(rows, cols);
+            self.model_rows = retained_rows(parser);
+        }
+        self.seq += 1;
     }
 
     pub fn push(&mut self, data: &[u8]) {
-        self.ensure_parser().process(data);
+        self.push_at(data, Instant::now());
     }
 
-    pub fn get_lines(&self, count: Option<usize>) -> String {
+    /// [`push`](Self::push) output arriving at `now`.
+    pub fn push_at(&mut self, data: &[u8], now: Instant) {
+        self.seq += 1;
+        if self.ttl.is_some() {
+            self.history.stamp(now);
+            self.model_since.get_or_insert(now);
+        }
+        let repaired = self.utf8.repair(data);
+        let data = match self.controls {
+            Some(controls) => controls.apply(&repaired),
+            None => Cow::Borrowed(&*repaired),
+        };
+        let data: &[u8] = &data;
+        let events = self.events.feed_indexed(data);
+
+        // vt100 only knows the DEC form of save/restore cursor (`ESC 7`,
+        // `ESC 8`), so hand it that in place of `CSI s` / `CSI u`. The ESC
+        // abandons the partial CSI it has already seen.
+        //
+        // Before the final byte of a screen switch, save the screen that is
+        // about to be hidden, since vt100 can only show the active one.
+        //
+        // A cursor position report gives the position as of the query.
+        let answer_queries = self.answer_queries;
+        let mut replies = Vec::new();
+        let parser = self.ensure_parser();
+        let (mut primary_saved, mut alternate_saved) = (None, None);
+        let mut fed = 0;
+        for (end, event) in &events {
+            match (event, data[*end]) {
+                (Event::CursorMove(CursorMove::Save), b's') => {
+                    parser.process(&data[fed..*end]);
+                    parser.process(b"\x1b7");
+                }
+                (Event::CursorMove(CursorMove::Restore), b'u') => {
+                    parser.process(&data[fed..*end]);
+                    parser.process(b"\x1b8");
+                }
+                (Event::AltScreen(entering), _) => {
+                    parser.process(&data[fed..*end]);
+                    let screen = parser.screen();
+                    if screen.alternate_screen() != *entering {
+                        let saved = Some(ScreenText::capture(screen));
+                        if *entering {
+                            primary_saved = saved;
+                        } else {
+                            alternate_saved = saved;
+                        }
+                    }
+                    parser.process(&data[*end..=*end]);
+                }
+                (Event::Query(query), _) if answer_queries => {
+                    parser.process(&data[fed..=*end]);
+                    replies.extend(answer(*query, parser.screen()));
+                }
+                _ => continue,
+            }
+            fed = end + 1;
+        }
+        parser.process(&data[fed..]);
+        self.model_rows = retained_rows(parser);
+        if primary_saved.is_some() {
+            self.primary_saved = primary_saved;
+        }
+        if alternate_saved.is_some() {
+            self.alternate_saved = alternate_saved;
+        }
+        self.replies.extend(replies);
+
+        for (_, event) in &events {
+            self.links.handle(event);
+            self.history.handle(event);
+            self.commands.handle(event);
+            if let Event::Title(title) = event {
+                self.title = Some(title.clone());
+            }
+        }
+        self.expire(now);
+    }
+
+    /// The terminal state a client rebuilds this screen from
+    /// (`tap_client::LocalScreen`).
+    pub fn state(&self) -> TerminalState {
+        let Some(parser) = &self.parser else {
+            return TerminalState {
+                size: self.size,
+                title: self.title.clone(),
+                screen: Vec::new(),
+            };
+        };
+        TerminalState {
+            size: parser.screen().size(),
+            title: self.title.clone(),
+            screen: parser.screen().state_formatted(),
+        }
+    }
+
+    /// Bytes that reproduce the current screen when written to a fresh
+    /// terminal of the same size: clear, styled cell contents, cursor, modes.
+    pub fn screen_replay(&self) -> Vec<u8> {
+        let Some(parser) = &self.parser else {
+            return Vec::new();
+        };
+        parser.screen().state_formatted()
+    }
+
+    /// Output of the last command the shell marked as finished (OSC 133).
+    pub fn last_command_output(&self) -> Option<&CommandOutput> {
+        self.commands.last()
+    }
+
+    /// OSC 8 hyperlinks seen in the output, as `(text, uri)`.
+    pub fn links(&self) -> Vec<(String, String)> {
+        self.links.links()
+    }
+
+    /// The last `count` lines (all if `None`). Unless `include_current` is
+    /// set, the cursor's row and anything below it are left out, so only
+    /// newline-terminated lines are returned.
+    pub fn get_lines(&self, count: Option<usize>, include_current: bool) -> String {
+        let Some(parser) = &self.parser else {
+            return String::new();
+        };
+
+        tail_lines(screen_text(parser.screen(), include_current), count)
+    }
+
+    /// Like [`get_lines`](Self::get_lines), but from the given screen even
+    /// when the other one is showing. A screen that isn't showing reads as
+    /// it was when the terminal last switched away from it, so the
+    /// alternate screen keeps a full-screen app's last contents after it
+    /// exits. Empty if that screen was never used.
+    pub fn get_screen_lines(
+        &self,
+        buffer: ScreenBuffer,
+        count: Option<usize>,
+        include_current: bool,
+    ) -> String {
         let Some(parser) = &self.parser else {
             return String::new();
         };
 
+        let alternate = buffer == ScreenBuffer::Alternate;
+        if parser.screen().alternate_screen() == alternate {
+            return self.get_lines(count, include_current);
+        }
+        let saved = if alternate {
+            &self.alternate_saved
+        } else {
+            &self.primary_saved
+        };
+        match saved {
+            Some(text) if include_current => tail_lines(text.all.clone(), count),
+            Some(text) => tail_lines(text.completed.clone(), count),
+            None => String::new(),
+        }
+    }
+
+    /// Up to `count` rows of styled cells from row `start`, where rows are
+    /// numbered from the oldest scrolled-off row through the bottom of the
+    /// screen. Also returns the total number of rows.
+    pub fn grid_rows(&mut self, start: usize, count: usize) -> (Vec<Vec<Cell>>, usize) {
+        let Some(parser) = &mut self.parser else {
+            return (Vec::new(), 0);
+        };
+
+        // Scrolling back past the top stops at the oldest retained row
+        parser.set_scrollback(usize::MAX);
+        let saved = parser.screen().scrollback();
+        let (rows, cols) = parser.screen().size();
+        let rows = usize::from(rows);
+        let total = saved + rows;
+        let end = start.saturating_add(count).min(total);
+
+        let mut grid = Vec::with_capacity(end.saturating_sub(start));
+        let mut row = start;
+        while row < end {
+            // Scroll so `row` is at the top of the view (or as close as the
+            // screen allows), then take what is visible
+            let offset = saved.saturating_sub(row);
+            parser.set_scrollback(offset);
+            let top = saved - offset;
+            let screen = parser.screen();
+            let last = end.min(top + rows);
+            for r in row..last {
+                let cells = (0..cols)
+                    .map(|col| grid_cell(screen.cell((r - top) as u16, col)))
+                    .collect();
+                grid.push(cells);
+            }
+            row = last;
+        }
+        parser.set_scrollback(0);
+        (grid, total)
+    }
+
+    /// Styled cells of the visible screen, top to bottom.
+    pub fn screen_rows(&self) -> Vec<Vec<Cell>> {
+        let Some(parser) = &self.parser else {
+            return Vec::new();
+        };
+
+        let screen = parser.screen();
+        let (rows, cols) = screen.size();
+        (0..rows)
+            .map(|row| {
+                (0..cols)
+                    .map(|col| grid_cell(screen.cell(row, col)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The visible screen's size as `(rows, cols)`.
+    pub fn screen_size(&self) -> (u16, u16) {
+        self.parser
+            .as_ref()
+            .map_or(self.size, |parser| parser.screen().size())
+    }
+
+    /// The text of each row of the visible screen, without trailing blanks.
+    pub fn screen_lines(&self) -> Vec<String> {
+        let Some(parser) = &self.parser else {
+            return vec![String::new(); usize::from(self.size.0)];
+        };
         let screen = parser.screen();
-        let all_contents = screen.contents();
+        let (_, cols) = screen.size();
+        screen
+            .rows(0, cols)
+            .map(|row| row.trim_end().to_string())
+            .collect()
+    }
+
+    /// Rows `top..top + rows` and columns `left..left + cols` of the visible
+    /// screen, clipped to it. Each row's text has wide characters once,
+    /// blanks as spaces, and no trailing blanks; cells are only collected
+    /// if `styled`.
+    pub fn region(
+        &self,
+        top: u16,
+        left: u16,
+        rows: u16,
+        cols: u16,
+        styled: bool,
+    ) -> (Vec<String>, Vec<Vec<Cell>>) {
+        let Some(parser) = &self.parser else {
+            return (Vec::new(), Vec::new());
+        };
 
-        match count {
-            Some(n) => {
-                let lines: Vec<&str> = all_contents.lines().collect();
-                let start = lines.len().saturating_sub(n);
-                lines[start..].join("\n")
+        let screen = parser.screen();
+        let (height, width) = screen.size();
+        let bottom = top.saturating_add(rows).min(height);
+        let right = left.saturating_add(cols).min(width);
+        let mut lines = Vec::new();
+        let mut cells = Vec::new();
+        for row in top..bottom {
+            let mut line = String::new();
+            for col in left..right {
+                match screen.cell(row, col) {
+                    Some(cell) if cell.is_wide_continuation() => {}
+                    Some(cell) if cell.has_contents() => line.push_str(&cell.contents()),
+                    _ => line.push(' '),
+                }
+            }
+            lines.push(line.trim_end().to_string());
+            if styled {
+                cells.push(
+                    (left..right)
+                        .map(|col| grid_cell(screen.cell(row, col)))
+                        .collect(),
+                );
             }
-            None => all_contents,
         }
+        (lines, cells)
+    }
+
+    /// Approximate memory held: the terminal model's cells (screen and
+    /// scrolled-off rows), the plain-text line history, and saved screens.
+    pub fn approx_bytes(&self) -> usize {
+        let saved: usize = [&self.primary_saved, &self.alternate_saved]
+            .into_iter()
+            .flatten()
+            .map(|text| text.all.len() + text.completed.len())
+            .sum();
+        let cells = self.parser.as_ref().map_or(0, |parser| {
+            let (rows, cols) = parser.screen().size();
+            (self.model_rows + usize::from(rows)) * usize::from(cols) * CELL_BYTES
+        });
+        cells + self.history.bytes() + saved
+    }
+
+    /// Drop history until [`approx_bytes`](Self::approx_bytes) is at most
+    /// `target`, or only the screen is left. The terminal model loses its
+    /// scrolled-off rows and keeps at most half of `target` in them from
+    /// then on; the oldest plain-text lines go next.
+    pub fn shrink_to(&mut self, target: usize) {
+        self.primary_saved = None;
+        self.alternate_saved = None;
+        let Some(parser) = &mut self.parser else {
+            self.history.trim_to_bytes(target);
+            return;
+        };
+
+        let (rows, cols) = parser.screen().size();
+        let row_bytes = usize::from(cols).max(1) * CELL_BYTES;
+        let screen_bytes = usize::from(rows) * row_bytes;
+        let keep_rows = target.saturating_sub(screen_bytes) / 2 / row_bytes;
+        // Rebuilding from the visible screen would lose the primary screen
+        // behind a full-screen app; leave the model alone until it exits
+        if self.model_rows > keep_rows && !parser.screen().alternate_screen() {
+            let screen = parser.screen().state_formatted();
+            let mut fresh = vt100::Parser::new(rows, cols, keep_rows);
+            fresh.process(&screen);
+            *parser = fresh;
+            self.model_rows = retained_rows(parser);
+        }
+
+        let cells = (self.model_rows + usize::from(rows)) * row_bytes;
+        self.history.trim_to_bytes(target.saturating_sub(cells));
+    }
+
+    /// Drop lines older than `ttl` from then on, as output arrives and on
+    /// calls to [`expire`](Self::expire). `None` keeps them until a line or
+    /// memory limit pushes them out.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Drop what is older than the TTL as of `now`. Plain-text lines go
+    /// one by one. The terminal model doesn't date its rows, so it drops
+    /// all its scrolled-off rows once the oldest might be too old, which
+    /// is at most once per TTL.
+    pub fn expire(&mut self, now: Instant) {
+        let Some(cutoff) = self.ttl.and_then(|ttl| now.checked_sub(ttl)) else {
+            return;
+        };
+        self.history.expire(cutoff);
+
+        let (Some(parser), Some(since)) = (&mut self.parser, self.model_since) else {
+            return;
+        };
+        // As in `shrink_to`, leave a full-screen app's model alone
+        if since >= cutoff || parser.screen().alternate_screen() {
+            return;
+        }
+        if self.model_rows > 0 {
+            let (rows, cols) = parser.screen().size();
+            let screen = parser.screen().state_formatted();
+            let mut fresh = vt100::Parser::new(rows, cols, self.max_lines);
+            fresh.process(&screen);
+            *parser = fresh;
+            self.model_rows = 0;
+        }
+        self.alternate_saved = None;
+        self.model_since = Some(now);
+    }
+
+    /// Completed line `index`, counting from the oldest line still retained.
+    pub fn get_line(&self, index: usize) -> Option<&str> {
+        self.history.get(index)
+    }
+
+    /// Number of completed lines retained.
+    pub fn line_count(&self) -> usize {
+        self.history.line_count()
+    }
+
+    /// A copy of the completed lines, cheap enough to take under the lock
+    /// and read after releasing it.
+    pub fn lines(&self) -> Lines {
+        self.history.lines().clone()
+    }
+
+    /// Lines trimmed (or cleared) so far; index `i` is absolute line
+    /// `lines_discarded() + i`.
+    pub fn lines_discarded(&self) -> u64 {
+        self.history.discarded()
+    }
+
+    pub fn set_normalize_controls(&mut self, controls: Option<ControlNormalization>) {
+        self.controls = controls;
+    }
+
+    pub fn set_record_input(&mut self, record: bool) {
+        self.record_input = record;
+    }
+
+    pub fn set_answer_queries(&mut self, answer: bool) {
+        self.answer_queries = answer;
+    }
+
+    /// Answers to queries in the output pushed since the last call, to be
+    /// written to the child as if the terminal had sent them.
+    pub fn take_replies(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.replies)
+    }
+
+    /// Note injected text in the transcript, if enabled. It is never fed to
+    /// the screen, so output, subscribers, and the grid are unaffected.
+    pub fn record_input(&mut self, text: &str) {
+        if self.record_input {
+            self.history.record_input(text);
+        }
+    }
+
+    /// Completed lines with any recorded input interleaved.
+    pub fn transcript(&self) -> Vec<TranscriptEntry> {
+        self.history.transcript()
     }
 
     pub fn cursor_position(&self) -> (usize, usize) {
@@ -53,9 +550,143 @@ impl ScrollbackBuffer {
         )
     }
 
-    #[allow(dead_code)]
+    /// Input modes the program has set; all off before any output.
+    pub fn input_modes(&self) -> InputModes {
+        let Some(parser) = &self.parser else {
+            return InputModes::default();
+        };
+        let screen = parser.screen();
+        InputModes {
+            application_cursor: screen.application_cursor(),
+            application_keypad: screen.application_keypad(),
+        }
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.parser
+            .as_ref()
+            .is_none_or(|parser| !parser.screen().hide_cursor())
+    }
+
+    /// Text on the cursor's row up to the cursor, i.e. the line still being written.
+    pub fn current_line(&self) -> String {
+        let Some(parser) = &self.parser else {
+            return String::new();
+        };
+
+        let screen = parser.screen();
+        let (row, col) = screen.cursor_position();
+        let mut line = String::new();
+        for c in 0..col {
+            match screen.cell(row, c) {
+                Some(cell) if cell.is_wide_continuation() => {}
+                Some(cell) if cell.has_contents() => line.push_str(&cell.contents()),
+                _ => line.push(' '),
+            }
+        }
+        line
+    }
+
+    /// The last `count` completed lines (all if `None`) with `\r\n` endings,
+    /// then the line still being written: text that leaves a terminal's
+    /// cursor where the next output continues.
+    pub fn replay_text(&self, count: Option<usize>) -> String {
+        let mut text = String::new();
+        for line in self.get_lines(count, false).lines() {
+            text.push_str(line);
+            text.push_str("\r\n");
+        }
+        text.push_str(&self.current_line());
+        text
+    }
+
     pub fn clear(&mut self) {
+        self.utf8.reset();
         self.parser = None;
+        self.primary_saved = None;
+        self.alternate_saved = None;
+        self.events.reset();
+        self.links.clear();
+        self.history.clear();
+        self.commands.clear();
+        self.title = None;
+        self.model_since = None;
+        self.model_rows = 0;
+        self.seq += 1;
+    }
+}
+
+/// What a VT-style terminal answers to `query` showing `screen`.
+fn answer(query: Query, screen: &vt100::Screen) -> Vec<u8> {
+    match query {
+        Query::Status => b"\x1b[0n".to_vec(),
+        Query::CursorPosition => {
+            let (row, col) = screen.cursor_position();
+            format!("\x1b[{};{}R", u32::from(row) + 1, u32::from(col) + 1).into_bytes()
+        }
+        // A VT220 with ANSI color
+        Query::DeviceAttributes => b"\x1b[?62;22c".to_vec(),
+    }
+}
+
+/// How many scrolled-off rows the terminal model holds.
+fn retained_rows(parser: &mut vt100::Parser) -> usize {
+    // Scrolling back past the top stops at the oldest retained row
+    parser.set_scrollback(usize::MAX);
+    let rows = parser.screen().scrollback();
+    parser.set_scrollback(0);
+    rows
+}
+
+/// The screen's text, without trailing blanks on each line; unless
+/// `include_current` is set, only the rows above the cursor.
+fn screen_text(screen: &vt100::Screen, include_current: bool) -> String {
+    let (cursor_row, _) = screen.cursor_position();
+    let (rows, cols) = screen.size();
+    let end = if include_current { rows } else { cursor_row };
+    let mut text = String::new();
+    for (row, line) in screen.rows(0, cols).take(end.into()).enumerate() {
+        text.push_str(&line);
+        if !screen.row_wrapped(row as u16) {
+            text.truncate(text.trim_end_matches(' ').len());
+            text.push('\n');
+        }
+    }
+    text.trim_end().to_string()
+}
+
+/// The last `count` lines of `content` (all if `None`).
+fn tail_lines(content: String, count: Option<usize>) -> String {
+    match count {
+        Some(n) => {
+            let lines: Vec<&str> = content.lines().collect();
+            let start = lines.len().saturating_sub(n);
+            lines[start..].join("\n")
+        }
+        None => content,
+    }
+}
+
+fn grid_cell(cell: Option<&vt100::Cell>) -> Cell {
+    let Some(cell) = cell else {
+        return Cell::default();
+    };
+    Cell {
+        text: cell.contents(),
+        fg: grid_color(cell.fgcolor()),
+        bg: grid_color(cell.bgcolor()),
+        bold: cell.bold(),
+        italic: cell.italic(),
+        underline: cell.underline(),
+        inverse: cell.inverse(),
+    }
+}
+
+fn grid_color(color: vt100::Color) -> Option<Color> {
+    match color {
+        vt100::Color::Default => None,
+        vt100::Color::Idx(i) => Some(Color::Indexed(i)),
+        vt100::Color::Rgb(r, g, b) => Some(Color::Rgb(r, g, b)),
     }
 }
 
@@ -67,27 +698,156 @@ mod tests {
     fn test_push_simple_text() {
         let mut buf = ScrollbackBuffer::new();
         buf.push(b"hello world");
-        assert_eq!(buf.get_lines(None).trim(), "hello world");
+        assert_eq!(buf.get_lines(None, true).trim(), "hello world");
+    }
+
+    #[test]
+    fn test_normalize_controls() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.set_normalize_controls(Some(ControlNormalization::Caret));
+        buf.push(b"nul\x00 soh\x01\r\n");
+        buf.set_normalize_controls(Some(ControlNormalization::Drop));
+        buf.push(b"nul\x00 soh\x01\r\n");
+        let content = buf.get_lines(None, true);
+        assert_eq!(
+            content.lines().collect::<Vec<_>>(),
+            vec!["nul^@ soh^A", "nul soh"]
+        );
+    }
+
+    #[test]
+    fn test_region() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"hello world\r\nfoo \x1b[1mbar\x1b[0m baz\x1b[24;61Hstatus: ready");
+
+        let (lines, cells) = buf.region(1, 4, 1, 3, false);
+        assert_eq!(lines, ["bar"]);
+        assert!(cells.is_empty());
+        let (_, cells) = buf.region(1, 4, 1, 3, true);
+        assert!(cells[0].iter().all(|cell| cell.bold));
+
+        // Past the bottom and right edges, only what's on screen comes back
+        let (lines, cells) = buf.region(22, 60, 5, 100, true);
+        assert_eq!(lines, ["", "status: ready"]);
+        assert_eq!(cells[1].len(), 20);
+        assert_eq!(buf.region(0, 0, 2, 5, false).0, ["hello", "foo b"]);
+        assert!(buf.region(30, 0, 1, 1, false).0.is_empty());
     }
 
     #[test]
     fn test_push_with_newlines() {
         let mut buf = ScrollbackBuffer::new();
         buf.push(b"line1\r\nline2\r\nline3");
-        let content = buf.get_lines(None);
+        let content = buf.get_lines(None, true);
         assert!(content.contains("line1"));
         assert!(content.contains("line2"));
         assert!(content.contains("line3"));
     }
 
+    #[test]
+    fn test_crlf_line_endings() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"a\r\nb\r\n");
+        let content = buf.get_lines(None, true);
+        assert_eq!(content.lines().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(buf.cursor_position(), (2, 0));
+    }
+
+    #[test]
+    fn test_crlf_split_across_pushes() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"a\r");
+        buf.push(b"\nb\r");
+        buf.push(b"\n");
+        assert_eq!(
+            buf.get_lines(None, true).lines().collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(buf.cursor_position(), (2, 0));
+    }
+
+    #[test]
+    fn test_blank_crlf_lines() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"a\r\n\r\nb");
+        assert_eq!(
+            buf.get_lines(None, true).lines().collect::<Vec<_>>(),
+            vec!["a", "", "b"]
+        );
+        assert_eq!(buf.cursor_position(), (2, 1));
+    }
+
+    #[test]
+    fn test_lone_cr_overwrites_line() {
+        let mut buf = ScrollbackBuffer::new();
+        // Progress-bar style redraws, then a shorter overwrite
+        buf.push(b"10%\r50%\r100%\r\nabc\rX\r\n");
+        assert_eq!(
+            buf.get_lines(None, true).lines().collect::<Vec<_>>(),
+            vec!["100%", "Xbc"]
+        );
+        assert_eq!(buf.cursor_position(), (2, 0));
+    }
+
     #[test]
     fn test_get_last_n_lines() {
         let mut buf = ScrollbackBuffer::new();
         buf.push(b"line1\r\nline2\r\nline3\r\nline4\r\n");
-        let last_two = buf.get_lines(Some(2));
+        let last_two = buf.get_lines(Some(2), true);
         assert!(last_two.contains("line3") || last_two.contains("line4"));
     }
 
+    #[test]
+    fn test_get_lines_with_current_line() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"one\r\ntwo\r\n$");
+        assert_eq!(buf.get_lines(None, true), "one\ntwo\n$");
+        assert_eq!(buf.get_lines(Some(1), true), "$");
+    }
+
+    #[test]
+    fn test_get_lines_without_current_line() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"one\r\ntwo\r\n$");
+        assert_eq!(buf.get_lines(None, false), "one\ntwo");
+        assert_eq!(buf.get_lines(Some(1), false), "two");
+
+        // Output after the prompt doesn't change the completed lines
+        buf.push(b"ech");
+        assert_eq!(buf.get_lines(None, false), "one\ntwo");
+    }
+
+    #[test]
+    fn test_get_lines_without_current_line_keeps_wrapped_rows() {
+        let mut buf = ScrollbackBuffer::new();
+        let long = "x".repeat(100);
+        buf.push(format!("{long}\r\n> ").as_bytes());
+        assert_eq!(buf.get_lines(None, false), long);
+    }
+
+    #[test]
+    fn test_get_line_by_index() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"first\r\nsecond\r\n$ ");
+        assert_eq!(buf.line_count(), 2);
+        assert_eq!(buf.get_line(0), Some("first"));
+        assert_eq!(buf.get_line(1), Some("second"));
+        assert_eq!(buf.get_line(2), None);
+    }
+
+    #[test]
+    fn test_get_line_after_trimming() {
+        let mut buf = ScrollbackBuffer::with_max_lines(3);
+        for i in 0..5 {
+            buf.push(format!("line{i}\r\n").as_bytes());
+        }
+        assert_eq!(buf.line_count(), 3);
+        assert_eq!(buf.get_line(0), Some("line2"));
+        assert_eq!(buf.get_line(2), Some("line4"));
+        assert_eq!(buf.get_line(3), None);
+        assert_eq!(buf.lines_discarded(), 2);
+    }
+
     #[test]
     fn test_cursor_position() {
         let mut buf = ScrollbackBuffer::new();
@@ -97,24 +857,439 @@ mod tests {
         assert_eq!(col, 5);
     }
 
+    #[test]
+    fn test_cursor_visibility() {
+        let mut buffer = ScrollbackBuffer::new();
+        assert!(buffer.cursor_visible());
+        buffer.push(b"\x1b[?25l");
+        assert!(!buffer.cursor_visible());
+        buffer.push(b"\x1b[?25h");
+        assert!(buffer.cursor_visible());
+    }
+
+    #[test]
+    fn test_input_modes_follow_the_program() {
+        let mut buffer = ScrollbackBuffer::new();
+        assert_eq!(buffer.input_modes(), InputModes::default());
+        buffer.push(b"\x1b[?1h\x1b=");
+        assert_eq!(
+            buffer.input_modes(),
+            InputModes {
+                application_cursor: true,
+                application_keypad: true,
+            }
+        );
+        buffer.push(b"\x1b[?1l\x1b>");
+        assert_eq!(buffer.input_modes(), InputModes::default());
+    }
+
+    #[test]
+    fn test_current_line() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"done\r\n$ ");
+        assert_eq!(buf.current_line(), "$ ");
+    }
+
+    #[test]
+    fn test_current_line_empty_after_newline() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"done\r\n");
+        assert_eq!(buf.current_line(), "");
+    }
+
     #[test]
     fn test_clear() {
         let mut buf = ScrollbackBuffer::new();
         buf.push(b"some content\r\n");
         buf.clear();
-        assert_eq!(buf.get_lines(None), "");
+        assert_eq!(buf.get_lines(None, true), "");
         assert_eq!(buf.cursor_position(), (0, 0));
     }
 
+    #[test]
+    fn test_answers_queries_as_of_the_query() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"ab\x1b[6n");
+        assert!(buf.take_replies().is_empty());
+
+        buf.set_answer_queries(true);
+        buf.push(b"\r\nab\x1b[6ncd\x1b[5n\x1b[c");
+        assert_eq!(buf.take_replies(), b"\x1b[2;3R\x1b[0n\x1b[?62;22c");
+        assert!(buf.take_replies().is_empty());
+        assert_eq!(buf.get_lines(None, true).trim_end(), "ab\nabcd");
+    }
+
+    #[test]
+    fn test_model_follows_window_size() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.set_size(40, 120);
+        buf.set_answer_queries(true);
+        buf.push(b"\x1b[999;999H\x1b[6n");
+        assert_eq!(buf.take_replies(), b"\x1b[40;120R");
+        assert_eq!(buf.screen_size(), (40, 120));
+        assert_eq!(buf.screen_rows().len(), 40);
+
+        // Resizing the running model keeps its contents
+        buf.push(b"\x1b[Hhello");
+        let seq = buf.seq();
+        buf.set_size(10, 30);
+        assert!(buf.seq() > seq);
+        assert_eq!(buf.cursor_position(), (0, 5));
+        assert_eq!(buf.screen_lines().len(), 10);
+        assert_eq!(buf.screen_lines()[0], "hello");
+    }
+
+    #[test]
+    fn test_ttl_expires_old_lines() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut buf = ScrollbackBuffer::with_max_lines(100);
+        buf.set_ttl(Some(Duration::from_secs(60)));
+        for i in 0..30 {
+            buf.push_at(format!("old {i}\r\n").as_bytes(), at(0));
+        }
+        buf.push_at(b"new\r\n", at(45));
+        assert_eq!(buf.line_count(), 31);
+        assert!(buf.grid_rows(0, 1).1 > 24);
+
+        // Output at 61s expires the lines from 0s, and the model's old rows
+        buf.push_at(b"newer\r\n", at(61));
+        assert_eq!(buf.line_count(), 2);
+        assert_eq!(buf.get_line(0), Some("new"));
+        assert_eq!(buf.grid_rows(0, 1).1, 24);
+        assert!(buf.get_lines(None, true).contains("newer"));
+
+        // No output needed
+        buf.expire(at(200));
+        assert_eq!(buf.line_count(), 0);
+    }
+
+    #[test]
+    fn test_state_then_output_matches() {
+        let mut server = ScrollbackBuffer::new();
+        server.push(b"\x1b]2;build\x07$ make\r\n\x1b[1;32mok\x1b[0m\r\n\x1b[7m$ ");
+        let mut local = tap_client::LocalScreen::from_state(&server.state());
+        assert_eq!(local.title(), Some("build"));
+
+        // The reverse video still in effect carries over
+        let later = b"next\x1b[0m\r\nline\x1b[2;4Hxy\x1b]2;done\x07";
+        server.push(later);
+        local.push(later);
+
+        assert_eq!(local.lines(), server.screen_lines());
+        let (row, col) = local.cursor_position();
+        assert_eq!(
+            (usize::from(row), usize::from(col)),
+            server.cursor_position()
+        );
+        assert_eq!(local.title(), Some("done"));
+        assert_eq!(local.state(), server.state());
+    }
+
+    #[test]
+    fn test_screen_replay_round_trip() {
+        let mut original = ScrollbackBuffer::new();
+        original.push(
+            b"plain\r\n\x1b[1;31mbold red\x1b[0m and \x1b[44mblue bg\x1b[0m\r\n\x1b[5;10Hmoved",
+        );
+
+        let mut replayed = ScrollbackBuffer::new();
+        replayed.push(&original.screen_replay());
+
+        assert_eq!(
+            replayed.get_lines(None, true),
+            original.get_lines(None, true)
+        );
+        assert_eq!(replayed.cursor_position(), original.cursor_position());
+
+        let a = original.parser.as_ref().unwrap().screen();
+        let b = replayed.parser.as_ref().unwrap().screen();
+        for row in 0..5 {
+            for col in 0..20 {
+                let (x, y) = (a.cell(row, col).unwrap(), b.cell(row, col).unwrap());
+                assert_eq!(x.contents(), y.contents());
+                assert_eq!(x.fgcolor(), y.fgcolor());
+                assert_eq!(x.bgcolor(), y.bgcolor());
+                assert_eq!(x.bold(), y.bold());
+            }
+        }
+    }
+
+    #[test]
+    fn test_hyperlink_text_and_uri() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"\x1b]8;;https://example.com\x1b\\click me\x1b]8;;\x1b\\\r\n");
+        assert!(buf.get_lines(None, true).contains("click me"));
+        assert!(!buf.get_lines(None, true).contains("example.com"));
+        assert_eq!(
+            buf.links(),
+            vec![("click me".to_string(), "https://example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_reset_sequence_restores_defaults() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"\x1b[1;31mstyled\x1b[10;20H");
+        buf.push(b"\x1bc");
+        assert_eq!(buf.cursor_position(), (0, 0));
+        assert_eq!(buf.get_lines(None, true).trim(), "");
+
+        buf.push(b"x");
+        let cell = buf
+            .parser
+            .as_ref()
+            .unwrap()
+            .screen()
+            .cell(0, 0)
+            .unwrap()
+            .clone();
+        assert_eq!(cell.fgcolor(), vt100::Color::Default);
+        assert!(!cell.bold());
+    }
+
     #[test]
     fn test_strips_ansi_escapes() {
         let mut buf = ScrollbackBuffer::new();
         // Color escape sequence for red text
         buf.push(b"\x1b[31mred text\x1b[0m");
-        let content = buf.get_lines(None);
+        let content = buf.get_lines(None, true);
         // Should contain the text but not raw escape sequences
         assert!(content.contains("red text"));
         assert!(!content.contains("\x1b[31m"));
         assert!(!content.contains("[31m"));
     }
+
+    fn row_text(row: &[Cell]) -> String {
+        row.iter().map(|cell| cell.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_full_grid_includes_scrolled_off_rows() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"\x1b[1;31mred\x1b[0m \x1b[48;2;1;2;3mbg\x1b[0m\r\n");
+        for i in 0..30 {
+            buf.push(format!("line {i}\r\n").as_bytes());
+        }
+
+        // 31 lines plus the empty cursor row, 8 of them scrolled off
+        let (rows, total) = buf.grid_rows(0, usize::MAX);
+        assert_eq!(total, 32);
+        assert_eq!(rows.len(), 32);
+        assert!(rows.iter().all(|row| row.len() == 80));
+
+        let first = &rows[0];
+        assert_eq!(row_text(first), "red bg");
+        assert_eq!(first[0].fg, Some(Color::Indexed(1)));
+        assert!(first[0].bold);
+        assert_eq!(
+            first[3],
+            Cell {
+                text: " ".to_string(),
+                ..Cell::default()
+            }
+        );
+        assert_eq!(first[4].bg, Some(Color::Rgb(1, 2, 3)));
+        assert!(!first[4].bold);
+        assert_eq!(row_text(&rows[30]), "line 29");
+
+        // The live view is left where it was
+        assert!(buf.get_lines(Some(1), false).contains("line 29"));
+    }
+
+    #[test]
+    fn test_full_grid_pages() {
+        let mut buf = ScrollbackBuffer::new();
+        for i in 0..40 {
+            buf.push(format!("line {i}\r\n").as_bytes());
+        }
+
+        // A page straddling the last scrolled-off row and the first screen row
+        let (rows, total) = buf.grid_rows(16, 2);
+        assert_eq!(total, 41);
+        assert_eq!(
+            rows.iter().map(|row| row_text(row)).collect::<Vec<_>>(),
+            vec!["line 16", "line 17"]
+        );
+
+        let (rows, _) = buf.grid_rows(40, 10);
+        assert_eq!(rows.len(), 1);
+        assert!(buf.grid_rows(41, 10).0.is_empty());
+    }
+
+    #[test]
+    fn test_dec_save_restore_cursor() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"abc\x1b7\x1b[5;10Hxyz");
+        assert_eq!(buf.cursor_position(), (4, 12));
+        buf.push(b"\x1b8");
+        assert_eq!(buf.cursor_position(), (0, 3));
+    }
+
+    #[test]
+    fn test_csi_save_restore_cursor() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"abc\x1b[s\r\n\x1b[2Bmoved\x1b[u");
+        assert_eq!(buf.cursor_position(), (0, 3));
+
+        // The grid follows: output lands at the restored position
+        buf.push(b"Z");
+        assert_eq!(buf.get_lines(Some(1), true), "moved");
+        assert!(buf.get_lines(None, true).starts_with("abcZ\n"));
+    }
+
+    #[test]
+    fn test_csi_save_restore_split_across_pushes() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"ab\x1b[");
+        buf.push(b"s\x1b[10;10H\x1b");
+        buf.push(b"[u");
+        assert_eq!(buf.cursor_position(), (0, 2));
+    }
+
+    #[test]
+    fn test_injected_input_in_transcript_only_when_enabled() {
+        for record in [false, true] {
+            let mut buf = ScrollbackBuffer::new();
+            buf.set_record_input(record);
+            buf.push(b"$ ");
+            buf.record_input("ls\r");
+            buf.push(b"ls\r\nfile\r\n");
+
+            let inputs: Vec<_> = buf
+                .transcript()
+                .into_iter()
+                .filter(|entry| matches!(entry, TranscriptEntry::Input { .. }))
+                .collect();
+            if record {
+                assert_eq!(
+                    inputs,
+                    vec![TranscriptEntry::Input {
+                        text: "ls\r".to_string()
+                    }]
+                );
+            } else {
+                assert!(inputs.is_empty());
+            }
+            // The screen only ever shows the child's output
+            assert_eq!(buf.get_lines(None, false), "$ ls\nfile");
+        }
+    }
+
+    #[test]
+    fn test_alternate_screen_kept_after_exit() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"$ top\r\n");
+        assert_eq!(
+            buf.get_screen_lines(ScreenBuffer::Alternate, None, true),
+            ""
+        );
+
+        // The TUI draws on the alternate screen...
+        buf.push(b"\x1b[?1049h\x1b[H\x1b[2Jtasks: 3\r\nload: 0.5");
+        assert_eq!(
+            buf.get_screen_lines(ScreenBuffer::Alternate, None, true),
+            "tasks: 3\nload: 0.5"
+        );
+        assert_eq!(
+            buf.get_screen_lines(ScreenBuffer::Primary, None, false),
+            "$ top"
+        );
+
+        // ...and its last frame survives returning to the shell, even when
+        // it arrives in the same chunk as the switch back
+        buf.push(b"\x1b[Hquitting\x1b[?1049l$ ");
+        assert_eq!(
+            buf.get_screen_lines(ScreenBuffer::Alternate, None, true),
+            "quitting\nload: 0.5"
+        );
+        assert_eq!(
+            buf.get_screen_lines(ScreenBuffer::Alternate, Some(1), true),
+            "load: 0.5"
+        );
+        assert_eq!(
+            buf.get_screen_lines(ScreenBuffer::Primary, None, true),
+            "$ top\n$"
+        );
+        assert_eq!(buf.get_lines(None, true), "$ top\n$");
+    }
+
+    #[test]
+    fn test_invalid_utf8_becomes_replacement_characters() {
+        let mut buf = ScrollbackBuffer::new();
+        buf.push(b"bin \xff\xfe end\r\ncut \xe2\x82");
+        buf.push(b"\r\nnext ");
+        buf.push(&"€".as_bytes()[..1]);
+        buf.push(&"€".as_bytes()[1..]);
+
+        let content = buf.get_lines(None, true);
+        assert_eq!(
+            content.lines().collect::<Vec<_>>(),
+            ["bin \u{FFFD}\u{FFFD} end", "cut \u{FFFD}", "next €"]
+        );
+        let response = tap_protocol::Response::Scrollback { content };
+        assert!(serde_json::to_string(&response).is_ok());
+        assert_eq!(buf.get_line(1), Some("cut \u{FFFD}"));
+    }
+
+    #[test]
+    fn test_shrink_to_drops_history_but_keeps_screen() {
+        let mut buf = ScrollbackBuffer::new();
+        for i in 0..2000 {
+            buf.push(format!("line {i}\r\n").as_bytes());
+        }
+        buf.push(b"$ ");
+        let before = buf.approx_bytes();
+
+        // Room for the screen and a little line history
+        let target = 70 * 1024;
+        buf.shrink_to(target);
+        assert!(buf.approx_bytes() <= target, "{}", buf.approx_bytes());
+        assert!(buf.approx_bytes() < before);
+        assert_eq!(buf.current_line(), "$ ");
+        assert!(buf.get_lines(None, true).contains("line 1999"));
+        assert!(buf.lines_discarded() > 0);
+
+        // The model stays capped as more output arrives
+        for i in 0..2000 {
+            buf.push(format!("more {i}\r\n").as_bytes());
+        }
+        assert!(buf.approx_bytes() <= target + buf.history.bytes());
+    }
+
+    #[test]
+    fn test_large_reads_dont_stall_pushes() {
+        use std::time::{Duration, Instant};
+
+        let lock = parking_lot::RwLock::new(ScrollbackBuffer::new());
+        let line = format!("{}\r\n", "x".repeat(200));
+        for _ in 0..DEFAULT_SCROLLBACK_LINES {
+            lock.write().push(line.as_bytes());
+        }
+
+        // A push only waits for as long as a reader holds the lock, which
+        // is the copy; building ~2 MB of reply, as `GetScrollbackBytes`
+        // would, happens after it's released
+        let (mut held, mut building) = (Duration::ZERO, Duration::ZERO);
+        for _ in 0..20 {
+            let start = Instant::now();
+            let lines = {
+                let guard = lock.read();
+                guard.lines()
+            };
+            held += start.elapsed();
+            let start = Instant::now();
+            let (content, _) = lines.tail_bytes(usize::MAX);
+            building += start.elapsed();
+            assert!(content.len() > 1_000_000);
+
+            // Output pushed meanwhile doesn't change the copy
+            lock.write().push(b"new\r\n");
+            assert_eq!(lines.tail_bytes(usize::MAX).0.len(), content.len());
+        }
+        assert!(
+            held * 10 < building,
+            "lock held {held:?}, building {building:?}"
+        );
+    }
 }
@@ -0,0 +1,328 @@
+//! Edits to a socket directory's `sessions.json` index while a session runs,
+//! and cleaning up after sessions that died without removing themselves.
+
+use std::fs::{File, OpenOptions};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::ErrorKind;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{Flock, FlockArg};
+use serde_json::Value;
+
+/// An exclusive hold on a socket directory's `sessions.json`, released on
+/// drop. Everything that rewrites the index takes it, so a launch and a
+/// `tap gc` running at once don't lose each other's edits.
+pub struct IndexLock {
+    _file: Flock<File>,
+}
+
+/// Wait for exclusive use of the `sessions.json` in `dir`.
+pub fn lock(dir: &Path) -> std::io::Result<IndexLock> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join("sessions.json.lock"))?;
+    let file = Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, e)| e)?;
+    Ok(IndexLock { _file: file })
+}
+
+/// Most arguments of a session's command kept in the index.
+const MAX_INDEXED_ARGS: usize = 32;
+
+/// Longest argument kept in the index, in characters.
+const MAX_INDEXED_ARG_CHARS: usize = 256;
+
+/// `command` as listed in the index, so a huge argument list or here-doc
+/// doesn't bloat it: the first [`MAX_INDEXED_ARGS`] arguments, each cut to
+/// [`MAX_INDEXED_ARG_CHARS`] ending in `…`, then `… (N more)` if any were
+/// left out. The running session still reports the whole command.
+pub fn indexed_command(command: &[String]) -> Vec<String> {
+    let mut indexed: Vec<String> = command
+        .iter()
+        .take(MAX_INDEXED_ARGS)
+        .map(
+            |arg| match arg.char_indices().nth(MAX_INDEXED_ARG_CHARS - 1) {
+                Some((cut, _)) if arg[cut..].chars().nth(1).is_some() => {
+                    format!("{}…", &arg[..cut])
+                }
+                _ => arg.clone(),
+            },
+        )
+        .collect();
+    if command.len() > MAX_INDEXED_ARGS {
+        indexed.push(format!("… ({} more)", command.len() - MAX_INDEXED_ARGS));
+    }
+    indexed
+}
+
+/// Hex digits in a short code when no running session already uses them.
+const SHORT_ID_LEN: usize = 6;
+
+/// A short code to type in place of session `id`: the first six hex digits
+/// of a hash of it, or more if a running session in the index `sessions`
+/// (in `dir`) already answers to that as its ID, name, or code.
+pub fn short_id(id: &str, dir: &Path, sessions: &[Value]) -> String {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    let hex = format!("{:016x}", hasher.finish());
+    let taken = |code: &str| {
+        sessions.iter().any(|session| {
+            let Some(other) = field(session, "id") else {
+                return false;
+            };
+            other != id
+                && dir.join(format!("{other}.sock")).exists()
+                && (other == code
+                    || field(session, "name") == Some(code)
+                    || field(session, "short_id") == Some(code))
+        })
+    };
+    (SHORT_ID_LEN..=hex.len())
+        .map(|len| &hex[..len])
+        .find(|code| !taken(code))
+        .unwrap_or(id)
+        .to_string()
+}
+
+fn field<'a>(session: &'a Value, key: &str) -> Option<&'a str> {
+    session.get(key).and_then(Value::as_str)
+}
+
+/// Give session `id` the display name `name` in the index at `file`. The
+/// name can't be empty, or the ID or name of another running session.
+pub fn rename(file: &Path, id: &str, name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("Session name must not be empty".to_string());
+    }
+    let dir = file.parent().unwrap_or(Path::new("."));
+    let _lock = lock(dir).map_err(|e| format!("Failed to lock {}: {e}", file.display()))?;
+    let content = std::fs::read_to_string(file)
+        .map_err(|e| format!("Failed to read {}: {e}", file.display()))?;
+    let mut sessions: Vec<Value> = serde_json::from_str(&content)
+        .map_err(|e| format!("Failed to parse {}: {e}", file.display()))?;
+
+    // Entries whose socket is gone are stale and don't hold on to names
+    let taken = sessions.iter().any(|session| {
+        let Some(other) = field(session, "id") else {
+            return false;
+        };
+        other != id
+            && dir.join(format!("{other}.sock")).exists()
+            && (other == name
+                || field(session, "name") == Some(name)
+                || field(session, "short_id") == Some(name))
+    });
+    if taken {
+        return Err(format!("Session name {name} is already taken"));
+    }
+
+    let Some(entry) = sessions
+        .iter_mut()
+        .find(|session| field(session, "id") == Some(id))
+    else {
+        return Err(format!("Session {id} is not in {}", file.display()));
+    };
+    entry["name"] = Value::from(name);
+    std::fs::write(file, serde_json::to_string_pretty(&sessions).unwrap())
+        .map_err(|e| format!("Failed to write {}: {e}", file.display()))
+}
+
+/// What [`gc`] removed, or would remove on a dry run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Socket files nothing is listening on.
+    pub sockets: Vec<PathBuf>,
+    /// IDs of `sessions.json` entries without a live socket.
+    pub entries: Vec<String>,
+}
+
+/// Whether a session is listening on `socket`. Only a refused connection or
+/// a missing file counts as dead; a socket we can't probe, e.g. for lack of
+/// permission, is left alone.
+fn is_live(socket: &Path) -> bool {
+    match UnixStream::connect(socket) {
+        Ok(_) => true,
+        Err(e) => !matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::NotFound),
+    }
+}
+
+/// Remove the socket files in `dir` that nothing listens on and the
+/// `sessions.json` entries left behind by sessions that didn't exit
+/// cleanly. With `dry_run`, only report what would go.
+pub fn gc(dir: &Path, dry_run: bool) -> std::io::Result<GcReport> {
+    if !dir.exists() {
+        return Ok(GcReport::default());
+    }
+    let _lock = lock(dir)?;
+    let mut report = GcReport::default();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "sock") && !is_live(&path) {
+            report.sockets.push(path);
+        }
+    }
+    report.sockets.sort();
+
+    let file = dir.join("sessions.json");
+    let mut sessions: Vec<Value> = match std::fs::read_to_string(&file) {
+        Ok(content) => serde_json::from_str(&content)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    sessions.retain(|session| {
+        let id = field(session, "id").unwrap_or_default();
+        let live = !id.is_empty() && is_live(&dir.join(format!("{id}.sock")));
+        if !live {
+            report.entries.push(id.to_string());
+        }
+        live
+    });
+
+    if !dry_run {
+        for socket in &report.sockets {
+            std::fs::remove_file(socket)?;
+        }
+        if !report.entries.is_empty() {
+            std::fs::write(&file, serde_json::to_string_pretty(&sessions).unwrap())?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sessions index with a live socket for each of `ids`.
+    fn seed(dir: &Path, ids: &[&str]) -> std::path::PathBuf {
+        let sessions: Vec<Value> = ids
+            .iter()
+            .map(|id| serde_json::json!({ "id": id, "pid": 1, "started": "", "command": [] }))
+            .collect();
+        for id in ids {
+            std::fs::write(dir.join(format!("{id}.sock")), "").unwrap();
+        }
+        let file = dir.join("sessions.json");
+        std::fs::write(&file, serde_json::to_string(&sessions).unwrap()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_rename_sets_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = seed(tmp.path(), &["blue-moon", "red-sun"]);
+
+        rename(&file, "blue-moon", "build").unwrap();
+        let sessions: Vec<tap_protocol::Session> =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(sessions[0].id, "blue-moon");
+        assert_eq!(sessions[0].name.as_deref(), Some("build"));
+        assert_eq!(sessions[1].name, None);
+
+        // Renaming again, even to the same name, is fine
+        rename(&file, "blue-moon", "build").unwrap();
+    }
+
+    #[test]
+    fn test_rename_refuses_collisions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = seed(tmp.path(), &["blue-moon", "red-sun"]);
+        rename(&file, "red-sun", "build").unwrap();
+
+        assert!(rename(&file, "blue-moon", "build").is_err());
+        assert!(rename(&file, "blue-moon", "red-sun").is_err());
+        assert!(rename(&file, "blue-moon", " ").is_err());
+
+        // A session that has gone away frees its name
+        std::fs::remove_file(tmp.path().join("red-sun.sock")).unwrap();
+        rename(&file, "blue-moon", "build").unwrap();
+    }
+
+    #[test]
+    fn test_short_id_is_unique_among_live_sessions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = seed(tmp.path(), &["blue-moon", "red-sun"]);
+        let sessions: Vec<Value> =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        let code = short_id("green-sea", tmp.path(), &sessions);
+        assert_eq!(code.len(), SHORT_ID_LEN);
+        assert!(code.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(short_id("green-sea", tmp.path(), &sessions), code);
+
+        // Taken by a running session's code, then also by another's name
+        let mut sessions = sessions;
+        sessions[0]["short_id"] = Value::from(code.clone());
+        let longer = short_id("green-sea", tmp.path(), &sessions);
+        assert_eq!(longer.len(), SHORT_ID_LEN + 1);
+        assert!(longer.starts_with(&code));
+        sessions[1]["name"] = Value::from(longer.clone());
+        assert_eq!(
+            short_id("green-sea", tmp.path(), &sessions).len(),
+            SHORT_ID_LEN + 2
+        );
+
+        // Codes of sessions that have gone away are free again
+        std::fs::remove_file(tmp.path().join("blue-moon.sock")).unwrap();
+        std::fs::remove_file(tmp.path().join("red-sun.sock")).unwrap();
+        assert_eq!(short_id("green-sea", tmp.path(), &sessions), code);
+    }
+
+    #[test]
+    fn test_indexed_command_is_capped() {
+        let short = vec!["sh".to_string(), "-c".to_string(), "true".to_string()];
+        assert_eq!(indexed_command(&short), short);
+
+        let exact = "x".repeat(MAX_INDEXED_ARG_CHARS);
+        let long = "é".repeat(MAX_INDEXED_ARG_CHARS + 1);
+        let mut many = vec![exact.clone(), long];
+        many.extend((0..40).map(|i| i.to_string()));
+        let indexed = indexed_command(&many);
+        assert_eq!(indexed.len(), MAX_INDEXED_ARGS + 1);
+        assert_eq!(indexed[0], exact);
+        assert_eq!(
+            indexed[1],
+            format!("{}…", "é".repeat(MAX_INDEXED_ARG_CHARS - 1))
+        );
+        assert_eq!(indexed[MAX_INDEXED_ARGS - 1], "29");
+        assert_eq!(indexed[MAX_INDEXED_ARGS], "… (10 more)");
+    }
+
+    #[test]
+    fn test_gc_removes_only_dead_sessions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file = seed(dir, &["live-one", "crashed", "no-socket"]);
+        // Sessions that are running have a listener; the rest are files
+        // left behind by ones that died
+        std::fs::remove_file(dir.join("live-one.sock")).unwrap();
+        let _listener = std::os::unix::net::UnixListener::bind(dir.join("live-one.sock")).unwrap();
+        std::fs::remove_file(dir.join("no-socket.sock")).unwrap();
+        std::fs::write(dir.join("orphan.sock"), "").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let expected = GcReport {
+            sockets: vec![dir.join("crashed.sock"), dir.join("orphan.sock")],
+            entries: vec!["crashed".to_string(), "no-socket".to_string()],
+        };
+        let before = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(gc(dir, true).unwrap(), expected);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), before);
+        assert!(dir.join("orphan.sock").exists());
+
+        assert_eq!(gc(dir, false).unwrap(), expected);
+        let sessions: Vec<tap_protocol::Session> =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        let ids: Vec<_> = sessions.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["live-one"]);
+        assert!(dir.join("live-one.sock").exists());
+        assert!(dir.join("notes.txt").exists());
+        assert!(!dir.join("crashed.sock").exists());
+        assert!(!dir.join("orphan.sock").exists());
+
+        // Nothing left to clean
+        assert_eq!(gc(dir, false).unwrap(), GcReport::default());
+    }
+}
@@ -0,0 +1,178 @@
+//! Forwarding client-requested signals to the child (`tap signal`), and
+//! stopping and continuing it (`tap pause` / `tap resume`).
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::sys::signal::{Signal, kill, killpg};
+use nix::unistd::{Pid, getpgid, getpgrp};
+
+/// Whether the child is stopped by `Pause`.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Parse a signal given as a number (`10`) or a name, with or without the
+/// `SIG` prefix and in any case (`SIGUSR1`, `usr1`).
+pub fn parse_signal(s: &str) -> Result<i32, String> {
+    if let Ok(number) = s.parse::<i32>() {
+        return Signal::try_from(number)
+            .map(|sig| sig as i32)
+            .map_err(|_| format!("unknown signal number {number}"));
+    }
+    let upper = s.to_ascii_uppercase();
+    let name = if upper.starts_with("SIG") {
+        upper
+    } else {
+        format!("SIG{upper}")
+    };
+    Signal::from_str(&name)
+        .map(|sig| sig as i32)
+        .map_err(|_| format!("unknown signal `{s}`"))
+}
+
+/// Send `signal` to the child, or to its whole process group.
+///
+/// `SIGKILL` and `SIGSTOP` are refused: the first skips the child's cleanup
+/// (`Terminate` escalates to it only after a grace period), and the second
+/// leaves the session hung with no way to type `fg` (`Pause` is the
+/// tracked way to do that).
+pub fn forward(child: Pid, signal: i32, group: bool) -> Result<(), String> {
+    let sig = Signal::try_from(signal).map_err(|_| format!("unknown signal number {signal}"))?;
+    match sig {
+        Signal::SIGKILL => return Err("SIGKILL is not forwarded; use terminate".to_string()),
+        Signal::SIGSTOP => return Err("SIGSTOP is not forwarded; use pause".to_string()),
+        _ => {}
+    }
+    // The child leads its own session, so its process group ID is its PID
+    let result = if group {
+        killpg(child, sig)
+    } else {
+        kill(child, sig)
+    };
+    result.map_err(|e| format!("failed to send {sig}: {e}"))
+}
+
+/// Stop (`SIGSTOP`) or continue (`SIGCONT`) the child, or its whole process
+/// group, and remember which for [`is_paused`].
+///
+/// A group that includes this process is refused, so the server never
+/// stops itself along with the child.
+pub fn set_paused(child: Pid, paused: bool, group: bool) -> Result<(), String> {
+    let sig = if paused {
+        Signal::SIGSTOP
+    } else {
+        Signal::SIGCONT
+    };
+    let result = if group {
+        let pgid = getpgid(Some(child)).map_err(|e| format!("failed to get process group: {e}"))?;
+        if pgid == getpgrp() {
+            return Err("the command shares tap's process group; leave out group".to_string());
+        }
+        killpg(pgid, sig)
+    } else {
+        kill(child, sig)
+    };
+    result.map_err(|e| format!("failed to send {sig}: {e}"))?;
+    PAUSED.store(paused, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether the child was last stopped by [`set_paused`] rather than continued.
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Forget a pause once the child has exited, so a dead child isn't
+/// reported as stopped.
+pub fn clear_paused() {
+    PAUSED.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGUSR1"), Ok(Signal::SIGUSR1 as i32));
+        assert_eq!(parse_signal("usr2"), Ok(Signal::SIGUSR2 as i32));
+        assert_eq!(parse_signal("15"), Ok(15));
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("999").is_err());
+    }
+
+    #[test]
+    fn test_refuses_kill_and_stop() {
+        let me = Pid::this();
+        assert!(forward(me, Signal::SIGKILL as i32, false).is_err());
+        assert!(forward(me, Signal::SIGSTOP as i32, false).is_err());
+        assert!(forward(me, 999, false).is_err());
+    }
+
+    #[test]
+    fn test_forwards_sigusr1() {
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("trap 'echo got usr1; exit 0' USR1; echo ready; while :; do sleep 0.05; done")
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        assert_eq!(line, "ready\n");
+
+        let pid = Pid::from_raw(child.id() as i32);
+        forward(pid, Signal::SIGUSR1 as i32, false).unwrap();
+        line.clear();
+        stdout.read_line(&mut line).unwrap();
+        assert_eq!(line, "got usr1\n");
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_pause_stops_output_until_resume() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+        use std::time::Duration;
+
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("while :; do echo tick; sleep 0.01; done")
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&ticks);
+        std::thread::spawn(move || {
+            for _ in stdout.lines() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let pid = Pid::from_raw(child.id() as i32);
+        let settle = || std::thread::sleep(Duration::from_millis(200));
+
+        // Started by us, so it's in our process group
+        assert!(set_paused(pid, true, true).is_err());
+        assert!(!is_paused());
+
+        set_paused(pid, true, false).unwrap();
+        assert!(is_paused());
+        settle();
+        let paused_at = ticks.load(Ordering::Relaxed);
+        settle();
+        assert_eq!(ticks.load(Ordering::Relaxed), paused_at);
+
+        set_paused(pid, false, false).unwrap();
+        assert!(!is_paused());
+        settle();
+        assert!(ticks.load(Ordering::Relaxed) > paused_at);
+
+        set_paused(pid, true, false).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        clear_paused();
+        assert!(!is_paused());
+    }
+}
@@ -0,0 +1,331 @@
+//! Launching the child on a PTY, or on plain pipes (`--no-pty`).
+
+use std::ffi::CString;
+use std::fmt;
+use std::io::Read;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStringExt;
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::unistd::{self, ForkResult, Pid};
+
+use crate::child_env;
+use crate::limits::{self, ChildLimits};
+
+/// Exit status of a child whose command could not be found, as in a shell.
+pub const COMMAND_NOT_FOUND_EXIT: i32 = 127;
+
+/// Exit status of a child whose command exists but could not be run.
+pub const COMMAND_NOT_EXECUTABLE_EXIT: i32 = 126;
+
+/// The child's `exec` failed; it has already exited.
+#[derive(Debug)]
+pub struct ExecError {
+    pub command: String,
+    pub errno: Errno,
+}
+
+impl ExecError {
+    /// The exit status the child used: 127 if the command doesn't exist,
+    /// 126 for any other failure.
+    pub fn exit_code(&self) -> i32 {
+        exec_failed_exit(self.errno)
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.command, self.errno.desc())
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+fn exec_failed_exit(errno: Errno) -> i32 {
+    if errno == Errno::ENOENT {
+        COMMAND_NOT_FOUND_EXIT
+    } else {
+        COMMAND_NOT_EXECUTABLE_EXIT
+    }
+}
+
+/// A command resolved ahead of `fork`, since little is safe to do between
+/// `fork` and `exec` in a threaded process.
+pub struct ChildCommand {
+    program: Option<CString>,
+    /// The command as given, searched for on `PATH` if `program` is unset.
+    name: CString,
+    args: Vec<CString>,
+    env: Vec<CString>,
+    limits: ChildLimits,
+}
+
+impl ChildCommand {
+    /// Prepare `command` to run with exactly `env`.
+    pub fn new(command: &[String], env: &[(String, String)]) -> eyre::Result<Self> {
+        let args: Vec<CString> = command
+            .iter()
+            .map(|s| CString::new(s.as_str()))
+            .collect::<Result<_, _>>()?;
+        if args.is_empty() {
+            return Err(eyre::eyre!("No command to run"));
+        }
+        let program = child_env::resolve_program(&command[0], env)
+            .and_then(|path| CString::new(path.into_os_string().into_vec()).ok());
+        let env = env
+            .iter()
+            .map(|(k, v)| CString::new(format!("{k}={v}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            program,
+            name: args[0].clone(),
+            args,
+            env,
+            limits: ChildLimits::default(),
+        })
+    }
+
+    /// Apply `limits` in the child before exec, once they pass validation.
+    pub fn with_limits(mut self, limits: ChildLimits) -> eyre::Result<Self> {
+        limits.validate()?;
+        self.limits = limits;
+        Ok(self)
+    }
+
+    /// Start it as a login shell: `argv[0]` is the program's file name
+    /// prefixed with `-`, as `login` does, so the shell reads profile files.
+    pub fn login(mut self) -> eyre::Result<Self> {
+        let name = self.name.to_string_lossy();
+        let base = name.rsplit('/').next().unwrap_or(&name);
+        self.args[0] = CString::new(format!("-{base}"))?;
+        Ok(self)
+    }
+
+    /// Pass `-i` first, so a shell acts interactive whatever its stdin is.
+    pub fn interactive(mut self) -> Self {
+        self.args.insert(1, c"-i".to_owned());
+        self
+    }
+
+    /// Fork and exec on the PTY `slave`, which becomes the child's controlling
+    /// terminal and stdio. `master` is closed in the child. Fails with an
+    /// [`ExecError`] if the command couldn't be run.
+    pub fn spawn(&self, master: &OwnedFd, slave: OwnedFd) -> eyre::Result<Pid> {
+        let (status_read, status_write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        match unsafe { unistd::fork() } {
+            Ok(ForkResult::Child) => {
+                unsafe {
+                    libc::close(master.as_raw_fd());
+                }
+
+                unistd::setsid().expect("setsid failed");
+
+                // Set controlling terminal
+                unsafe {
+                    libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY as _, 0);
+                }
+
+                // Dup slave to stdin/stdout/stderr using libc directly
+                let slave_raw = slave.as_raw_fd();
+                unsafe {
+                    libc::dup2(slave_raw, libc::STDIN_FILENO);
+                    libc::dup2(slave_raw, libc::STDOUT_FILENO);
+                    libc::dup2(slave_raw, libc::STDERR_FILENO);
+                }
+
+                if slave_raw > 2 {
+                    drop(slave);
+                }
+
+                self.exec(&status_write)
+            }
+            Ok(ForkResult::Parent { child }) => {
+                self.check_exec(child, status_read, status_write)?;
+                Ok(child)
+            }
+            Err(e) => Err(eyre::eyre!("Fork failed: {e}")),
+        }
+    }
+
+    /// Fork and exec with pipes instead of a PTY, so the child sees it is
+    /// not interactive. Returns its PID, the write end of its stdin, and the
+    /// read end of its stdout and stderr, which share one pipe so their
+    /// output stays in order. The child gets its own session and no
+    /// controlling terminal.
+    pub fn spawn_piped(&self) -> eyre::Result<(Pid, OwnedFd, OwnedFd)> {
+        let (status_read, status_write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let (stdin_read, stdin_write) = unistd::pipe()?;
+        let (output_read, output_write) = unistd::pipe()?;
+        match unsafe { unistd::fork() } {
+            Ok(ForkResult::Child) => {
+                unistd::setsid().expect("setsid failed");
+
+                unsafe {
+                    libc::dup2(stdin_read.as_raw_fd(), libc::STDIN_FILENO);
+                    libc::dup2(output_write.as_raw_fd(), libc::STDOUT_FILENO);
+                    libc::dup2(output_write.as_raw_fd(), libc::STDERR_FILENO);
+                }
+
+                for fd in [stdin_read, stdin_write, output_read, output_write] {
+                    if fd.as_raw_fd() > 2 {
+                        drop(fd);
+                    } else {
+                        std::mem::forget(fd);
+                    }
+                }
+
+                self.exec(&status_write)
+            }
+            Ok(ForkResult::Parent { child }) => {
+                self.check_exec(child, status_read, status_write)?;
+                // Close-on-exec copies, so later children (e.g. an output
+                // filter) don't hold the child's stdin open
+                Ok((child, stdin_write.try_clone()?, output_read.try_clone()?))
+            }
+            Err(e) => Err(eyre::eyre!("Fork failed: {e}")),
+        }
+    }
+
+    /// Apply the limits and replace the (forked) process with the command.
+    /// If `exec` fails, its errno goes to the parent over `status` (closed
+    /// by a successful `exec`) and the child exits with 127 or 126.
+    fn exec(&self, status: &OwnedFd) -> ! {
+        if let Err(message) = self.limits.apply() {
+            unsafe {
+                libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len());
+                libc::_exit(limits::SETUP_FAILED_EXIT);
+            }
+        }
+        let Err(errno) = match &self.program {
+            Some(program) => unistd::execve(program, &self.args, &self.env),
+            None => unistd::execvp(&self.name, &self.args),
+        };
+        let raw = (errno as i32).to_ne_bytes();
+        unsafe {
+            libc::write(status.as_raw_fd(), raw.as_ptr().cast(), raw.len());
+            libc::_exit(exec_failed_exit(errno));
+        }
+    }
+
+    /// Wait for the child to `exec`. The status pipe closes on success; if
+    /// the child reported an errno instead, reap it and fail.
+    fn check_exec(
+        &self,
+        child: Pid,
+        status_read: OwnedFd,
+        status_write: OwnedFd,
+    ) -> eyre::Result<()> {
+        drop(status_write);
+        let mut raw = [0u8; 4];
+        if std::fs::File::from(status_read)
+            .read_exact(&mut raw)
+            .is_err()
+        {
+            return Ok(());
+        }
+        let _ = nix::sys::wait::waitpid(child, None);
+        Err(ExecError {
+            command: self.name.to_string_lossy().into_owned(),
+            errno: Errno::from_raw(i32::from_ne_bytes(raw)),
+        }
+        .into())
+    }
+}
+
+/// Expand `\n` to `\r\n`, as a terminal's line discipline (`ONLCR`) would,
+/// for output read from a pipe rather than a PTY.
+pub fn onlcr(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 16);
+    for &byte in data {
+        if byte == b'\n' {
+            out.push(b'\r');
+        }
+        out.push(byte);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nice_applies_to_child() {
+        let env = child_env::build(false, &[]);
+        let child = ChildCommand::new(&["nice".to_string()], &env)
+            .unwrap()
+            .with_limits(ChildLimits {
+                nice: Some(19),
+                ..ChildLimits::default()
+            })
+            .unwrap();
+        let (pid, stdin, output) = child.spawn_piped().unwrap();
+        drop(stdin);
+
+        let mut printed = String::new();
+        std::fs::File::from(output)
+            .read_to_string(&mut printed)
+            .unwrap();
+        assert_eq!(crate::exit::wait_for_child(pid).exit_code(), 0);
+        assert_eq!(printed.trim(), "19");
+    }
+
+    #[test]
+    fn test_closing_stderr_early_keeps_capturing_stdout() {
+        // stderr shares the output pipe, so closing it leaves stdout's end
+        // open and the read only hits EOF once the child is gone
+        let command = ["sh", "-c", "echo before; exec 2>&-; sleep 0.2; echo after"];
+        let env = child_env::build(false, &[]);
+        let child = ChildCommand::new(&command.map(String::from), &env).unwrap();
+        let (pid, stdin, output) = child.spawn_piped().unwrap();
+        drop(stdin);
+
+        let mut printed = String::new();
+        std::fs::File::from(output)
+            .read_to_string(&mut printed)
+            .unwrap();
+        assert_eq!(crate::exit::wait_for_child(pid).exit_code(), 0);
+        assert_eq!(printed, "before\nafter\n");
+    }
+
+    #[test]
+    fn test_login_shell_argv0() {
+        // With `-c` and no further arguments, `$0` is the shell's argv[0]
+        let command = ["sh", "-c", "echo \"$0\""].map(String::from);
+        let env = child_env::build(false, &[]);
+        let child = ChildCommand::new(&command, &env).unwrap().login().unwrap();
+        let (pid, stdin, output) = child.spawn_piped().unwrap();
+        drop(stdin);
+
+        let mut printed = String::new();
+        std::fs::File::from(output)
+            .read_to_string(&mut printed)
+            .unwrap();
+        assert_eq!(crate::exit::wait_for_child(pid).exit_code(), 0);
+        assert_eq!(printed, "-sh\n");
+
+        let child = ChildCommand::new(&["/bin/bash".to_string()], &env)
+            .unwrap()
+            .login()
+            .unwrap()
+            .interactive();
+        assert_eq!(child.args, [c"-bash".to_owned(), c"-i".to_owned()]);
+        assert_eq!(child.name.as_c_str(), c"/bin/bash");
+    }
+
+    #[test]
+    fn test_missing_command_reports_exec_error() {
+        let env = child_env::build(false, &[]);
+        let child = ChildCommand::new(&["tap-no-such-command".to_string()], &env).unwrap();
+        let err = child.spawn_piped().unwrap_err();
+        let err = err.downcast_ref::<ExecError>().unwrap();
+        assert_eq!(err.errno, Errno::ENOENT);
+        assert_eq!(err.exit_code(), COMMAND_NOT_FOUND_EXIT);
+        assert_eq!(
+            err.to_string(),
+            "tap-no-such-command: No such file or directory"
+        );
+    }
+}
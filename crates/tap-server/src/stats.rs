@@ -0,0 +1,176 @@
+//! Session counters, output chunk timing, and scrollback load shedding.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tap_protocol::SessionStats;
+
+/// A scrollback push slower than this is considered a stall.
+const SLOW_PUSH_THRESHOLD: Duration = Duration::from_millis(50);
+/// How long to skip scrollback updates after a stall.
+const SHED_DURATION: Duration = Duration::from_millis(500);
+/// How many reads from the child [`RECENT_CHUNKS`] remembers.
+const RECENT_CHUNK_CAPACITY: usize = 256;
+
+/// Process-wide session counters.
+pub struct Counters {
+    pub bytes_out: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub dropped_scrollback_bytes: AtomicU64,
+    pub slow_pushes: AtomicU64,
+    pub dropped_fifo_bytes: AtomicU64,
+    pub shed_subscriber_bytes: AtomicU64,
+}
+
+pub static COUNTERS: Counters = Counters {
+    bytes_out: AtomicU64::new(0),
+    bytes_in: AtomicU64::new(0),
+    dropped_scrollback_bytes: AtomicU64::new(0),
+    slow_pushes: AtomicU64::new(0),
+    dropped_fifo_bytes: AtomicU64::new(0),
+    shed_subscriber_bytes: AtomicU64::new(0),
+};
+
+/// Bump a counter by a byte count.
+pub fn add(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> SessionStats {
+        SessionStats {
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            dropped_scrollback_bytes: self.dropped_scrollback_bytes.load(Ordering::Relaxed),
+            slow_pushes: self.slow_pushes.load(Ordering::Relaxed),
+            dropped_fifo_bytes: self.dropped_fifo_bytes.load(Ordering::Relaxed),
+            shed_subscriber_bytes: self.shed_subscriber_bytes.load(Ordering::Relaxed),
+            // Tracked by the scrollback buffer and output channel, not here
+            lines_discarded: 0,
+            memory_bytes: 0,
+            subscribers: 0,
+        }
+    }
+}
+
+/// Timing of the most recent reads from the child, for `GetRecentChunks`.
+pub static RECENT_CHUNKS: Mutex<ChunkLog> = Mutex::new(ChunkLog::new(RECENT_CHUNK_CAPACITY));
+
+/// Ring of `(elapsed_ms, byte_len)` for recent output chunks, oldest first.
+pub struct ChunkLog {
+    chunks: VecDeque<(u64, usize)>,
+    capacity: usize,
+}
+
+impl ChunkLog {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Note a chunk of `len` bytes read `elapsed` after the session started.
+    pub fn record(&mut self, elapsed: Duration, len: usize) {
+        if self.chunks.len() == self.capacity {
+            self.chunks.pop_front();
+        }
+        self.chunks
+            .push_back((elapsed.as_millis().try_into().unwrap_or(u64::MAX), len));
+    }
+
+    /// The last `count` chunks, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<(u64, usize)> {
+        let start = self.chunks.len().saturating_sub(count);
+        self.chunks.range(start..).copied().collect()
+    }
+
+    /// Mean size of the remembered chunks, or 0 if there are none.
+    pub fn average_len(&self) -> usize {
+        let total: usize = self.chunks.iter().map(|&(_, len)| len).sum();
+        total.checked_div(self.chunks.len()).unwrap_or(0)
+    }
+}
+
+/// Skips scrollback updates for a while after a push stalls the I/O loop,
+/// so stdout and subscribers keep flowing under pathological output.
+pub struct LoadShedder {
+    threshold: Duration,
+    shed_for: Duration,
+    shedding_until: Option<Instant>,
+}
+
+impl LoadShedder {
+    pub fn new() -> Self {
+        Self::with_limits(SLOW_PUSH_THRESHOLD, SHED_DURATION)
+    }
+
+    pub fn with_limits(threshold: Duration, shed_for: Duration) -> Self {
+        Self {
+            threshold,
+            shed_for,
+            shedding_until: None,
+        }
+    }
+
+    /// Whether scrollback updates should be skipped at `now`.
+    pub fn shedding(&mut self, now: Instant) -> bool {
+        match self.shedding_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                self.shedding_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record how long a push took. Returns true if it was slow and shedding began.
+    pub fn record(&mut self, elapsed: Duration, now: Instant) -> bool {
+        if elapsed < self.threshold {
+            return false;
+        }
+        self.shedding_until = Some(now + self.shed_for);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fast_pushes_never_shed() {
+        let mut shedder = LoadShedder::new();
+        let now = Instant::now();
+        assert!(!shedder.record(Duration::from_millis(1), now));
+        assert!(!shedder.shedding(now));
+    }
+
+    #[test]
+    fn test_slow_push_sheds_then_recovers() {
+        let mut shedder =
+            LoadShedder::with_limits(Duration::from_millis(10), Duration::from_millis(100));
+        let now = Instant::now();
+
+        assert!(shedder.record(Duration::from_millis(20), now));
+        assert!(shedder.shedding(now + Duration::from_millis(50)));
+        assert!(!shedder.shedding(now + Duration::from_millis(100)));
+        assert!(!shedder.shedding(now + Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn test_chunk_log_keeps_latest_in_order() {
+        let mut log = ChunkLog::new(4);
+        for i in 0..6u64 {
+            log.record(Duration::from_millis(i * 10), i as usize + 1);
+        }
+
+        let chunks = log.recent(10);
+        assert_eq!(chunks, vec![(20, 3), (30, 4), (40, 5), (50, 6)]);
+        assert!(chunks.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert_eq!(log.recent(2), vec![(40, 5), (50, 6)]);
+    }
+}
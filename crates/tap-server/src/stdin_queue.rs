@@ -0,0 +1,190 @@
+//! Optional buffering between local stdin and the PTY (`--stdin-buffer`).
+//!
+//! By default stdin is written to the master inline, so a child that stops
+//! reading input stalls the main loop once the PTY's input buffer is full.
+//! With a [`StdinQueue`], a writer thread drains a bounded queue into the
+//! master and the loop keeps serving child output and clients. What happens
+//! to input arriving while the queue is full is set by [`StdinOverflow`].
+
+use std::os::fd::RawFd;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{Notify, mpsc};
+use tracing::{error, warn};
+
+use crate::{pty_writer, stats};
+
+/// What to do with stdin while the queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StdinOverflow {
+    /// Stop reading stdin until the child catches up. Nothing is lost; the
+    /// local terminal buffers keystrokes meanwhile.
+    #[default]
+    Block,
+    /// Keep reading and discard input that doesn't fit.
+    Drop,
+}
+
+impl FromStr for StdinOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!(
+                "invalid stdin overflow policy: {s} (expected block or drop)"
+            )),
+        }
+    }
+}
+
+/// Bounded queue of stdin bytes, written to the master by its own thread.
+pub struct StdinQueue {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    /// Bytes accepted but not yet written.
+    queued: Arc<AtomicUsize>,
+    drained: Arc<Notify>,
+    capacity: usize,
+    overflow: StdinOverflow,
+}
+
+impl StdinQueue {
+    /// Start the writer thread for `fd`. `capacity` is in bytes.
+    pub fn spawn(fd: RawFd, capacity: usize, overflow: StdinOverflow) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let drained = Arc::new(Notify::new());
+
+        let (writer_queued, writer_drained) = (queued.clone(), drained.clone());
+        std::thread::Builder::new()
+            .name("tap-stdin".to_string())
+            .spawn(move || {
+                while let Some(data) = rx.blocking_recv() {
+                    if let Err(e) = pty_writer::write_all(fd, &data) {
+                        error!("Failed to write stdin to PTY: {e}");
+                        break;
+                    }
+                    stats::add(&stats::COUNTERS.bytes_in, data.len());
+                    writer_queued.fetch_sub(data.len(), Ordering::Relaxed);
+                    writer_drained.notify_one();
+                }
+            })
+            .expect("failed to spawn stdin writer thread");
+
+        Self {
+            tx,
+            queued,
+            drained,
+            capacity,
+            overflow,
+        }
+    }
+
+    /// Whether stdin should be read now. Under [`StdinOverflow::Block`] this
+    /// is false while the queue is full; a read may overshoot the capacity
+    /// by up to one chunk.
+    pub fn has_room(&self) -> bool {
+        self.overflow == StdinOverflow::Drop || self.queued() < self.capacity
+    }
+
+    /// Queue `data` for the child. Returns false if it was dropped because
+    /// the queue is full.
+    pub fn push(&self, data: Vec<u8>) -> bool {
+        if self.overflow == StdinOverflow::Drop && self.queued() + data.len() > self.capacity {
+            warn!("Stdin buffer full; dropping {} bytes", data.len());
+            return false;
+        }
+        self.queued.fetch_add(data.len(), Ordering::Relaxed);
+        if self.tx.send(data).is_err() {
+            // The writer stopped after a write error, which it has logged
+            return false;
+        }
+        true
+    }
+
+    /// Wait until the writer has made progress.
+    pub async fn drained(&self) {
+        self.drained.notified().await;
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Wait for room in `queue`. Never completes without a queue.
+pub async fn room(queue: Option<&StdinQueue>) {
+    match queue {
+        Some(queue) => queue.drained().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    use nix::unistd;
+
+    const CHUNK: usize = 4096;
+
+    #[test]
+    fn test_parse_overflow() {
+        assert_eq!("block".parse(), Ok(StdinOverflow::Block));
+        assert_eq!("drop".parse(), Ok(StdinOverflow::Drop));
+        assert!("spill".parse::<StdinOverflow>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_burst_to_stalled_child_does_not_block() {
+        // A pipe nobody reads stands in for a child that stopped reading
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        let queue = StdinQueue::spawn(write_end.as_raw_fd(), 16 * 1024, StdinOverflow::Block);
+
+        let start = Instant::now();
+        let mut pushed = 0;
+        while queue.has_room() && pushed < 1024 * 1024 {
+            assert!(queue.push(vec![b'x'; CHUNK]));
+            pushed += CHUNK;
+        }
+        assert!(!queue.has_room(), "queue never filled");
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Once the child reads again, the queue drains and stdin resumes
+        let reader = std::thread::spawn(move || {
+            let mut file = std::fs::File::from(read_end);
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut total = 0;
+            while total < pushed {
+                total += file.read(&mut buf).unwrap();
+            }
+            total
+        });
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !queue.has_room() {
+                queue.drained().await;
+            }
+        })
+        .await
+        .expect("queue did not drain");
+        assert_eq!(reader.join().unwrap(), pushed);
+    }
+
+    #[tokio::test]
+    async fn test_drop_policy_discards_overflow() {
+        let (_read_end, write_end) = unistd::pipe().unwrap();
+        let queue = StdinQueue::spawn(write_end.as_raw_fd(), 16 * 1024, StdinOverflow::Drop);
+
+        let start = Instant::now();
+        let accepted = (0..256).filter(|_| queue.push(vec![b'x'; CHUNK])).count();
+        assert!(queue.has_room());
+        assert!(accepted < 256, "nothing was dropped");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
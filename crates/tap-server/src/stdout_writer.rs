@@ -0,0 +1,108 @@
+//! Serialized writes to our own stdout.
+//!
+//! Child output, the startup banner, terminal resets requested by clients,
+//! and keyboard mode changes all go to the same stdout, from different
+//! tasks. Each call to [`write_all`] is delivered contiguously: writers take
+//! turns, and a write is finished before the next one starts, so output
+//! can't be garbled by another source writing in the middle of it.
+
+use std::io::{self, Write};
+use std::sync::LazyLock;
+
+use parking_lot::{Mutex, MutexGuard};
+
+static STDOUT: LazyLock<SerialWriter<io::Stdout>> =
+    LazyLock::new(|| SerialWriter::new(io::stdout()));
+
+/// A writer that only one caller uses at a time.
+pub struct SerialWriter<W> {
+    inner: Mutex<W>,
+}
+
+impl<W: Write> SerialWriter<W> {
+    pub const fn new(inner: W) -> Self {
+        Self {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    /// Write all of `data` without interleaving with other writers.
+    pub fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        self.inner.lock().write_all(data)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().flush()
+    }
+
+    /// Hold the writer for several writes in a row.
+    pub fn lock(&self) -> MutexGuard<'_, W> {
+        self.inner.lock()
+    }
+}
+
+/// Write all of `data` to stdout without interleaving with other writers.
+pub fn write_all(data: &[u8]) -> io::Result<()> {
+    STDOUT.write_all(data)
+}
+
+pub fn flush() -> io::Result<()> {
+    STDOUT.flush()
+}
+
+/// Hold stdout for several writes in a row, e.g. for `execute!`.
+pub fn lock() -> MutexGuard<'static, io::Stdout> {
+    STDOUT.lock()
+}
+
+/// [`write_all`] from async code, off the runtime's threads since stdout
+/// can block. Flushes afterwards if `flush` is set.
+pub async fn write_async(data: Vec<u8>, flush: bool) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut stdout = STDOUT.lock();
+        stdout.write_all(&data)?;
+        if flush {
+            let _ = stdout.flush();
+        }
+        Ok(())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// [`flush`] from async code.
+pub async fn flush_async() {
+    let _ = tokio::task::spawn_blocking(flush).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_writes_dont_interleave() {
+        let writer = SerialWriter::new(Vec::new());
+        std::thread::scope(|s| {
+            for byte in b'a'..=b'h' {
+                let writer = &writer;
+                s.spawn(move || {
+                    let block = vec![byte; 4096];
+                    for _ in 0..50 {
+                        // Several writes under one hold stay together too
+                        let mut held = writer.lock();
+                        held.write_all(&block[..2048]).unwrap();
+                        held.write_all(&block[2048..]).unwrap();
+                        drop(held);
+                        writer.write_all(&block).unwrap();
+                    }
+                });
+            }
+        });
+
+        let output = writer.inner.into_inner();
+        assert_eq!(output.len(), 8 * 50 * 2 * 4096);
+        for block in output.chunks(4096) {
+            assert!(block.iter().all(|&b| b == block[0]), "a write was split");
+        }
+    }
+}
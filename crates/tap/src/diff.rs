@@ -0,0 +1,139 @@
+//! Human-readable comparison of two screen snapshots (`tap diff`).
+
+use std::fmt::Write;
+
+use tap_client::{Cell, Snapshot};
+
+/// Describe how `b` differs from `a`: size, cursor, then each changed row
+/// as old and new text with `^` under the cells that changed (in content or
+/// style). `None` if they are identical.
+pub fn diff(a: &Snapshot, b: &Snapshot) -> Option<String> {
+    if a == b {
+        return None;
+    }
+
+    let mut out = String::new();
+    if a.size != b.size {
+        let _ = writeln!(
+            out,
+            "size: {}x{} -> {}x{}",
+            a.size.0, a.size.1, b.size.0, b.size.1
+        );
+    }
+    if a.cursor != b.cursor {
+        let _ = writeln!(
+            out,
+            "cursor: row {}, col {} -> row {}, col {}",
+            a.cursor.0, a.cursor.1, b.cursor.0, b.cursor.1
+        );
+    }
+
+    let blank = Vec::new();
+    for row in 0..a.rows.len().max(b.rows.len()) {
+        let old = a.rows.get(row).unwrap_or(&blank);
+        let new = b.rows.get(row).unwrap_or(&blank);
+        let changed: Vec<bool> = (0..old.len().max(new.len()))
+            .map(|col| cell(old, col) != cell(new, col))
+            .collect();
+        if !changed.contains(&true) {
+            continue;
+        }
+
+        let (old_text, new_text) = (row_text(old), row_text(new));
+        if old_text == new_text {
+            let _ = writeln!(out, "row {row} (style only):");
+        } else {
+            let _ = writeln!(out, "row {row}:");
+        }
+        let _ = writeln!(out, "  - {old_text}");
+        let _ = writeln!(out, "  + {new_text}");
+        let markers: String = changed.iter().map(|&c| if c { '^' } else { ' ' }).collect();
+        let _ = writeln!(out, "    {}", markers.trim_end());
+    }
+    Some(out)
+}
+
+/// Cell `col` of `row`; missing cells compare as blank.
+fn cell(row: &[Cell], col: usize) -> Cell {
+    row.get(col).cloned().unwrap_or_default()
+}
+
+/// A row's text, one column per cell, without trailing blanks.
+fn row_text(row: &[Cell]) -> String {
+    let text: String = row
+        .iter()
+        .map(|cell| {
+            if cell.text.is_empty() {
+                " "
+            } else {
+                &cell.text
+            }
+        })
+        .collect();
+    text.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(lines: &[&str]) -> Snapshot {
+        Snapshot {
+            size: (24, 80),
+            cursor: (0, 0),
+            rows: lines
+                .iter()
+                .map(|line| {
+                    line.chars()
+                        .map(|c| Cell {
+                            text: c.to_string(),
+                            ..Cell::default()
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_identical_snapshots() {
+        let a = snapshot(&["$ ls", "file"]);
+        assert_eq!(diff(&a, &a.clone()), None);
+    }
+
+    #[test]
+    fn test_changed_text_cursor_and_size() {
+        let a = snapshot(&["$ ls", "hello world"]);
+        let mut b = snapshot(&["$ ls", "hello there"]);
+        b.cursor = (1, 11);
+        b.size = (30, 80);
+
+        assert_eq!(
+            diff(&a, &b).unwrap(),
+            "size: 24x80 -> 30x80\n\
+             cursor: row 0, col 0 -> row 1, col 11\n\
+             row 1:\n  \
+             - hello world\n  \
+             + hello there\n          \
+             ^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_style_only_change() {
+        let a = snapshot(&["ok"]);
+        let mut b = a.clone();
+        b.rows[0][1].bold = true;
+
+        let out = diff(&a, &b).unwrap();
+        assert!(out.starts_with("row 0 (style only):\n"), "{out}");
+        assert!(out.ends_with("     ^\n"), "{out}");
+    }
+
+    #[test]
+    fn test_added_row() {
+        let a = snapshot(&["one"]);
+        let b = snapshot(&["one", "two"]);
+        assert_eq!(diff(&a, &b).unwrap(), "row 1:\n  - \n  + two\n    ^^^\n");
+    }
+}
@@ -0,0 +1,171 @@
+//! Evenly spaced screen frames for GIF and video renderers (`tap frames`).
+//!
+//! Each tick samples the screen as a [`Snapshot`]. Changed frames are
+//! written as `frame-NNNNNN.json`; every tick, changed or not, gets a line in
+//! `index.jsonl` naming the file that shows it, so a renderer can keep the
+//! timing without storing repeated frames.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde_json::json;
+use tap_client::{Client, Snapshot};
+
+/// Writes frames and the index into a directory.
+pub struct FrameWriter {
+    dir: PathBuf,
+    index: File,
+    ticks: u64,
+    last: Option<(Snapshot, String)>,
+}
+
+impl FrameWriter {
+    /// Start a frame sequence in `dir`, creating it if needed.
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index: File::create(dir.join("index.jsonl"))?,
+            ticks: 0,
+            last: None,
+        })
+    }
+
+    /// Record the screen at `elapsed` since the first frame. Returns
+    /// whether it changed since the previous frame.
+    pub fn push(&mut self, snapshot: Snapshot, elapsed: Duration) -> io::Result<bool> {
+        let changed = self.last.as_ref().is_none_or(|(last, _)| *last != snapshot);
+        if changed {
+            let file = format!("frame-{:06}.json", self.ticks);
+            std::fs::write(self.dir.join(&file), serde_json::to_vec(&snapshot)?)?;
+            self.last = Some((snapshot, file));
+        }
+        let file = self.last.as_ref().map_or("", |(_, file)| file.as_str());
+        let entry = json!({
+            "index": self.ticks,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "file": file,
+            "unchanged": !changed,
+        });
+        writeln!(self.index, "{entry}")?;
+        self.ticks += 1;
+        Ok(changed)
+    }
+}
+
+/// Sample the session's screen every `interval` into `writer`, up to
+/// `count` frames (until the session goes away if `None`). Returns how many
+/// frames were taken.
+pub async fn sample(
+    client: &mut Client,
+    writer: &mut FrameWriter,
+    interval: Duration,
+    count: Option<u64>,
+) -> eyre::Result<u64> {
+    let start = tokio::time::Instant::now();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut taken = 0;
+    while count.is_none_or(|count| taken < count) {
+        let tick = ticker.tick().await;
+        let snapshot = match client.snapshot().await {
+            Ok(snapshot) => snapshot,
+            // The session ended; what we have is the whole recording
+            Err(tap_client::Error::ConnectionClosed | tap_client::Error::Io(_)) => break,
+            Err(e) => return Err(e.into()),
+        };
+        writer.push(snapshot, tick - start)?;
+        taken += 1;
+    }
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tap_client::Cell;
+
+    fn screen(text: &str) -> Snapshot {
+        Snapshot {
+            size: (24, 80),
+            cursor: (0, text.len()),
+            rows: vec![
+                text.chars()
+                    .map(|c| Cell {
+                        text: c.to_string(),
+                        ..Cell::default()
+                    })
+                    .collect(),
+            ],
+        }
+    }
+
+    fn index(dir: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(dir.join("index.jsonl"))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_unchanged_frames_are_indexed_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = FrameWriter::create(dir.path()).unwrap();
+        for (i, text) in ["$", "$", "$ l", "$ ls"].iter().enumerate() {
+            writer
+                .push(screen(text), Duration::from_millis(100 * i as u64))
+                .unwrap();
+        }
+
+        let entries = index(dir.path());
+        assert_eq!(entries.len(), 4);
+        let files: Vec<&str> = entries
+            .iter()
+            .map(|e| e["file"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            files,
+            [
+                "frame-000000.json",
+                "frame-000000.json",
+                "frame-000002.json",
+                "frame-000003.json"
+            ]
+        );
+        assert_eq!(entries[1]["unchanged"], true);
+        assert_eq!(entries[3]["elapsed_ms"], 300);
+
+        let frame: Snapshot =
+            serde_json::from_slice(&std::fs::read(dir.path().join(files[3])).unwrap()).unwrap();
+        assert_eq!(frame, screen("$ ls"));
+        assert!(!dir.path().join("frame-000001.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_sample_takes_evenly_spaced_frames() {
+        let server = tap_client::testing::MockSession::new()
+            .scrollback("hello")
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = FrameWriter::create(dir.path()).unwrap();
+
+        let taken = sample(&mut client, &mut writer, Duration::from_millis(20), Some(3))
+            .await
+            .unwrap();
+
+        assert_eq!(taken, 3);
+        let entries = index(dir.path());
+        assert_eq!(entries.len(), 3);
+        let elapsed: Vec<u64> = entries
+            .iter()
+            .map(|e| e["elapsed_ms"].as_u64().unwrap())
+            .collect();
+        assert_eq!(elapsed[0], 0);
+        assert!(elapsed.windows(2).all(|pair| pair[1] >= pair[0] + 20));
+    }
+}
@@ -0,0 +1,245 @@
+//! Self-contained HTML rendering of a session's grid (`tap export-html`).
+
+use std::fmt::Write;
+
+use tap_client::{Cell, Color};
+
+/// Page colors used for cells with the terminal's default colors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+}
+
+impl Theme {
+    /// Default foreground and background.
+    pub fn colors(self) -> (&'static str, &'static str) {
+        match self {
+            Theme::Dark => ("#d4d4d4", "#1e1e1e"),
+            Theme::Light => ("#1e1e1e", "#ffffff"),
+        }
+    }
+}
+
+/// The 16 standard colors, as xterm draws them.
+const BASE_COLORS: [&str; 16] = [
+    "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+pub fn css_color(color: Color) -> String {
+    match color {
+        Color::Indexed(i @ 0..16) => BASE_COLORS[usize::from(i)].to_string(),
+        // 6x6x6 color cube
+        Color::Indexed(i @ 16..232) => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + 40 * v };
+            let i = i - 16;
+            format!(
+                "#{:02x}{:02x}{:02x}",
+                level(i / 36),
+                level(i / 6 % 6),
+                level(i % 6)
+            )
+        }
+        // Grayscale ramp
+        Color::Indexed(i) => {
+            let v = 8 + 10 * (i - 232);
+            format!("#{v:02x}{v:02x}{v:02x}")
+        }
+        Color::Rgb(r, g, b) => format!("#{r:02x}{g:02x}{b:02x}"),
+    }
+}
+
+pub fn escape(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+/// Builds the page a page of grid rows at a time.
+pub struct HtmlExport {
+    theme: Theme,
+    body: String,
+    /// Blank rows not yet written; dropped if nothing follows them.
+    blank_rows: usize,
+}
+
+impl HtmlExport {
+    pub fn new(theme: Theme) -> Self {
+        Self {
+            theme,
+            body: String::new(),
+            blank_rows: 0,
+        }
+    }
+
+    /// Append rows, oldest first.
+    pub fn push_rows(&mut self, rows: &[Vec<Cell>]) {
+        for row in rows {
+            let end = row
+                .iter()
+                .rposition(|cell| !cell.text.trim().is_empty() || cell.bg.is_some() || cell.inverse)
+                .map_or(0, |i| i + 1);
+            if end == 0 {
+                self.blank_rows += 1;
+                continue;
+            }
+            for _ in 0..std::mem::take(&mut self.blank_rows) {
+                self.body.push('\n');
+            }
+            self.push_cells(&row[..end]);
+            self.body.push('\n');
+        }
+    }
+
+    /// Write one row as runs of identically styled cells.
+    fn push_cells(&mut self, cells: &[Cell]) {
+        let mut start = 0;
+        while start < cells.len() {
+            let style = self.style(&cells[start]);
+            let len = cells[start..]
+                .iter()
+                .take_while(|cell| self.style(cell) == style)
+                .count();
+            if !style.is_empty() {
+                let _ = write!(self.body, "<span style=\"{style}\">");
+            }
+            for cell in &cells[start..start + len] {
+                if cell.text.is_empty() {
+                    self.body.push(' ');
+                } else {
+                    escape(&cell.text, &mut self.body);
+                }
+            }
+            if !style.is_empty() {
+                self.body.push_str("</span>");
+            }
+            start += len;
+        }
+    }
+
+    /// Inline CSS for a cell; empty for default styling.
+    fn style(&self, cell: &Cell) -> String {
+        let (default_fg, default_bg) = self.theme.colors();
+        let (mut fg, mut bg) = (cell.fg.map(css_color), cell.bg.map(css_color));
+        if cell.inverse {
+            (fg, bg) = (
+                Some(bg.unwrap_or_else(|| default_bg.to_string())),
+                Some(fg.unwrap_or_else(|| default_fg.to_string())),
+            );
+        }
+
+        let mut css = Vec::new();
+        if let Some(fg) = fg {
+            css.push(format!("color:{fg}"));
+        }
+        if let Some(bg) = bg {
+            css.push(format!("background:{bg}"));
+        }
+        if cell.bold {
+            css.push("font-weight:bold".to_string());
+        }
+        if cell.italic {
+            css.push("font-style:italic".to_string());
+        }
+        if cell.underline {
+            css.push("text-decoration:underline".to_string());
+        }
+        css.join(";")
+    }
+
+    /// The finished page.
+    pub fn finish(self, title: &str) -> String {
+        let (fg, bg) = self.theme.colors();
+        let mut page =
+            String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>");
+        escape(title, &mut page);
+        let _ = write!(
+            page,
+            "</title>\n<style>\nbody {{ margin: 0; background: {bg}; color: {fg}; }}\n\
+             pre {{ margin: 0; padding: 1em; font-family: ui-monospace, Menlo, Consolas, monospace; \
+             line-height: 1.2; }}\n</style>\n</head>\n<body>\n<pre>"
+        );
+        page.push_str(&self.body);
+        page.push_str("</pre>\n</body>\n</html>\n");
+        page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(text: &str, style: Cell) -> Vec<Cell> {
+        text.chars()
+            .map(|c| Cell {
+                text: c.to_string(),
+                ..style.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_colored_spans_and_escaping() {
+        let red = Cell {
+            fg: Some(Color::Indexed(1)),
+            bold: true,
+            ..Cell::default()
+        };
+        let mut row = cells("err", red);
+        row.extend(cells(" a<b & \"c\"", Cell::default()));
+        row.extend(cells(
+            "ok",
+            Cell {
+                bg: Some(Color::Rgb(0, 0x80, 0xff)),
+                ..Cell::default()
+            },
+        ));
+        row.extend(vec![Cell::default(); 10]);
+
+        let mut export = HtmlExport::new(Theme::Dark);
+        export.push_rows(&[
+            row,
+            vec![Cell::default(); 20],
+            cells("end", Cell::default()),
+        ]);
+        let html = export.finish("tap <session>");
+
+        assert!(html.contains(
+            "<pre><span style=\"color:#cd0000;font-weight:bold\">err</span> a&lt;b &amp; &quot;c&quot;\
+             <span style=\"background:#0080ff\">ok</span>\n\nend\n</pre>"
+        ));
+        assert!(html.contains("<title>tap &lt;session&gt;</title>"));
+        assert!(html.contains("background: #1e1e1e"));
+    }
+
+    #[test]
+    fn test_light_theme_and_inverse() {
+        let mut export = HtmlExport::new(Theme::Light);
+        export.push_rows(&[cells(
+            "x",
+            Cell {
+                inverse: true,
+                ..Cell::default()
+            },
+        )]);
+        let html = export.finish("t");
+        assert!(html.contains("<span style=\"color:#ffffff;background:#1e1e1e\">x</span>"));
+        assert!(html.contains("background: #ffffff"));
+    }
+
+    #[test]
+    fn test_palette() {
+        assert_eq!(css_color(Color::Indexed(9)), "#ff0000");
+        assert_eq!(css_color(Color::Indexed(196)), "#ff0000");
+        assert_eq!(css_color(Color::Indexed(244)), "#808080");
+        assert_eq!(css_color(Color::Rgb(1, 2, 3)), "#010203");
+    }
+}
@@ -1,27 +1,86 @@
 //! Unified CLI for tap terminal sessions.
 
+mod diff;
+mod frames;
+mod html;
+mod save;
+mod scenario;
+mod svg;
+
 use clap::{Parser, Subcommand};
-use tap_client::{Client, list_sessions};
-use tap_server::ServerConfig;
-use tokio::io::AsyncWriteExt;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use tap_client::{Client, ScreenBuffer, Session, TranscriptEntry, list_sessions, list_sessions_in};
+use tap_server::{
+    ChildLimits, ControlNormalization, FifoPolicy, FlushPolicy, ServerConfig, StdinOverflow,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Parser)]
-#[command(name = "tap", about = "Terminal introspection and control")]
+#[command(
+    name = "tap",
+    about = "Terminal introspection and control",
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
     #[command(subcommand)]
     command: Option<Command>,
+    /// Command to start a session with when no subcommand is given (`tap -- htop`).
+    #[arg(last = true)]
+    run: Vec<String>,
 }
 
 #[derive(Subcommand)]
 enum Command {
     /// Start a recording session (default when no command given).
-    Start {
-        /// Command to run (defaults to $SHELL).
-        #[arg(trailing_var_arg = true)]
+    #[command(alias = "run")]
+    Start(Box<StartArgs>),
+    /// Run a command on a PTY, print everything it output, and exit with its code.
+    RunCapture {
+        /// Print the rendered text instead of the raw output (no escapes).
+        #[arg(long)]
+        plain: bool,
+        /// Command to run.
+        #[arg(trailing_var_arg = true, required = true)]
         command: Vec<String>,
     },
     /// List all active sessions.
-    List,
+    List {
+        /// Keep redrawing the list as sessions come and go (until Ctrl-C).
+        #[arg(short, long)]
+        watch: bool,
+        /// Seconds between refreshes with --watch.
+        #[arg(long, default_value_t = 1, requires = "watch")]
+        interval: u64,
+        /// List sessions from this socket directory instead (repeatable).
+        #[arg(long = "dir", value_name = "PATH")]
+        dirs: Vec<PathBuf>,
+    },
+    /// Show a live table of every session's throughput, subscribers and
+    /// uptime (until q or Ctrl-C).
+    Top {
+        /// Seconds between refreshes.
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Remove sockets and `sessions.json` entries left by sessions that
+    /// died without cleaning up.
+    Gc {
+        /// Clean this socket directory instead of the default one.
+        #[arg(long, value_name = "PATH")]
+        dir: Option<PathBuf>,
+        /// Show what would be removed without removing it.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show metadata for a session.
+    Info {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
     /// Get scrollback buffer from a session.
     Scrollback {
         /// Session ID (uses latest if not specified).
@@ -30,6 +89,185 @@ enum Command {
         /// Number of lines to retrieve.
         #[arg(short, long)]
         lines: Option<usize>,
+        /// Leave out the in-progress line (e.g. the prompt).
+        #[arg(long)]
+        no_current: bool,
+        /// Read the primary or alternate screen instead of the one showing;
+        /// `alternate` keeps a full-screen app's last screen after it exits.
+        #[arg(long, value_parser = parse_screen_buffer)]
+        buffer: Option<ScreenBuffer>,
+    },
+    /// Get the current (incomplete) line, e.g. the prompt.
+    Line {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// List OSC 8 hyperlinks seen in the output.
+    Links {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// Print bytes that redraw the current screen in a terminal.
+    Replay {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// Save the session's scrollback and screen, with colors, as an HTML page.
+    ExportHtml {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// File to write.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Dark page background (the default).
+        #[arg(long, conflicts_with = "light")]
+        dark: bool,
+        /// Light page background.
+        #[arg(long)]
+        light: bool,
+    },
+    /// Export the current screen, with colors, as an SVG image.
+    ExportSvg {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// File to write.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Dark background (the default).
+        #[arg(long, conflicts_with = "light")]
+        dark: bool,
+        /// Light background.
+        #[arg(long)]
+        light: bool,
+        /// Font size in pixels; cells are 0.6 of it wide and 1.2 tall.
+        #[arg(long, default_value_t = 14)]
+        font_size: u32,
+    },
+    /// Save the scrollback to a file, without attaching.
+    Save {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// File to write.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Only the last N lines.
+        #[arg(short, long)]
+        lines: Option<usize>,
+        /// plain, ansi (with colors and attributes), or json.
+        #[arg(long, default_value = "plain")]
+        format: save::SaveFormat,
+    },
+    /// Save the visible screen (cells, cursor, size) as JSON, for `tap diff`.
+    Snapshot {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// File to write (stdout if not given).
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Sample the screen at a fixed rate into a directory of frames for a
+    /// GIF or video renderer. Stops when the session ends.
+    Frames {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// Directory for `frame-NNNNNN.json` files and `index.jsonl`.
+        #[arg(short, long = "frames-dir", value_name = "DIR")]
+        dir: PathBuf,
+        /// Frames per second.
+        #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..=1000))]
+        fps: u32,
+        /// Stop after this many frames.
+        #[arg(short = 'n', long)]
+        count: Option<u64>,
+    },
+    /// Compare two saved snapshots; exits 1 if they differ.
+    Diff {
+        /// The earlier snapshot.
+        a: PathBuf,
+        /// The later snapshot.
+        b: PathBuf,
+    },
+    /// Print output lines with injected input interleaved (see --echo-injections).
+    Transcript {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// Check whether a session's command is still running (exits 1 if not).
+    Health {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// Give a running session a new display name (its ID stays the same).
+    Rename {
+        /// Session ID or name (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// The new name.
+        new_name: String,
+    },
+    /// Show session counters.
+    Stats {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// Reset the terminal and scrollback to a clean state.
+    Reset {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// End a session, stopping its command.
+    Kill {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+    },
+    /// Send a signal to a session's command, e.g. `tap signal USR1`.
+    Signal {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// Signal the command's whole process group.
+        #[arg(long)]
+        group: bool,
+        /// Signal name (`SIGUSR1`, `usr1`) or number.
+        #[arg(value_parser = tap_server::parse_signal)]
+        signal: i32,
+    },
+    /// Stop the session's command (SIGSTOP) until `tap resume`.
+    Pause {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// Stop the command's whole process group.
+        #[arg(long)]
+        group: bool,
+    },
+    /// Continue a command stopped by `tap pause`.
+    Resume {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// Continue the command's whole process group.
+        #[arg(long)]
+        group: bool,
+    },
+    /// Show the environment the session's command was launched with.
+    Env {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
     },
     /// Get cursor position.
     Cursor {
@@ -50,15 +288,494 @@ enum Command {
         session: Option<String>,
         /// Text to inject.
         text: String,
+        /// Type one character at a time, pausing this many milliseconds between each.
+        #[arg(long)]
+        delay_ms: Option<u64>,
+        /// Send the text in this encoding (e.g. latin1) instead of UTF-8.
+        #[arg(long, conflicts_with = "delay_ms")]
+        encoding: Option<String>,
+    },
+    /// Run a scripted scenario against a session: a YAML or JSON list of
+    /// `inject`, `key`, `wait` and `delay_ms` steps.
+    Scenario {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// Scenario file; read as JSON if it ends in `.json`, else YAML.
+        file: PathBuf,
+    },
+    /// Watch a session live and type into it; Ctrl-] detaches.
+    Attach {
+        /// Session ID or name (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
+        /// Only watch: keystrokes other than Ctrl-] are never sent, and the
+        /// server refuses input on the connection.
+        #[arg(long)]
+        read_only: bool,
     },
     /// Subscribe to live output stream.
     Subscribe {
         /// Session ID (uses latest if not specified).
         #[arg(short, long)]
         session: Option<String>,
+        /// Only print completed lines matching this regex.
+        #[arg(long, value_name = "REGEX")]
+        grep: Option<String>,
+    },
+    /// Print cursor moves live, with timestamps (until Ctrl-C).
+    WatchCursor {
+        /// Session ID (uses latest if not specified).
+        #[arg(short, long)]
+        session: Option<String>,
     },
 }
 
+#[derive(clap::Args, Default)]
+struct StartArgs {
+    /// When to flush mirrored output: always, line, or interval:<ms>.
+    #[arg(long, default_value = "always")]
+    flush_policy: FlushPolicy,
+    /// Free-text description shown in `tap list`.
+    #[arg(long)]
+    description: Option<String>,
+    /// Pipe child output through this shell command before recording it.
+    #[arg(long)]
+    output_filter: Option<String>,
+    /// Run this shell command when the session ends (gets TAP_SESSION_ID, TAP_EXIT_CODE).
+    #[arg(long)]
+    on_exit: Option<String>,
+    /// Wait for the on-exit command to finish before exiting.
+    #[arg(long, requires = "on_exit")]
+    wait_on_exit: bool,
+    /// Set an environment variable for the command (repeatable).
+    #[arg(long = "env", value_name = "NAME=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+    /// Start the command with an empty environment (plus any --env).
+    #[arg(long)]
+    env_clear: bool,
+    /// Set TERM for the command, e.g. `xterm-256color`, so output doesn't
+    /// depend on where tap runs. Overrides `--env TERM=...`; without it the
+    /// command inherits tap's TERM.
+    #[arg(long, value_name = "TERM")]
+    term: Option<String>,
+    /// Largest client request accepted, in bytes (default 1 MiB).
+    #[arg(long, value_name = "BYTES")]
+    max_request_size: Option<usize>,
+    /// Seconds the command gets to exit after SIGTERM before it is killed (default 3).
+    #[arg(long, value_name = "SECS")]
+    term_grace: Option<u64>,
+    /// Queue up to this many bytes of input for a command that isn't reading,
+    /// instead of stalling the session.
+    #[arg(long, value_name = "BYTES")]
+    stdin_buffer: Option<usize>,
+    /// When that queue is full: block (stop reading input) or drop it.
+    #[arg(long, default_value = "block", requires = "stdin_buffer")]
+    stdin_overflow: StdinOverflow,
+    /// Record injected input in the transcript (`tap transcript`), tagged as input.
+    #[arg(long)]
+    echo_injections: bool,
+    /// Keep stray control bytes like NUL out of scrollback: drop them, or
+    /// caret (show as `^@`, `^A`, ...).
+    #[arg(long, value_name = "MODE")]
+    normalize_controls: Option<ControlNormalization>,
+    /// Start the shell as a login shell (`argv[0]` of `-bash` and so on), so
+    /// it reads profile files as in a new terminal.
+    #[arg(long)]
+    login: bool,
+    /// Pass the shell `-i` so it runs as interactive.
+    #[arg(long)]
+    interactive: bool,
+    /// Run the command on pipes instead of a terminal, e.g. for daemons and
+    /// build tools. Its stdout and stderr are captured together.
+    #[arg(long)]
+    no_pty: bool,
+    /// Don't echo the command's output here; it still reaches scrollback
+    /// and subscribers. For running headless under a supervisor.
+    #[arg(long)]
+    no_local_output: bool,
+    /// Don't read input from here; the command only gets input injected by
+    /// clients, and stdin closing doesn't end the session.
+    #[arg(long)]
+    no_local_input: bool,
+    /// If the socket directory is read-only or full, put the session in a
+    /// directory under the system temp dir instead of failing.
+    #[arg(long)]
+    socket_dir_fallback: bool,
+    /// Batch output sent to subscribers for up to this many milliseconds, for
+    /// fewer, larger frames. Local output stays immediate.
+    #[arg(long, value_name = "MS")]
+    subscriber_coalesce: Option<u64>,
+    /// Also write all output to a named pipe at this path (created if
+    /// missing), for `cat <fifo>` or any other consumer.
+    #[arg(long, value_name = "PATH")]
+    output_fifo: Option<PathBuf>,
+    /// Output while no reader has the FIFO open: drop it, or buffer up to
+    /// 1 MiB for the next reader.
+    #[arg(long, default_value = "drop", requires = "output_fifo")]
+    output_fifo_policy: FifoPolicy,
+    /// Answer the command's terminal queries (cursor position, status,
+    /// device attributes) from tap's own screen model. For headless runs;
+    /// a real terminal showing the output would answer them too.
+    #[arg(long)]
+    answer_queries: bool,
+    /// Bind the socket and list the session, but only start the command
+    /// once a client has subscribed, so it sees every byte of output.
+    #[arg(long)]
+    wait_for_client: bool,
+    /// Exit with an error if no client subscribes within this many
+    /// milliseconds.
+    #[arg(long, value_name = "MS", requires = "wait_for_client")]
+    wait_timeout: Option<u64>,
+    /// Let clients watch what is typed here (`subscribe_input`). Off by
+    /// default, since keystrokes can include passwords.
+    #[arg(long)]
+    share_input: bool,
+    /// Only let clients send this request type, e.g. `get_scrollback`
+    /// (repeatable). Anything else is refused with `permission_denied`.
+    #[arg(long, value_name = "REQUEST_TYPE", value_parser = parse_request_type)]
+    allow: Vec<String>,
+    /// Run the command at this niceness (-20 to 19).
+    #[arg(long, allow_hyphen_values = true, value_name = "N")]
+    nice: Option<i32>,
+    /// Limit the command to this many open files.
+    #[arg(long, value_name = "N")]
+    rlimit_nofile: Option<u64>,
+    /// Run the command as this user ID (requires root).
+    #[arg(long, value_name = "UID")]
+    setuid: Option<u32>,
+    /// Run the command as this group ID (requires root).
+    #[arg(long, value_name = "GID")]
+    setgid: Option<u32>,
+    /// Try to keep scrollback and subscriber queues under this many bytes,
+    /// dropping old history (then lagging subscribers' backlog) when over.
+    #[arg(long, value_name = "BYTES")]
+    memory_budget: Option<usize>,
+    /// Drop scrollback lines older than this many seconds. The 10,000-line
+    /// limit and --memory-budget still apply: a line goes as soon as any
+    /// limit says so.
+    #[arg(long, value_name = "SECS")]
+    scrollback_ttl: Option<u64>,
+    /// Allow a symlinked socket directory or sessions file (warn instead of refusing).
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Worker threads for the session's async runtime (default 2). A session
+    /// is mostly idle I/O; raise this only if hundreds of subscribers each
+    /// need a share of CPU to keep up with fast output.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    io_threads: Option<u16>,
+    /// Command to run (defaults to $SHELL).
+    #[arg(trailing_var_arg = true)]
+    command: Vec<String>,
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got `{s}`")),
+    }
+}
+
+fn parse_request_type(s: &str) -> Result<String, String> {
+    tap_protocol::request_type(s)
+        .map(str::to_string)
+        .ok_or_else(|| {
+            format!(
+                "unknown request type `{s}` (known: {})",
+                tap_protocol::REQUEST_TYPES.join(", ")
+            )
+        })
+}
+
+fn parse_screen_buffer(s: &str) -> Result<ScreenBuffer, String> {
+    match s {
+        "primary" => Ok(ScreenBuffer::Primary),
+        "alternate" => Ok(ScreenBuffer::Alternate),
+        _ => Err(format!("expected primary or alternate, got `{s}`")),
+    }
+}
+
+/// Width of the description column in `list` before truncating.
+const DESCRIPTION_WIDTH: usize = 30;
+
+/// Truncate to `max` characters, marking the cut with an ellipsis.
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        s.to_string()
+    } else {
+        let mut out: String = s.chars().take(max.saturating_sub(1)).collect();
+        out.push('…');
+        out
+    }
+}
+
+fn format_sessions(sessions: &[Session]) -> String {
+    let mut out = format!(
+        "{:<25} {:<16} {:<8} {:<25} {:<DESCRIPTION_WIDTH$} COMMAND\n",
+        "ID", "NAME", "PID", "STARTED", "DESCRIPTION"
+    );
+    for session in sessions {
+        let description = truncate(
+            session.description.as_deref().unwrap_or(""),
+            DESCRIPTION_WIDTH,
+        );
+        out.push_str(&format!(
+            "{:<25} {:<16} {:<8} {:<25} {:<DESCRIPTION_WIDTH$} {}\n",
+            session.id,
+            session.name.as_deref().unwrap_or("-"),
+            session.pid,
+            session.started,
+            description,
+            session.command.join(" ")
+        ));
+    }
+    out
+}
+
+fn render_list(sessions: &[Session]) -> String {
+    if sessions.is_empty() {
+        "No active sessions\n".to_string()
+    } else {
+        format_sessions(sessions)
+    }
+}
+
+/// What `gc` cleaned, one line per socket or entry.
+fn render_gc(report: &tap_server::GcReport, dry_run: bool) -> String {
+    let verb = if dry_run { "would remove" } else { "removed" };
+    let mut out = String::new();
+    for socket in &report.sockets {
+        out.push_str(&format!("{verb} socket {}\n", socket.display()));
+    }
+    for id in &report.entries {
+        out.push_str(&format!("{verb} sessions.json entry {id}\n"));
+    }
+    if out.is_empty() {
+        out = "Nothing to clean\n".to_string();
+    }
+    out
+}
+
+/// `list` output for several socket directories, one table per directory.
+/// Directories that can't be read are skipped with a warning.
+fn render_list_dirs(dirs: &[PathBuf]) -> String {
+    let mut out = String::new();
+    for dir in dirs {
+        match list_sessions_in(dir) {
+            Ok(sessions) => {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(&format!("{}:\n{}", dir.display(), render_list(&sessions)));
+            }
+            Err(e) => eprintln!("warning: skipping {}: {e}", dir.display()),
+        }
+    }
+    if out.is_empty() {
+        out = render_list(&[]);
+    }
+    out
+}
+
+/// The `list` table: the default socket directory, or each of `dirs`.
+fn list_table(dirs: &[PathBuf]) -> eyre::Result<String> {
+    if dirs.is_empty() {
+        Ok(render_list(&list_sessions()?))
+    } else {
+        Ok(render_list_dirs(dirs))
+    }
+}
+
+/// Redraws for `list --watch`, skipping refreshes where nothing changed.
+#[derive(Default)]
+struct ListWatcher {
+    last: Option<String>,
+}
+
+impl ListWatcher {
+    /// The frame to draw, if `table` differs from the last one drawn.
+    fn update(&mut self, table: String) -> Option<String> {
+        if self.last.as_ref() == Some(&table) {
+            return None;
+        }
+        self.last = Some(table.clone());
+        // Overwrite in place (clearing each line's tail and anything below)
+        // in one write, so the redraw doesn't flicker
+        let table = table.replace('\n', "\x1b[K\n");
+        Some(format!("\x1b[H{table}\x1b[J"))
+    }
+}
+
+async fn watch_sessions(interval: std::time::Duration, dirs: &[PathBuf]) -> eyre::Result<()> {
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(b"\x1b[2J").await?;
+    let mut watcher = ListWatcher::default();
+    loop {
+        if let Some(frame) = watcher.update(list_table(dirs)?) {
+            stdout.write_all(frame.as_bytes()).await?;
+            stdout.flush().await?;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// One session's byte counters at one poll, for `top`'s rates.
+#[derive(Debug, Clone, Copy)]
+struct StatsSample {
+    at: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl StatsSample {
+    /// Bytes per second in and out since `earlier`.
+    fn rates_since(&self, earlier: &StatsSample) -> (f64, f64) {
+        let secs = self.at.duration_since(earlier.at).as_secs_f64();
+        if secs <= 0.0 {
+            return (0.0, 0.0);
+        }
+        // A counter going backwards means the ID now names another session
+        let rate = |now: u64, then: u64| now.saturating_sub(then) as f64 / secs;
+        (
+            rate(self.bytes_in, earlier.bytes_in),
+            rate(self.bytes_out, earlier.bytes_out),
+        )
+    }
+}
+
+/// A row of the `top` table.
+struct TopRow {
+    id: String,
+    name: Option<String>,
+    child_alive: bool,
+    uptime_secs: u64,
+    subscribers: u64,
+    /// Bytes per second in and out, once there are two polls to compare.
+    rates: Option<(f64, f64)>,
+}
+
+/// `top`'s memory between refreshes: each session's last sample.
+#[derive(Default)]
+struct Top {
+    samples: HashMap<String, StatsSample>,
+}
+
+impl Top {
+    /// Poll every listed session. Sessions that can't be reached (exiting
+    /// as we poll) are left out, and those no longer listed are forgotten.
+    async fn poll(&mut self) -> eyre::Result<Vec<TopRow>> {
+        let mut samples = HashMap::new();
+        let mut rows = Vec::new();
+        for session in list_sessions()? {
+            let Ok(mut client) = Client::connect(&session.id).await else {
+                continue;
+            };
+            let Ok(stats) = client.get_stats().await else {
+                continue;
+            };
+            let Ok((child_alive, _, uptime_secs, _)) = client.health().await else {
+                continue;
+            };
+            let sample = StatsSample {
+                at: Instant::now(),
+                bytes_in: stats.bytes_in,
+                bytes_out: stats.bytes_out,
+            };
+            let rates = self
+                .samples
+                .get(&session.id)
+                .map(|earlier| sample.rates_since(earlier));
+            samples.insert(session.id.clone(), sample);
+            rows.push(TopRow {
+                id: session.id,
+                name: session.name,
+                child_alive,
+                uptime_secs,
+                subscribers: stats.subscribers,
+                rates,
+            });
+        }
+        self.samples = samples;
+        Ok(rows)
+    }
+}
+
+/// A byte rate in B/s, KiB/s or MiB/s.
+fn format_rate(bytes_per_sec: f64) -> String {
+    if bytes_per_sec < 1024.0 {
+        format!("{bytes_per_sec:.0}B/s")
+    } else if bytes_per_sec < 1024.0 * 1024.0 {
+        format!("{:.1}K/s", bytes_per_sec / 1024.0)
+    } else {
+        format!("{:.1}M/s", bytes_per_sec / (1024.0 * 1024.0))
+    }
+}
+
+fn format_uptime(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn render_top(rows: &[TopRow]) -> String {
+    if rows.is_empty() {
+        return "No active sessions\n".to_string();
+    }
+    let mut out = format!(
+        "{:<25} {:<16} {:<5} {:>9} {:>4} {:>10} {:>10}\n",
+        "ID", "NAME", "ALIVE", "UPTIME", "SUBS", "IN", "OUT"
+    );
+    for row in rows {
+        let (rate_in, rate_out) = match row.rates {
+            Some((rate_in, rate_out)) => (format_rate(rate_in), format_rate(rate_out)),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        out.push_str(&format!(
+            "{:<25} {:<16} {:<5} {:>9} {:>4} {:>10} {:>10}\n",
+            row.id,
+            row.name.as_deref().unwrap_or("-"),
+            if row.child_alive { "yes" } else { "no" },
+            format_uptime(row.uptime_secs),
+            row.subscribers,
+            rate_in,
+            rate_out
+        ));
+    }
+    out
+}
+
+/// Redraw the `top` table every `interval` until q, Ctrl-C or Escape is
+/// pressed. Expects the terminal in raw mode.
+async fn top(interval: Duration) -> eyre::Result<()> {
+    let mut stdout = tokio::io::stdout();
+    let mut stdin = tokio::io::stdin();
+    let mut stdin_open = true;
+    let mut keys = [0u8; 64];
+    let mut top = Top::default();
+    stdout.write_all(b"\x1b[2J").await?;
+    loop {
+        // Raw mode: each line needs its own carriage return
+        let table = render_top(&top.poll().await?).replace('\n', "\x1b[K\r\n");
+        stdout
+            .write_all(format!("\x1b[H{table}\x1b[J").as_bytes())
+            .await?;
+        stdout.flush().await?;
+
+        let refresh = tokio::time::sleep(interval);
+        tokio::pin!(refresh);
+        loop {
+            tokio::select! {
+                _ = &mut refresh => break,
+                result = stdin.read(&mut keys), if stdin_open => {
+                    let n = result?;
+                    stdin_open = n > 0;
+                    if keys[..n].iter().any(|b| matches!(b, b'q' | b'Q' | 0x03 | 0x1b)) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
 async fn get_client(session: Option<String>) -> eyre::Result<Client> {
     match session {
         Some(id) => Client::connect(&id).await.map_err(Into::into),
@@ -66,17 +783,147 @@ async fn get_client(session: Option<String>) -> eyre::Result<Client> {
     }
 }
 
-async fn run_start(command: Vec<String>) -> eyre::Result<()> {
+fn format_cursor(row: usize, col: usize, visible: bool) -> String {
+    let visibility = if visible { "visible" } else { "hidden" };
+    format!("row={row} col={col} {visibility}")
+}
+
+/// Ends `tap attach` (Ctrl-]).
+const DETACH_KEY: u8 = 0x1d;
+
+/// Mirror the session seen by `viewer` to `out`, and send `keys` to it
+/// through `controller`, until the detach key, the end of `keys`, or the end
+/// of the session. Without a controller, keys other than the detach key are
+/// dropped.
+async fn attach<W>(
+    mut viewer: Client,
+    mut controller: Option<Client>,
+    mut keys: impl AsyncRead + Unpin,
+    mut out: W,
+) -> eyre::Result<()>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    out.write_all(&viewer.get_screen_replay().await?).await?;
+    out.flush().await?;
+    viewer.subscribe().await?;
+    // Output gets its own task and connection, so reading it is never
+    // interrupted by a keystroke
+    let mut mirror = tokio::spawn(async move {
+        while let Some(data) = viewer.read_output().await? {
+            out.write_all(&data).await?;
+            out.flush().await?;
+        }
+        eyre::Ok(())
+    });
+
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            result = keys.read(&mut buf) => {
+                let n = result?;
+                let pressed = &buf[..n];
+                let detach = pressed.iter().position(|&b| b == DETACH_KEY);
+                let typed = &pressed[..detach.unwrap_or(n)];
+                if let Some(controller) = &mut controller
+                    && !typed.is_empty()
+                {
+                    controller.inject(&String::from_utf8_lossy(typed)).await?;
+                }
+                if n == 0 || detach.is_some() {
+                    break;
+                }
+            }
+            result = &mut mirror => return result?,
+        }
+    }
+    mirror.abort();
+    Ok(())
+}
+
+/// Print each cursor change as `<timestamp> row=<r> col=<c> visible|hidden`
+/// until the session closes.
+async fn watch_cursor(
+    client: &mut Client,
+    out: &mut (impl tokio::io::AsyncWrite + Unpin),
+) -> eyre::Result<()> {
+    client.subscribe_cursor().await?;
+    while let Some((row, col, visible)) = client.read_cursor().await? {
+        let timestamp = chrono::Local::now().format("%H:%M:%S%.3f");
+        let line = format!("{timestamp} {}\n", format_cursor(row, col, visible));
+        out.write_all(line.as_bytes()).await?;
+        out.flush().await?;
+    }
+    Ok(())
+}
+
+async fn run_start(args: StartArgs) -> eyre::Result<()> {
     let config = ServerConfig {
-        command,
+        command: args.command,
+        login: args.login,
+        interactive: args.interactive,
         session_id: None,
+        flush_policy: args.flush_policy,
+        follow_symlinks: args.follow_symlinks,
+        description: args.description,
+        output_filter: args.output_filter,
+        on_exit: args.on_exit,
+        wait_on_exit: args.wait_on_exit,
+        env: args.env,
+        env_clear: args.env_clear,
+        term: args.term,
+        max_request_size: args.max_request_size,
+        term_grace: args.term_grace.map(std::time::Duration::from_secs),
+        stdin_buffer: args.stdin_buffer,
+        stdin_overflow: args.stdin_overflow,
+        echo_injections: args.echo_injections,
+        normalize_controls: args.normalize_controls,
+        no_pty: args.no_pty,
+        no_local_output: args.no_local_output,
+        no_local_input: args.no_local_input,
+        socket_dir_fallback: args.socket_dir_fallback,
+        subscriber_coalesce: args
+            .subscriber_coalesce
+            .map(std::time::Duration::from_millis),
+        limits: ChildLimits {
+            nice: args.nice,
+            max_open_files: args.rlimit_nofile,
+            uid: args.setuid,
+            gid: args.setgid,
+        },
+        output_fifo: args.output_fifo,
+        fifo_policy: args.output_fifo_policy,
+        answer_queries: args.answer_queries,
+        wait_for_client: args.wait_for_client,
+        wait_timeout: args.wait_timeout.map(std::time::Duration::from_millis),
+        share_input: args.share_input,
+        allow: args.allow,
+        memory_budget: args.memory_budget,
+        scrollback_ttl: args.scrollback_ttl.map(Duration::from_secs),
     };
     let exit_code = tap_server::run(config).await?;
     std::process::exit(exit_code);
 }
 
-#[tokio::main]
-async fn main() -> eyre::Result<()> {
+/// Runtime worker threads for `tap start` without `--io-threads`.
+const DEFAULT_IO_THREADS: usize = 2;
+
+/// The async runtime for `command`: a few workers for a session, whose work
+/// is mostly waiting on I/O, and a single thread for client commands. The
+/// default of a worker per core adds up with many sessions on one machine.
+fn build_runtime(command: &Command) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = match command {
+        Command::Start(args) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(args.io_threads.map_or(DEFAULT_IO_THREADS, usize::from));
+            builder
+        }
+        _ => tokio::runtime::Builder::new_current_thread(),
+    };
+    builder.enable_all().build()
+}
+
+fn main() -> eyre::Result<()> {
     color_eyre::install()?;
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
@@ -85,34 +932,262 @@ async fn main() -> eyre::Result<()> {
     let args = Args::parse();
 
     // Default to Start if no command given
-    let command = args.command.unwrap_or(Command::Start { command: vec![] });
+    let command = args.command.unwrap_or_else(|| {
+        Command::Start(Box::new(StartArgs {
+            command: args.run,
+            ..StartArgs::default()
+        }))
+    });
 
+    build_runtime(&command)?.block_on(run(command))
+}
+
+async fn run(command: Command) -> eyre::Result<()> {
     match command {
-        Command::Start { command } => {
-            run_start(command).await?;
+        Command::Start(args) => {
+            run_start(*args).await?;
         }
-        Command::List => {
-            let sessions = list_sessions()?;
-            if sessions.is_empty() {
-                println!("No active sessions");
-            } else {
-                println!("{:<25} {:<8} {:<25} COMMAND", "ID", "PID", "STARTED");
-                for session in sessions {
-                    println!(
-                        "{:<25} {:<8} {:<25} {}",
-                        session.id,
-                        session.pid,
-                        session.started,
-                        session.command.join(" ")
-                    );
+        Command::RunCapture { plain, command } => {
+            let (output, code) = tap_server::capture(&command, plain)?;
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(&output).await?;
+            stdout.flush().await?;
+            std::process::exit(code);
+        }
+        Command::List {
+            watch,
+            interval,
+            dirs,
+        } => {
+            if watch {
+                let interval = std::time::Duration::from_secs(interval);
+                tokio::select! {
+                    result = watch_sessions(interval, &dirs) => result?,
+                    _ = tokio::signal::ctrl_c() => {}
                 }
+            } else {
+                print!("{}", list_table(&dirs)?);
+            }
+        }
+        Command::Top { interval } => {
+            crossterm::terminal::enable_raw_mode()?;
+            let result = top(Duration::from_secs(interval)).await;
+            let _ = crossterm::terminal::disable_raw_mode();
+            result?;
+        }
+        Command::Gc { dir, dry_run } => {
+            let dir = dir.unwrap_or_else(tap_client::socket_dir);
+            let report = tap_server::gc(&dir, dry_run)?;
+            print!("{}", render_gc(&report, dry_run));
+        }
+        Command::Info { session } => {
+            let mut client = get_client(session).await?;
+            let tap_client::SessionInfo {
+                session: info,
+                paused,
+            } = client.get_session_info().await?;
+            println!("id: {}", info.id);
+            if let Some(short_id) = info.short_id {
+                println!("short id: {short_id}");
+            }
+            println!("pid: {}", info.pid);
+            println!("started: {}", info.started);
+            println!("command: {}", info.command.join(" "));
+            if let Some(description) = info.description {
+                println!("description: {description}");
+            }
+            if paused {
+                println!("paused: yes");
             }
         }
-        Command::Scrollback { session, lines } => {
+        Command::Scrollback {
+            session,
+            lines,
+            no_current,
+            buffer,
+        } => {
             let mut client = get_client(session).await?;
-            let content = client.get_scrollback(lines).await?;
+            let content = match buffer {
+                Some(buffer) => client.get_screen_buffer(buffer, lines, !no_current).await?,
+                None => client.get_scrollback_lines(lines, !no_current).await?,
+            };
             print!("{content}");
         }
+        Command::Line { session } => {
+            let mut client = get_client(session).await?;
+            let content = client.get_current_line().await?;
+            println!("{content}");
+        }
+        Command::Links { session } => {
+            let mut client = get_client(session).await?;
+            for (text, uri) in client.get_links().await? {
+                println!("{text}\t{uri}");
+            }
+        }
+        Command::Replay { session } => {
+            let mut client = get_client(session).await?;
+            let data = client.get_screen_replay().await?;
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(&data).await?;
+            stdout.flush().await?;
+        }
+        Command::ExportHtml {
+            session,
+            output,
+            dark: _,
+            light,
+        } => {
+            let mut client = get_client(session).await?;
+            let title = format!(
+                "tap session {}",
+                client.get_session_info().await?.session.id
+            );
+            let theme = if light {
+                html::Theme::Light
+            } else {
+                html::Theme::Dark
+            };
+            let mut export = html::HtmlExport::new(theme);
+            let mut start = 0;
+            loop {
+                let (rows, total) = client.get_full_grid(start, None).await?;
+                export.push_rows(&rows);
+                start += rows.len();
+                if rows.is_empty() || start >= total {
+                    break;
+                }
+            }
+            std::fs::write(&output, export.finish(&title))?;
+        }
+        Command::ExportSvg {
+            session,
+            output,
+            dark: _,
+            light,
+            font_size,
+        } => {
+            let mut client = get_client(session).await?;
+            let theme = if light {
+                html::Theme::Light
+            } else {
+                html::Theme::Dark
+            };
+            let snapshot = client.snapshot().await?;
+            std::fs::write(&output, svg::render(&snapshot, theme, font_size))?;
+        }
+        Command::Save {
+            session,
+            output,
+            lines,
+            format,
+        } => {
+            let mut client = get_client(session).await?;
+            std::fs::write(&output, save::render(&mut client, format, lines).await?)?;
+        }
+        Command::Snapshot { session, output } => {
+            let mut client = get_client(session).await?;
+            let json = serde_json::to_string_pretty(&client.snapshot().await?)?;
+            match output {
+                Some(path) => std::fs::write(path, json + "\n")?,
+                None => println!("{json}"),
+            }
+        }
+        Command::Frames {
+            session,
+            dir,
+            fps,
+            count,
+        } => {
+            let mut client = get_client(session).await?;
+            let mut writer = frames::FrameWriter::create(&dir)?;
+            let interval = std::time::Duration::from_secs(1) / fps;
+            let taken = frames::sample(&mut client, &mut writer, interval, count).await?;
+            eprintln!("{taken} frames in {}", dir.display());
+        }
+        Command::Diff { a, b } => {
+            let load = |path: &PathBuf| -> eyre::Result<tap_client::Snapshot> {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| eyre::eyre!("{}: {e}", path.display()))?;
+                serde_json::from_str(&content).map_err(|e| eyre::eyre!("{}: {e}", path.display()))
+            };
+            if let Some(changes) = diff::diff(&load(&a)?, &load(&b)?) {
+                print!("{changes}");
+                std::process::exit(1);
+            }
+        }
+        Command::Transcript { session } => {
+            let mut client = get_client(session).await?;
+            for entry in client.get_transcript().await? {
+                match entry {
+                    TranscriptEntry::Output { text } => println!("{text}"),
+                    TranscriptEntry::Input { text } => println!("> {}", text.escape_debug()),
+                }
+            }
+        }
+        Command::Health { session } => {
+            let mut client = get_client(session).await?;
+            let (alive, pid, uptime, paused) = client.health().await?;
+            let state = match (alive, paused) {
+                (false, _) => "exited",
+                (true, true) => "paused",
+                (true, false) => "alive",
+            };
+            println!("{state} pid={pid} uptime={uptime}s");
+            if !alive {
+                std::process::exit(1);
+            }
+        }
+        Command::Rename { session, new_name } => {
+            let mut client = get_client(session).await?;
+            client.rename(&new_name).await?;
+        }
+        Command::Stats { session } => {
+            let mut client = get_client(session).await?;
+            let stats = client.get_stats().await?;
+            println!("bytes_out: {}", stats.bytes_out);
+            println!("bytes_in: {}", stats.bytes_in);
+            println!(
+                "dropped_scrollback_bytes: {}",
+                stats.dropped_scrollback_bytes
+            );
+            println!("slow_pushes: {}", stats.slow_pushes);
+            println!("lines_discarded: {}", stats.lines_discarded);
+            println!("dropped_fifo_bytes: {}", stats.dropped_fifo_bytes);
+            println!("shed_subscriber_bytes: {}", stats.shed_subscriber_bytes);
+            println!("memory_bytes: {}", stats.memory_bytes);
+            println!("subscribers: {}", stats.subscribers);
+        }
+        Command::Reset { session } => {
+            let mut client = get_client(session).await?;
+            client.reset_terminal().await?;
+            println!("Reset");
+        }
+        Command::Kill { session } => {
+            let mut client = get_client(session).await?;
+            client.terminate().await?;
+        }
+        Command::Signal {
+            session,
+            group,
+            signal,
+        } => {
+            let mut client = get_client(session).await?;
+            client.signal(signal, group).await?;
+        }
+        Command::Pause { session, group } => {
+            let mut client = get_client(session).await?;
+            client.pause(group).await?;
+        }
+        Command::Resume { session, group } => {
+            let mut client = get_client(session).await?;
+            client.resume(group).await?;
+        }
+        Command::Env { session } => {
+            let mut client = get_client(session).await?;
+            for (name, value) in client.get_child_env().await? {
+                println!("{name}={value}");
+            }
+        }
         Command::Cursor { session } => {
             let mut client = get_client(session).await?;
             let (row, col) = client.get_cursor().await?;
@@ -123,21 +1198,368 @@ async fn main() -> eyre::Result<()> {
             let (rows, cols) = client.get_size().await?;
             println!("{rows}x{cols}");
         }
-        Command::Inject { session, text } => {
+        Command::Inject {
+            session,
+            text,
+            delay_ms,
+            encoding,
+        } => {
             let mut client = get_client(session).await?;
-            client.inject(&text).await?;
+            match delay_ms {
+                Some(ms) => {
+                    client
+                        .inject_typed(&text, std::time::Duration::from_millis(ms))
+                        .await?;
+                }
+                None => client.inject_encoded(&text, encoding.as_deref()).await?,
+            }
             println!("Injected");
         }
-        Command::Subscribe { session } => {
+        Command::Scenario { session, file } => {
+            let scenario = scenario::Scenario::load(&file)?;
+            let mut control = get_client(session).await?;
+            // Same session even if a newer one started meanwhile
+            let id = control.get_session_info().await?.session.id;
+            let mut output = Client::connect(&id).await?;
+            output.subscribe().await?;
+            scenario.run(&mut control, &mut output).await?;
+            println!("Scenario passed ({} steps)", scenario.step_count());
+        }
+        Command::Attach { session, read_only } => {
+            let mut viewer = get_client(session).await?;
+            let controller = if read_only {
+                viewer.set_read_only().await?;
+                None
+            } else {
+                // Same session even if a newer one started meanwhile
+                let id = viewer.get_session_info().await?.session.id;
+                Some(Client::connect(&id).await?)
+            };
+            crossterm::terminal::enable_raw_mode()?;
+            let result = attach(viewer, controller, tokio::io::stdin(), tokio::io::stdout()).await;
+            let _ = crossterm::terminal::disable_raw_mode();
+            eprintln!("\r\n[detached]");
+            result?;
+        }
+        Command::Subscribe { session, grep } => {
             let mut client = get_client(session).await?;
-            client.subscribe().await?;
+            match grep {
+                Some(pattern) => client.subscribe_filtered(&pattern).await?,
+                None => client.subscribe().await?,
+            }
             let mut stdout = tokio::io::stdout();
             while let Some(data) = client.read_output().await? {
                 stdout.write_all(&data).await?;
                 stdout.flush().await?;
             }
         }
+        Command::WatchCursor { session } => {
+            let mut client = get_client(session).await?;
+            let mut stdout = tokio::io::stdout();
+            tokio::select! {
+                result = watch_cursor(&mut client, &mut stdout) => result?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(description: Option<&str>) -> Session {
+        Session {
+            id: "blue-moon-fire".to_string(),
+            pid: 42,
+            started: "2024-01-01T00:00:00+00:00".to_string(),
+            command: vec!["make".to_string(), "test".to_string()],
+            description: description.map(str::to_string),
+            name: None,
+            short_id: None,
+        }
+    }
+
+    #[test]
+    fn test_list_shows_description() {
+        let table = format_sessions(&[session(Some("nightly build run #42"))]);
+        assert!(table.contains("DESCRIPTION"));
+        assert!(table.contains("nightly build run #42"));
+        assert!(table.contains("make test"));
+    }
+
+    #[test]
+    fn test_list_shows_name_after_rename() {
+        let tmp = tempfile::tempdir().unwrap();
+        let index =
+            r#"[{"id":"blue-moon-fire","pid":42,"started":"","command":[],"name":"nightly"}]"#;
+        std::fs::write(tmp.path().join("sessions.json"), index).unwrap();
+        std::fs::write(tmp.path().join("blue-moon-fire.sock"), "").unwrap();
+
+        let table = render_list(&list_sessions_in(tmp.path()).unwrap());
+        assert!(table.contains("NAME"));
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.starts_with("blue-moon-fire"), "{row}");
+        assert!(row.contains("nightly"), "{row}");
+    }
+
+    #[test]
+    fn test_list_truncates_long_description() {
+        let long = "a".repeat(100);
+        let table = format_sessions(&[session(Some(&long))]);
+        assert!(table.contains(&format!("{}…", "a".repeat(DESCRIPTION_WIDTH - 1))));
+        assert!(!table.contains(&long));
+    }
+
+    /// Attach to a mock session, type `ls`, then detach.
+    async fn attach_and_type(read_only: bool) -> Vec<String> {
+        let server = tap_client::testing::MockSession::new()
+            .output("$ ")
+            .spawn()
+            .unwrap();
+        let mut viewer = server.connect().await.unwrap();
+        let controller = if read_only {
+            viewer.set_read_only().await.unwrap();
+            None
+        } else {
+            Some(server.connect().await.unwrap())
+        };
+        let keys: &[u8] = b"ls\r\x1d";
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            attach(viewer, controller, keys, tokio::io::sink()),
+        )
+        .await
+        .expect("attach did not detach")
+        .unwrap();
+        server.injected()
+    }
+
+    #[tokio::test]
+    async fn test_attach_forwards_keystrokes() {
+        assert_eq!(attach_and_type(false).await, ["ls\r"]);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_attach_sends_no_keystrokes() {
+        assert!(attach_and_type(true).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watch_cursor_prints_moves() {
+        let server = tap_client::testing::MockSession::new()
+            .cursor_move(0, 0, true)
+            .cursor_move(0, 2, true)
+            .cursor_move(5, 10, false)
+            .spawn()
+            .unwrap();
+        let mut client = server.connect().await.unwrap();
+
+        let mut out = Vec::new();
+        // The mock keeps the connection open, so stop once the script is read
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            watch_cursor(&mut client, &mut out),
+        )
+        .await;
+
+        let out = String::from_utf8(out).unwrap();
+        let moves: Vec<&str> = out
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(
+            moves,
+            [
+                "row=0 col=0 visible",
+                "row=0 col=2 visible",
+                "row=5 col=10 hidden"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_env_var() {
+        assert_eq!(
+            parse_env_var("FOO=a=b").unwrap(),
+            ("FOO".to_string(), "a=b".to_string())
+        );
+        assert_eq!(
+            parse_env_var("EMPTY=").unwrap(),
+            ("EMPTY".to_string(), String::new())
+        );
+        assert!(parse_env_var("FOO").is_err());
+        assert!(parse_env_var("=x").is_err());
+    }
+
+    #[test]
+    fn test_start_env_args() {
+        let args = Args::parse_from(["tap", "start", "--env", "A=1", "--env-clear", "--", "sh"]);
+        let Some(Command::Start(start)) = args.command else {
+            panic!("expected start");
+        };
+        assert_eq!(start.env, vec![("A".to_string(), "1".to_string())]);
+        assert!(start.env_clear);
+        assert_eq!(start.command, vec!["sh".to_string()]);
+    }
+
+    #[test]
+    fn test_signal_args() {
+        let args = Args::parse_from(["tap", "signal", "-s", "dev", "--group", "term"]);
+        let Some(Command::Signal {
+            session,
+            group,
+            signal,
+        }) = args.command
+        else {
+            panic!("expected signal");
+        };
+        assert_eq!(session.as_deref(), Some("dev"));
+        assert!(group);
+        assert_eq!(signal, 15);
+        assert!(Args::try_parse_from(["tap", "signal", "NOPE"]).is_err());
+    }
+
+    #[test]
+    fn test_bare_args_start_session() {
+        let args = Args::parse_from(["tap", "--", "htop", "-d", "10"]);
+        assert!(args.command.is_none());
+        assert_eq!(args.run, ["htop", "-d", "10"]);
+    }
+
+    #[test]
+    fn test_run_alias_starts_session() {
+        let args = Args::parse_from(["tap", "run", "--", "htop"]);
+        let Some(Command::Start(start)) = args.command else {
+            panic!("expected start");
+        };
+        assert_eq!(start.command, ["htop"]);
+    }
+
+    #[test]
+    fn test_io_threads_runtime() {
+        let args = Args::parse_from(["tap", "start", "--io-threads", "1", "--", "sh"]);
+        let command = args.command.unwrap();
+        assert!(matches!(
+            &command,
+            Command::Start(start) if start.io_threads == Some(1)
+        ));
+        let runtime = build_runtime(&command).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 1);
+        assert_eq!(runtime.block_on(async { 7 }), 7);
+
+        let default = Command::Start(Box::default());
+        let runtime = build_runtime(&default).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), DEFAULT_IO_THREADS);
+
+        assert!(Args::try_parse_from(["tap", "start", "--io-threads", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_client_subcommands() {
+        let args = Args::parse_from(["tap", "scrollback", "-s", "blue-moon", "-l", "5"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Scrollback { session: Some(ref s), lines: Some(5), .. }) if s == "blue-moon"
+        ));
+
+        let args = Args::parse_from(["tap", "inject", "ls\n"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Inject { session: None, ref text, delay_ms: None, .. }) if text == "ls\n"
+        ));
+    }
+
+    #[test]
+    fn test_top_rates_between_polls() {
+        let start = Instant::now();
+        let first = StatsSample {
+            at: start,
+            bytes_in: 100,
+            bytes_out: 1_000,
+        };
+        let second = StatsSample {
+            at: start + Duration::from_millis(500),
+            bytes_in: 150,
+            bytes_out: 4_096,
+        };
+        assert_eq!(second.rates_since(&first), (100.0, 6192.0));
+        // Same instant, or a reused ID whose counters restarted
+        assert_eq!(first.rates_since(&first), (0.0, 0.0));
+        assert_eq!(
+            first.rates_since(&StatsSample {
+                at: start - Duration::from_secs(1),
+                ..second
+            }),
+            (0.0, 0.0)
+        );
+
+        let table = render_top(&[TopRow {
+            id: "blue-moon-fire".to_string(),
+            name: None,
+            child_alive: true,
+            uptime_secs: 3_725,
+            subscribers: 2,
+            rates: Some(second.rates_since(&first)),
+        }]);
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.starts_with("blue-moon-fire"), "{table}");
+        assert!(row.contains(" 1:02:05 "), "{table}");
+        assert!(row.ends_with("100B/s     6.0K/s"), "{table}");
+    }
+
+    #[test]
+    fn test_list_watcher_redraws_on_change() {
+        let mut watcher = ListWatcher::default();
+        let first = watcher.update(render_list(&[])).unwrap();
+        assert!(first.contains("No active sessions"));
+        assert_eq!(watcher.update(render_list(&[])), None);
+
+        // A session appears
+        let appeared = watcher.update(render_list(&[session(None)])).unwrap();
+        assert!(appeared.contains("blue-moon-fire"));
+        assert!(appeared.starts_with("\x1b[H"));
+        assert_eq!(watcher.update(render_list(&[session(None)])), None);
+
+        // And goes away again
+        let gone = watcher.update(render_list(&[])).unwrap();
+        assert!(gone.contains("No active sessions"));
+        assert!(!gone.contains("blue-moon-fire"));
+    }
+
+    /// A socket directory holding `id`'s index entry and socket file.
+    fn seed_socket_dir(id: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let index = format!(
+            r#"[{{"id":"{id}","pid":42,"started":"2024-01-01T00:00:00+00:00","command":["sh"]}}]"#
+        );
+        std::fs::write(dir.path().join("sessions.json"), index).unwrap();
+        std::fs::write(dir.path().join(format!("{id}.sock")), "").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_list_across_dirs() {
+        let a = seed_socket_dir("red-fox-run");
+        let b = seed_socket_dir("blue-owl-sing");
+        // Not a directory, so it can't be read
+        let bad = a.path().join("sessions.json").join("nope");
+
+        let table = render_list_dirs(&[a.path().to_path_buf(), bad, b.path().to_path_buf()]);
+        let a_at = table.find(&format!("{}:\n", a.path().display())).unwrap();
+        let b_at = table.find(&format!("{}:\n", b.path().display())).unwrap();
+        let red = table.find("red-fox-run").unwrap();
+        let blue = table.find("blue-owl-sing").unwrap();
+        assert!(a_at < red && red < b_at && b_at < blue);
+        assert!(!table.contains("nope"));
+    }
+
+    #[test]
+    fn test_truncate_short_unchanged() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly10!", 10), "exactly10!");
+        assert_eq!(truncate("elevenchars", 10), "elevencha…");
+    }
+}
@@ -0,0 +1,228 @@
+//! On-demand captures of a session's scrollback to a file (`tap save`).
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use serde_json::json;
+use tap_client::{Cell, Client, Color};
+
+/// How `tap save` writes the scrollback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaveFormat {
+    /// Text only.
+    #[default]
+    Plain,
+    /// Text with SGR escapes for colors and attributes, for `less -R` or `cat`.
+    Ansi,
+    /// `{"session": ..., "lines": [...]}`.
+    Json,
+}
+
+impl FromStr for SaveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "ansi" => Ok(Self::Ansi),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected plain, ansi, or json, got `{s}`")),
+        }
+    }
+}
+
+/// The session's scrollback (the last `lines` lines, or all of it) in
+/// `format`, ready to write to a file.
+pub async fn render(
+    client: &mut Client,
+    format: SaveFormat,
+    lines: Option<usize>,
+) -> eyre::Result<String> {
+    Ok(match format {
+        SaveFormat::Plain => terminated(client.get_scrollback(lines).await?),
+        SaveFormat::Ansi => {
+            let rows = full_grid(client).await?;
+            let rows = trim_blank_rows(&rows);
+            let start = lines.map_or(0, |n| rows.len().saturating_sub(n));
+            rows[start..].iter().map(|row| ansi_row(row)).collect()
+        }
+        SaveFormat::Json => {
+            let id = client.get_session_info().await?.session.id;
+            let content = client.get_scrollback(lines).await?;
+            let lines: Vec<&str> = content.lines().collect();
+            let value = json!({ "session": id, "lines": lines });
+            serde_json::to_string_pretty(&value)? + "\n"
+        }
+    })
+}
+
+/// `text` with a final newline, unless it's empty.
+fn terminated(mut text: String) -> String {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+/// Every row of the grid, fetched a page at a time.
+async fn full_grid(client: &mut Client) -> eyre::Result<Vec<Vec<Cell>>> {
+    let mut grid = Vec::new();
+    loop {
+        let (rows, total) = client.get_full_grid(grid.len(), None).await?;
+        if rows.is_empty() {
+            break;
+        }
+        grid.extend(rows);
+        if grid.len() >= total {
+            break;
+        }
+    }
+    Ok(grid)
+}
+
+/// Whether a cell shows nothing: blank text and no background or inverse.
+fn is_blank(cell: &Cell) -> bool {
+    cell.text.trim().is_empty() && cell.bg.is_none() && !cell.inverse
+}
+
+/// `rows` without the blank rows below the last line of output.
+fn trim_blank_rows(rows: &[Vec<Cell>]) -> &[Vec<Cell>] {
+    let end = rows
+        .iter()
+        .rposition(|row| !row.iter().all(is_blank))
+        .map_or(0, |i| i + 1);
+    &rows[..end]
+}
+
+/// One row as text with SGR escapes, without trailing blanks, ending in a
+/// reset (if styled) and a newline.
+fn ansi_row(row: &[Cell]) -> String {
+    let end = row
+        .iter()
+        .rposition(|cell| !is_blank(cell))
+        .map_or(0, |i| i + 1);
+    let mut out = String::new();
+    let mut current = String::new();
+    for cell in &row[..end] {
+        let style = sgr(cell);
+        if style != current {
+            let _ = write!(out, "\x1b[0{style}m");
+            current = style;
+        }
+        out.push_str(if cell.text.is_empty() {
+            " "
+        } else {
+            &cell.text
+        });
+    }
+    if !current.is_empty() {
+        out.push_str("\x1b[0m");
+    }
+    out.push('\n');
+    out
+}
+
+/// SGR parameters for a cell's style, each prefixed with `;`; empty for the
+/// default style.
+fn sgr(cell: &Cell) -> String {
+    let mut params = String::new();
+    for (on, code) in [
+        (cell.bold, 1),
+        (cell.italic, 3),
+        (cell.underline, 4),
+        (cell.inverse, 7),
+    ] {
+        if on {
+            let _ = write!(params, ";{code}");
+        }
+    }
+    if let Some(fg) = cell.fg {
+        color(&mut params, fg, 30);
+    }
+    if let Some(bg) = cell.bg {
+        color(&mut params, bg, 40);
+    }
+    params
+}
+
+/// Append a color: `base` is 30 for foreground, 40 for background.
+fn color(params: &mut String, color: Color, base: u8) {
+    let _ = match color {
+        Color::Indexed(i @ 0..8) => write!(params, ";{}", base + i),
+        Color::Indexed(i @ 8..16) => write!(params, ";{}", base + 60 + i - 8),
+        Color::Indexed(i) => write!(params, ";{};5;{i}", base + 8),
+        Color::Rgb(r, g, b) => write!(params, ";{};2;{r};{g};{b}", base + 8),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tap_client::testing::MockSession;
+
+    fn cells(text: &str, style: Cell) -> Vec<Cell> {
+        text.chars()
+            .map(|c| Cell {
+                text: c.to_string(),
+                ..style.clone()
+            })
+            .collect()
+    }
+
+    fn session() -> MockSession {
+        let red = Cell {
+            fg: Some(Color::Indexed(1)),
+            bold: true,
+            ..Cell::default()
+        };
+        let mut error = cells("error", red);
+        error.extend(cells(": disk full   ", Cell::default()));
+        let prompt = cells("$ ", Cell::default());
+        MockSession::new()
+            .scrollback("make\nerror: disk full\n$")
+            .grid(vec![
+                cells("make", Cell::default()),
+                error,
+                prompt,
+                cells("    ", Cell::default()),
+            ])
+    }
+
+    #[tokio::test]
+    async fn test_save_formats() {
+        let server = session().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+
+        let plain = render(&mut client, SaveFormat::Plain, None).await.unwrap();
+        assert_eq!(plain, "make\nerror: disk full\n$\n");
+
+        let ansi = render(&mut client, SaveFormat::Ansi, None).await.unwrap();
+        assert_eq!(ansi, "make\n\x1b[0;1;31merror\x1b[0m: disk full\n$\n");
+
+        let json = render(&mut client, SaveFormat::Json, None).await.unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["session"], "mock");
+        assert_eq!(value["lines"], json!(["make", "error: disk full", "$"]));
+    }
+
+    #[tokio::test]
+    async fn test_save_last_lines() {
+        let server = session().spawn().unwrap();
+        let mut client = server.connect().await.unwrap();
+
+        let plain = render(&mut client, SaveFormat::Plain, Some(2))
+            .await
+            .unwrap();
+        assert_eq!(plain, "error: disk full\n$\n");
+        let ansi = render(&mut client, SaveFormat::Ansi, Some(1))
+            .await
+            .unwrap();
+        assert_eq!(ansi, "$\n");
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("ansi".parse(), Ok(SaveFormat::Ansi));
+        assert!("html".parse::<SaveFormat>().is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Scripted runs against a session (`tap scenario`): type text and keys,
+//! wait for output, pause, one step after another.
+//!
+//! A scenario file is a list of steps, in YAML or JSON:
+//!
+//! ```yaml
+//! - inject: "make test\n"
+//! - wait: "passed|failed"
+//! - key: ctrl-c
+//! - delay_ms: 200
+//! - wait: { pattern: '\$ $', timeout_ms: 30000 }
+//! ```
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use regex::Regex;
+use serde::Deserialize;
+use tap_client::Client;
+
+/// How long a `wait` step waits unless it says otherwise.
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One step as written in the file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Step {
+    /// Type text as is.
+    Inject(String),
+    /// Press a key by name, e.g. `enter` or `ctrl-c`.
+    Key(String),
+    /// Wait for output matching a regex.
+    Wait(WaitStep),
+    /// Pause for this many milliseconds.
+    DelayMs(u64),
+}
+
+/// A `wait` step: just the pattern, or the pattern and a timeout.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+enum WaitStep {
+    Pattern(String),
+    WithTimeout {
+        pattern: String,
+        timeout_ms: Option<u64>,
+    },
+}
+
+/// A step ready to run.
+#[derive(Debug)]
+enum Action {
+    Inject(String),
+    Key(String),
+    Wait { pattern: Regex, timeout: Duration },
+    Delay(Duration),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Inject(text) => write!(f, "inject {text:?}"),
+            Action::Key(name) => write!(f, "key {name}"),
+            Action::Wait { pattern, .. } => write!(f, "wait for /{pattern}/"),
+            Action::Delay(delay) => write!(f, "delay {}ms", delay.as_millis()),
+        }
+    }
+}
+
+/// A parsed scenario: keys resolved and patterns compiled, so a bad step is
+/// reported before anything is sent.
+#[derive(Debug)]
+pub struct Scenario {
+    actions: Vec<Action>,
+}
+
+impl Scenario {
+    /// Read a scenario file: JSON if it ends in `.json`, YAML otherwise.
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| eyre::eyre!("failed to read {}: {e}", path.display()))?;
+        let json = path.extension().is_some_and(|ext| ext == "json");
+        Self::parse(&text, json).map_err(|e| eyre::eyre!("{}: {e}", path.display()))
+    }
+
+    fn parse(text: &str, json: bool) -> eyre::Result<Self> {
+        // serde_yaml only reads enums written as `!tag`, so YAML goes
+        // through a plain value to accept the `- inject: ...` form.
+        let steps: Vec<Step> = if json {
+            serde_json::from_str(text)?
+        } else {
+            serde_json::from_value(serde_yaml::from_str(text)?)?
+        };
+        let actions = steps
+            .into_iter()
+            .enumerate()
+            .map(|(i, step)| action(step).map_err(|e| eyre::eyre!("step {}: {e}", i + 1)))
+            .collect::<eyre::Result<_>>()?;
+        Ok(Self { actions })
+    }
+
+    pub fn step_count(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Run the steps in order: typing through `control`, watching output
+    /// through `output`, which must already be subscribed. A wait only
+    /// matches output after what the previous wait matched. Fails at the
+    /// first step that times out or can't complete, naming it.
+    pub async fn run(&self, control: &mut Client, output: &mut Client) -> eyre::Result<()> {
+        let mut watch = OutputWatch::default();
+        for (i, action) in self.actions.iter().enumerate() {
+            let fail = |e: String| eyre::eyre!("step {} ({action}) failed: {e}", i + 1);
+            match action {
+                Action::Inject(text) => {
+                    control
+                        .inject(text)
+                        .await
+                        .map_err(|e| fail(e.to_string()))?;
+                }
+                Action::Key(name) => {
+                    // The arrows depend on the mode the program has set
+                    let modes = control
+                        .get_input_modes()
+                        .await
+                        .map_err(|e| fail(e.to_string()))?;
+                    let bytes = key_bytes(name, modes.application_cursor)
+                        .ok_or_else(|| fail(format!("unknown key: {name}")))?;
+                    control
+                        .inject(&bytes)
+                        .await
+                        .map_err(|e| fail(e.to_string()))?;
+                }
+                Action::Wait { pattern, timeout } => {
+                    watch.wait(output, pattern, *timeout).await.map_err(fail)?;
+                }
+                Action::Delay(delay) => tokio::time::sleep(*delay).await,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn action(step: Step) -> Result<Action, String> {
+    Ok(match step {
+        Step::Inject(text) => Action::Inject(text),
+        Step::Key(name) => {
+            if key_bytes(&name, false).is_none() {
+                return Err(format!("unknown key: {name}"));
+            }
+            Action::Key(name)
+        }
+        Step::Wait(wait) => {
+            let (pattern, timeout_ms) = match wait {
+                WaitStep::Pattern(pattern) => (pattern, None),
+                WaitStep::WithTimeout {
+                    pattern,
+                    timeout_ms,
+                } => (pattern, timeout_ms),
+            };
+            Action::Wait {
+                pattern: Regex::new(&pattern).map_err(|e| e.to_string())?,
+                timeout: timeout_ms.map_or(DEFAULT_WAIT_TIMEOUT, Duration::from_millis),
+            }
+        }
+        Step::DelayMs(ms) => Action::Delay(Duration::from_millis(ms)),
+    })
+}
+
+/// What a terminal sends for the named key: `enter`, `tab`, `escape`,
+/// `backspace`, `space`, the arrows, `home`, `end`, or `ctrl-<letter>`.
+/// With `application_cursor` (DECCKM) set, the arrows, `home`, and `end`
+/// send `ESC O` sequences instead of `ESC [` ones.
+fn key_bytes(name: &str, application_cursor: bool) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    let cursor_key = match name.as_str() {
+        "up" => Some('A'),
+        "down" => Some('B'),
+        "right" => Some('C'),
+        "left" => Some('D'),
+        "home" => Some('H'),
+        "end" => Some('F'),
+        _ => None,
+    };
+    if let Some(key) = cursor_key {
+        let intro = if application_cursor { "\x1bO" } else { "\x1b[" };
+        return Some(format!("{intro}{key}"));
+    }
+    let bytes = match name.as_str() {
+        "enter" | "return" => "\r",
+        "tab" => "\t",
+        "escape" | "esc" => "\x1b",
+        "backspace" => "\x7f",
+        "space" => " ",
+        _ => {
+            let &[letter] = name.strip_prefix("ctrl-")?.as_bytes() else {
+                return None;
+            };
+            if !letter.is_ascii_lowercase() {
+                return None;
+            }
+            return Some(char::from(letter & 0x1f).to_string());
+        }
+    };
+    Some(bytes.to_string())
+}
+
+/// Output seen so far, and how much of it earlier waits used up.
+#[derive(Debug, Default)]
+struct OutputWatch {
+    raw: Vec<u8>,
+    /// Offset into the escape-stripped text.
+    consumed: usize,
+}
+
+impl OutputWatch {
+    /// The text not yet matched, without escape sequences.
+    fn unmatched(&self) -> String {
+        let text = tap_client::strip_ansi(&String::from_utf8_lossy(&self.raw));
+        text.get(self.consumed..).unwrap_or_default().to_string()
+    }
+
+    /// Mark output up to the end of the first match of `pattern` as used,
+    /// if there is one.
+    fn take_match(&mut self, pattern: &Regex) -> bool {
+        let text = self.unmatched();
+        let Some(found) = pattern.find(&text) else {
+            return false;
+        };
+        self.consumed += found.end();
+        true
+    }
+
+    async fn wait(
+        &mut self,
+        output: &mut Client,
+        pattern: &Regex,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.take_match(pattern) {
+            match tokio::time::timeout_at(deadline, output.read_output()).await {
+                Ok(Ok(Some(data))) => self.raw.extend_from_slice(&data),
+                Ok(Ok(None)) => return Err("session ended".to_string()),
+                Ok(Err(e)) => return Err(e.to_string()),
+                Err(_) => {
+                    return Err(format!(
+                        "timed out after {}ms; output since the last match: {:?}",
+                        timeout.as_millis(),
+                        self.unmatched()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_yaml_and_json() {
+        let yaml = "
+- inject: \"echo hi\\n\"
+- key: Ctrl-C
+- wait: hi
+- wait: { pattern: '\\$ $', timeout_ms: 500 }
+- delay_ms: 20
+";
+        let scenario = Scenario::parse(yaml, false).unwrap();
+        let steps: Vec<String> = scenario.actions.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            steps,
+            [
+                "inject \"echo hi\\n\"",
+                "key Ctrl-C",
+                "wait for /hi/",
+                "wait for /\\$ $/",
+                "delay 20ms"
+            ]
+        );
+        assert!(matches!(&scenario.actions[1], Action::Key(name) if name == "Ctrl-C"));
+        assert!(matches!(
+            scenario.actions[3],
+            Action::Wait { timeout, .. } if timeout == Duration::from_millis(500)
+        ));
+
+        let json = r#"[{"inject": "ls\n"}, {"wait": {"pattern": "x"}}]"#;
+        let scenario = Scenario::parse(json, true).unwrap();
+        assert_eq!(scenario.step_count(), 2);
+        assert!(matches!(
+            scenario.actions[1],
+            Action::Wait { timeout, .. } if timeout == DEFAULT_WAIT_TIMEOUT
+        ));
+    }
+
+    #[test]
+    fn test_bad_step_is_reported_up_front() {
+        let err = Scenario::parse("- inject: a\n- key: hyper-x\n", false).unwrap_err();
+        assert_eq!(err.to_string(), "step 2: unknown key: hyper-x");
+        let err = Scenario::parse("- wait: '('\n", false).unwrap_err();
+        assert!(
+            err.to_string().starts_with("step 1: regex parse error"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_arrows_follow_application_cursor_mode() {
+        assert_eq!(key_bytes("up", false).as_deref(), Some("\x1b[A"));
+        assert_eq!(key_bytes("Left", false).as_deref(), Some("\x1b[D"));
+        assert_eq!(key_bytes("up", true).as_deref(), Some("\x1bOA"));
+        assert_eq!(key_bytes("end", true).as_deref(), Some("\x1bOF"));
+        // Other keys are the same in both modes
+        assert_eq!(key_bytes("ctrl-c", true).as_deref(), Some("\x03"));
+        assert_eq!(key_bytes("enter", true).as_deref(), Some("\r"));
+    }
+
+    #[tokio::test]
+    async fn test_injected_arrows_change_with_the_mode() {
+        let scenario = Scenario::parse("- key: up\n- key: enter\n", false).unwrap();
+        for (application_cursor, up) in [(false, "\x1b[A"), (true, "\x1bOA")] {
+            let server = tap_client::testing::MockSession::new()
+                .input_modes(tap_client::InputModes {
+                    application_cursor,
+                    ..Default::default()
+                })
+                .spawn()
+                .unwrap();
+            let mut control = server.connect().await.unwrap();
+            let mut output = server.connect().await.unwrap();
+            scenario.run(&mut control, &mut output).await.unwrap();
+            assert_eq!(server.injected(), [up, "\r"]);
+        }
+    }
+
+    #[test]
+    fn test_waits_consume_output() {
+        let mut watch = OutputWatch {
+            raw: b"\x1b[32mok\x1b[0m 1\r\nok 2\r\n".to_vec(),
+            consumed: 0,
+        };
+        let ok = Regex::new(r"ok \d").unwrap();
+        assert!(watch.take_match(&ok));
+        assert!(watch.take_match(&ok));
+        assert!(!watch.take_match(&ok));
+        assert_eq!(watch.unmatched(), "\r\n");
+    }
+}
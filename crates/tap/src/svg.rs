@@ -0,0 +1,211 @@
+//! SVG rendering of a session's screen (`tap export-svg`), for embedding
+//! terminal output in docs and bug reports.
+
+use std::fmt::Write;
+
+use tap_client::{Cell, Snapshot};
+
+use crate::html::{Theme, css_color, escape};
+
+/// A run of cells as drawn: inverse applied, default colors filled in.
+#[derive(Debug, PartialEq)]
+struct Style {
+    fg: String,
+    /// `None` for the page background.
+    bg: Option<String>,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+}
+
+impl Style {
+    fn of(cell: &Cell, theme: Theme) -> Self {
+        let (default_fg, default_bg) = theme.colors();
+        let (mut fg, mut bg) = (cell.fg.map(css_color), cell.bg.map(css_color));
+        if cell.inverse {
+            (fg, bg) = (
+                Some(bg.unwrap_or_else(|| default_bg.to_string())),
+                Some(fg.unwrap_or_else(|| default_fg.to_string())),
+            );
+        }
+        Self {
+            fg: fg.unwrap_or_else(|| default_fg.to_string()),
+            bg,
+            bold: cell.bold,
+            italic: cell.italic,
+            underline: cell.underline,
+        }
+    }
+}
+
+/// A length in tenths of a pixel, written without a needless `.0`.
+fn px(tenths: u32) -> String {
+    match tenths % 10 {
+        0 => (tenths / 10).to_string(),
+        frac => format!("{}.{frac}", tenths / 10),
+    }
+}
+
+/// The screen as an SVG image: a cell is `0.6 * font_size` wide and
+/// `1.2 * font_size` tall, with a margin of one `font_size` all round.
+/// Backgrounds are rectangles under runs of text in one style.
+pub fn render(snapshot: &Snapshot, theme: Theme, font_size: u32) -> String {
+    let (cell_w, cell_h, margin) = (font_size * 6, font_size * 12, font_size * 10);
+    let (rows, cols) = (u32::from(snapshot.size.0), u32::from(snapshot.size.1));
+    let (width, height) = (2 * margin + cols * cell_w, 2 * margin + rows * cell_h);
+    let (_, page_bg) = theme.colors();
+
+    let mut backgrounds = String::new();
+    let mut text = String::new();
+    for (y, row) in (0..).zip(&snapshot.rows) {
+        let styles: Vec<Style> = row.iter().map(|cell| Style::of(cell, theme)).collect();
+        let mut start = 0;
+        while start < row.len() {
+            let len = styles[start..]
+                .iter()
+                .take_while(|style| **style == styles[start])
+                .count();
+            let style = &styles[start];
+            let x = margin + start as u32 * cell_w;
+            let top = margin + y * cell_h;
+            if let Some(bg) = &style.bg {
+                let _ = writeln!(
+                    backgrounds,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{bg}\"/>",
+                    px(x),
+                    px(top),
+                    px(len as u32 * cell_w),
+                    px(cell_h)
+                );
+            }
+
+            let run: String = row[start..start + len]
+                .iter()
+                .map(|cell| match cell.text.as_str() {
+                    "" => " ",
+                    text => text,
+                })
+                .collect();
+            if !run.trim().is_empty() {
+                let _ = write!(
+                    text,
+                    "<text x=\"{}\" y=\"{}\" fill=\"{}\"",
+                    px(x),
+                    px(top + font_size * 10),
+                    style.fg
+                );
+                if style.bold {
+                    text.push_str(" font-weight=\"bold\"");
+                }
+                if style.italic {
+                    text.push_str(" font-style=\"italic\"");
+                }
+                if style.underline {
+                    text.push_str(" text-decoration=\"underline\"");
+                }
+                text.push('>');
+                escape(run.trim_end(), &mut text);
+                text.push_str("</text>\n");
+            }
+            start += len;
+        }
+    }
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\" font-family=\"ui-monospace, Menlo, Consolas, monospace\" \
+         font-size=\"{font_size}\" xml:space=\"preserve\">",
+        w = px(width),
+        h = px(height),
+    );
+    let _ = writeln!(
+        svg,
+        "<rect width=\"100%\" height=\"100%\" fill=\"{page_bg}\"/>"
+    );
+    svg.push_str(&backgrounds);
+    svg.push_str(&text);
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use tap_client::Color;
+
+    use super::*;
+
+    fn cells(text: &str, style: Cell) -> Vec<Cell> {
+        text.chars()
+            .map(|c| Cell {
+                text: c.to_string(),
+                ..style.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_text_runs_and_fills() {
+        let red = Cell {
+            fg: Some(Color::Indexed(1)),
+            bold: true,
+            ..Cell::default()
+        };
+        let blue_bg = Cell {
+            bg: Some(Color::Indexed(4)),
+            ..Cell::default()
+        };
+        let mut first = cells("err", red);
+        first.extend(cells(" ", Cell::default()));
+        first.extend(cells("ok", blue_bg));
+        first.extend(cells(" a<b", Cell::default()));
+        let snapshot = Snapshot {
+            size: (2, 10),
+            cursor: (1, 0),
+            rows: vec![first, vec![Cell::default(); 10]],
+        };
+
+        let svg = render(&snapshot, Theme::Dark, 10);
+        // 6px wide cells, 12px tall, 10px margin
+        assert!(svg.starts_with(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"80\" height=\"44\" viewBox=\"0 0 80 44\""
+        ));
+        assert!(svg.contains("<rect width=\"100%\" height=\"100%\" fill=\"#1e1e1e\"/>"));
+        assert!(
+            svg.contains("<rect x=\"34\" y=\"10\" width=\"12\" height=\"12\" fill=\"#0000ee\"/>")
+        );
+        assert!(
+            svg.contains(
+                "<text x=\"10\" y=\"20\" fill=\"#cd0000\" font-weight=\"bold\">err</text>"
+            )
+        );
+        assert!(svg.contains("<text x=\"34\" y=\"20\" fill=\"#d4d4d4\">ok</text>"));
+        assert!(svg.contains("<text x=\"46\" y=\"20\" fill=\"#d4d4d4\"> a&lt;b</text>"));
+        // Blank runs draw nothing
+        assert_eq!(svg.matches("<text").count(), 3);
+    }
+
+    #[test]
+    fn test_fractional_metrics_and_light_inverse() {
+        let snapshot = Snapshot {
+            size: (1, 2),
+            cursor: (0, 0),
+            rows: vec![cells(
+                "x",
+                Cell {
+                    inverse: true,
+                    ..Cell::default()
+                },
+            )],
+        };
+        let svg = render(&snapshot, Theme::Light, 14);
+        assert!(svg.contains("width=\"44.8\" height=\"44.8\""));
+        assert!(
+            svg.contains(
+                "<rect x=\"14\" y=\"14\" width=\"8.4\" height=\"16.8\" fill=\"#1e1e1e\"/>"
+            )
+        );
+        assert!(svg.contains("<text x=\"14\" y=\"28\" fill=\"#ffffff\">x</text>"));
+    }
+}
@@ -0,0 +1,553 @@
+//! End-to-end tests against a real `tap` session.
+
+use std::time::Duration;
+
+use tap_client::testing::spawn_session_with;
+
+const TAP: &str = env!("CARGO_BIN_EXE_tap");
+
+#[tokio::test]
+async fn injected_command_reaches_scrollback() {
+    let (_session, mut client) = spawn_session_with(TAP, &["sh"]).await.unwrap();
+
+    client.inject("echo spawned-$((40 + 2))\n").await.unwrap();
+
+    let mut scrollback = String::new();
+    for _ in 0..100 {
+        scrollback = client.get_scrollback(None).await.unwrap();
+        if scrollback.contains("spawned-42") {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("output never arrived: {scrollback:?}");
+}
+
+#[tokio::test]
+async fn move_cursor_reaches_a_raw_mode_app() {
+    // In raw mode without echo, cat hands the sequences straight back to the
+    // terminal, as a full-screen app would act on them
+    let (_session, mut client) =
+        spawn_session_with(TAP, &["sh", "-c", "stty raw -echo; echo ready; cat"])
+            .await
+            .unwrap();
+    for _ in 0..100 {
+        if client.get_scrollback(None).await.unwrap().contains("ready") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    client.move_cursor(4, 10).await.unwrap();
+    wait_for_cursor(&mut client, |cursor| cursor == (4, 10)).await;
+    client.inject_at(2, 3, "hi").await.unwrap();
+    wait_for_cursor(&mut client, |cursor| cursor == (2, 5)).await;
+    let scrollback = client.get_scrollback(None).await.unwrap();
+    assert_eq!(scrollback.lines().nth(2), Some("   hi"), "{scrollback:?}");
+}
+
+#[tokio::test]
+async fn scenario_runs_steps_and_reports_a_timed_out_wait() {
+    let (session, _client) = spawn_session_with(TAP, &["sh"]).await.unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let run = |steps: &str| {
+        let file = dir.path().join("steps.yaml");
+        std::fs::write(&file, steps).unwrap();
+        tokio::process::Command::new(TAP)
+            .args(["scenario", "--session", session.id()])
+            .arg(&file)
+            .env("TAP_SOCKET_DIR", session.socket_dir())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap()
+            .wait_with_output()
+    };
+
+    let passed = run("- inject: \"echo scenario-$((40 + 2))\"\n\
+                      - key: enter\n\
+                      - wait: scenario-42\n")
+    .await
+    .unwrap();
+    assert!(
+        passed.status.success(),
+        "{}",
+        String::from_utf8_lossy(&passed.stderr)
+    );
+
+    let failed = run("- inject: \"echo one\\n\"\n\
+                      - wait: { pattern: never-printed, timeout_ms: 300 }\n")
+    .await
+    .unwrap();
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(
+        stderr.contains("step 2 (wait for /never-printed/) failed: timed out after 300ms"),
+        "{stderr}"
+    );
+}
+
+#[tokio::test]
+async fn scenario_arrows_follow_application_cursor_mode() {
+    let (session, _client) = spawn_session_with(TAP, &["sh"]).await.unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("steps.yaml");
+    // The tty echoes what the arrow sent, ESC shown as `^[`
+    std::fs::write(
+        &file,
+        r#"- inject: "printf '\\033[?1h'; echo app-$((40 + 2)); cat >/dev/null\n"
+- wait: app-42
+- key: up
+- wait: '\^\[OA'
+"#,
+    )
+    .unwrap();
+    let output = tokio::process::Command::new(TAP)
+        .args(["scenario", "--session", session.id()])
+        .arg(&file)
+        .env("TAP_SOCKET_DIR", session.socket_dir())
+        .output()
+        .await
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[tokio::test]
+async fn long_commands_are_capped_in_the_index_only() {
+    let here_doc = "x".repeat(10_000);
+    let mut command = vec!["sh", "-c", "sleep 30", "sh", here_doc.as_str()];
+    command.extend(std::iter::repeat_n("arg", 100));
+    let (session, mut client) = spawn_session_with(TAP, &command).await.unwrap();
+
+    let index = std::fs::read_to_string(session.socket_dir().join("sessions.json")).unwrap();
+    let index: Vec<tap_client::Session> = serde_json::from_str(&index).unwrap();
+    let indexed = &index[0].command;
+    assert_eq!(indexed.len(), 33);
+    assert!(indexed[4].len() < 1_000 && indexed[4].ends_with('…'));
+    assert_eq!(indexed[32], "… (73 more)");
+
+    let live = client.get_session_info().await.unwrap().session.command;
+    assert_eq!(live, command);
+}
+
+#[tokio::test]
+async fn short_ids_are_unique_and_resolve() {
+    let (first, _client) = spawn_session_with(TAP, &["sleep", "30"]).await.unwrap();
+    let dir = first.socket_dir().to_path_buf();
+    let mut second = std::process::Command::new(TAP)
+        .args(["start", "--no-local-input", "--", "sleep", "30"])
+        .env("TAP_SOCKET_DIR", &dir)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut sessions = Vec::new();
+    for _ in 0..100 {
+        sessions = tap_client::list_sessions_in(&dir).unwrap();
+        if sessions.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(sessions.len(), 2, "{sessions:?}");
+    let codes: Vec<&str> = sessions
+        .iter()
+        .map(|session| session.short_id.as_deref().expect("no short id"))
+        .collect();
+    assert_ne!(codes[0], codes[1]);
+    assert!(codes.iter().all(|code| code.len() >= 6));
+
+    for (session, code) in sessions.iter().zip(&codes) {
+        let output = std::process::Command::new(TAP)
+            .args(["info", "-s", code])
+            .env("TAP_SOCKET_DIR", &dir)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            stdout.contains(&format!("id: {}\n", session.id)),
+            "{stdout}"
+        );
+        assert!(stdout.contains(&format!("short id: {code}\n")), "{stdout}");
+    }
+
+    let _ = second.kill();
+    let output = second.wait_with_output().unwrap();
+    let banner = String::from_utf8_lossy(&output.stdout);
+    let second_code = sessions
+        .iter()
+        .find(|session| session.id != first.id())
+        .and_then(|session| session.short_id.clone())
+        .unwrap();
+    assert!(
+        banner.contains(&format!("or {second_code} for short")),
+        "{banner}"
+    );
+}
+
+#[tokio::test]
+async fn queries_are_answered_headlessly() {
+    // Reads the 6-byte report for row 1, column 3 without waiting for a newline
+    let script = r#"stty raw -echo; printf 'ab\033[6n'; reply=$(dd bs=1 count=6 2>/dev/null)
+stty sane; [ "$reply" = "$(printf '\033[1;3R')" ] && echo CPR-OK || echo CPR-BAD; sleep 1"#;
+    let dir = tempfile::tempdir().unwrap();
+    let child = std::process::Command::new(TAP)
+        .args([
+            "start",
+            "--answer-queries",
+            "--no-local-output",
+            "--no-local-input",
+        ])
+        .args(["--", "sh", "-c", script])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut scrollback = String::new();
+    for _ in 0..100 {
+        if let Some(session) = tap_client::list_sessions_in(dir.path()).unwrap().first()
+            && let Ok(mut client) =
+                tap_client::Client::connect_path(dir.path().join(format!("{}.sock", session.id)))
+                    .await
+        {
+            scrollback = client.get_scrollback(None).await.unwrap();
+            if scrollback.contains("CPR-") {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(scrollback.contains("CPR-OK"), "{scrollback:?}");
+    child.wait_with_output().unwrap();
+}
+
+#[tokio::test]
+async fn term_overrides_the_inherited_value() {
+    let dir = tempfile::tempdir().unwrap();
+    let child = std::process::Command::new(TAP)
+        .args([
+            "start",
+            "--term",
+            "xterm-256color",
+            "--no-local-output",
+            "--no-local-input",
+        ])
+        .args(["--", "sh", "-c", "echo \"term=$TERM\"; sleep 1"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .env("TERM", "dumb")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut scrollback = String::new();
+    for _ in 0..100 {
+        if let Some(session) = tap_client::list_sessions_in(dir.path()).unwrap().first()
+            && let Ok(mut client) =
+                tap_client::Client::connect_path(dir.path().join(format!("{}.sock", session.id)))
+                    .await
+        {
+            scrollback = client.get_scrollback(None).await.unwrap();
+            if scrollback.contains("term=") {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(scrollback.contains("term=xterm-256color"), "{scrollback:?}");
+    child.wait_with_output().unwrap();
+}
+
+#[tokio::test]
+async fn shared_input_streams_typed_keys() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut child = std::process::Command::new(TAP)
+        .args(["start", "--share-input", "--no-local-output", "--", "cat"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut watcher = None;
+    for _ in 0..100 {
+        if let Some(session) = tap_client::list_sessions_in(dir.path()).unwrap().first()
+            && let Ok(client) =
+                tap_client::Client::connect_path(dir.path().join(format!("{}.sock", session.id)))
+                    .await
+        {
+            watcher = Some(client);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut watcher = watcher.expect("session never came up");
+    watcher.subscribe_input().await.unwrap();
+
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"typed-keys\n").unwrap();
+    let mut typed = Vec::new();
+    while !typed.ends_with(b"typed-keys\n") {
+        let data = tokio::time::timeout(Duration::from_secs(5), watcher.read_input())
+            .await
+            .expect("typed bytes never arrived")
+            .unwrap()
+            .expect("input stream ended");
+        typed.extend(data);
+    }
+
+    // Ctrl-D ends `cat`, and with it the session
+    stdin.write_all(b"\x04").unwrap();
+    child.wait().unwrap();
+}
+
+#[tokio::test]
+async fn waiting_for_a_client_misses_no_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let child = std::process::Command::new(TAP)
+        .args([
+            "start",
+            "--wait-for-client",
+            "--no-local-output",
+            "--no-local-input",
+        ])
+        .args(["--", "sh", "-c", "echo first-byte"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut client = None;
+    for _ in 0..100 {
+        if let Some(session) = tap_client::list_sessions_in(dir.path()).unwrap().first()
+            && let Ok(c) =
+                tap_client::Client::connect_path(dir.path().join(format!("{}.sock", session.id)))
+                    .await
+        {
+            client = Some(c);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut client = client.expect("session never came up");
+    // Listed and connectable, but the command hasn't run yet
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(client.get_scrollback(None).await.unwrap(), "");
+
+    client.subscribe().await.unwrap();
+    let mut output = Vec::new();
+    while let Some(data) = tokio::time::timeout(Duration::from_secs(5), client.read_output())
+        .await
+        .expect("output never arrived")
+        .unwrap()
+    {
+        output.extend(data);
+    }
+    assert!(
+        String::from_utf8_lossy(&output).starts_with("first-byte"),
+        "{:?}",
+        String::from_utf8_lossy(&output)
+    );
+    child.wait_with_output().unwrap();
+}
+
+#[test]
+fn wait_for_client_times_out_without_a_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = std::process::Command::new(TAP)
+        .args(["start", "--wait-for-client", "--wait-timeout", "100"])
+        .args(["--no-local-input", "--", "echo", "never"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("no client subscribed within 100 ms"),
+        "{stderr}"
+    );
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("never"));
+    assert!(tap_client::list_sessions_in(dir.path()).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn sigterm_while_waiting_for_a_client_removes_the_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut child = std::process::Command::new(TAP)
+        .args(["start", "--wait-for-client", "--no-local-input"])
+        .args(["--", "echo", "never"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if !tap_client::list_sessions_in(dir.path()).unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(!tap_client::list_sessions_in(dir.path()).unwrap().is_empty());
+
+    std::process::Command::new("kill")
+        .arg(child.id().to_string())
+        .status()
+        .unwrap();
+    let status = child.wait().unwrap();
+    assert!(status.success(), "{status}");
+    assert!(tap_client::list_sessions_in(dir.path()).unwrap().is_empty());
+}
+
+#[test]
+fn bad_output_fifo_fails_before_the_session_starts() {
+    let dir = tempfile::tempdir().unwrap();
+    let not_a_fifo = dir.path().join("plain");
+    std::fs::write(&not_a_fifo, "").unwrap();
+    let output = std::process::Command::new(TAP)
+        .args(["start", "--no-local-input", "--output-fifo"])
+        .arg(&not_a_fifo)
+        .args(["--", "echo", "never"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is not a FIFO"), "{stderr}");
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("never"));
+    assert!(tap_client::list_sessions_in(dir.path()).unwrap().is_empty());
+}
+
+async fn wait_for_cursor(client: &mut tap_client::Client, done: impl Fn((usize, usize)) -> bool) {
+    let mut cursor = (0, 0);
+    for _ in 0..100 {
+        cursor = client.get_cursor().await.unwrap();
+        if done(cursor) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("cursor stayed at {cursor:?}");
+}
+
+#[tokio::test]
+async fn no_local_output_still_feeds_clients() {
+    let dir = tempfile::tempdir().unwrap();
+    let child = std::process::Command::new(TAP)
+        .args(["start", "--no-local-output", "--no-local-input", "--"])
+        .args(["sh", "-c", "echo headless-$((40 + 2)); sleep 1"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut scrollback = String::new();
+    for _ in 0..100 {
+        if let Some(session) = tap_client::list_sessions_in(dir.path()).unwrap().first()
+            && let Ok(mut client) =
+                tap_client::Client::connect_path(dir.path().join(format!("{}.sock", session.id)))
+                    .await
+        {
+            scrollback = client.get_scrollback(None).await.unwrap();
+            if scrollback.contains("headless-42") {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(scrollback.contains("headless-42"), "{scrollback:?}");
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(
+        output.stdout.is_empty(),
+        "{:?}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[tokio::test]
+async fn recent_chunks_are_ordered() {
+    let (_session, mut client) = spawn_session_with(TAP, &["sh"]).await.unwrap();
+    for i in 0..3 {
+        client.inject(&format!("echo chunk-{i}\n")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let chunks = client.get_recent_chunks(2).await.unwrap();
+    assert_eq!(chunks.len(), 2);
+    let all = client.get_recent_chunks(1000).await.unwrap();
+    assert!(all.len() >= 3, "{all:?}");
+    assert!(all.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    assert!(all.iter().all(|&(_, len)| len > 0));
+}
+
+#[tokio::test]
+async fn wait_exit_returns_the_exit_code() {
+    let (_session, mut client) = spawn_session_with(TAP, &["sh", "-c", "sleep 0.3; exit 3"])
+        .await
+        .unwrap();
+
+    let (code, signal) = client
+        .wait_exit(Some(Duration::from_secs(10)))
+        .await
+        .unwrap();
+    assert_eq!(code, Some(3));
+    assert_eq!(signal, None);
+}
+
+#[tokio::test]
+async fn dropping_the_guard_removes_the_session() {
+    let (session, _client) = spawn_session_with(TAP, &["sleep", "30"]).await.unwrap();
+    let dir = session.socket_dir().to_path_buf();
+    assert!(dir.join(format!("{}.sock", session.id())).exists());
+
+    drop(session);
+    assert!(!dir.exists());
+}
+
+#[test]
+fn missing_command_exits_127_without_a_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = std::process::Command::new(TAP)
+        .args(["start", "--", "tap-no-such-command"])
+        .env("TAP_SOCKET_DIR", dir.path())
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(127));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("tap-no-such-command: No such file or directory"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    assert!(tap_client::list_sessions_in(dir.path()).unwrap().is_empty());
+    let sockets = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sock".as_ref()))
+        .count();
+    assert_eq!(sockets, 0);
+}